@@ -6,7 +6,7 @@ extern crate log;
 
 extern crate error_chain;
 
-use electrs::{config::Config, errors::*, store::DBStore};
+use electrs::{cipher::Cipher, config::Config, errors::*, store::DBStore};
 
 use error_chain::ChainedError;
 
@@ -17,7 +17,12 @@ fn run(config: Config) -> Result<()> {
             config.db_path
         );
     }
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ true);
+    let store = DBStore::open_shards(
+        &config.db_path,
+        &config.db_shard_paths,
+        /*low_memory=*/ true,
+        Cipher::from_config(&config)?,
+    );
     store.compact();
     Ok(())
 }
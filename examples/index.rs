@@ -7,7 +7,7 @@ extern crate log;
 
 use electrs::{
     cache::BlockTxIDsCache, config::Config, daemon::Daemon, errors::*, fake::FakeStore,
-    index::Index, metrics::Metrics, signal::Waiter,
+    hooks::Hooks, index::Index, metrics::Metrics, signal::Waiter,
 };
 use error_chain::ChainedError;
 use std::sync::Arc;
@@ -27,6 +27,7 @@ fn run() -> Result<()> {
         config.network_type,
         signal.clone(),
         cache,
+        Hooks::from_config(&config),
         &metrics,
     )?;
     let fake_store = FakeStore {};
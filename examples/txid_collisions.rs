@@ -3,7 +3,7 @@ extern crate electrs;
 extern crate hex;
 extern crate log;
 
-use electrs::{config::Config, store::DBStore};
+use electrs::{cipher::Cipher, config::Config, store::DBStore};
 
 fn max_collision(store: DBStore, prefix: &[u8]) {
     let prefix_len = prefix.len();
@@ -40,7 +40,12 @@ fn run(config: Config) {
     if !config.db_path.exists() {
         panic!("DB {:?} must exist when running this tool!", config.db_path);
     }
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ false);
+    let store = DBStore::open_shards(
+        &config.db_path,
+        &config.db_shard_paths,
+        /*low_memory=*/ false,
+        Cipher::from_config(&config).expect("failed to load encryption keys"),
+    );
     max_collision(store, b"T");
 }
 
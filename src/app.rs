@@ -1,14 +1,36 @@
-use bitcoin::hash_types::BlockHash;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::{config::Config, daemon, errors::*, index, signal::Waiter, store};
+use crate::{
+    chain::BlockHash,
+    cipher::Cipher,
+    config::Config,
+    daemon,
+    errors::*,
+    index,
+    metrics::{Gauge, MetricOpts, Metrics},
+    replicate::{ReplicatedStore, ReplicationLog, SealedBatch},
+    signal::Waiter,
+    store::{self, WriteStore},
+};
 
 pub struct App {
     store: store::DBStore,
     index: index::Index,
     daemon: daemon::Daemon,
-    banner: String,
+    banner: String, // may contain placeholders (see `render_banner`)
+    started: Instant,
+    headers_only: bool,
     tip: Mutex<BlockHash>,
+    daemon_status: Mutex<Option<String>>, // set while the daemon is syncing
+    stale_tip_threshold: Option<Duration>,
+    stale_tip_banner: bool,
+    stale_tip: Mutex<Option<String>>, // set while the best block is older than the threshold
+    out_of_sync_blocks: Option<usize>,
+    out_of_sync_age: Option<Duration>,
+    daemon_height: Mutex<Option<usize>>, // as of the last index update
+    tip_age: Gauge,
+    daemon_contact_age: Gauge,
 }
 
 impl App {
@@ -17,17 +39,49 @@ impl App {
         index: index::Index,
         daemon: daemon::Daemon,
         config: &Config,
+        metrics: &Metrics,
     ) -> Result<Arc<App>> {
         Ok(Arc::new(App {
             store,
             index,
             daemon: daemon.reconnect()?,
             banner: config.server_banner.clone(),
+            started: Instant::now(),
+            headers_only: config.headers_only,
             tip: Mutex::new(BlockHash::default()),
+            daemon_status: Mutex::new(None),
+            stale_tip_threshold: config.stale_tip_threshold,
+            stale_tip_banner: config.stale_tip_banner,
+            stale_tip: Mutex::new(None),
+            out_of_sync_blocks: config.out_of_sync_blocks,
+            out_of_sync_age: config.out_of_sync_age,
+            daemon_height: Mutex::new(None),
+            tip_age: metrics.gauge_int(MetricOpts::new(
+                "electrs_tip_age",
+                "Age of the best indexed block, by its timestamp (in seconds)",
+            )),
+            daemon_contact_age: metrics.gauge_int(MetricOpts::new(
+                "electrs_daemon_contact_age",
+                "Time since the last successful bitcoind RPC (in seconds)",
+            )),
         }))
     }
 
-    fn write_store(&self) -> &impl store::WriteStore {
+    /// Creates a consistent copy of the index at `path` (see `backup.rs`).
+    pub fn checkpoint(&self, path: &std::path::Path) -> Result<()> {
+        self.store.checkpoint(path)
+    }
+
+    /// Returns the number of persisted statuses kept (see `index::prune_statuses`).
+    pub fn prune_statuses(&self, min_height: u32) -> usize {
+        index::prune_statuses(&self.store, min_height)
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.store.cipher()
+    }
+
+    pub fn write_store(&self) -> &impl store::WriteStore {
         &self.store
     }
     // TODO: use index for queries.
@@ -40,21 +94,210 @@ impl App {
     pub fn daemon(&self) -> &daemon::Daemon {
         &self.daemon
     }
+    /// Returns true if only the block headers are indexed (see `--headers-only`).
+    pub fn headers_only(&self) -> bool {
+        self.headers_only
+    }
 
-    pub fn update(&self, signal: &Waiter) -> Result<bool> {
+    /// Indexes the new blocks (appending the written rows to the replication `log`, if set).
+    pub fn update(&self, signal: &Waiter, log: Option<&ReplicationLog>) -> Result<bool> {
+        let status = self.daemon().sync_status()?;
+        let syncing = status.is_some();
+        if let Some(ref status) = status {
+            warn!("delaying index update: {}", status);
+        }
+        *self.daemon_status.lock().unwrap() = status;
+        if syncing {
+            return Ok(false);
+        }
         let mut tip = self.tip.lock().expect("failed to lock tip");
         let new_block = *tip != self.daemon().getbestblockhash()?;
         if new_block {
-            *tip = self.index().update(self.write_store(), &signal)?;
+            if self.out_of_sync_blocks.is_some() {
+                *self.daemon_height.lock().unwrap() = Some(self.daemon().getblockcount()?);
+            }
+            *tip = match log {
+                Some(log) => self
+                    .index()
+                    .update(&ReplicatedStore::new(&self.store, log), &signal)?,
+                None => self.index().update(self.write_store(), &signal)?,
+            };
+        }
+        Ok(new_block)
+    }
+
+    /// Applies the rows replicated from a primary (see `replicate.rs`), returning true if the tip
+    /// has changed.
+    pub fn apply_replicated(
+        &self,
+        batches: Vec<SealedBatch>,
+        log: Option<&ReplicationLog>,
+    ) -> Result<bool> {
+        if batches.is_empty() {
+            return Ok(false);
         }
+        // the rows include the new blocks' headers
+        self.index.reload_with(&self.store, || {
+            for batch in batches {
+                match log {
+                    Some(log) => ReplicatedStore::new(&self.store, log).write_sealed(batch),
+                    None => self.store.write_sealed(batch.rows),
+                }
+            }
+            self.store.flush();
+        })?;
+        let new_tip = self
+            .index
+            .best_header()
+            .map_or_else(BlockHash::default, |header| *header.hash());
+        let mut tip = self.tip.lock().expect("failed to lock tip");
+        let new_block = *tip != new_tip;
+        *tip = new_tip;
         Ok(new_block)
     }
 
+    /// Fails (with the daemon's sync progress) if the daemon is still syncing.
+    pub fn check_daemon_synced(&self) -> Result<()> {
+        match *self.daemon_status.lock().unwrap() {
+            Some(ref status) => bail!("{}", status),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails if the index is too far behind bitcoind, or if the best indexed block is too old (see
+    /// `out_of_sync_blocks` and `out_of_sync_mins`), instead of serving stale results.
+    pub fn check_in_sync(&self) -> Result<()> {
+        let height = self.index.best_header().map_or(0, |tip| tip.height());
+        if let Some(max_blocks) = self.out_of_sync_blocks {
+            if let Some(daemon_height) = *self.daemon_height.lock().unwrap() {
+                let behind = daemon_height.saturating_sub(height);
+                if behind > max_blocks {
+                    bail!(
+                        "server out of sync: index is {} blocks behind the daemon",
+                        behind
+                    );
+                }
+            }
+        }
+        if let (Some(max_age), Some(tip_age)) = (self.out_of_sync_age, self.tip_age()) {
+            if tip_age > max_age.as_secs() {
+                bail!(
+                    "server out of sync: best block is {} minutes old",
+                    tip_age / 60
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the best indexed block's age, by its timestamp (in seconds).
+    fn tip_age(&self) -> Option<u64> {
+        let tip_time = u64::from(self.index.best_header()?.header().time);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Some(now.saturating_sub(tip_time))
+    }
+
+    /// Updates the tip and daemon contact age metrics, and warns if the best block is stale (which
+    /// usually means that bitcoind is stuck or disconnected from its peers).
+    pub fn check_tip(&self) {
+        let contact_age = self.daemon.last_contact().elapsed().as_secs();
+        self.daemon_contact_age.set(contact_age as i64);
+        let tip_age = match self.tip_age() {
+            Some(tip_age) => tip_age,
+            None => return,
+        };
+        self.tip_age.set(tip_age as i64);
+
+        let syncing = self.daemon_status.lock().unwrap().is_some(); // reported separately
+        let warning = match self.stale_tip_threshold {
+            Some(threshold) if !syncing && tip_age > threshold.as_secs() => Some(format!(
+                "best block is {} minutes old (last bitcoind contact {} seconds ago)",
+                tip_age / 60,
+                contact_age
+            )),
+            _ => None,
+        };
+        let mut stale_tip = self.stale_tip.lock().unwrap();
+        match (stale_tip.is_some(), &warning) {
+            (false, Some(msg)) => warn!("stale tip: {}", msg),
+            (true, None) => info!("tip is no longer stale"),
+            _ => (),
+        }
+        *stale_tip = warning;
+    }
+
     pub fn get_banner(&self) -> Result<String> {
-        Ok(format!(
-            "{}\n{}",
-            self.banner,
-            self.daemon.get_subversion()?
-        ))
+        let subversion = self.daemon.get_subversion()?;
+        let height = self.index.best_header().map_or(0, |tip| tip.height());
+        let mut banner = render_banner(&self.banner, &subversion, height, self.started.elapsed());
+        if !self.banner.contains("{bitcoind_version}") {
+            banner.push('\n');
+            banner.push_str(&subversion);
+        }
+        if self.stale_tip_banner {
+            if let Some(ref warning) = *self.stale_tip.lock().unwrap() {
+                banner.push_str(&format!("\nWARNING: {}", warning));
+            }
+        }
+        Ok(banner)
+    }
+}
+
+// e.g. "3d 4h 5m"
+fn format_uptime(uptime: Duration) -> String {
+    let mins = uptime.as_secs() / 60;
+    let (days, hours, mins) = (mins / (24 * 60), mins / 60 % 24, mins % 60);
+    if days > 0 {
+        format!("{}d {}h {}m", days, hours, mins)
+    } else if hours > 0 {
+        format!("{}h {}m", hours, mins)
+    } else {
+        format!("{}m", mins)
+    }
+}
+
+/// Replaces the banner's `{electrs_version}`, `{bitcoind_version}`, `{height}` (of the best
+/// indexed block) and `{uptime}` placeholders.
+fn render_banner(banner: &str, bitcoind_version: &str, height: usize, uptime: Duration) -> String {
+    banner
+        .replace("{electrs_version}", env!("CARGO_PKG_VERSION"))
+        .replace("{bitcoind_version}", bitcoind_version)
+        .replace("{height}", &height.to_string())
+        .replace("{uptime}", &format_uptime(uptime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_uptime, render_banner};
+    use std::time::Duration;
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(59)), "0m");
+        assert_eq!(format_uptime(Duration::from_secs(3 * 3600 + 125)), "3h 2m");
+        assert_eq!(format_uptime(Duration::from_secs(86400 + 60)), "1d 0h 1m");
+    }
+
+    #[test]
+    fn test_render_banner() {
+        let banner = render_banner(
+            "electrs {electrs_version} on {bitcoind_version}, height {height}, up {uptime}",
+            "/Satoshi:22.0.0/",
+            700_000,
+            Duration::from_secs(600),
+        );
+        assert_eq!(
+            banner,
+            format!(
+                "electrs {} on /Satoshi:22.0.0/, height 700000, up 10m",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert_eq!(
+            render_banner("{unknown}", "", 0, Duration::default()),
+            "{unknown}"
+        );
     }
 }
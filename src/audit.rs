@@ -0,0 +1,160 @@
+//! Cross-validating the index against bitcoind's UTXO set (see `electrs audit`): the confirmed
+//! unspent outputs of each given address are looked up in the index, and compared with the ones
+//! found by bitcoind's `scantxoutset` (at the same height).
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use serde_json::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::chain::Txid;
+use crate::errors::*;
+use crate::index::compute_script_hash;
+use crate::query::Query;
+
+type Utxo = (Txid, usize, u64); // (txid, output_index, value)
+
+/// Reads addresses, one per line. Empty lines and lines starting with '#' are ignored.
+pub fn read_addresses(path: &Path) -> Result<Vec<String>> {
+    let contents =
+        fs::read_to_string(path).chain_err(|| format!("failed to read {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+fn parse_amount(value: &Value) -> Result<u64> {
+    let amount = value.as_f64().chain_err(|| "invalid amount")?;
+    Ok((amount * 100_000_000.0).round() as u64) // BTC to satoshis
+}
+
+// Returns the scan's height, and the found UTXOs by their output scripts.
+fn parse_scan(result: &Value) -> Result<(usize, HashMap<Vec<u8>, BTreeSet<Utxo>>)> {
+    if result["success"].as_bool() != Some(true) {
+        bail!("UTXO set scan failed: {}", result);
+    }
+    let height = result["height"].as_u64().chain_err(|| "missing height")? as usize;
+    let mut utxos = HashMap::<Vec<u8>, BTreeSet<Utxo>>::new();
+    for unspent in result["unspents"]
+        .as_array()
+        .chain_err(|| "missing unspents")?
+    {
+        let script = unspent["scriptPubKey"]
+            .as_str()
+            .chain_err(|| "missing scriptPubKey")?;
+        let script = hex::decode(script).chain_err(|| "non-hex scriptPubKey")?;
+        let txid = unspent["txid"].as_str().chain_err(|| "missing txid")?;
+        let txid = Txid::from_hex(txid).chain_err(|| "invalid txid")?;
+        let vout = unspent["vout"].as_u64().chain_err(|| "missing vout")? as usize;
+        let value = parse_amount(&unspent["amount"])?;
+        utxos.entry(script).or_default().insert((txid, vout, value));
+    }
+    Ok((height, utxos))
+}
+
+fn describe(utxos: &BTreeSet<Utxo>) -> String {
+    utxos
+        .iter()
+        .map(|(txid, vout, value)| format!("{}:{} ({} sat)", txid.to_hex(), vout, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Compares the confirmed UTXOs of each address in the index with bitcoind's UTXO set, writing
+/// a line per address, and fails if any of them diverge.
+pub fn audit<W: Write>(query: &Query, addresses: &[String], mut out: W) -> Result<()> {
+    let daemon = query.daemon();
+    let scripts = daemon.address_scripts(addresses)?;
+    let descriptors: Vec<String> = addresses
+        .iter()
+        .map(|address| format!("addr({})", address))
+        .collect();
+    info!("scanning the UTXO set for {} addresses", addresses.len());
+    let (height, mut node_utxos) = parse_scan(&daemon.scantxoutset(&descriptors)?)?;
+    let tip = query.get_best_header()?;
+    if height != tip.height() {
+        bail!(
+            "index is at height {}, but bitcoind scanned its UTXO set at height {} \
+             (please run electrs to catch up, and audit again)",
+            tip.height(),
+            height
+        );
+    }
+    let mut mismatches = 0;
+    for (address, script) in addresses.iter().zip(scripts) {
+        let status = query.status(&compute_script_hash(&script))?;
+        let index_utxos: BTreeSet<Utxo> = status
+            .confirmed_unspent()
+            .into_iter()
+            .map(|out| (out.txn_id, out.output_index, out.value))
+            .collect();
+        let node_utxos = node_utxos.remove(&script).unwrap_or_default();
+        let balance: u64 = node_utxos.iter().map(|(_, _, value)| value).sum();
+        let line = if index_utxos == node_utxos {
+            format!(
+                "{}: ok ({} UTXOs, {} sat)",
+                address,
+                node_utxos.len(),
+                balance
+            )
+        } else {
+            mismatches += 1;
+            let missing: BTreeSet<Utxo> = node_utxos.difference(&index_utxos).cloned().collect();
+            let extra: BTreeSet<Utxo> = index_utxos.difference(&node_utxos).cloned().collect();
+            let index_balance: u64 = index_utxos.iter().map(|(_, _, value)| value).sum();
+            format!(
+                "{}: MISMATCH (index: {} sat, bitcoind: {} sat; missing: [{}], unexpected: [{}])",
+                address,
+                index_balance,
+                balance,
+                describe(&missing),
+                describe(&extra)
+            )
+        };
+        writeln!(out, "{}", line).chain_err(|| "failed to write audit")?;
+    }
+    writeln!(
+        out,
+        "audited {} addresses at height {}: {} mismatches",
+        addresses.len(),
+        height,
+        mismatches
+    )
+    .chain_err(|| "failed to write audit")?;
+    out.flush().chain_err(|| "failed to flush output")?;
+    if mismatches > 0 {
+        bail!("{} addresses don't match bitcoind's UTXO set", mismatches);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_scan;
+    use crate::chain::Txid;
+    use bitcoin::hashes::hex::FromHex;
+
+    #[test]
+    fn test_parse_scan() {
+        let txid = "00".repeat(31) + "01";
+        let result = json!({
+            "success": true,
+            "height": 123,
+            "unspents": [
+                {"txid": txid, "vout": 1, "scriptPubKey": "0014ab", "amount": 0.0001},
+                {"txid": txid, "vout": 2, "scriptPubKey": "0014ab", "amount": 1.23456789},
+            ],
+        });
+        let (height, utxos) = parse_scan(&result).unwrap();
+        assert_eq!(height, 123);
+        let txid = Txid::from_hex(&txid).unwrap();
+        let utxos: Vec<_> = utxos[&vec![0x00, 0x14, 0xab]].iter().cloned().collect();
+        assert_eq!(utxos, vec![(txid, 1, 10_000), (txid, 2, 123_456_789)]);
+
+        assert!(parse_scan(&json!({"success": false})).is_err());
+    }
+}
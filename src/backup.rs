@@ -0,0 +1,155 @@
+//! Periodic index backups: consistent RocksDB checkpoints, taken between index updates into
+//! `backup_dir` (as `<unix time>/shard-<n>`), keeping only the most recent `backup_retention` ones.
+//! Each backup is checkpointed into `<unix time>.tmp`, and renamed once all its shards are done, so
+//! an interrupted backup is never mistaken for a complete one.
+use error_chain::ChainedError;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::app::App;
+use crate::config::Config;
+use crate::errors::*;
+use crate::metrics::{Counter, Gauge, MetricOpts, Metrics};
+
+pub struct Backups {
+    dir: PathBuf,
+    interval: Duration,
+    retention: usize,
+    last_success: Gauge, // unix time
+    failures: Counter,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+const PARTIAL_SUFFIX: &str = ".tmp"; // of a backup which is still being checkpointed
+
+// Returns the complete backups' times (which are their directories' names, so the partial ones
+// are skipped), sorted from oldest to newest.
+fn list_backups(dir: &Path) -> Result<Vec<u64>> {
+    let entries = fs::read_dir(dir).chain_err(|| format!("failed to list {:?}", dir))?;
+    let mut times: Vec<u64> = entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    times.sort();
+    Ok(times)
+}
+
+// Removes the partial backups left by an interrupted backup (e.g. a crash).
+fn remove_partial_backups(dir: &Path) -> Result<()> {
+    let entries = fs::read_dir(dir).chain_err(|| format!("failed to list {:?}", dir))?;
+    for entry in entries {
+        let path = entry
+            .chain_err(|| format!("failed to list {:?}", dir))?
+            .path();
+        let partial = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.ends_with(PARTIAL_SUFFIX));
+        if partial {
+            warn!("removing partial backup {:?}", path);
+            fs::remove_dir_all(&path).chain_err(|| format!("failed to remove {:?}", path))?;
+        }
+    }
+    Ok(())
+}
+
+// Returns the backups to remove, so that only the `retention` newest ones are kept.
+fn expired(times: &[u64], retention: usize) -> &[u64] {
+    &times[..times.len().saturating_sub(retention)]
+}
+
+impl Backups {
+    pub fn from_config(config: &Config, metrics: &Metrics) -> Option<Backups> {
+        let dir = config.backup_dir.clone()?;
+        Some(Backups {
+            dir,
+            interval: config.backup_interval,
+            retention: config.backup_retention,
+            last_success: metrics.gauge_int(MetricOpts::new(
+                "electrs_backup_last_success",
+                "Time of the last successful index backup (unix time)",
+            )),
+            failures: metrics.counter(MetricOpts::new(
+                "electrs_backup_failures",
+                "# of failed index backups",
+            )),
+        })
+    }
+
+    fn backup(&self, app: &App) -> Result<()> {
+        fs::create_dir_all(&self.dir).chain_err(|| format!("failed to create {:?}", self.dir))?;
+        remove_partial_backups(&self.dir)?;
+        let times = list_backups(&self.dir)?;
+        let last = times.last().cloned();
+        if let Some(last) = last {
+            self.last_success.set(last as i64);
+            if now().saturating_sub(last) < self.interval.as_secs() {
+                return Ok(());
+            }
+        }
+        let time = now();
+        let path = self.dir.join(time.to_string());
+        let partial = self.dir.join(format!("{}{}", time, PARTIAL_SUFFIX));
+        info!("backing up the index to {:?}", path);
+        let checkpoint = fs::create_dir(&partial)
+            .chain_err(|| format!("failed to create {:?}", partial))
+            .and_then(|()| app.checkpoint(&partial))
+            .and_then(|()| {
+                fs::rename(&partial, &path)
+                    .chain_err(|| format!("failed to rename {:?} to {:?}", partial, path))
+            });
+        if let Err(e) = checkpoint {
+            let _ = fs::remove_dir_all(&partial); // don't keep a partial backup
+            return Err(e);
+        }
+        self.last_success.set(time as i64);
+        let times = list_backups(&self.dir)?;
+        for time in expired(&times, self.retention) {
+            let path = self.dir.join(time.to_string());
+            info!("removing expired backup {:?}", path);
+            fs::remove_dir_all(&path).chain_err(|| format!("failed to remove {:?}", path))?;
+        }
+        Ok(())
+    }
+
+    /// Backs up the index, if the last backup is older than the configured interval.
+    pub fn run(&self, app: &App) {
+        if let Err(e) = self.backup(app) {
+            self.failures.inc();
+            warn!("index backup failed: {}", e.display_chain());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expired, list_backups, remove_partial_backups};
+    use std::fs;
+
+    #[test]
+    fn test_expired() {
+        assert_eq!(expired(&[1, 2, 3], 2), &[1]);
+        assert_eq!(expired(&[1, 2, 3], 3), &[] as &[u64]);
+        assert_eq!(expired(&[1], 5), &[] as &[u64]);
+        assert_eq!(expired(&[1, 2], 0), &[1, 2]);
+    }
+
+    #[test]
+    fn test_partial_backups() {
+        let dir = std::env::temp_dir().join(format!("electrs-backups-{}", std::process::id()));
+        for name in &["20", "10", "30.tmp", "other"] {
+            fs::create_dir_all(dir.join(name).join("shard-0")).unwrap();
+        }
+        assert_eq!(list_backups(&dir).unwrap(), vec![10, 20]);
+        remove_partial_backups(&dir).unwrap();
+        assert!(!dir.join("30.tmp").exists());
+        assert!(dir.join("other").exists());
+        assert_eq!(list_backups(&dir).unwrap(), vec![10, 20]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
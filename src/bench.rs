@@ -0,0 +1,214 @@
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use serde_json::Value;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+use crate::index::compute_script_hash;
+use crate::query::Query;
+use crate::store::{DBStore, Row, WriteStore};
+use crate::util::spawn_thread;
+
+const WRITE_BATCH_SIZE: usize = 10_000; // rows
+
+/// Collects latency samples, and reports their distribution.
+pub struct Latencies {
+    samples: Vec<Duration>,
+}
+
+impl Latencies {
+    pub fn new() -> Latencies {
+        Latencies { samples: vec![] }
+    }
+
+    pub fn measure<T, F: FnOnce() -> T>(&mut self, func: F) -> T {
+        let start = Instant::now();
+        let result = func();
+        self.samples.push(start.elapsed());
+        result
+    }
+
+    pub fn extend(&mut self, other: Latencies) {
+        self.samples.extend(other.samples);
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn total(&self) -> Duration {
+        self.samples.iter().sum()
+    }
+
+    /// Returns the sample at the given percentile (using the nearest-rank method).
+    pub fn percentile(&mut self, percent: f64) -> Duration {
+        if self.samples.is_empty() {
+            return Duration::default();
+        }
+        self.samples.sort_unstable();
+        let rank = (percent / 100.0 * self.samples.len() as f64).ceil() as usize;
+        self.samples[rank.max(1).min(self.samples.len()) - 1]
+    }
+
+    pub fn report(&mut self, name: &str) {
+        let mean = match self.len() {
+            0 => Duration::default(),
+            n => self.total() / n as u32,
+        };
+        println!(
+            "{}: {} samples, mean={:?} p50={:?} p90={:?} p99={:?} max={:?}",
+            name,
+            self.len(),
+            mean,
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.percentile(100.0),
+        );
+    }
+}
+
+fn bench_rows(batch: usize) -> impl Iterator<Item = Row> {
+    // pseudo-random keys, similar to the funding rows' (see `TxOutRow`)
+    (0..WRITE_BATCH_SIZE).map(move |i| {
+        let seed = ((batch * WRITE_BATCH_SIZE + i) as u64).to_le_bytes();
+        let hash = compute_script_hash(&seed);
+        Row {
+            key: [b"O", &hash[..16]].concat(),
+            value: vec![],
+        }
+    })
+}
+
+/// Measures the write throughput of a new (temporary) DB at `path`, which is removed afterwards.
+pub fn bench_write(path: &Path, batches: usize) -> Result<()> {
+    if path.exists() {
+        bail!("{:?} already exists", path);
+    }
+    let mut latencies = Latencies::new();
+    {
+        let store = DBStore::open(path, /*low_memory=*/ false);
+        for batch in 0..batches {
+            let rows: Vec<Row> = bench_rows(batch).collect();
+            latencies.measure(|| store.write(rows));
+        }
+        latencies.measure(|| store.flush());
+    }
+    let total = latencies.total().as_secs_f64();
+    let rows = batches * WRITE_BATCH_SIZE;
+    latencies.report(&format!("write ({} rows per batch)", WRITE_BATCH_SIZE));
+    println!(
+        "wrote {} rows in {:.3}s ({:.0} rows/s)",
+        rows,
+        total,
+        rows as f64 / total
+    );
+    fs::remove_dir_all(path).chain_err(|| format!("failed to remove {:?}", path))
+}
+
+/// Measures the latency of loading each script hash status (as done by Electrum RPC).
+pub fn bench_query(query: &Query, script_hashes: &[Sha256dHash], rounds: usize) -> Result<()> {
+    if script_hashes.is_empty() {
+        bail!("no script hashes to query");
+    }
+    let mut latencies = Latencies::new();
+    for _ in 0..rounds {
+        for script_hash in script_hashes {
+            latencies.measure(|| query.status(&script_hash[..]))?;
+        }
+    }
+    latencies.report("query");
+    Ok(())
+}
+
+fn run_client(addr: SocketAddr, requests: Vec<Value>) -> Result<Latencies> {
+    let mut stream =
+        TcpStream::connect(addr).chain_err(|| format!("failed to connect {}", addr))?;
+    let mut reader = BufReader::new(stream.try_clone().chain_err(|| "failed to clone stream")?);
+    let mut latencies = Latencies::new();
+    for request in requests {
+        let line = request.to_string() + "\n";
+        latencies.measure(|| -> Result<()> {
+            stream
+                .write_all(line.as_bytes())
+                .chain_err(|| "failed to send request")?;
+            let mut reply = String::new();
+            reader
+                .read_line(&mut reply)
+                .chain_err(|| "failed to read reply")?;
+            if reply.is_empty() {
+                bail!("server disconnected");
+            }
+            Ok(())
+        })?;
+    }
+    Ok(latencies)
+}
+
+/// Simulates concurrent clients sending requests to an Electrum server at `addr`.
+/// If no script hashes are given, `server.ping` requests are sent.
+pub fn bench_load(
+    addr: SocketAddr,
+    clients: usize,
+    requests: usize,
+    script_hashes: &[Sha256dHash],
+) -> Result<()> {
+    let handles: Vec<_> = (0..clients)
+        .map(|client| {
+            let requests: Vec<Value> = (0..requests)
+                .map(
+                    |i| match script_hashes.get((client + i) % script_hashes.len().max(1)) {
+                        Some(script_hash) => json!({
+                            "id": i,
+                            "method": "blockchain.scripthash.get_history",
+                            "params": [script_hash.to_hex()]
+                        }),
+                        None => json!({"id": i, "method": "server.ping", "params": []}),
+                    },
+                )
+                .collect();
+            spawn_thread("bench_client", move || run_client(addr, requests))
+        })
+        .collect();
+    let start = Instant::now();
+    let mut latencies = Latencies::new();
+    for handle in handles {
+        latencies.extend(handle.join().expect("client panicked")?);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    latencies.report(&format!("load ({} clients)", clients));
+    println!(
+        "{} requests in {:.3}s ({:.0} requests/s)",
+        latencies.len(),
+        elapsed,
+        latencies.len() as f64 / elapsed
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Latencies;
+    use std::time::Duration;
+
+    #[test]
+    fn test_percentiles() {
+        let mut latencies = Latencies::new();
+        assert_eq!(latencies.percentile(50.0), Duration::default());
+        latencies.samples = (1..=10).rev().map(Duration::from_millis).collect();
+        assert_eq!(latencies.percentile(0.0), Duration::from_millis(1));
+        assert_eq!(latencies.percentile(50.0), Duration::from_millis(5));
+        assert_eq!(latencies.percentile(90.0), Duration::from_millis(9));
+        assert_eq!(latencies.percentile(99.0), Duration::from_millis(10));
+        assert_eq!(latencies.percentile(100.0), Duration::from_millis(10));
+        assert_eq!(latencies.total(), Duration::from_millis(55));
+    }
+}
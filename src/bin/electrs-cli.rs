@@ -0,0 +1,258 @@
+//! A minimal Electrum protocol client, for smoke tests and debugging of a running server.
+extern crate electrs;
+
+#[macro_use]
+extern crate error_chain;
+#[macro_use]
+extern crate serde_json;
+
+use error_chain::ChainedError;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Lines, Write};
+use std::net::TcpStream;
+use std::process;
+
+use electrs::chain::{deserialize, BlockHeader};
+use electrs::errors::*;
+
+const USAGE: &str = "Usage: electrs-cli [--server <addr:port>] [--json] <command> [<args>...]
+
+Commands:
+    tip                          Show the best block header
+    headers <height> [<count>]   Show block headers, starting from the specified height
+    history <scripthash>         Show the transaction history of a script hash
+    balance <scripthash>         Show the balance of a script hash
+    subscribe <scripthash>       Subscribe to a script hash, and print its status changes
+    broadcast <tx-hex>           Broadcast a raw transaction";
+
+const DEFAULT_SERVER: &str = "127.0.0.1:50001";
+
+struct Client {
+    stream: TcpStream,
+    lines: Lines<BufReader<TcpStream>>,
+    next_id: u64,
+}
+
+impl Client {
+    fn connect(addr: &str) -> Result<Client> {
+        let stream =
+            TcpStream::connect(addr).chain_err(|| format!("failed to connect {}", addr))?;
+        let reader = BufReader::new(stream.try_clone().chain_err(|| "failed to clone stream")?);
+        Ok(Client {
+            stream,
+            lines: reader.lines(),
+            next_id: 0,
+        })
+    }
+
+    fn recv(&mut self) -> Result<Value> {
+        let line = self
+            .lines
+            .next()
+            .chain_err(|| "server disconnected")?
+            .chain_err(|| "failed to read reply")?;
+        serde_json::from_str(&line).chain_err(|| format!("invalid reply: {}", line))
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let request =
+            json!({"jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params});
+        let line = request.to_string() + "\n";
+        self.stream
+            .write_all(line.as_bytes())
+            .chain_err(|| format!("failed to send {}", method))?;
+        loop {
+            let mut reply = self.recv()?;
+            if reply.get("id") != Some(&json!(self.next_id)) {
+                continue; // skip notifications (and unrelated replies)
+            }
+            if let Some(err) = reply.get("error") {
+                bail!("{} failed: {}", method, err);
+            }
+            match reply.get_mut("result") {
+                Some(result) => return Ok(result.take()),
+                None => bail!("no result in reply: {}", reply),
+            }
+        }
+    }
+}
+
+fn parse_header(value: &Value) -> Result<BlockHeader> {
+    let header_hex = value.as_str().chain_err(|| "non-string header")?;
+    let header_bytes = hex::decode(header_hex).chain_err(|| "non-hex header")?;
+    deserialize(&header_bytes).chain_err(|| format!("invalid header: {}", header_hex))
+}
+
+fn print_header(height: u64, header: &BlockHeader) {
+    let spec = time::Timespec::new(i64::from(header.time), 0);
+    println!(
+        "{:>8} {} {}",
+        height,
+        header.block_hash(),
+        time::at_utc(spec).rfc3339()
+    );
+}
+
+fn print_json(value: &Value) -> Result<()> {
+    let text = serde_json::to_string_pretty(value).chain_err(|| "failed to format JSON")?;
+    println!("{}", text);
+    Ok(())
+}
+
+fn arg<'a>(args: &'a [String], index: usize, name: &str) -> Result<&'a str> {
+    args.get(index)
+        .map(String::as_str)
+        .chain_err(|| format!("missing <{}> argument", name))
+}
+
+fn run(client: &mut Client, command: &str, args: &[String], json_output: bool) -> Result<()> {
+    client.call(
+        "server.version",
+        json!([format!("electrs-cli {}", env!("CARGO_PKG_VERSION")), "1.4"]),
+    )?;
+    match command {
+        "tip" => {
+            let tip = client.call("blockchain.headers.subscribe", json!([]))?;
+            if json_output {
+                return print_json(&tip);
+            }
+            let height = tip["height"].as_u64().chain_err(|| "invalid height")?;
+            print_header(height, &parse_header(&tip["hex"])?);
+        }
+        "headers" => {
+            let height: u64 = arg(args, 0, "height")?
+                .parse()
+                .chain_err(|| "invalid height")?;
+            let count: u64 = match args.get(1) {
+                Some(count) => count.parse().chain_err(|| "invalid count")?,
+                None => 1,
+            };
+            let result = client.call("blockchain.block.headers", json!([height, count]))?;
+            if json_output {
+                return print_json(&result);
+            }
+            let headers_hex = result["hex"].as_str().chain_err(|| "non-string headers")?;
+            let returned = result["count"].as_u64().chain_err(|| "invalid count")?;
+            let header_size = 80 * 2; // in hex digits
+            if headers_hex.len() as u64 != returned * header_size as u64 {
+                bail!(
+                    "expected {} headers, got {} hex digits",
+                    returned,
+                    headers_hex.len()
+                );
+            }
+            for (i, start) in (0..headers_hex.len()).step_by(header_size).enumerate() {
+                let header_hex = &headers_hex[start..start + header_size];
+                print_header(height + i as u64, &parse_header(&json!(header_hex))?);
+            }
+            // the server returns fewer headers past its tip (or above its maximum per request)
+            if returned < count {
+                eprintln!("returned {} of the {} requested headers", returned, count);
+            }
+        }
+        "history" => {
+            let history = client.call(
+                "blockchain.scripthash.get_history",
+                json!([arg(args, 0, "scripthash")?]),
+            )?;
+            if json_output {
+                return print_json(&history);
+            }
+            for item in history.as_array().chain_err(|| "non-array history")? {
+                let height = item["height"].as_i64().unwrap_or_default();
+                match item.get("fee") {
+                    Some(fee) => println!(
+                        "{:>8} {} (fee: {} sat)",
+                        height,
+                        item["tx_hash"].as_str().unwrap_or_default(),
+                        fee
+                    ),
+                    None => println!(
+                        "{:>8} {}",
+                        height,
+                        item["tx_hash"].as_str().unwrap_or_default()
+                    ),
+                }
+            }
+        }
+        "balance" => {
+            let balance = client.call(
+                "blockchain.scripthash.get_balance",
+                json!([arg(args, 0, "scripthash")?]),
+            )?;
+            if json_output {
+                return print_json(&balance);
+            }
+            println!("confirmed:   {} sat", balance["confirmed"]);
+            println!("unconfirmed: {} sat", balance["unconfirmed"]);
+        }
+        "subscribe" => {
+            let script_hash = arg(args, 0, "scripthash")?;
+            let status = client.call("blockchain.scripthash.subscribe", json!([script_hash]))?;
+            if json_output {
+                print_json(&status)?;
+            } else {
+                println!("{} status: {}", script_hash, status);
+            }
+            loop {
+                let notification = client.recv()?;
+                if json_output {
+                    print_json(&notification)?;
+                } else if notification["method"] == "blockchain.scripthash.subscribe" {
+                    println!(
+                        "{} status: {}",
+                        notification["params"][0].as_str().unwrap_or_default(),
+                        notification["params"][1]
+                    );
+                }
+            }
+        }
+        "broadcast" => {
+            let txid = client.call(
+                "blockchain.transaction.broadcast",
+                json!([arg(args, 0, "tx-hex")?]),
+            )?;
+            if json_output {
+                return print_json(&txid);
+            }
+            println!("{}", txid.as_str().chain_err(|| "non-string txid")?);
+        }
+        _ => bail!("unknown command: {}\n\n{}", command, USAGE),
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut server = DEFAULT_SERVER.to_owned();
+    let mut json_output = false;
+    let mut args = std::env::args().skip(1);
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--server" => {
+                server = args.next().unwrap_or_else(|| {
+                    eprintln!("Error: missing value for --server");
+                    process::exit(1)
+                })
+            }
+            "--json" => json_output = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.is_empty() {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+    let command = positional.remove(0);
+    let result = Client::connect(&server)
+        .and_then(|mut client| run(&mut client, &command, &positional, json_output));
+    if let Err(e) = result {
+        eprintln!("Error: {}", e.display_chain());
+        process::exit(1);
+    }
+}
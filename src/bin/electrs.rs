@@ -1,35 +1,49 @@
 extern crate electrs;
 
+#[macro_use]
 extern crate error_chain;
 #[macro_use]
 extern crate log;
 
 use error_chain::ChainedError;
+use std::collections::HashSet;
+use std::io::{self, BufWriter};
+use std::path::Path;
 use std::process;
 use std::sync::Arc;
+use std::time::Duration;
 
 use electrs::{
     app::App,
-    bulk,
-    cache::{BlockTxIDsCache, TransactionCache},
-    config::Config,
+    audit,
+    backup::Backups,
+    bench, bulk,
+    cache::{BlockCache, BlockTxIDsCache, HeaderChunkCache, MerkleCache, TransactionCache},
+    cipher::Cipher,
+    config::{Config, SubcommandArgs},
+    conformance,
     daemon::Daemon,
+    dump,
     errors::*,
-    index::Index,
+    export,
+    hooks::{Event, Hooks},
+    index::{self, Index},
     metrics::Metrics,
     query::Query,
-    rpc::RPC,
+    record::{self, Recorder},
+    replicate::{Follower, ReplicationLog},
+    rpc::{BroadcastPolicy, Listeners, RPC},
+    schedule::Scheduler,
     signal::Waiter,
+    standby::Standby,
     store::{full_compaction, is_fully_compacted, DBStore},
+    util::{spawn_thread, HeaderEntry},
+    watch::WalletWatcher,
 };
 
-fn run_server(config: &Config) -> Result<()> {
-    let signal = Waiter::start();
-    let metrics = Metrics::new(config.monitoring_addr);
-    metrics.start();
-    let blocktxids_cache = Arc::new(BlockTxIDsCache::new(config.blocktxids_cache_size, &metrics));
-
-    let daemon = Daemon::new(
+fn open_daemon(config: &Config, signal: &Waiter, metrics: &Metrics) -> Result<Daemon> {
+    let blocktxids_cache = Arc::new(BlockTxIDsCache::new(config.blocktxids_cache_size, metrics));
+    Daemon::new(
         &config.daemon_dir,
         &config.blocks_dir,
         config.daemon_rpc_addr,
@@ -37,41 +51,223 @@ fn run_server(config: &Config) -> Result<()> {
         config.network_type,
         signal.clone(),
         blocktxids_cache,
-        &metrics,
-    )?;
+        Hooks::from_config(config),
+        metrics,
+    )
+}
+
+fn run_server(config: &Config) -> Result<()> {
+    let signal = Waiter::start();
+    let metrics = Metrics::new(config.monitoring_addr).with_access(
+        config.monitoring_socket.clone(),
+        config.monitoring_token.clone(),
+    );
+    metrics.start();
+    serve(config, &signal, &metrics)
+}
+
+// Serve several networks from a single process, sharing its metrics server (which is configured
+// by the first network's config).
+fn run_multi(configs: Vec<Config>) -> Result<()> {
+    let first = configs.first().chain_err(|| "no networks configured")?;
+    let mut networks = HashSet::new();
+    for config in &configs {
+        if !networks.insert(config.network_type) {
+            bail!(
+                "network {} is configured more than once",
+                config.network_type
+            );
+        }
+        // both are process-wide (see `index::enable_txid_search` and `redact::enable`)
+        if config.txid_search != first.txid_search || config.redact_logs != first.redact_logs {
+            bail!(
+                "network {} disagrees with network {} on txid_search or redact_logs",
+                config.network_type,
+                first.network_type
+            );
+        }
+    }
+    let metrics = Metrics::new(first.monitoring_addr).with_access(
+        first.monitoring_socket.clone(),
+        first.monitoring_token.clone(),
+    );
+    metrics.start();
+    let servers: Vec<_> = configs
+        .into_iter()
+        .map(|config| {
+            let network = config.network_type.to_string();
+            let metrics = metrics.with_network(&network);
+            let handle = spawn_thread(&format!("serve_{}", network), move || {
+                // each network waits on its own signal registration
+                serve(&config, &Waiter::start(), &metrics)
+            });
+            (network, handle)
+        })
+        .collect();
+    let mut failed = 0;
+    for (network, handle) in servers {
+        if let Err(e) = handle.join().expect("server panicked") {
+            error!("{} server failed: {}", network, e.display_chain());
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{} of the servers failed", failed);
+    }
+    Ok(())
+}
+
+// Index and serve a single network.
+fn serve(config: &Config, signal: &Waiter, metrics: &Metrics) -> Result<()> {
+    let daemon = open_daemon(config, signal, metrics)?;
+    if config.txid_search {
+        index::enable_txid_search();
+    }
     // Perform initial indexing from local blk*.dat block files.
-    let store = DBStore::open(&config.db_path, /*low_memory=*/ config.jsonrpc_import);
-    let index = Index::load(&store, &daemon, &metrics, config.index_batch_size)?;
-    let store = if is_fully_compacted(&store) {
+    let store = DBStore::open_shards(
+        &config.db_path,
+        &config.db_shard_paths,
+        /*low_memory=*/ config.jsonrpc_import,
+        Cipher::from_config(config)?,
+    );
+    index::check_headers_only(&store, config.headers_only)?;
+    let hooks = Hooks::from_config(config);
+    let load = || {
+        Index::load(&store, &daemon, metrics, config.index_batch_size)
+            .map(|index| index.with_headers_only(config.headers_only))
+    };
+    let index = match hooks.check(load()) {
+        // a follower's index can only be fixed by copying its primary's index again
+        Err(Error(ErrorKind::Corruption(msg), _)) if config.replicate_from.is_none() => {
+            warn!("index is corrupted ({}), trying to repair it", msg);
+            index::repair_headers(&store)?;
+            hooks.check(load())?
+        }
+        result => result?,
+    };
+    let store = if config.replicate_from.is_some() {
+        // a follower applies its primary's updates, starting from a copy of its index
+        if !is_fully_compacted(&store) {
+            bail!(
+                "index at {:?} is not ready (please copy the primary's index first)",
+                config.db_path
+            );
+        }
+        store
+    } else if is_fully_compacted(&store) {
         store // initial import and full compaction are over
-    } else if config.jsonrpc_import {
-        index.update(&store, &signal)?; // slower: uses JSONRPC for fetching blocks
+    } else if config.jsonrpc_import || config.headers_only {
+        index.update(&store, signal)?; // slower: uses JSONRPC for fetching blocks
         full_compaction(store)
     } else {
         // faster, but uses more memory
         let store =
-            bulk::index_blk_files(&daemon, config.bulk_index_threads, &metrics, &signal, store)?;
+            bulk::index_blk_files(&daemon, config.bulk_index_threads, metrics, signal, store)?;
         let store = full_compaction(store);
-        index.reload(&store); // make sure the block header index is up-to-date
+        hooks.check(index.reload(&store))?; // make sure the block header index is up-to-date
         store
     }
     .enable_compaction(); // enable auto compactions before starting incremental index updates.
 
-    let app = App::new(store, index, daemon, &config)?;
-    let tx_cache = TransactionCache::new(config.tx_cache_size, &metrics);
-    let query = Query::new(app.clone(), &metrics, tx_cache, config.txid_limit);
+    let app = App::new(store, index, daemon, &config, metrics)?;
+    let tx_cache = TransactionCache::new(config.tx_cache_size, metrics);
+    let block_cache = BlockCache::new(config.block_cache_size, metrics);
+    let header_chunk_cache = HeaderChunkCache::new(config.header_chunk_cache_size, metrics);
+    let merkle_cache = MerkleCache::new(config.merkle_cache_size, metrics);
+    let query = Query::new(
+        app.clone(),
+        metrics,
+        tx_cache,
+        block_cache,
+        header_chunk_cache,
+        merkle_cache,
+        config.txid_limit,
+        config.max_history,
+        config.fee_estimate_mode.clone(),
+        config.max_persisted_statuses,
+        config.persisted_status_ttl,
+    );
     let relayfee = query.get_relayfee()?;
     debug!("relayfee: {} BTC", relayfee);
+    start_tip_monitor(app.clone(), signal.clone(), config.wait_duration);
+    let replication = config.replication_addr.map(|addr| {
+        let secret = config.replication_secret.as_deref();
+        ReplicationLog::start(addr, secret, app.read_store())
+    });
+    let follower = config.replicate_from.map(|primary| {
+        let secret = config.replication_secret.as_deref();
+        Follower::start(primary, secret, app.read_store())
+    });
+    if let Some(ref follower) = follower {
+        info!("running as a follower of {}", follower.primary());
+    }
 
     let mut server = None; // Electrum RPC server
+    let recorder = Recorder::from_config(config)?;
+    let listeners = Listeners::from_config(config)?;
+    if let Some(ref onion) = listeners.onion {
+        info!("published onion service {}:{}", onion.host(), onion.port());
+    }
+    let backups = Backups::from_config(config, metrics);
+    let mut watcher = WalletWatcher::from_config(config, app.daemon())?;
+    if let Some(ref watcher) = watcher {
+        info!("monitoring the addresses of wallet {:?}", watcher.wallet());
+    }
+    let mut standby = Standby::start(config, signal.clone());
+    if let Some(ref standby) = standby {
+        info!("running as a standby of {}", standby.primary());
+    }
     loop {
-        app.update(&signal)?;
-        query.update_mempool()?;
-        server
-            .get_or_insert_with(|| {
-                RPC::start(config.electrum_rpc_addr, query.clone(), &metrics, relayfee)
-            })
-            .notify(); // update subscribed clients
+        let prev_tip = app.index().best_header();
+        let new_block = match follower {
+            Some(ref follower) => {
+                app.apply_replicated(follower.received(), replication.as_deref())?
+            }
+            None => app.update(signal, replication.as_deref())?,
+        };
+        query.persist_statuses(new_block);
+        if new_block {
+            // warm the caches before notifying the clients about the new block
+            if let Err(e) = query.prefetch_tip() {
+                warn!("failed to prefetch tip: {}", e.display_chain());
+            }
+            notify_new_tip(&app, &hooks, prev_tip);
+        }
+        // between the index updates, so the backups are consistent
+        if let Some(ref backups) = backups {
+            backups.run(&app);
+        }
+        if let Some(ref tls) = listeners.tls {
+            tls.reload(); // e.g. a renewed certificate
+        }
+        if app.check_daemon_synced().is_ok() {
+            query.update_mempool()?;
+            if let Some(ref mut watcher) = watcher {
+                if let Err(e) = watcher.update(&query, &hooks) {
+                    warn!("{}", e.display_chain());
+                }
+            }
+        }
+        // a standby keeps its index, mempool and caches warm, without serving clients
+        if standby.as_ref().map_or(true, Standby::should_promote) {
+            if let Some(primary) = standby.take().map(|s| s.primary()) {
+                warn!("primary {} is down, serving clients", primary);
+                hooks.notify(Event::Promotion { primary });
+            }
+            server
+                .get_or_insert_with(|| {
+                    RPC::start(
+                        listeners.clone(),
+                        query.clone(),
+                        metrics,
+                        relayfee,
+                        BroadcastPolicy::from_config(&config),
+                        Scheduler::from_config(&config),
+                        recorder.clone(),
+                    )
+                })
+                .notify(); // update subscribed clients
+        }
         if let Err(err) = signal.wait(config.wait_duration) {
             info!("stopping server: {}", err);
             break;
@@ -80,9 +276,221 @@ fn run_server(config: &Config) -> Result<()> {
     Ok(())
 }
 
+// Checks the tip's age in a separate thread, since the main loop blocks while bitcoind is unreachable.
+fn start_tip_monitor(app: Arc<App>, signal: Waiter, duration: Duration) {
+    spawn_thread("tip_monitor", move || loop {
+        app.check_tip();
+        if signal.wait(duration).is_err() {
+            break;
+        }
+    });
+}
+
+fn notify_new_tip(app: &App, hooks: &Hooks, prev_tip: Option<HeaderEntry>) {
+    let tip = match app.index().best_header() {
+        Some(tip) => tip,
+        None => return,
+    };
+    if let Some(prev_tip) = prev_tip {
+        if app
+            .index()
+            .get_header_by_blockhash(prev_tip.hash())
+            .is_none()
+        {
+            hooks.notify(Event::Reorg {
+                height: tip.height(),
+                blockhash: *tip.hash(),
+                stale_height: prev_tip.height(),
+                stale_blockhash: *prev_tip.hash(),
+            });
+        }
+    }
+    hooks.notify(Event::NewBlock {
+        height: tip.height(),
+        blockhash: *tip.hash(),
+    });
+}
+
+// Open an existing index (without updating it), for offline queries.
+fn open_query(config: &Config, signal: &Waiter, metrics: &Metrics) -> Result<Arc<Query>> {
+    let daemon = open_daemon(config, signal, metrics)?;
+    let store = DBStore::open_shards(
+        &config.db_path,
+        &config.db_shard_paths,
+        /*low_memory=*/ false,
+        Cipher::from_config(config)?,
+    );
+    if !is_fully_compacted(&store) {
+        bail!(
+            "index at {:?} is not ready (please run electrs first)",
+            config.db_path
+        );
+    }
+    let index = Index::load(&store, &daemon, metrics, config.index_batch_size)?;
+    let app = App::new(store, index, daemon, &config, metrics)?;
+    let tx_cache = TransactionCache::new(config.tx_cache_size, metrics);
+    let block_cache = BlockCache::new(config.block_cache_size, metrics);
+    let header_chunk_cache = HeaderChunkCache::new(config.header_chunk_cache_size, metrics);
+    let merkle_cache = MerkleCache::new(config.merkle_cache_size, metrics);
+    Ok(Query::new(
+        app,
+        metrics,
+        tx_cache,
+        block_cache,
+        header_chunk_cache,
+        merkle_cache,
+        config.txid_limit,
+        /*max_history=*/ 0, // e.g. for exporting large histories
+        config.fee_estimate_mode.clone(),
+        config.max_persisted_statuses,
+        config.persisted_status_ttl,
+    ))
+}
+
+// Export address histories from an existing index.
+fn run_export(config: &Config, args: &SubcommandArgs) -> Result<()> {
+    let format: export::Format = args.get("format").unwrap_or("csv").parse()?;
+    let script_hashes = export::read_script_hashes(Path::new(args.require("scripthash-file")?))?;
+    let signal = Waiter::start();
+    let metrics = Metrics::new(config.monitoring_addr);
+    let query = open_query(config, &signal, &metrics)?;
+    let stdout = io::stdout();
+    export::export_history(
+        &query,
+        &script_hashes,
+        format,
+        BufWriter::new(stdout.lock()),
+    )
+}
+
+// Inspect (or re-encrypt) the rows of an existing index.
+fn run_db(config: &Config, args: &SubcommandArgs) -> Result<()> {
+    if !config.db_path.exists() {
+        bail!("no index found at {:?}", config.db_path);
+    }
+    let store = DBStore::open_shards(
+        &config.db_path,
+        &config.db_shard_paths,
+        /*low_memory=*/ false,
+        Cipher::from_config(config)?,
+    );
+    let stdout = io::stdout();
+    let out = BufWriter::new(stdout.lock());
+    match args.action(0) {
+        Some("dump") => {
+            let limit = match args.get("limit") {
+                Some(_) => Some(parse_count(args, "limit", 0)?),
+                None => None,
+            };
+            dump::dump_rows(&store, args.get("prefix").unwrap_or(""), limit, out)
+        }
+        Some("get") => dump::get_row(&store, args.require("key")?, out),
+        Some("reencrypt") => {
+            let count = store.reencrypt()?;
+            info!("re-encrypted {} rows using the current key", count);
+            // their script hashes may be blinded by an old key (they are persisted again later)
+            let count = index::clear_statuses(&store);
+            info!("deleted {} persisted statuses", count);
+            Ok(())
+        }
+        Some(action) => bail!("unknown db action: {} (use dump, get or reencrypt)", action),
+        None => bail!("missing db action (use dump, get or reencrypt)"),
+    }
+}
+
+fn parse_count(args: &SubcommandArgs, name: &str, default: usize) -> Result<usize> {
+    match args.get(name) {
+        Some(value) => value
+            .parse()
+            .chain_err(|| format!("invalid --{} argument: {}", name, value)),
+        None => Ok(default),
+    }
+}
+
+// Measure index writes, index queries or Electrum RPC load.
+fn run_bench(config: &Config, args: &SubcommandArgs) -> Result<()> {
+    let script_hashes = match args.get("scripthash-file") {
+        Some(path) => export::read_script_hashes(Path::new(path))?,
+        None => vec![],
+    };
+    match args.require("mode")? {
+        "write" => bench::bench_write(
+            &config.db_path.with_extension("bench"),
+            parse_count(args, "count", 100)?,
+        ),
+        "query" => {
+            let signal = Waiter::start();
+            let metrics = Metrics::new(config.monitoring_addr);
+            let query = open_query(config, &signal, &metrics)?;
+            bench::bench_query(&query, &script_hashes, parse_count(args, "count", 1)?)
+        }
+        "load" => bench::bench_load(
+            config.electrum_rpc_addr,
+            parse_count(args, "clients", 10)?,
+            parse_count(args, "count", 1000)?,
+            &script_hashes,
+        ),
+        mode => bail!(
+            "unknown benchmark mode: {} (use write, query or load)",
+            mode
+        ),
+    }
+}
+
+// Cross-validate the index against bitcoind's UTXO set.
+fn run_audit(config: &Config, args: &SubcommandArgs) -> Result<()> {
+    let addresses = audit::read_addresses(Path::new(args.require("address-file")?))?;
+    let signal = Waiter::start();
+    let metrics = Metrics::new(config.monitoring_addr);
+    let query = open_query(config, &signal, &metrics)?;
+    let stdout = io::stdout();
+    audit::audit(&query, &addresses, BufWriter::new(stdout.lock()))
+}
+
+fn run_selftest(config: &Config, args: &SubcommandArgs) -> Result<()> {
+    conformance::run(config.electrum_rpc_addr, args.get("scripthash"))
+}
+
+// Replay recorded client sessions (see `--record-sessions`) against a running server.
+fn run_replay(config: &Config, args: &SubcommandArgs) -> Result<()> {
+    record::replay(
+        config.electrum_rpc_addr,
+        Path::new(args.require("session-file")?),
+    )
+}
+
 fn main() {
-    let config = Config::from_args();
-    if let Err(e) = run_server(&config) {
+    let subcommand = std::env::args_os().nth(1);
+    let result = match subcommand.as_ref().and_then(|arg| arg.to_str()) {
+        Some("export") => {
+            let (config, args) = Config::for_subcommand(&["scripthash-file", "format"]);
+            run_export(&config, &args)
+        }
+        Some("db") => {
+            let (config, args) = Config::for_subcommand(&["prefix", "key", "limit"]);
+            run_db(&config, &args)
+        }
+        Some("bench") => {
+            let (config, args) =
+                Config::for_subcommand(&["mode", "count", "clients", "scripthash-file"]);
+            run_bench(&config, &args)
+        }
+        Some("audit") => {
+            let (config, args) = Config::for_subcommand(&["address-file"]);
+            run_audit(&config, &args)
+        }
+        Some("selftest") => {
+            let (config, args) = Config::for_subcommand(&["scripthash"]);
+            run_selftest(&config, &args)
+        }
+        Some("replay") => {
+            let (config, args) = Config::for_subcommand(&["session-file"]);
+            run_replay(&config, &args)
+        }
+        Some("multi") => run_multi(Config::for_networks()),
+        _ => run_server(&Config::from_args()),
+    };
+    if let Err(e) = result {
         error!("server failed: {}", e.display_chain());
         process::exit(1);
     }
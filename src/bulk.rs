@@ -1,6 +1,3 @@
-use bitcoin::blockdata::block::Block;
-use bitcoin::consensus::encode::{deserialize, Decodable};
-use bitcoin::hash_types::BlockHash;
 use std::collections::HashSet;
 use std::fs;
 use std::io::Cursor;
@@ -11,10 +8,13 @@ use std::sync::{
 };
 use std::thread;
 
+use crate::chain::{BlockHash, Decodable};
+use crate::checkpoints;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::index::{index_block, last_indexed_block, read_indexed_blockhashes};
+use crate::index::{last_indexed_block, read_indexed_blockhashes};
 use crate::metrics::{CounterVec, Histogram, HistogramOpts, HistogramVec, MetricOpts, Metrics};
+use crate::raw_block::{block_hash, index_raw_block};
 use crate::signal::Waiter;
 use crate::store::{DBStore, Row, WriteStore};
 use crate::util::{spawn_thread, HeaderList, SyncChannel};
@@ -81,13 +81,13 @@ impl Parser {
 
     fn index_blkfile(&self, blob: Vec<u8>) -> Result<Vec<Row>> {
         let timer = self.duration.with_label_values(&["parse"]).start_timer();
-        let blocks = parse_blocks(blob, self.magic)?;
+        let blocks = parse_blocks(&blob, self.magic)?;
         timer.observe_duration();
 
         let mut rows = Vec::<Row>::new();
         let timer = self.duration.with_label_values(&["index"]).start_timer();
         for block in blocks {
-            let blockhash = block.block_hash();
+            let blockhash = block_hash(block)?;
             if let Some(header) = self.current_headers.header_by_blockhash(&blockhash) {
                 if self
                     .indexed_blockhashes
@@ -95,7 +95,7 @@ impl Parser {
                     .expect("indexed_blockhashes")
                     .insert(blockhash)
                 {
-                    rows.extend(index_block(&block, header.height()));
+                    rows.extend(index_raw_block(block, header.height())?.rows);
                     self.block_count.with_label_values(&["indexed"]).inc();
                 } else {
                     self.block_count.with_label_values(&["duplicate"]).inc();
@@ -114,8 +114,9 @@ impl Parser {
     }
 }
 
-fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
-    let mut cursor = Cursor::new(&blob);
+// Returns the serialized blocks (which are indexed without being deserialized).
+fn parse_blocks(blob: &[u8], magic: u32) -> Result<Vec<&[u8]>> {
+    let mut cursor = Cursor::new(blob);
     let mut blocks = vec![];
     let max_pos = blob.len() as u64;
     while cursor.position() < max_pos {
@@ -146,8 +147,9 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<Block>> {
             }
             Err(_) => break, // EOF
         }
-        let block: Block = deserialize(&blob[start as usize..end as usize])
-            .chain_err(|| format!("failed to parse block at {}..{}", start, end))?;
+        let block = blob
+            .get(start as usize..end as usize)
+            .chain_err(|| format!("truncated block at {}..{}", start, end))?;
         blocks.push(block);
         cursor.set_position(end as u64);
     }
@@ -158,6 +160,7 @@ fn load_headers(daemon: &Daemon) -> Result<HeaderList> {
     let tip = daemon.getbestblockhash()?;
     let mut headers = HeaderList::empty();
     let new_headers = headers.order(daemon.get_new_headers(&headers, &tip)?);
+    checkpoints::verify(daemon.network(), &new_headers)?;
     headers.apply(new_headers, tip);
     Ok(headers)
 }
@@ -271,10 +274,10 @@ mod tests {
     fn test_incomplete_block_parsing() {
         let magic = 0x0709110b;
         let raw_blocks = hex_decode(fixture("incomplete_block.hex")).unwrap();
-        let blocks = parse_blocks(raw_blocks, magic).unwrap();
+        let blocks = parse_blocks(&raw_blocks, magic).unwrap();
         assert_eq!(blocks.len(), 2);
         assert_eq!(
-            blocks[1].block_hash().into_inner().to_vec(),
+            block_hash(blocks[1]).unwrap().into_inner().to_vec(),
             hex_decode("d55acd552414cc44a761e8d6b64a4d555975e208397281d115336fc500000000").unwrap()
         );
     }
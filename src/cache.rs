@@ -1,13 +1,11 @@
+use crate::chain::{deserialize, BlockHash, Transaction, TxMerkleNode, Txid};
 use crate::errors::*;
 use crate::metrics::{CounterVec, MetricOpts, Metrics};
 
-use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::consensus::encode::deserialize;
-use bitcoin::hash_types::{BlockHash, Txid};
 use lru::LruCache;
 use prometheus::IntGauge;
 use std::hash::Hash;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 struct SizedLruCache<K, V> {
     map: LruCache<K, (V, usize)>,
@@ -59,6 +57,13 @@ impl<K: Hash + Eq, V> SizedLruCache<K, V> {
 
         self.usage.set(self.bytes_usage as i64);
     }
+
+    fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, byte_size) = self.map.pop(key)?;
+        self.bytes_usage -= byte_size;
+        self.usage.set(self.bytes_usage as i64);
+        Some(value)
+    }
 }
 
 pub struct BlockTxIDsCache {
@@ -92,12 +97,135 @@ impl BlockTxIDsCache {
         }
 
         let txids = load_txids_func()?;
+        self.add(blockhash, txids.clone());
+        Ok(txids)
+    }
+
+    pub fn add(&self, blockhash: &BlockHash, txids: Vec<Txid>) {
         let byte_size = 32 /* hash size */ * (1 /* key */ + txids.len() /* values */);
+        self.map.lock().unwrap().put(*blockhash, txids, byte_size);
+    }
+}
+
+pub struct BlockCache {
+    // Store serialized blocks (to be served as-is).
+    map: Mutex<SizedLruCache<BlockHash, Vec<u8>>>,
+}
+
+impl BlockCache {
+    pub fn new(bytes_capacity: usize, metrics: &Metrics) -> BlockCache {
+        let lookups = metrics.counter_vec(
+            MetricOpts::new("electrs_blocks_cache", "# of cache lookups for blocks"),
+            &["type"],
+        );
+        let usage = metrics.gauge_int(MetricOpts::new(
+            "electrs_blocks_cache_size",
+            "Cache usage for blocks (bytes)",
+        ));
+        BlockCache {
+            map: Mutex::new(SizedLruCache::new(bytes_capacity, lookups, usage)),
+        }
+    }
+
+    pub fn get_or_else<F>(&self, blockhash: &BlockHash, load_block_func: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce() -> Result<Vec<u8>>,
+    {
+        if let Some(serialized_block) = self.map.lock().unwrap().get(blockhash) {
+            return Ok(serialized_block.clone());
+        }
+        let serialized_block = load_block_func()?;
+        let byte_size = 32 /* key (hash size) */ + serialized_block.len();
         self.map
             .lock()
             .unwrap()
-            .put(*blockhash, txids.clone(), byte_size);
-        Ok(txids)
+            .put(*blockhash, serialized_block.clone(), byte_size);
+        Ok(serialized_block)
+    }
+}
+
+pub struct HeaderChunkCache {
+    // Store the concatenated hex of complete header chunks (keyed by their last block hash).
+    map: Mutex<SizedLruCache<BlockHash, Arc<String>>>,
+}
+
+impl HeaderChunkCache {
+    pub fn new(bytes_capacity: usize, metrics: &Metrics) -> HeaderChunkCache {
+        let lookups = metrics.counter_vec(
+            MetricOpts::new(
+                "electrs_header_chunks_cache",
+                "# of cache lookups for header chunks",
+            ),
+            &["type"],
+        );
+        let usage = metrics.gauge_int(MetricOpts::new(
+            "electrs_header_chunks_cache_size",
+            "Cache usage for header chunks (bytes)",
+        ));
+        HeaderChunkCache {
+            map: Mutex::new(SizedLruCache::new(bytes_capacity, lookups, usage)),
+        }
+    }
+
+    pub fn get_or_else<F>(&self, last_blockhash: &BlockHash, hex_func: F) -> Arc<String>
+    where
+        F: FnOnce() -> String,
+    {
+        if let Some(chunk_hex) = self.map.lock().unwrap().get(last_blockhash) {
+            return chunk_hex.clone();
+        }
+        let chunk_hex = Arc::new(hex_func());
+        let byte_size = 32 /* key (hash size) */ + chunk_hex.len();
+        self.map
+            .lock()
+            .unwrap()
+            .put(*last_blockhash, chunk_hex.clone(), byte_size);
+        chunk_hex
+    }
+}
+
+pub struct MerkleCache {
+    // Store the merkle tree levels of blocks (from their txids to the root), for merkle proofs.
+    map: Mutex<SizedLruCache<BlockHash, Arc<Vec<Vec<TxMerkleNode>>>>>,
+}
+
+impl MerkleCache {
+    pub fn new(bytes_capacity: usize, metrics: &Metrics) -> MerkleCache {
+        let lookups = metrics.counter_vec(
+            MetricOpts::new(
+                "electrs_merkle_cache",
+                "# of cache lookups for merkle trees",
+            ),
+            &["type"],
+        );
+        let usage = metrics.gauge_int(MetricOpts::new(
+            "electrs_merkle_cache_size",
+            "Cache usage for merkle trees (bytes)",
+        ));
+        MerkleCache {
+            map: Mutex::new(SizedLruCache::new(bytes_capacity, lookups, usage)),
+        }
+    }
+
+    pub fn get_or_else<F>(
+        &self,
+        blockhash: &BlockHash,
+        levels_func: F,
+    ) -> Arc<Vec<Vec<TxMerkleNode>>>
+    where
+        F: FnOnce() -> Vec<Vec<TxMerkleNode>>,
+    {
+        if let Some(levels) = self.map.lock().unwrap().get(blockhash) {
+            return levels.clone();
+        }
+        let levels = Arc::new(levels_func());
+        let nodes: usize = levels.iter().map(Vec::len).sum();
+        let byte_size = 32 /* hash size */ * (1 /* key */ + nodes);
+        self.map
+            .lock()
+            .unwrap()
+            .put(*blockhash, levels.clone(), byte_size);
+        levels
     }
 }
 
@@ -133,12 +261,31 @@ impl TransactionCache {
         }
         let serialized_txn = load_txn_func()?;
         let txn = deserialize(&serialized_txn).chain_err(|| "failed to parse serialized tx")?;
+        self.add(txid, serialized_txn);
+        Ok(txn)
+    }
+
+    /// Returns the serialized transaction (if cached).
+    pub fn get(&self, txid: &Txid) -> Option<Vec<u8>> {
+        self.map.lock().unwrap().get(txid).cloned()
+    }
+
+    pub fn add(&self, txid: &Txid, serialized_txn: Vec<u8>) {
         let byte_size = 32 /* key (hash size) */ + serialized_txn.len();
         self.map
             .lock()
             .unwrap()
             .put(*txid, serialized_txn, byte_size);
-        Ok(txn)
+    }
+
+    /// Returns the total size of the transactions which may be cached (in bytes).
+    pub fn capacity(&self) -> usize {
+        self.map.lock().unwrap().bytes_capacity
+    }
+
+    /// Evicts the transaction (so it will be fetched again from `bitcoind`).
+    pub fn remove(&self, txid: &Txid) -> bool {
+        self.map.lock().unwrap().remove(txid).is_some()
     }
 }
 
@@ -269,5 +416,44 @@ mod tests {
             tx
         );
         assert_eq!(misses, 1);
+
+        assert!(cache.remove(&txid));
+        assert!(!cache.remove(&txid));
+        assert!(cache.get(&txid).is_none());
+    }
+
+    #[test]
+    fn test_header_chunk_cache() {
+        let dummy_metrics = Metrics::new("127.0.0.1:60000".parse().unwrap());
+        let cache = HeaderChunkCache::new(1024, &dummy_metrics);
+        let blockhash: BlockHash = gen_hash(1);
+
+        let chunk = cache.get_or_else(&blockhash, || "00".repeat(80));
+        assert_eq!(chunk.len(), 160);
+        let cached = cache.get_or_else(&blockhash, || panic!("should not be called"));
+        assert!(Arc::ptr_eq(&chunk, &cached));
+
+        // too large to be cached
+        let other: BlockHash = gen_hash(2);
+        cache.get_or_else(&other, || "00".repeat(1024));
+        assert_eq!(cache.get_or_else(&other, || "".to_owned()).len(), 0);
+    }
+
+    #[test]
+    fn test_merkle_cache() {
+        let dummy_metrics = Metrics::new("127.0.0.1:60000".parse().unwrap());
+        let cache = MerkleCache::new(32 * 4, &dummy_metrics);
+        let blockhash: BlockHash = gen_hash(1);
+        let levels = || vec![vec![gen_hash(2), gen_hash(3)], vec![gen_hash(4)]];
+
+        let tree = cache.get_or_else(&blockhash, levels);
+        assert_eq!(tree.len(), 2);
+        let cached = cache.get_or_else(&blockhash, || panic!("should not be called"));
+        assert!(Arc::ptr_eq(&tree, &cached));
+
+        // too large to be cached
+        let other: BlockHash = gen_hash(5);
+        cache.get_or_else(&other, || vec![vec![gen_hash(6); 4]]);
+        assert!(cache.get_or_else(&other, Vec::new).is_empty());
     }
 }
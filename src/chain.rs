@@ -0,0 +1,128 @@
+//! Chain-specific block and transaction types: Bitcoin by default, or Elements-based chains
+//! (e.g. Liquid) when the `liquid` feature is enabled.
+//!
+//! The rest of the code should use these types (instead of importing them from `bitcoin`),
+//! so that it can be built for both.
+
+use bitcoin::network::constants::Network;
+
+use crate::errors::*;
+
+#[cfg(not(feature = "liquid"))]
+pub use bitcoin::{
+    blockdata::block::{Block, BlockHeader},
+    blockdata::transaction::{OutPoint, Transaction, TxIn, TxOut},
+    consensus::encode::{deserialize, serialize, Decodable},
+    hash_types::{BlockHash, TxMerkleNode, Txid},
+};
+
+#[cfg(feature = "liquid")]
+pub use elements::{
+    encode::{deserialize, serialize, Decodable},
+    Block, BlockHash, BlockHeader, OutPoint, Transaction, TxIn, TxMerkleNode, TxOut, Txid,
+};
+
+/// Returns the output's value (in satoshis), or `None` if it is confidential.
+#[cfg(not(feature = "liquid"))]
+pub fn output_value(output: &TxOut) -> Option<u64> {
+    Some(output.value)
+}
+
+/// Returns the output's value (in satoshis), or `None` if it is confidential.
+#[cfg(feature = "liquid")]
+pub fn output_value(output: &TxOut) -> Option<u64> {
+    match output.value {
+        elements::confidential::Value::Explicit(value) => Some(value),
+        _ => None,
+    }
+}
+
+/// Returns the output spent by this input, or `None` if it doesn't spend an output of this chain
+/// (i.e. a coinbase input, or an Elements peg-in).
+pub fn spent_output(input: &TxIn) -> Option<&OutPoint> {
+    #[cfg(feature = "liquid")]
+    {
+        if input.is_pegin {
+            return None;
+        }
+    }
+    if input.previous_output.txid == Txid::default() {
+        None
+    } else {
+        Some(&input.previous_output)
+    }
+}
+
+/// Returns whether the transaction is a coinbase (i.e. its only input spends the null outpoint).
+pub fn is_coinbase(txn: &Transaction) -> bool {
+    match txn.input.as_slice() {
+        [input] => {
+            input.previous_output.txid == Txid::default()
+                && input.previous_output.vout == u32::max_value()
+        }
+        _ => false,
+    }
+}
+
+/// Returns the output script paying to `address`, failing if it is invalid (or belongs to another
+/// network).
+#[cfg(not(feature = "liquid"))]
+pub fn address_script(address: &str, network: Network) -> Result<Option<Vec<u8>>> {
+    use bitcoin::util::address::Address;
+    use std::str::FromStr;
+
+    let address = Address::from_str(address).chain_err(|| "invalid address")?;
+    // testnet, signet and regtest share the same base58 prefixes (and the first two, the same
+    // bech32 prefix)
+    let same_network = address.network == network
+        || (address.network == Network::Testnet
+            && (network == Network::Regtest || network == Network::Signet));
+    if !same_network {
+        bail!("{} address on {}", address.network, network);
+    }
+    Ok(Some(address.script_pubkey().into_bytes()))
+}
+
+/// Returns `None`, since Elements addresses are validated (and converted) by the daemon.
+#[cfg(feature = "liquid")]
+pub fn address_script(_address: &str, _network: Network) -> Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::{address_script, is_coinbase};
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    #[test]
+    fn test_address_script() {
+        let script = address_script("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", Network::Bitcoin)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            hex::encode(script),
+            "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac"
+        );
+        let script = address_script(
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx",
+            Network::Signet,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            hex::encode(script),
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+        assert!(address_script("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa", Network::Testnet).is_err());
+        assert!(address_script("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNb", Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn test_is_coinbase() {
+        let mut txn = genesis_block(Network::Bitcoin).txdata[0].clone();
+        assert!(is_coinbase(&txn));
+        txn.input[0].previous_output.vout = 0;
+        assert!(!is_coinbase(&txn));
+    }
+}
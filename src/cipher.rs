@@ -0,0 +1,223 @@
+//! Encryption of the index rows' values at rest (see `db_encryption_key_file`), using
+//! ChaCha20-Poly1305 with a random nonce per value. The rows' keys aren't encrypted, since the
+//! index looks them up (and scans them) by their prefixes.
+//!
+//! Each encrypted value is stored as `key id (4 bytes) || nonce (12 bytes) || ciphertext || tag
+//! (16 bytes)`, so after a key rotation the values can still be decrypted by the rotated-out key
+//! (until they are re-encrypted, see `DBStore::reencrypt`).
+//!
+//! The keys of the rows identifying a wallet (i.e. the persisted statuses' script hashes) are
+//! replaced by their keyed hashes (see `Cipher::blind`), since they can still be looked up.
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::errors::*;
+use crate::util::{Bytes, FullHash};
+
+const KEY_LEN: usize = 32;
+const KEY_ID_LEN: usize = 4;
+const HEADER_LEN: usize = KEY_ID_LEN + NONCE_LEN;
+
+type KeyId = [u8; KEY_ID_LEN];
+
+struct Key {
+    id: KeyId, // a fingerprint of the key, selecting it for decryption
+    key: LessSafeKey,
+}
+
+pub struct Cipher {
+    keys: Vec<Key>, // the first one encrypts, and the other (rotated-out) ones only decrypt
+    blinding: hmac::Key, // derived from the current key
+    rng: SystemRandom,
+}
+
+impl Cipher {
+    pub fn from_config(config: &Config) -> Result<Option<Cipher>> {
+        let path = match &config.db_encryption_key_file {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let keys = std::iter::once(path)
+            .chain(config.db_old_encryption_key_files.iter())
+            .map(|path| load_key(path))
+            .collect::<Result<Vec<_>>>()?;
+        Cipher::new(&keys).map(Some)
+    }
+
+    fn new(keys: &[[u8; KEY_LEN]]) -> Result<Cipher> {
+        let current = keys.first().chain_err(|| "missing encryption key")?;
+        let blinding = hmac::sign(
+            &hmac::Key::new(hmac::HMAC_SHA256, current),
+            b"electrs blinding key",
+        );
+        let mut ids: Vec<KeyId> = vec![];
+        let mut result = vec![];
+        for key in keys {
+            let id = key_id(key);
+            if ids.contains(&id) {
+                bail!("duplicate encryption key {}", hex::encode(id));
+            }
+            ids.push(id);
+            let key = UnboundKey::new(&CHACHA20_POLY1305, key).expect("invalid key length");
+            result.push(Key {
+                id,
+                key: LessSafeKey::new(key),
+            });
+        }
+        Ok(Cipher {
+            keys: result,
+            blinding: hmac::Key::new(hmac::HMAC_SHA256, blinding.as_ref()),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Encrypts a row's value using the current key, authenticating its row's key (so values
+    /// can't be swapped between rows). Empty values (e.g. of the funding and spending rows) are
+    /// kept, since they carry no information.
+    pub fn encrypt(&self, row_key: &[u8], value: &[u8]) -> Bytes {
+        if value.is_empty() {
+            return vec![];
+        }
+        let key = &self.keys[0];
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng.fill(&mut nonce).expect("failed to generate nonce");
+        let mut sealed = value.to_vec();
+        key.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(row_key),
+                &mut sealed,
+            )
+            .expect("failed to encrypt");
+        [&key.id[..], &nonce[..], &sealed[..]].concat()
+    }
+
+    pub fn decrypt(&self, row_key: &[u8], value: &[u8]) -> Result<Bytes> {
+        if value.is_empty() {
+            return Ok(vec![]);
+        }
+        if value.len() < HEADER_LEN + CHACHA20_POLY1305.tag_len() {
+            bail!("encrypted value is too short ({} bytes)", value.len());
+        }
+        let (id, rest) = value.split_at(KEY_ID_LEN);
+        let (nonce, sealed) = rest.split_at(NONCE_LEN);
+        let key = self
+            .keys
+            .iter()
+            .find(|key| key.id[..] == id[..])
+            .chain_err(|| {
+                format!(
+                    "value is encrypted by an unknown key {} (see db_old_encryption_key_files)",
+                    hex::encode(id)
+                )
+            })?;
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        nonce_bytes.copy_from_slice(nonce);
+        let mut buf = sealed.to_vec();
+        let len = key
+            .key
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce_bytes),
+                Aad::from(row_key),
+                &mut buf,
+            )
+            .map_err(|_| Error::from("failed to decrypt value (corrupted, or moved between rows)"))?
+            .len();
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// Returns a keyed hash of `data` (e.g. a script hash), for looking up a row without storing
+    /// `data` itself. It depends on the current key, so the rows keyed by it are lost on rotation.
+    pub fn blind(&self, data: &[u8]) -> FullHash {
+        let mut result = FullHash::default();
+        result.copy_from_slice(hmac::sign(&self.blinding, data).as_ref());
+        result
+    }
+
+    /// Returns whether the value is encrypted by the current key (or is empty).
+    pub fn is_current(&self, value: &[u8]) -> bool {
+        value.is_empty() || value.starts_with(&self.keys[0].id)
+    }
+}
+
+fn key_id(key: &[u8; KEY_LEN]) -> KeyId {
+    let mut sha2 = Sha256::new();
+    sha2.update(b"electrs index key id");
+    sha2.update(&key[..]);
+    let mut id = [0u8; KEY_ID_LEN];
+    id.copy_from_slice(&sha2.finalize()[..KEY_ID_LEN]);
+    id
+}
+
+// A hex-encoded 32-byte key (e.g. generated by `openssl rand -hex 32`).
+fn load_key(path: &Path) -> Result<[u8; KEY_LEN]> {
+    let contents = fs::read_to_string(path)
+        .chain_err(|| format!("failed to read encryption key {}", path.display()))?;
+    let bytes = hex::decode(contents.trim())
+        .chain_err(|| format!("invalid encryption key {}", path.display()))?;
+    if bytes.len() != KEY_LEN {
+        bail!(
+            "invalid encryption key {} (expected {} hex-encoded bytes, got {})",
+            path.display(),
+            KEY_LEN,
+            bytes.len()
+        );
+    }
+    let mut key = [0u8; KEY_LEN];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cipher, HEADER_LEN};
+
+    #[test]
+    fn test_encrypt() {
+        let cipher = Cipher::new(&[[1; 32]]).unwrap();
+        let value = cipher.encrypt(b"T1", b"\x7b\x00\x00\x00");
+        assert_eq!(value.len(), HEADER_LEN + 4 + 16);
+        assert_eq!(cipher.decrypt(b"T1", &value).unwrap(), b"\x7b\x00\x00\x00");
+        assert_ne!(value, cipher.encrypt(b"T1", b"\x7b\x00\x00\x00")); // random nonces
+        assert!(cipher.decrypt(b"T2", &value).is_err()); // moved to another row
+        assert!(cipher.decrypt(b"T1", &value[..value.len() - 1]).is_err());
+
+        // empty values aren't encrypted
+        assert!(cipher.encrypt(b"O1", b"").is_empty());
+        assert!(cipher.decrypt(b"O1", b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = Cipher::new(&[[1; 32]]).unwrap();
+        let value = old.encrypt(b"T1", b"header");
+        assert!(old.is_current(&value));
+
+        let rotated = Cipher::new(&[[2; 32], [1; 32]]).unwrap();
+        assert!(!rotated.is_current(&value));
+        assert_eq!(rotated.decrypt(b"T1", &value).unwrap(), b"header");
+        let value = rotated.encrypt(b"T1", b"header");
+        assert!(rotated.is_current(&value));
+
+        let new = Cipher::new(&[[2; 32]]).unwrap();
+        assert_eq!(new.decrypt(b"T1", &value).unwrap(), b"header");
+        assert!(new.decrypt(b"T1", &old.encrypt(b"T1", b"header")).is_err());
+        assert!(Cipher::new(&[[2; 32], [2; 32]]).is_err());
+    }
+
+    #[test]
+    fn test_blind() {
+        let cipher = Cipher::new(&[[1; 32]]).unwrap();
+        assert_eq!(cipher.blind(&[3; 32]), cipher.blind(&[3; 32]));
+        assert_ne!(cipher.blind(&[3; 32]), cipher.blind(&[4; 32]));
+        assert_ne!(cipher.blind(&[3; 32]), [3; 32]);
+        let rotated = Cipher::new(&[[2; 32], [1; 32]]).unwrap();
+        assert_ne!(cipher.blind(&[3; 32]), rotated.blind(&[3; 32]));
+    }
+}
@@ -1,21 +1,24 @@
 use bitcoin::network::constants::Network;
 use dirs_next::home_dir;
+use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ffi::{OsStr, OsString};
 use std::fmt;
 use std::fs;
-use std::net::SocketAddr;
 use std::net::ToSocketAddrs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, Once};
 use std::time::Duration;
 
-use crate::daemon::CookieGetter;
+use crate::daemon::{parse_estimate_mode, CookieGetter};
 use crate::errors::*;
 
 const DEFAULT_SERVER_ADDRESS: [u8; 4] = [127, 0, 0, 1]; // by default, serve on IPv4 localhost
 
+static LOGGING: Once = Once::new(); // logging is process-wide (even when serving several networks)
+
 mod internal {
     #![allow(unused)]
 
@@ -87,6 +90,15 @@ impl ResolvAddr {
             std::process::exit(1)
         })
     }
+
+    /// Resolves a comma-separated list of addresses (e.g. `127.0.0.1:50001,[::1]:50001`),
+    /// but prints error and exits in case of failure.
+    fn resolve_list_or_exit(self) -> Vec<SocketAddr> {
+        self.0
+            .split(',')
+            .map(|addr| ResolvAddr(addr.trim().to_owned()).resolve_or_exit())
+            .collect()
+    }
 }
 
 /// This newtype implements `ParseArg` for `Network`.
@@ -125,22 +137,176 @@ pub struct Config {
     pub log: stderrlog::StdErrLog,
     pub network_type: Network,
     pub db_path: PathBuf,
+    pub db_shard_paths: Vec<PathBuf>,
+    pub db_encryption_key_file: Option<PathBuf>,
+    pub db_old_encryption_key_files: Vec<PathBuf>,
     pub daemon_dir: PathBuf,
     pub blocks_dir: PathBuf,
     pub daemon_rpc_addr: SocketAddr,
-    pub electrum_rpc_addr: SocketAddr,
+    pub electrum_rpc_addr: SocketAddr, // the first of `electrum_rpc_addrs` (e.g. for `selftest`)
+    pub electrum_rpc_addrs: Vec<SocketAddr>,
+    pub electrum_tls_addr: Option<SocketAddr>,
+    pub electrum_quic_addr: Option<SocketAddr>,
+    pub electrum_http_addr: Option<SocketAddr>,
+    pub proxy_protocol: bool,
+    pub proxy_protocol_trusted: Vec<IpAddr>, // the load balancers' addresses
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tor_control_addr: Option<SocketAddr>,
+    pub tor_control_password: Option<String>,
     pub monitoring_addr: SocketAddr,
+    pub monitoring_socket: Option<PathBuf>,
+    pub monitoring_token: Option<String>,
+    pub redact_logs: bool,
+    pub txid_search: bool,
     pub jsonrpc_import: bool,
+    pub headers_only: bool,
     pub wait_duration: Duration,
     pub index_batch_size: usize,
     pub bulk_index_threads: usize,
     pub tx_cache_size: usize,
     pub txid_limit: usize,
+    pub max_persisted_statuses: usize,
+    pub persisted_status_ttl: Option<u32>, // in blocks
+    pub max_history: usize,
+    pub require_version_negotiation: bool,
+    pub expensive_request_slots: usize,
+    pub request_cost_budget: usize,
+    pub max_subscriptions: usize,
+    pub subscription_ttl: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+    pub qos_tiers: Vec<TierConfig>, // followed by the tenants
+    pub peer_bandwidth_limit_kb: usize,
+    pub max_connections: Option<usize>,
+    pub evict_idle_peers: bool,
+    pub global_bandwidth_limit_kb: usize,
+    pub fee_estimate_mode: Option<String>,
+    pub broadcast_rate_limit: usize,
+    pub broadcast_global_rate_limit: usize,
+    pub broadcast_max_fee_rate: Option<f64>,
+    pub broadcast_token: Option<String>,
+    pub broadcast_precheck: bool,
     pub server_banner: String,
     pub blocktxids_cache_size: usize,
+    pub block_cache_size: usize,
+    pub header_chunk_cache_size: usize,
+    pub merkle_cache_size: usize,
+    pub event_hook: Option<String>,
+    pub import_wallet: Option<String>,
+    pub stale_tip_threshold: Option<Duration>,
+    pub stale_tip_banner: bool,
+    pub out_of_sync_blocks: Option<usize>,
+    pub out_of_sync_age: Option<Duration>,
+    pub standby_of: Option<SocketAddr>,
+    pub failover_timeout: Duration,
+    pub replication_addr: Option<SocketAddr>,
+    pub replicate_from: Option<SocketAddr>,
+    pub replication_secret: Option<String>,
+    pub backup_dir: Option<PathBuf>,
+    pub backup_interval: Duration,
+    pub backup_retention: usize,
+    pub record_sessions: Option<PathBuf>,
+    pub anonymize_recorded_sessions: bool,
     pub cookie_getter: Arc<dyn CookieGetter>,
 }
 
+/// Request limits of a QoS tier (see `qos_tiers`), or of a tenant (see `tenants`).
+#[derive(Clone)]
+pub struct TierConfig {
+    pub name: String,
+    pub password: String,
+    pub request_cost_budget: usize,
+    pub max_subscriptions: usize,
+    pub txid_limit: usize,
+    // A tenant's limits apply to all its connections together (instead of per client).
+    pub tenant: bool,
+    pub allowed_methods: Vec<String>, // names, or prefixes ending with '*' (empty - all)
+}
+
+// the password is skipped, to avoid logging it
+impl fmt::Debug for TierConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TierConfig")
+            .field("name", &self.name)
+            .field("request_cost_budget", &self.request_cost_budget)
+            .field("max_subscriptions", &self.max_subscriptions)
+            .field("txid_limit", &self.txid_limit)
+            .field("tenant", &self.tenant)
+            .field("allowed_methods", &self.allowed_methods)
+            .finish()
+    }
+}
+
+fn parse_tiers(tiers: &str) -> std::result::Result<Vec<TierConfig>, String> {
+    parse_tier_specs(tiers, /*tenant=*/ false)
+}
+
+fn parse_tenants(tenants: &str) -> std::result::Result<Vec<TierConfig>, String> {
+    parse_tier_specs(tenants, /*tenant=*/ true)
+}
+
+// Tenants are specified as tiers, followed by their allowed methods.
+fn parse_tier_specs(specs: &str, tenant: bool) -> std::result::Result<Vec<TierConfig>, String> {
+    let (kind, fields) = if tenant {
+        ("tenant", 6)
+    } else {
+        ("QoS tier", 5)
+    };
+    let mut result: Vec<TierConfig> = vec![];
+    for spec in specs.split(',') {
+        let parts: Vec<&str> = spec.split(':').collect();
+        if parts.len() != fields || parts[0].is_empty() || parts[1].is_empty() {
+            return Err(format!(
+                "invalid {} #{} (expected 'name:password:request_cost_budget:max_subscriptions:txid_limit{}')",
+                kind,
+                result.len() + 1,
+                if tenant { ":allowed_methods" } else { "" }
+            ));
+        }
+        let limit = |i: usize| {
+            parts[i]
+                .parse::<usize>()
+                .map_err(|err| format!("invalid {} {} limit: {}", kind, parts[0], err))
+        };
+        let allowed_methods = match parts.get(5) {
+            Some(methods) => methods
+                .split(';')
+                .filter(|method| !method.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            None => vec![],
+        };
+        result.push(TierConfig {
+            name: parts[0].to_owned(),
+            password: parts[1].to_owned(),
+            request_cost_budget: limit(2)?,
+            max_subscriptions: limit(3)?,
+            txid_limit: limit(4)?,
+            tenant,
+            allowed_methods,
+        });
+    }
+    Ok(result)
+}
+
+// Enables the limits which are disabled (i.e. set to 0), so the configured ones can only be
+// tightened by `--public-server`.
+fn apply_public_server_preset(config: &mut internal::Config) {
+    fn enable<T: Default + PartialEq>(value: &mut T, preset: T) {
+        if *value == T::default() {
+            *value = preset;
+        }
+    }
+    enable(&mut config.txid_limit, 100);
+    enable(&mut config.request_cost_budget, 600);
+    enable(&mut config.max_subscriptions, 10_000);
+    enable(&mut config.broadcast_rate_limit, 10);
+    enable(&mut config.broadcast_global_rate_limit, 100);
+    enable(&mut config.stale_tip_threshold_mins, 120);
+    config.stale_tip_banner = true;
+    config.redact_logs = true;
+}
+
 /// Returns default daemon directory
 fn default_daemon_dir() -> PathBuf {
     let mut home = home_dir().unwrap_or_else(|| {
@@ -195,9 +361,108 @@ fn select_auth(auth: Option<String>, cookie: Option<String>) -> Option<String> {
     }
 }
 
+/// Options passed to a subcommand (e.g. `electrs export --format csv`), which are not part of
+/// the regular configuration.
+pub struct SubcommandArgs {
+    actions: Vec<String>,
+    options: HashMap<String, String>,
+}
+
+impl SubcommandArgs {
+    /// Returns the positional arguments following the subcommand (e.g. `electrs db dump`).
+    pub fn action(&self, index: usize) -> Option<&str> {
+        self.actions.get(index).map(String::as_str)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    pub fn require(&self, name: &str) -> Result<&str> {
+        self.get(name)
+            .chain_err(|| format!("missing --{} argument", name))
+    }
+}
+
+fn os_string_or_exit(value: OsString) -> String {
+    value.into_string().unwrap_or_else(|value| {
+        eprintln!("Error: {}", InvalidUtf8(value));
+        std::process::exit(1)
+    })
+}
+
+/// Splits `electrs <subcommand> [actions...] [args...]` into the subcommand's positional actions,
+/// its own `--<name> <value>` options and the remaining arguments (which are parsed as usual by
+/// `configure_me`).
+fn split_subcommand_args(
+    args: impl Iterator<Item = OsString>,
+    names: &[&str],
+) -> (Vec<OsString>, SubcommandArgs) {
+    let mut args = args.peekable();
+    let mut rest: Vec<OsString> = args.next().into_iter().collect(); // program name
+    args.next(); // subcommand name
+    let mut actions = vec![];
+    while let Some(arg) = args.peek() {
+        if arg.to_string_lossy().starts_with('-') {
+            break;
+        }
+        actions.push(os_string_or_exit(args.next().unwrap()));
+    }
+    let mut options = HashMap::new();
+    while let Some(arg) = args.next() {
+        let name = match arg.to_str() {
+            Some(arg) if arg.starts_with("--") && names.contains(&&arg[2..]) => arg[2..].to_owned(),
+            _ => {
+                rest.push(arg);
+                continue;
+            }
+        };
+        let value = args.next().unwrap_or_else(|| {
+            eprintln!("Error: missing value for --{}", name);
+            std::process::exit(1)
+        });
+        options.insert(name, os_string_or_exit(value));
+    }
+    (rest, SubcommandArgs { actions, options })
+}
+
 impl Config {
     /// Parses args, env vars, config files and post-processes them
     pub fn from_args() -> Config {
+        Config::from_custom_args(std::env::args_os())
+    }
+
+    /// Parses the command line of a subcommand: the options listed in `names` are returned
+    /// separately, while the rest is parsed into `Config` (as in `Config::from_args()`).
+    pub fn for_subcommand(names: &[&str]) -> (Config, SubcommandArgs) {
+        let (args, subcommand_args) = split_subcommand_args(std::env::args_os(), names);
+        (Config::from_custom_args(args), subcommand_args)
+    }
+
+    /// Parses the command line of the `multi` subcommand: each of the comma-separated files in
+    /// `--network-confs` configures a single network (on top of the rest of the arguments).
+    pub fn for_networks() -> Vec<Config> {
+        let (args, subcommand_args) =
+            split_subcommand_args(std::env::args_os(), &["network-confs"]);
+        let files = subcommand_args
+            .require("network-confs")
+            .unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                std::process::exit(1)
+            });
+        files
+            .split(',')
+            .map(|file| {
+                // config files are read in order, so the rest of the arguments take precedence
+                let mut network_args = args.clone();
+                network_args.insert(1, "--conf".into());
+                network_args.insert(2, file.into());
+                Config::from_custom_args(network_args)
+            })
+            .collect()
+    }
+
+    fn from_custom_args<A: IntoIterator<Item = OsString>>(args: A) -> Config {
         use internal::ResultExt;
 
         let system_config: &OsStr = "/etc/electrs/config.toml".as_ref();
@@ -211,7 +476,13 @@ impl Config {
             .chain(std::iter::once(system_config));
 
         let (mut config, _) =
-            internal::Config::including_optional_config_files(configs).unwrap_or_exit();
+            internal::Config::custom_args_and_optional_files(args, configs).unwrap_or_exit();
+        if config.public_server {
+            apply_public_server_preset(&mut config);
+        }
+        if config.onion_only {
+            config.redact_logs = true;
+        }
 
         let db_subdir = match config.network {
             // We must keep the name "mainnet" due to backwards compatibility
@@ -222,6 +493,21 @@ impl Config {
         };
 
         config.db_dir.push(db_subdir);
+        let db_shard_paths = config.db_shard_dirs.as_ref().map_or(vec![], |dirs| {
+            dirs.split(',')
+                .map(|dir| Path::new(dir).join(db_subdir))
+                .collect()
+        });
+        let db_old_encryption_key_files: Vec<PathBuf> = config
+            .db_old_encryption_key_files
+            .as_ref()
+            .map_or(vec![], |files| {
+                files.split(',').map(PathBuf::from).collect()
+            });
+        if config.db_encryption_key_file.is_none() && !db_old_encryption_key_files.is_empty() {
+            eprintln!("Error: db_old_encryption_key_files requires db_encryption_key_file");
+            std::process::exit(1);
+        }
 
         let default_daemon_port = match config.network {
             Network::Bitcoin => 8332,
@@ -246,10 +532,95 @@ impl Config {
             (DEFAULT_SERVER_ADDRESS, default_daemon_port).into(),
             ResolvAddr::resolve_or_exit,
         );
-        let electrum_rpc_addr: SocketAddr = config.electrum_rpc_addr.map_or(
-            (DEFAULT_SERVER_ADDRESS, default_electrum_port).into(),
-            ResolvAddr::resolve_or_exit,
+        let electrum_rpc_addrs: Vec<SocketAddr> = config.electrum_rpc_addr.map_or_else(
+            || vec![(DEFAULT_SERVER_ADDRESS, default_electrum_port).into()],
+            ResolvAddr::resolve_list_or_exit,
         );
+        let electrum_rpc_addr = electrum_rpc_addrs[0];
+        let electrum_tls_addr: Option<SocketAddr> =
+            config.electrum_tls_addr.map(ResolvAddr::resolve_or_exit);
+        let electrum_quic_addr: Option<SocketAddr> =
+            config.electrum_quic_addr.map(ResolvAddr::resolve_or_exit);
+        let electrum_http_addr: Option<SocketAddr> =
+            config.electrum_http_addr.map(ResolvAddr::resolve_or_exit);
+        let tor_control_addr: Option<SocketAddr> =
+            config.tor_control_addr.map(ResolvAddr::resolve_or_exit);
+        if config.standby_of.is_some() && config.replicate_from.is_some() {
+            // the replication stream stops along with the primary
+            eprintln!("Error: standby_of can't be used with replicate_from");
+            std::process::exit(1);
+        }
+        if tor_control_addr.is_some() && config.proxy_protocol {
+            // Tor would connect to electrum_rpc_addr without a PROXY header
+            eprintln!("Error: tor_control_addr can't be used with proxy_protocol");
+            std::process::exit(1);
+        }
+        let proxy_protocol_trusted: Vec<IpAddr> =
+            config
+                .proxy_protocol_trusted
+                .as_ref()
+                .map_or(vec![], |addrs| {
+                    addrs
+                        .split(',')
+                        .map(|addr| {
+                            addr.trim().parse().unwrap_or_else(|_| {
+                                eprintln!(
+                                    "Error: invalid proxy_protocol_trusted address: {}",
+                                    addr
+                                );
+                                std::process::exit(1)
+                            })
+                        })
+                        .collect()
+                });
+        if config.proxy_protocol == proxy_protocol_trusted.is_empty() {
+            // otherwise, any client could spoof its address (e.g. to evade the per-IP limits)
+            eprintln!("Error: proxy_protocol requires proxy_protocol_trusted (and vice versa)");
+            std::process::exit(1);
+        }
+        if electrum_tls_addr.is_some() && (config.tls_cert.is_none() || config.tls_key.is_none()) {
+            eprintln!("Error: electrum_tls_addr requires tls_cert and tls_key");
+            std::process::exit(1);
+        }
+        if electrum_quic_addr.is_some() {
+            if !cfg!(feature = "quic") {
+                eprintln!(
+                    "Error: electrum_quic_addr requires electrs built with the 'quic' feature"
+                );
+                std::process::exit(1);
+            }
+            if config.tls_cert.is_none() || config.tls_key.is_none() {
+                eprintln!("Error: electrum_quic_addr requires tls_cert and tls_key");
+                std::process::exit(1);
+            }
+        }
+        // an onion service forwards its clients' connections from a loopback address
+        for addr in &electrum_rpc_addrs {
+            if config.onion_only && !addr.ip().is_loopback() {
+                eprintln!(
+                    "Error: onion_only requires a loopback electrum_rpc_addr (not {})",
+                    addr
+                );
+                std::process::exit(1);
+            }
+        }
+        let other_addrs = [
+            ("electrum_tls_addr", electrum_tls_addr),
+            ("electrum_quic_addr", electrum_quic_addr),
+            ("electrum_http_addr", electrum_http_addr),
+        ];
+        for (name, addr) in other_addrs.iter() {
+            match addr {
+                Some(addr) if config.onion_only && !addr.ip().is_loopback() => {
+                    eprintln!(
+                        "Error: onion_only requires a loopback {} (not {})",
+                        name, addr
+                    );
+                    std::process::exit(1);
+                }
+                _ => (),
+            }
+        }
         let monitoring_addr: SocketAddr = config.monitoring_addr.map_or(
             (DEFAULT_SERVER_ADDRESS, default_monitoring_port).into(),
             ResolvAddr::resolve_or_exit,
@@ -282,33 +653,176 @@ impl Config {
         } else {
             stderrlog::Timestamp::Off
         });
-        log.init().unwrap_or_else(|err| {
-            eprintln!("Error: logging initialization failed: {}", err);
-            std::process::exit(1)
+        LOGGING.call_once(|| {
+            log.init().unwrap_or_else(|err| {
+                eprintln!("Error: logging initialization failed: {}", err);
+                std::process::exit(1)
+            })
         });
+        if config.redact_logs {
+            crate::redact::enable();
+        }
         // Could have been default, but it's useful to allow the user to specify 0 when overriding
         // configs.
         if config.bulk_index_threads == 0 {
             config.bulk_index_threads = num_cpus::get();
         }
+        if config.expensive_request_slots == 0 {
+            config.expensive_request_slots = num_cpus::get();
+        }
+        let mut qos_tiers = config
+            .qos_tiers
+            .as_ref()
+            .map_or(Ok(vec![]), |tiers| parse_tiers(tiers))
+            .unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                std::process::exit(1)
+            });
+        let tenants = config
+            .tenants
+            .as_ref()
+            .map_or(Ok(vec![]), |tenants| parse_tenants(tenants))
+            .unwrap_or_else(|err| {
+                eprintln!("Error: {}", err);
+                std::process::exit(1)
+            });
+        qos_tiers.extend(tenants);
+        let mut names: Vec<&str> = qos_tiers.iter().map(|tier| &tier.name[..]).collect();
+        names.push("anonymous");
+        names.sort_unstable();
+        if let Some(name) = names.windows(2).find(|pair| pair[0] == pair[1]) {
+            eprintln!("Error: duplicate QoS tier (or tenant) name: {}", name[0]);
+            std::process::exit(1)
+        }
+        let fee_estimate_mode = config.fee_estimate_mode.as_ref().map(|mode| {
+            parse_estimate_mode(mode).unwrap_or_else(|err| {
+                eprintln!("Error: invalid fee_estimate_mode: {}", err);
+                std::process::exit(1)
+            })
+        });
+        let server_banner = match config.server_banner_file {
+            Some(path) => fs::read_to_string(&path)
+                .map(|banner| banner.trim_end().to_owned())
+                .unwrap_or_else(|err| {
+                    eprintln!("Error: failed to read {}: {}", path.display(), err);
+                    std::process::exit(1)
+                }),
+            None => config.server_banner,
+        };
+        let subscription_ttl = match config.subscription_ttl_mins {
+            0 => None,
+            mins => Some(Duration::from_secs(mins * 60)),
+        };
+        let persisted_status_ttl = match config.persisted_status_ttl_blocks {
+            0 => None,
+            blocks => Some(blocks as u32),
+        };
+        let idle_timeout = match config.idle_timeout_mins {
+            0 => None,
+            mins => Some(Duration::from_secs(mins * 60)),
+        };
+        let replication_addr = config.replication_addr.map(ResolvAddr::resolve_or_exit);
+        if let Some(addr) = replication_addr {
+            // the followers apply the replicated rows, so they must be authenticated
+            if !addr.ip().is_loopback() && config.replication_secret.is_none() {
+                eprintln!(
+                    "Error: replication_addr {} requires replication_secret (unless it's a loopback address)",
+                    addr
+                );
+                std::process::exit(1);
+            }
+        }
+        let max_connections = match config.max_connections {
+            0 => None,
+            count => Some(count),
+        };
+        let stale_tip_threshold = match config.stale_tip_threshold_mins {
+            0 => None,
+            mins => Some(Duration::from_secs(mins * 60)),
+        };
+        let out_of_sync_blocks = match config.out_of_sync_blocks {
+            0 => None,
+            blocks => Some(blocks),
+        };
+        let out_of_sync_age = match config.out_of_sync_mins {
+            0 => None,
+            mins => Some(Duration::from_secs(mins * 60)),
+        };
         const MB: f32 = (1 << 20) as f32;
         let config = Config {
             log,
             network_type: config.network,
             db_path: config.db_dir,
+            db_shard_paths,
+            db_encryption_key_file: config.db_encryption_key_file,
+            db_old_encryption_key_files,
             daemon_dir: config.daemon_dir,
             blocks_dir,
             daemon_rpc_addr,
             electrum_rpc_addr,
+            electrum_rpc_addrs,
+            electrum_tls_addr,
+            electrum_quic_addr,
+            electrum_http_addr,
+            proxy_protocol: config.proxy_protocol,
+            proxy_protocol_trusted,
+            tls_cert: config.tls_cert,
+            tls_key: config.tls_key,
+            tor_control_addr,
+            tor_control_password: config.tor_control_password,
             monitoring_addr,
+            monitoring_socket: config.monitoring_socket,
+            monitoring_token: config.monitoring_token,
+            redact_logs: config.redact_logs,
+            txid_search: config.txid_search,
             jsonrpc_import: config.jsonrpc_import,
+            headers_only: config.headers_only,
             wait_duration: Duration::from_secs(config.wait_duration_secs),
             index_batch_size: config.index_batch_size,
             bulk_index_threads: config.bulk_index_threads,
             tx_cache_size: (config.tx_cache_size_mb * MB) as usize,
             blocktxids_cache_size: (config.blocktxids_cache_size_mb * MB) as usize,
+            block_cache_size: (config.block_cache_size_mb * MB) as usize,
+            header_chunk_cache_size: (config.header_chunk_cache_size_mb * MB) as usize,
+            merkle_cache_size: (config.merkle_cache_size_mb * MB) as usize,
             txid_limit: config.txid_limit,
-            server_banner: config.server_banner,
+            max_persisted_statuses: config.max_persisted_statuses,
+            persisted_status_ttl,
+            max_history: config.max_history,
+            require_version_negotiation: config.require_version_negotiation,
+            expensive_request_slots: config.expensive_request_slots,
+            request_cost_budget: config.request_cost_budget,
+            max_subscriptions: config.max_subscriptions,
+            subscription_ttl,
+            idle_timeout,
+            qos_tiers,
+            peer_bandwidth_limit_kb: config.peer_bandwidth_limit_kb,
+            max_connections,
+            evict_idle_peers: config.evict_idle_peers,
+            global_bandwidth_limit_kb: config.global_bandwidth_limit_kb,
+            fee_estimate_mode,
+            broadcast_rate_limit: config.broadcast_rate_limit,
+            broadcast_global_rate_limit: config.broadcast_global_rate_limit,
+            broadcast_max_fee_rate: config.broadcast_max_fee_rate,
+            broadcast_token: config.broadcast_token,
+            broadcast_precheck: config.broadcast_precheck,
+            server_banner,
+            event_hook: config.event_hook,
+            import_wallet: config.import_wallet,
+            stale_tip_threshold,
+            stale_tip_banner: config.stale_tip_banner,
+            out_of_sync_blocks,
+            out_of_sync_age,
+            standby_of: config.standby_of.map(ResolvAddr::resolve_or_exit),
+            failover_timeout: Duration::from_secs(config.failover_timeout_secs),
+            replication_addr,
+            replicate_from: config.replicate_from.map(ResolvAddr::resolve_or_exit),
+            replication_secret: config.replication_secret,
+            backup_dir: config.backup_dir,
+            backup_interval: Duration::from_secs(config.backup_interval_hours * 3600),
+            backup_retention: config.backup_retention.max(1), // keep at least the new backup
+            record_sessions: config.record_sessions,
+            anonymize_recorded_sessions: config.anonymize_recorded_sessions,
             cookie_getter,
         };
         eprintln!("{:?}", config);
@@ -321,6 +835,8 @@ impl Config {
 }
 
 // CookieGetter + Debug isn't implemented in Rust, so we have to skip cookie_getter
+// (monitoring_token, broadcast_token, tor_control_password and replication_secret are skipped, to
+// avoid logging them)
 macro_rules! debug_struct {
     ($name:ty, $($field:ident,)*) => {
         impl fmt::Debug for $name {
@@ -339,18 +855,70 @@ debug_struct! { Config,
     log,
     network_type,
     db_path,
+    db_shard_paths,
+    db_encryption_key_file,
+    db_old_encryption_key_files,
     daemon_dir,
     blocks_dir,
     daemon_rpc_addr,
-    electrum_rpc_addr,
+    electrum_rpc_addrs,
+    electrum_tls_addr,
+    electrum_quic_addr,
+    electrum_http_addr,
+    proxy_protocol,
+    proxy_protocol_trusted,
+    tls_cert,
+    tls_key,
+    tor_control_addr,
     monitoring_addr,
+    monitoring_socket,
+    redact_logs,
+    txid_search,
     jsonrpc_import,
+    headers_only,
     index_batch_size,
     bulk_index_threads,
     tx_cache_size,
     txid_limit,
+    max_persisted_statuses,
+    persisted_status_ttl,
+    max_history,
+    require_version_negotiation,
+    expensive_request_slots,
+    request_cost_budget,
+    max_subscriptions,
+    subscription_ttl,
+    idle_timeout,
+    qos_tiers,
+    peer_bandwidth_limit_kb,
+    max_connections,
+    evict_idle_peers,
+    global_bandwidth_limit_kb,
+    fee_estimate_mode,
+    broadcast_rate_limit,
+    broadcast_global_rate_limit,
+    broadcast_max_fee_rate,
+    broadcast_precheck,
     server_banner,
     blocktxids_cache_size,
+    block_cache_size,
+    header_chunk_cache_size,
+    merkle_cache_size,
+    event_hook,
+    import_wallet,
+    stale_tip_threshold,
+    stale_tip_banner,
+    out_of_sync_blocks,
+    out_of_sync_age,
+    standby_of,
+    failover_timeout,
+    replication_addr,
+    replicate_from,
+    backup_dir,
+    backup_interval,
+    backup_retention,
+    record_sessions,
+    anonymize_recorded_sessions,
 }
 
 struct StaticCookie {
@@ -398,3 +966,89 @@ impl CookieGetter for CookieFile {
         Ok(contents)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_tenants, parse_tiers, split_subcommand_args};
+    use std::ffi::OsString;
+
+    #[test]
+    fn test_split_subcommand_args() {
+        let args = [
+            "electrs",
+            "export",
+            "--db-dir",
+            "/tmp/db",
+            "--format",
+            "csv",
+            "-vvv",
+            "--scripthash-file",
+            "hashes.txt",
+        ];
+        let (rest, args) = split_subcommand_args(
+            args.iter().map(OsString::from),
+            &["format", "scripthash-file"],
+        );
+        assert_eq!(rest, vec!["electrs", "--db-dir", "/tmp/db", "-vvv"]);
+        assert_eq!(args.action(0), None);
+        assert_eq!(args.options.len(), 2);
+        assert_eq!(args.get("format"), Some("csv"));
+        assert_eq!(args.get("scripthash-file"), Some("hashes.txt"));
+    }
+
+    #[test]
+    fn test_split_subcommand_actions() {
+        let args = [
+            "electrs",
+            "db",
+            "dump",
+            "--prefix",
+            "54",
+            "--network",
+            "regtest",
+        ];
+        let (rest, args) = split_subcommand_args(args.iter().map(OsString::from), &["prefix"]);
+        assert_eq!(rest, vec!["electrs", "--network", "regtest"]);
+        assert_eq!(args.action(0), Some("dump"));
+        assert_eq!(args.action(1), None);
+        assert_eq!(args.get("prefix"), Some("54"));
+    }
+
+    #[test]
+    fn test_parse_tiers() {
+        let tiers = parse_tiers("own:secret:0:0:0,partner:pass:6000:100000:1000").unwrap();
+        assert_eq!(tiers.len(), 2);
+        assert_eq!(
+            (&tiers[0].name[..], &tiers[0].password[..]),
+            ("own", "secret")
+        );
+        assert_eq!(tiers[1].request_cost_budget, 6000);
+        assert_eq!(tiers[1].max_subscriptions, 100_000);
+        assert_eq!(tiers[1].txid_limit, 1000);
+        assert!(!format!("{:?}", tiers).contains("secret"));
+
+        assert!(parse_tiers("own:secret:0:0").is_err());
+        assert!(parse_tiers("own::0:0:0").is_err());
+        let err = parse_tiers("own:secret:0:0:many").unwrap_err();
+        assert!(err.starts_with("invalid QoS tier own limit") && !err.contains("secret"));
+        assert!(!tiers[0].tenant && tiers[0].allowed_methods.is_empty());
+    }
+
+    #[test]
+    fn test_parse_tenants() {
+        let tenants = parse_tenants(
+            "shop:pass:600:1000:0:blockchain.scripthash.*;blockchain.relayfee,ops:pw:0:0:0:",
+        )
+        .unwrap();
+        assert_eq!(tenants.len(), 2);
+        assert!(tenants[0].tenant);
+        assert_eq!(
+            tenants[0].allowed_methods,
+            vec!["blockchain.scripthash.*", "blockchain.relayfee"]
+        );
+        assert!(tenants[1].allowed_methods.is_empty()); // all the methods are allowed
+
+        let err = parse_tenants("shop:pass:600:1000:0").unwrap_err();
+        assert!(err.starts_with("invalid tenant #1"));
+    }
+}
@@ -0,0 +1,691 @@
+//! Electrum protocol (1.4) conformance checks, exercising the supported methods of a running
+//! server and reporting any deviations from the specification (see `electrs selftest`).
+use bitcoin::hashes::hex::FromHex;
+use error_chain::ChainedError;
+use serde_json::Value;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+
+use crate::errors::*;
+
+const HEADER_HEX_LEN: usize = 160;
+const HASH_HEX_LEN: usize = 64;
+
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    next_id: u64,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Result<Client> {
+        let stream =
+            TcpStream::connect(addr).chain_err(|| format!("failed to connect {}", addr))?;
+        let reader = BufReader::new(stream.try_clone().chain_err(|| "failed to clone stream")?);
+        Ok(Client {
+            stream,
+            reader,
+            next_id: 0,
+        })
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> (u64, Value) {
+        self.next_id += 1;
+        let id = self.next_id;
+        let request = json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params});
+        (id, request)
+    }
+
+    fn send(&mut self, request: &Value) -> Result<()> {
+        self.stream
+            .write_all((request.to_string() + "\n").as_bytes())
+            .chain_err(|| "failed to send request")
+    }
+
+    // Returns the next response (skipping any notifications sent before it).
+    fn receive(&mut self) -> Result<Value> {
+        loop {
+            let mut line = String::new();
+            self.reader
+                .read_line(&mut line)
+                .chain_err(|| "failed to read response")?;
+            if line.is_empty() {
+                bail!("server disconnected");
+            }
+            let response: Value =
+                serde_json::from_str(&line).chain_err(|| format!("invalid JSON: {}", line))?;
+            if response.get("id").is_none() && response.get("method").is_some() {
+                continue; // a notification
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Returns the response to the request.
+    fn call(&mut self, method: &str, params: &Value) -> Result<Value> {
+        let (id, request) = self.request(method, params);
+        self.send(&request)?;
+        let response = self.receive()?;
+        expect_envelope(&response, id)?;
+        Ok(response)
+    }
+
+    /// Returns the responses to a batch of requests (in the same order).
+    fn call_batch(&mut self, calls: &[(&str, Value)]) -> Result<Vec<Value>> {
+        let (ids, requests): (Vec<u64>, Vec<Value>) = calls
+            .iter()
+            .map(|(method, params)| self.request(method, params))
+            .unzip();
+        self.send(&json!(requests))?;
+        let responses = self.receive()?;
+        let responses = expect_array(&responses)?;
+        if responses.len() != ids.len() {
+            bail!("{} responses to {} requests", responses.len(), ids.len());
+        }
+        for (response, id) in responses.iter().zip(ids) {
+            expect_envelope(response, id)?;
+        }
+        Ok(responses.clone())
+    }
+}
+
+fn expect_envelope(response: &Value, id: u64) -> Result<()> {
+    if response.get("jsonrpc") != Some(&json!("2.0")) {
+        bail!("missing 'jsonrpc': \"2.0\"");
+    }
+    if response.get("id") != Some(&json!(id)) {
+        bail!("unexpected id: {:?}", response.get("id"));
+    }
+    Ok(())
+}
+
+fn expect_result(response: &Value) -> Result<&Value> {
+    if let Some(error) = response.get("error") {
+        bail!("unexpected error: {}", error);
+    }
+    response.get("result").chain_err(|| "missing 'result'")
+}
+
+/// Errors should be objects with an integer code and a message (returning the code).
+fn expect_error(response: &Value) -> Result<i64> {
+    if response.get("result").is_some() {
+        bail!("unexpected result (instead of an error)");
+    }
+    let error = response.get("error").chain_err(|| "missing 'error'")?;
+    let code = field(error, "code")?
+        .as_i64()
+        .chain_err(|| format!("non-integer error code: {}", error))?;
+    expect_str(field(error, "message")?)?;
+    Ok(code)
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Result<&'a Value> {
+    value
+        .as_object()
+        .chain_err(|| format!("not an object: {}", value))?
+        .get(name)
+        .chain_err(|| format!("missing '{}' in {}", name, value))
+}
+
+fn expect_str(value: &Value) -> Result<&str> {
+    value
+        .as_str()
+        .chain_err(|| format!("not a string: {}", value))
+}
+
+fn expect_hex(value: &Value, len: Option<usize>) -> Result<&str> {
+    let hex = expect_str(value)?;
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) || hex.len() % 2 != 0 {
+        bail!("not a hex string: {}", value);
+    }
+    match len {
+        Some(len) if hex.len() != len => bail!("{} hex digits (instead of {})", hex.len(), len),
+        _ => Ok(hex),
+    }
+}
+
+fn expect_hashes(value: &Value) -> Result<()> {
+    for hash in expect_array(value)? {
+        expect_hex(hash, Some(HASH_HEX_LEN))?;
+    }
+    Ok(())
+}
+
+fn expect_u64(value: &Value) -> Result<u64> {
+    value
+        .as_u64()
+        .chain_err(|| format!("not a non-negative integer: {}", value))
+}
+
+fn expect_i64(value: &Value) -> Result<i64> {
+    value
+        .as_i64()
+        .chain_err(|| format!("not an integer: {}", value))
+}
+
+fn expect_number(value: &Value) -> Result<f64> {
+    value
+        .as_f64()
+        .chain_err(|| format!("not a number: {}", value))
+}
+
+fn expect_array(value: &Value) -> Result<&Vec<Value>> {
+    value
+        .as_array()
+        .chain_err(|| format!("not an array: {}", value))
+}
+
+fn expect_null(value: &Value) -> Result<()> {
+    if !value.is_null() {
+        bail!("not null: {}", value);
+    }
+    Ok(())
+}
+
+fn check_history(history: &Value) -> Result<()> {
+    expect_array(history)?
+        .iter()
+        .map(check_history_item)
+        .collect()
+}
+
+fn check_history_item(item: &Value) -> Result<()> {
+    let height = expect_i64(field(item, "height")?)?;
+    expect_hex(field(item, "tx_hash")?, Some(HASH_HEX_LEN))?;
+    if height <= 0 {
+        expect_u64(field(item, "fee")?)?; // required for mempool transactions
+    }
+    if let Some(first_seen) = item.get("first_seen") {
+        expect_u64(first_seen)?; // an extension (see `doc/usage.md`)
+    }
+    Ok(())
+}
+
+fn check_unspent_item(item: &Value) -> Result<()> {
+    expect_u64(field(item, "height")?)?;
+    expect_u64(field(item, "tx_pos")?)?;
+    expect_hex(field(item, "tx_hash")?, Some(HASH_HEX_LEN))?;
+    expect_u64(field(item, "value")?)?;
+    Ok(())
+}
+
+fn check_fee_histogram(histogram: &Value) -> Result<()> {
+    for entry in expect_array(histogram)? {
+        let entry = expect_array(entry)?;
+        if entry.len() != 2 {
+            bail!("expected [fee_rate, vsize] pairs");
+        }
+        expect_number(&entry[0])?;
+        expect_u64(&entry[1])?;
+    }
+    Ok(())
+}
+
+struct Session {
+    client: Client,
+    checks: usize,
+    deviations: Vec<String>,
+}
+
+impl Session {
+    fn record<T>(&mut self, method: &str, params: &Value, result: Result<T>) -> Option<T> {
+        self.checks += 1;
+        match result {
+            Ok(value) => {
+                println!("ok      {} {}", method, params);
+                Some(value)
+            }
+            Err(e) => {
+                let deviation = format!("{} {}: {}", method, params, e.display_chain());
+                println!("FAILED  {}", deviation.trim_end());
+                self.deviations.push(deviation);
+                None
+            }
+        }
+    }
+
+    /// Calls `method`, checking its result (and returning the value extracted by `check`).
+    fn check<T, F>(&mut self, method: &str, params: Value, check: F) -> Option<T>
+    where
+        F: FnOnce(&Value) -> Result<T>,
+    {
+        let result = self
+            .client
+            .call(method, &params)
+            .and_then(|response| check(expect_result(&response)?));
+        self.record(method, &params, result)
+    }
+
+    /// Calls the methods in a single batch, checking that each one returns a result.
+    fn check_batch(&mut self, calls: &[(&str, Value)]) {
+        let methods: Vec<&str> = calls.iter().map(|(method, _)| *method).collect();
+        let result = self.client.call_batch(calls).and_then(|responses| {
+            for response in &responses {
+                expect_result(response)?;
+            }
+            Ok(())
+        });
+        self.record("batch", &json!(methods), result);
+    }
+
+    /// Calls `method` with invalid parameters, expecting an error response.
+    fn check_error(&mut self, method: &str, params: Value) {
+        let result = self
+            .client
+            .call(method, &params)
+            .and_then(|response| expect_error(&response).map(|_| ()));
+        self.record(method, &params, result);
+    }
+
+    /// Calls `method`, expecting an error response with the given (JSON-RPC) code.
+    fn check_error_code(&mut self, method: &str, params: Value, code: i64) {
+        let result =
+            self.client
+                .call(method, &params)
+                .and_then(|response| match expect_error(&response)? {
+                    actual if actual == code => Ok(()),
+                    actual => bail!("unexpected error code {} (instead of {})", actual, code),
+                });
+        self.record(method, &params, result);
+    }
+}
+
+/// Exercises the supported methods of the Electrum server at `addr`, failing if it deviates from
+/// the protocol specification. `script_hash` (if given) is used for the `blockchain.scripthash.*`
+/// methods (otherwise, an unused script hash is queried).
+pub fn run(addr: SocketAddr, script_hash: Option<&str>) -> Result<()> {
+    let script_hash = script_hash.unwrap_or(
+        "0000000000000000000000000000000000000000000000000000000000000000", // unused
+    );
+    bitcoin::hashes::sha256d::Hash::from_hex(script_hash).chain_err(|| "invalid script hash")?;
+
+    let mut s = Session {
+        client: Client::connect(addr)?,
+        checks: 0,
+        deviations: vec![],
+    };
+    s.check("server.version", json!(["electrs-selftest", "1.4"]), |r| {
+        let version = expect_array(r)?;
+        if version.len() != 2 {
+            bail!("expected [server_software_version, protocol_version]");
+        }
+        expect_str(&version[0])?;
+        if expect_str(&version[1])? != "1.4" {
+            bail!("unexpected protocol version {}", version[1]);
+        }
+        Ok(())
+    });
+    // the negotiated version may not be changed
+    s.check_error("server.version", json!(["electrs-selftest", "1.2"]));
+    s.check("server.banner", json!([]), |r| expect_str(r).map(|_| ()));
+    s.check("server.donation_address", json!([]), |r| {
+        expect_str(r).map(|_| ())
+    });
+    s.check("server.features", json!([]), |r| {
+        expect_hex(&r["genesis_hash"], Some(HASH_HEX_LEN))?;
+        if !r["hosts"].is_object() {
+            bail!("expected hosts object");
+        }
+        if expect_str(&r["hash_function"])? != "sha256" {
+            bail!("unexpected hash function {}", r["hash_function"]);
+        }
+        expect_str(&r["protocol_max"])?;
+        expect_str(&r["protocol_min"]).map(|_| ())
+    });
+    s.check("server.peers.subscribe", json!([]), |r| {
+        expect_array(r).map(|_| ())
+    });
+    s.check("server.ping", json!([]), expect_null);
+    s.check_batch(&[
+        ("server.ping", json!([])),
+        ("blockchain.relayfee", json!([])),
+    ]);
+    s.check("blockchain.relayfee", json!([]), |r| {
+        expect_number(r).map(|_| ())
+    });
+    s.check("blockchain.estimatefee", json!([6]), |r| {
+        expect_number(r).map(|_| ())
+    });
+    s.check("blockchain.estimatefee", json!([6, "ECONOMICAL"]), |r| {
+        expect_number(r).map(|_| ())
+    });
+    s.check_error_code("blockchain.estimatefee", json!([6, "RECKLESS"]), -32602);
+    s.check("mempool.get_fee_histogram", json!([]), check_fee_histogram);
+    s.check(
+        "mempool.fee_histogram.subscribe",
+        json!([]),
+        check_fee_histogram,
+    );
+    // requires authentication
+    s.check_error("mempool.transactions.subscribe", json!([]));
+
+    let tip_height = s.check("blockchain.headers.subscribe", json!([]), |r| {
+        expect_hex(field(r, "hex")?, Some(HEADER_HEX_LEN))?;
+        expect_u64(field(r, "height")?)
+    });
+    let height = tip_height.unwrap_or(0);
+    s.check("blockchain.numblocks.subscribe", json!([]), |r| {
+        if expect_u64(r)? != height {
+            bail!("unexpected height (instead of {})", height);
+        }
+        Ok(())
+    });
+    s.check("blockchain.block.header", json!([height]), |r| {
+        expect_hex(r, Some(HEADER_HEX_LEN)).map(|_| ())
+    });
+    if height > 0 {
+        s.check("blockchain.block.header", json!([0, height]), |r| {
+            expect_hex(field(r, "header")?, Some(HEADER_HEX_LEN))?;
+            expect_hex(field(r, "root")?, Some(HASH_HEX_LEN))?;
+            expect_hashes(field(r, "branch")?)
+        });
+    }
+    let start_height = height.saturating_sub(9);
+    s.check("blockchain.block.headers", json!([start_height, 10]), |r| {
+        let count = expect_u64(field(r, "count")?)?;
+        expect_hex(field(r, "hex")?, Some(count as usize * HEADER_HEX_LEN))?;
+        expect_u64(field(r, "max")?)?;
+        if count != height - start_height + 1 {
+            bail!("unexpected count: {}", count);
+        }
+        Ok(())
+    });
+    s.check("blockchain.block.get", json!([height, true]), |r| {
+        if expect_array(r)?.is_empty() {
+            bail!("no transactions");
+        }
+        expect_hashes(r)
+    });
+    s.check("blockchain.block.get", json!([height]), |r| {
+        expect_hex(r, None).map(|_| ())
+    });
+    s.check_error("blockchain.block.header", json!(["not a height"]));
+    s.check_error("blockchain.block.header", json!([height + 1]));
+    s.check_error("blockchain.block.header", json!([0, height + 1])); // above the tip
+    if height > 1 {
+        s.check_error("blockchain.block.header", json!([height, height - 1])); // below the header
+    }
+
+    s.check_error("blockchain.address.get_balance", json!(["not an address"]));
+    s.check_error("blockchain.address.subscribe", json!([script_hash])); // not an address
+
+    // the history should be available without subscribing to the script hash
+    s.check(
+        "blockchain.scripthash.get_history",
+        json!([script_hash]),
+        check_history,
+    );
+    s.check(
+        "blockchain.scripthash.subscribe",
+        json!([script_hash]),
+        |r| {
+            if r.is_null() {
+                return Ok(()); // no history
+            }
+            expect_hex(r, Some(HASH_HEX_LEN)).map(|_| ())
+        },
+    );
+    s.check(
+        "blockchain.scripthashes.subscribe",
+        json!([[script_hash, script_hash]]),
+        |r| {
+            let statuses = expect_array(r)?;
+            if statuses.len() != 2 || statuses[0] != statuses[1] {
+                bail!("unexpected statuses: {}", r);
+            }
+            Ok(())
+        },
+    );
+    s.check(
+        "blockchain.scripthash.get_balance",
+        json!([script_hash]),
+        |r| {
+            expect_i64(field(r, "confirmed")?)?;
+            expect_i64(field(r, "unconfirmed")?)?;
+            Ok(())
+        },
+    );
+    s.check(
+        "blockchain.scripthash.get_history",
+        json!([script_hash]),
+        check_history,
+    );
+    s.check(
+        "blockchain.scripthash.get_history",
+        json!([script_hash, 0, 1]), // the first page (i.e. its first block)
+        check_history,
+    );
+    s.check(
+        "blockchain.scripthash.get_mempool",
+        json!([script_hash]),
+        |r| {
+            expect_array(r)?
+                .iter()
+                .map(check_history_item)
+                .collect::<Result<()>>()
+        },
+    );
+    s.check(
+        "blockchain.scripthash.get_history_since",
+        json!([script_hash, height]),
+        |r| {
+            expect_u64(field(r, "height")?)?;
+            expect_hex(field(r, "block_hash")?, Some(HASH_HEX_LEN))?;
+            if field(r, "reset")? != &json!(false) {
+                bail!("unexpected reset (without a block hash)");
+            }
+            for item in expect_array(field(r, "history")?)? {
+                check_history_item(item)?;
+                if expect_i64(field(item, "height")?)? > 0 {
+                    bail!("unexpected confirmed item (confirmed after the tip)");
+                }
+            }
+            Ok(())
+        },
+    );
+    s.check(
+        "blockchain.scripthash.get_first_use",
+        json!([script_hash]),
+        |r| {
+            if r.is_null() {
+                return Ok(()); // never used
+            }
+            expect_u64(field(r, "height")?)?;
+            expect_hex(field(r, "block_hash")?, Some(HASH_HEX_LEN))?;
+            expect_hex(field(r, "tx_hash")?, Some(HASH_HEX_LEN)).map(|_| ())
+        },
+    );
+    s.check(
+        "blockchain.scripthash.get_utxo_summary",
+        json!([script_hash]),
+        |r| {
+            let count = expect_u64(field(r, "count")?)?;
+            expect_u64(field(r, "total_value")?)?;
+            for key in &["largest", "smallest"] {
+                let value = field(r, key)?;
+                if count > 0 {
+                    expect_u64(value)?;
+                } else {
+                    expect_null(value)?;
+                }
+            }
+            let oldest_height = field(r, "oldest_height")?;
+            if !oldest_height.is_null() {
+                expect_u64(oldest_height)?; // null if there are no confirmed outputs
+            }
+            Ok(())
+        },
+    );
+    // requires authentication
+    s.check_error("blockchain.scripthash.refresh", json!([script_hash]));
+    s.check(
+        "blockchain.scripthash.listunspent",
+        json!([script_hash]),
+        |r| {
+            expect_array(r)?
+                .iter()
+                .map(check_unspent_item)
+                .collect::<Result<()>>()
+        },
+    );
+    s.check_error_code(
+        "blockchain.scripthash.get_history",
+        json!(["not a hash"]),
+        -32602, // invalid params
+    );
+
+    // the tip's coinbase transaction is used for the `blockchain.transaction.*` methods
+    let txid = s.check(
+        "blockchain.transaction.id_from_pos",
+        json!([height, 0]),
+        |r| expect_hex(r, Some(HASH_HEX_LEN)).map(str::to_owned),
+    );
+    s.check(
+        "blockchain.transaction.id_from_pos",
+        json!([height, 0, true]),
+        |r| {
+            expect_hex(field(r, "tx_hash")?, Some(HASH_HEX_LEN))?;
+            expect_hashes(field(r, "merkle")?)
+        },
+    );
+    if let Some(txid) = txid {
+        s.check("blockchain.transaction.get", json!([txid]), |r| {
+            expect_hex(r, None).map(|_| ())
+        });
+        s.check(
+            "blockchain.transaction.get_batch",
+            json!([[txid, txid]]),
+            |r| match expect_array(r)?.as_slice() {
+                [first, second] if first == second => expect_hex(first, None).map(|_| ()),
+                _ => bail!("expected the same transaction twice"),
+            },
+        );
+        s.check(
+            "blockchain.transaction.get_merkle",
+            json!([txid, height]),
+            |r| {
+                if expect_u64(field(r, "block_height")?)? != height {
+                    bail!("unexpected block_height");
+                }
+                if expect_u64(field(r, "pos")?)? != 0 {
+                    bail!("unexpected pos (instead of 0, for a coinbase transaction)");
+                }
+                expect_hashes(field(r, "merkle")?)
+            },
+        );
+        s.check("blockchain.transaction.search", json!([&txid[..16]]), |r| {
+            for item in expect_array(r)? {
+                expect_hex(field(item, "tx_hash")?, Some(HASH_HEX_LEN))?;
+                expect_u64(field(item, "height")?)?;
+            }
+            Ok(()) // may be empty (if txid search is disabled)
+        });
+        s.check(
+            "blockchain.outpoint.get_spent_status",
+            json!([[[txid, 0]]]),
+            |r| match expect_array(r)?.as_slice() {
+                [status] => match expect_str(field(status, "status")?)? {
+                    "unspent" => Ok(""),
+                    "mempool" => expect_hex(field(status, "tx_hash")?, Some(HASH_HEX_LEN)),
+                    "confirmed" => {
+                        expect_u64(field(status, "height")?)?;
+                        expect_hex(field(status, "tx_hash")?, Some(HASH_HEX_LEN))
+                    }
+                    other => bail!("unexpected status: {}", other),
+                }
+                .map(|_| ()),
+                _ => bail!("expected a single status"),
+            },
+        );
+        s.check("blockchain.outpoint.subscribe", json!([txid, 0]), |r| {
+            if expect_u64(field(r, "height")?)? != height {
+                bail!("unexpected height");
+            }
+            if let Some(spender) = r.get("spender_txhash") {
+                expect_hex(spender, Some(HASH_HEX_LEN))?;
+                expect_u64(field(r, "spender_height")?)?;
+            }
+            Ok(())
+        });
+        s.check(
+            "blockchain.outpoint.unsubscribe",
+            json!([txid, 0]),
+            |r| match r.as_bool() {
+                Some(true) => Ok(()),
+                _ => bail!("expected true (after subscribing)"),
+            },
+        );
+        s.check("blockchain.transaction.get_status", json!([txid]), |r| {
+            if expect_str(field(r, "status")?)? != "confirmed" {
+                bail!("unexpected status (instead of \"confirmed\")");
+            }
+            if expect_u64(field(r, "height")?)? != height {
+                bail!("unexpected height");
+            }
+            expect_hex(field(r, "block_hash")?, Some(HASH_HEX_LEN)).map(|_| ())
+        });
+        // confirmed transactions are not replaceable
+        s.check(
+            "blockchain.transaction.get_replaceability",
+            json!([txid]),
+            expect_null,
+        );
+        s.check(
+            "blockchain.transaction.get_confirmed_blockhash",
+            json!([txid]),
+            |r| expect_hex(field(r, "block_hash")?, Some(HASH_HEX_LEN)).map(|_| ()),
+        );
+    }
+    s.check_error("blockchain.transaction.get", json!(["not a txid"]));
+    s.check_error(
+        "blockchain.transaction.broadcast",
+        json!(["not a transaction"]),
+    );
+    s.check_error("server.authenticate", json!(["not a password"]));
+    s.check_error_code("no.such.method", json!([]), -32601); // method not found
+
+    println!(
+        "{} checks: {} deviations found",
+        s.checks,
+        s.deviations.len()
+    );
+    if !s.deviations.is_empty() {
+        bail!(
+            "server deviates from the protocol:\n{}",
+            s.deviations.join("\n")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_history_item, expect_envelope, expect_error, expect_hex, expect_result};
+
+    #[test]
+    fn test_response_checks() {
+        let ok = json!({"jsonrpc": "2.0", "id": 1, "result": "0011"});
+        assert!(expect_envelope(&ok, 1).is_ok());
+        assert!(expect_envelope(&ok, 2).is_err());
+        assert!(expect_envelope(&json!({"id": 1, "result": null}), 1).is_err());
+        assert_eq!(expect_result(&ok).unwrap(), &json!("0011"));
+        assert!(expect_error(&ok).is_err());
+
+        assert!(expect_error(&json!({"error": {"code": 1, "message": "failed"}})).is_ok());
+        assert!(expect_error(&json!({"error": "failed"})).is_err());
+        assert!(expect_result(&json!({"error": "failed"})).is_err());
+
+        assert!(expect_hex(&json!("0011"), Some(4)).is_ok());
+        assert!(expect_hex(&json!("0011"), Some(6)).is_err());
+        assert!(expect_hex(&json!("001"), None).is_err());
+        assert!(expect_hex(&json!("xy"), None).is_err());
+        assert!(expect_hex(&json!(11), None).is_err());
+
+        let tx_hash = "00".repeat(32);
+        assert!(check_history_item(&json!({"height": 1, "tx_hash": tx_hash})).is_ok());
+        assert!(check_history_item(&json!({"height": 0, "tx_hash": tx_hash})).is_err());
+        assert!(check_history_item(&json!({"height": 0, "tx_hash": tx_hash, "fee": 1})).is_ok());
+        let item = json!({"height": 0, "tx_hash": tx_hash, "fee": 1, "first_seen": "now"});
+        assert!(check_history_item(&item).is_err());
+    }
+}
@@ -1,7 +1,3 @@
-use bitcoin::blockdata::block::{Block, BlockHeader};
-use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::consensus::encode::{deserialize, serialize};
-use bitcoin::hash_types::{BlockHash, Txid};
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::Hash;
 use bitcoin::network::constants::Network;
@@ -10,12 +6,14 @@ use std::collections::{HashMap, HashSet};
 use std::io::{BufRead, BufReader, Lines, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::cache::BlockTxIDsCache;
+use crate::chain::{deserialize, serialize, Block, BlockHash, BlockHeader, Transaction, Txid};
 use crate::errors::*;
+use crate::hooks::{Event, Hooks};
 use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
 use crate::signal::Waiter;
 use crate::util::HeaderList;
@@ -52,6 +50,22 @@ fn tx_from_value(value: Value) -> Result<Transaction> {
     Ok(deserialize(&tx_bytes).chain_err(|| format!("failed to parse tx {}", tx_hex))?)
 }
 
+// bitcoind's error code for transactions rejected by its mempool (which `testmempoolaccept`
+// reports only by reason).
+const RPC_VERIFY_REJECTED: i64 = -26;
+
+/// Returns bitcoind's name of a fee estimate mode (i.e. its `estimatesmartfee` parameter).
+pub fn parse_estimate_mode(mode: &str) -> Result<String> {
+    let mode = mode.to_uppercase();
+    match mode.as_str() {
+        "UNSET" | "ECONOMICAL" | "CONSERVATIVE" => Ok(mode),
+        _ => bail!(
+            "unknown estimate mode {:?} (expected ECONOMICAL, CONSERVATIVE or UNSET)",
+            mode
+        ),
+    }
+}
+
 /// Parse JSONRPC error code, if exists.
 fn parse_error_code(err: &Value) -> Option<i64> {
     if err.is_null() {
@@ -64,9 +78,21 @@ fn check_error_code(reply_obj: &Map<String, Value>, method: &str) -> Result<()>
     if let Some(err) = reply_obj.get("error") {
         if let Some(code) = parse_error_code(&err) {
             match code {
-                // RPC_IN_WARMUP -> retry by later reconnection
-                -28 => bail!(ErrorKind::Connection(err.to_string())),
-                _ => bail!("{} RPC error: {}", method, err),
+                // RPC_IN_WARMUP (e.g. while verifying blocks) -> retry later
+                -28 => bail!(ErrorKind::Warmup(
+                    err.get("message")
+                        .and_then(Value::as_str)
+                        .unwrap_or("warming up")
+                        .to_owned()
+                )),
+                _ => bail!(ErrorKind::Daemon(
+                    method.to_owned(),
+                    code,
+                    err.get("message")
+                        .and_then(Value::as_str)
+                        .map(str::to_owned)
+                        .unwrap_or_else(|| err.to_string())
+                )),
             }
         }
     }
@@ -114,6 +140,31 @@ struct NetworkInfo {
     relayfee: f64, // in BTC
 }
 
+/// Fails if bitcoind's chain (as reported by `getblockchaininfo`) isn't the configured network.
+#[cfg(not(feature = "liquid"))]
+fn check_chain(network: Network, chain: &str) -> Result<()> {
+    let expected = match network {
+        Network::Bitcoin => "main",
+        Network::Testnet => "test",
+        Network::Regtest => "regtest",
+        Network::Signet => "signet",
+    };
+    if chain != expected {
+        bail!(
+            "bitcoind is running on '{}' chain, instead of {} (please check the 'network' option)",
+            chain,
+            network
+        );
+    }
+    Ok(())
+}
+
+// Elements-based chains have their own names.
+#[cfg(feature = "liquid")]
+fn check_chain(_network: Network, _chain: &str) -> Result<()> {
+    Ok(())
+}
+
 pub struct MempoolEntry {
     fee: u64,   // in satoshis
     vsize: u32, // in virtual bytes (= weight/4)
@@ -151,6 +202,7 @@ struct Connection {
     rx: Lines<BufReader<TcpStream>>,
     cookie_getter: Arc<dyn CookieGetter>,
     addr: SocketAddr,
+    path: String, // of the JSONRPC endpoint (e.g. a wallet's)
     signal: Waiter,
 }
 
@@ -183,18 +235,24 @@ impl Connection {
             rx: reader.lines(),
             cookie_getter,
             addr,
+            path: "/".to_owned(),
             signal,
         })
     }
 
     fn reconnect(&self) -> Result<Connection> {
-        Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
+        let conn = Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())?;
+        Ok(Connection {
+            path: self.path.clone(),
+            ..conn
+        })
     }
 
     fn send(&mut self, request: &str) -> Result<()> {
         let cookie = &self.cookie_getter.get()?;
         let msg = format!(
-            "POST / HTTP/1.1\nAuthorization: Basic {}\nContent-Length: {}\n\n{}",
+            "POST {} HTTP/1.1\nAuthorization: Basic {}\nContent-Length: {}\n\n{}",
+            self.path,
             base64::encode(cookie),
             request.len(),
             request,
@@ -290,6 +348,9 @@ pub struct Daemon {
     message_id: Counter, // for monotonic JSONRPC 'id'
     signal: Waiter,
     blocktxids_cache: Arc<BlockTxIDsCache>,
+    hooks: Arc<Hooks>,
+    unreachable: Arc<AtomicBool>, // to notify only once per disconnection
+    last_contact: Arc<Mutex<Instant>>,
 
     // monitoring
     latency: HistogramVec,
@@ -305,6 +366,7 @@ impl Daemon {
         network: Network,
         signal: Waiter,
         blocktxids_cache: Arc<BlockTxIDsCache>,
+        hooks: Arc<Hooks>,
         metrics: &Metrics,
     ) -> Result<Daemon> {
         let daemon = Daemon {
@@ -318,6 +380,9 @@ impl Daemon {
             )?),
             message_id: Counter::new(),
             blocktxids_cache,
+            hooks,
+            unreachable: Arc::new(AtomicBool::new(false)),
+            last_contact: Arc::new(Mutex::new(Instant::now())),
             signal: signal.clone(),
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrs_daemon_rpc", "Bitcoind RPC latency (in seconds)"),
@@ -342,18 +407,9 @@ impl Daemon {
         if blockchain_info.pruned {
             bail!("pruned node is not supported (use '-prune=0' bitcoind flag)".to_owned())
         }
-        loop {
-            let info = daemon.getblockchaininfo()?;
-            if !info.initialblockdownload {
-                break;
-            }
-            if network == Network::Regtest && info.headers == info.blocks {
-                break;
-            }
-            warn!(
-                "wait until IBD is over: headers={} blocks={} progress={}",
-                info.headers, info.blocks, info.verificationprogress
-            );
+        check_chain(network, &blockchain_info.chain)?;
+        while let Some(status) = daemon.sync_status()? {
+            warn!("wait until IBD is over: {}", status);
             signal.wait(Duration::from_secs(3))?;
         }
         Ok(daemon)
@@ -368,11 +424,21 @@ impl Daemon {
             message_id: Counter::new(),
             signal: self.signal.clone(),
             blocktxids_cache: Arc::clone(&self.blocktxids_cache),
+            hooks: Arc::clone(&self.hooks),
+            unreachable: Arc::clone(&self.unreachable),
+            last_contact: Arc::clone(&self.last_contact),
             latency: self.latency.clone(),
             size: self.size.clone(),
         })
     }
 
+    /// Returns a new connection to a loaded wallet's JSONRPC endpoint.
+    pub fn wallet(&self, name: &str) -> Result<Daemon> {
+        let daemon = self.reconnect()?;
+        daemon.conn.lock().unwrap().path = format!("/wallet/{}", name);
+        Ok(daemon)
+    }
+
     pub fn list_blk_files(&self) -> Result<Vec<PathBuf>> {
         let path = self.blocks_dir.join("blk*.dat");
         info!("listing block files at {:?}", path);
@@ -388,6 +454,15 @@ impl Daemon {
         self.network.magic()
     }
 
+    /// Returns the time of the last response from the daemon (shared by reconnected instances).
+    pub fn last_contact(&self) -> Instant {
+        *self.last_contact.lock().unwrap()
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
     fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
         let mut conn = self.conn.lock().unwrap();
         let timer = self.latency.with_label_values(&[method]).start_timer();
@@ -423,21 +498,45 @@ impl Daemon {
     }
 
     fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        loop {
+            match self.reconnecting_request_batch(method, params_list) {
+                Err(Error(ErrorKind::Warmup(msg), _)) => {
+                    warn!("waiting for bitcoind: {}", msg);
+                    self.signal.wait(Duration::from_secs(3))?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Retries the requests (after reconnecting) on connection errors.
+    fn reconnecting_request_batch(
+        &self,
+        method: &str,
+        params_list: &[Value],
+    ) -> Result<Vec<Value>> {
         loop {
             match self.handle_request_batch(method, params_list) {
                 Err(Error(ErrorKind::Connection(msg), _)) => {
                     warn!("reconnecting to bitcoind: {}", msg);
+                    if !self.unreachable.swap(true, Ordering::SeqCst) {
+                        self.hooks.notify(Event::DaemonUnreachable { message: msg });
+                    }
                     self.signal.wait(Duration::from_secs(3))?;
                     let mut conn = self.conn.lock().unwrap();
                     *conn = conn.reconnect()?;
                     continue;
                 }
-                result => return result,
+                result => {
+                    self.unreachable.store(false, Ordering::SeqCst);
+                    *self.last_contact.lock().unwrap() = Instant::now();
+                    return result;
+                }
             }
         }
     }
 
-    fn request(&self, method: &str, params: Value) -> Result<Value> {
+    pub(crate) fn request(&self, method: &str, params: Value) -> Result<Value> {
         let mut values = self.retry_request_batch(method, &[params])?;
         assert_eq!(values.len(), 1);
         Ok(values.remove(0))
@@ -454,6 +553,30 @@ impl Daemon {
         Ok(from_value(info).chain_err(|| "invalid blockchain info")?)
     }
 
+    /// Returns `None` if the daemon is synced, or else a description of its sync progress.
+    /// Doesn't wait for the daemon's warmup (e.g. while it is verifying blocks on startup).
+    pub fn sync_status(&self) -> Result<Option<String>> {
+        let info = match self.reconnecting_request_batch("getblockchaininfo", &[json!([])]) {
+            Ok(mut values) => values.remove(0),
+            Err(Error(ErrorKind::Warmup(msg), _)) => {
+                return Ok(Some(format!("daemon is starting: {}", msg)))
+            }
+            Err(e) => return Err(e),
+        };
+        let info: BlockchainInfo = from_value(info).chain_err(|| "invalid blockchain info")?;
+        if !info.initialblockdownload
+            || (self.network == Network::Regtest && info.headers == info.blocks)
+        {
+            return Ok(None);
+        }
+        Ok(Some(format!(
+            "daemon is syncing, progress {:.2}% (headers={} blocks={})",
+            info.verificationprogress * 100.0,
+            info.headers,
+            info.blocks
+        )))
+    }
+
     fn getnetworkinfo(&self) -> Result<NetworkInfo> {
         let info: Value = self.request("getnetworkinfo", json!([]))?;
         Ok(from_value(info).chain_err(|| "invalid network info")?)
@@ -463,6 +586,12 @@ impl Daemon {
         Ok(self.getnetworkinfo()?.subversion)
     }
 
+    /// Returns the fee rate (in BTC/kvB), or `None` if bitcoind has insufficient data.
+    pub fn estimatesmartfee(&self, blocks: usize, mode: &str) -> Result<Option<f64>> {
+        let estimate = self.request("estimatesmartfee", json!([blocks, mode]))?;
+        Ok(estimate.get("feerate").and_then(Value::as_f64))
+    }
+
     pub fn get_relayfee(&self) -> Result<f64> {
         Ok(self.getnetworkinfo()?.relayfee)
     }
@@ -471,6 +600,76 @@ impl Daemon {
         parse_hash(&self.request("getbestblockhash", json!([]))?).chain_err(|| "invalid blockhash")
     }
 
+    pub fn getblockcount(&self) -> Result<usize> {
+        let count = self.request("getblockcount", json!([]))?;
+        count
+            .as_u64()
+            .map(|count| count as usize)
+            .chain_err(|| "invalid block count")
+    }
+
+    /// Returns the wallet's addresses (including the watch-only and unused ones) which are not in
+    /// `known`, with their output scripts. Requires a wallet connection (see `Daemon::wallet`).
+    pub fn list_wallet_addresses(&self, known: &HashSet<String>) -> Result<Vec<(String, Vec<u8>)>> {
+        let received =
+            self.request(
+                "listreceivedbyaddress",
+                json!([
+                    /*minconf=*/ 0, /*include_empty=*/ true,
+                    /*include_watchonly=*/ true
+                ]),
+            )?;
+        let addresses: Vec<String> = received
+            .as_array()
+            .chain_err(|| "invalid received addresses")?
+            .iter()
+            .filter_map(|item| item["address"].as_str())
+            .filter(|address| !known.contains(*address))
+            .map(str::to_owned)
+            .collect();
+        if addresses.is_empty() {
+            return Ok(vec![]);
+        }
+        let params_list: Vec<Value> = addresses.iter().map(|address| json!([address])).collect();
+        let infos = self.requests("getaddressinfo", &params_list)?;
+        addresses
+            .into_iter()
+            .zip(infos)
+            .map(|(address, info)| {
+                let script = info["scriptPubKey"]
+                    .as_str()
+                    .chain_err(|| format!("missing {} script", address))?;
+                let script = hex::decode(script).chain_err(|| "non-hex script")?;
+                Ok((address, script))
+            })
+            .collect()
+    }
+
+    /// Returns the output scripts of the given addresses (which must be valid for the network).
+    pub fn address_scripts(&self, addresses: &[String]) -> Result<Vec<Vec<u8>>> {
+        let params_list: Vec<Value> = addresses.iter().map(|address| json!([address])).collect();
+        let infos = self.requests("validateaddress", &params_list)?;
+        addresses
+            .iter()
+            .zip(infos)
+            .map(|(address, info)| {
+                if info["isvalid"].as_bool() != Some(true) {
+                    bail!("invalid address: {}", address);
+                }
+                let script = info["scriptPubKey"]
+                    .as_str()
+                    .chain_err(|| format!("missing {} script", address))?;
+                Ok(hex::decode(script).chain_err(|| "non-hex script")?)
+            })
+            .collect()
+    }
+
+    /// Scans the UTXO set for the outputs matching the given descriptors (which may take a few
+    /// minutes), returning bitcoind's result as is.
+    pub fn scantxoutset(&self, descriptors: &[String]) -> Result<Value> {
+        self.request("scantxoutset", json!(["start", descriptors]))
+    }
+
     pub fn getblockheader(&self, blockhash: &BlockHash) -> Result<BlockHeader> {
         header_from_value(self.request(
             "getblockheader",
@@ -493,27 +692,34 @@ impl Daemon {
     }
 
     pub fn getblock(&self, blockhash: &BlockHash) -> Result<Block> {
-        let block = block_from_value(
-            self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ false]))?,
-        )?;
+        let block = block_from_value(self.getblock_value(blockhash)?)?;
         assert_eq!(block.block_hash(), *blockhash);
         Ok(block)
     }
 
-    fn load_blocktxids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
-        self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ 1]))?
-            .get("tx")
-            .chain_err(|| "block missing txids")?
-            .as_array()
-            .chain_err(|| "invalid block txids")?
-            .iter()
-            .map(parse_hash)
-            .collect::<Result<Vec<Txid>>>()
+    /// Returns the serialized block (to be indexed without deserializing it).
+    pub fn getblock_raw(&self, blockhash: &BlockHash) -> Result<Vec<u8>> {
+        let value = self.getblock_value(blockhash)?;
+        let block_hex = value.as_str().chain_err(|| "non-string block")?;
+        hex::decode(block_hex).chain_err(|| "non-hex block")
     }
 
-    pub fn getblocktxids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
+    fn getblock_value(&self, blockhash: &BlockHash) -> Result<Value> {
+        self.request("getblock", json!([blockhash.to_hex(), /*verbose=*/ false]))
+    }
+
+    /// Returns the block's cached txids, or loads them using `load_txids_func`.
+    pub fn getblocktxids<F>(&self, blockhash: &BlockHash, load_txids_func: F) -> Result<Vec<Txid>>
+    where
+        F: FnOnce() -> Result<Vec<Txid>>,
+    {
         self.blocktxids_cache
-            .get_or_else(&blockhash, || self.load_blocktxids(blockhash))
+            .get_or_else(&blockhash, load_txids_func)
+    }
+
+    /// Caches the block's txids (e.g. when the block is already available).
+    pub fn add_blocktxids(&self, blockhash: &BlockHash, txids: Vec<Txid>) {
+        self.blocktxids_cache.add(blockhash, txids)
     }
 
     pub fn gettransaction(
@@ -541,6 +747,25 @@ impl Daemon {
         Ok(self.request("getrawtransaction", args)?)
     }
 
+    /// Fetches the transactions using a single batch of requests.
+    pub fn gettransactions_raw(
+        &self,
+        txs: &[(Txid, Option<BlockHash>)],
+        verbose: bool,
+    ) -> Result<Vec<Value>> {
+        let params_list: Vec<Value> = txs
+            .iter()
+            .map(|(txhash, blockhash)| {
+                let mut args = json!([txhash.to_hex(), verbose]);
+                if let Some(blockhash) = blockhash {
+                    args.as_array_mut().unwrap().push(json!(blockhash.to_hex()));
+                }
+                args
+            })
+            .collect();
+        self.requests("getrawtransaction", &params_list)
+    }
+
     pub fn getmempooltxids(&self) -> Result<HashSet<Txid>> {
         let txids: Value = self.request("getrawmempool", json!([/*verbose=*/ false]))?;
         let mut result = HashSet::new();
@@ -567,15 +792,52 @@ impl Daemon {
         Ok(MempoolEntry::new(fee, vsize))
     }
 
-    pub fn broadcast(&self, tx: &Transaction) -> Result<Txid> {
+    /// Broadcasts a transaction, rejecting it if its fee rate exceeds `max_fee_rate` (in BTC/kvB).
+    /// Rejections by bitcoind are returned as `ErrorKind::Rejected` (with bitcoind's error code).
+    pub fn broadcast(&self, tx: &Transaction, max_fee_rate: Option<f64>) -> Result<Txid> {
         let tx = hex::encode(serialize(tx));
-        let txid = self.request("sendrawtransaction", json!([tx]))?;
+        let mut args = json!([tx]);
+        if let Some(max_fee_rate) = max_fee_rate {
+            args.as_array_mut().unwrap().push(json!(max_fee_rate));
+        }
+        let txid = self
+            .request("sendrawtransaction", args)
+            .map_err(|e| match e {
+                Error(ErrorKind::Daemon(_, code, msg), _) => ErrorKind::Rejected(code, msg).into(),
+                e => e,
+            })?;
         Ok(
             Txid::from_hex(txid.as_str().chain_err(|| "non-string txid")?)
                 .chain_err(|| "failed to parse txid")?,
         )
     }
 
+    /// Checks whether bitcoind would accept a transaction into its mempool (without relaying it),
+    /// returning `ErrorKind::Rejected` with its rejection reason otherwise.
+    pub fn test_mempool_accept(&self, tx: &Transaction, max_fee_rate: Option<f64>) -> Result<()> {
+        let mut args = json!([[hex::encode(serialize(tx))]]);
+        if let Some(max_fee_rate) = max_fee_rate {
+            args.as_array_mut().unwrap().push(json!(max_fee_rate));
+        }
+        let results = self.request("testmempoolaccept", args)?;
+        let result = results
+            .as_array()
+            .and_then(|results| results.first())
+            .chain_err(|| format!("invalid testmempoolaccept result: {}", results))?;
+        let allowed = result
+            .get("allowed")
+            .and_then(Value::as_bool)
+            .chain_err(|| format!("missing 'allowed': {}", result))?;
+        if !allowed {
+            let reason = result
+                .get("reject-reason")
+                .and_then(Value::as_str)
+                .unwrap_or("unknown reason");
+            bail!(ErrorKind::Rejected(RPC_VERIFY_REJECTED, reason.to_owned()));
+        }
+        Ok(())
+    }
+
     fn get_all_headers(&self, tip: &BlockHash) -> Result<Vec<BlockHeader>> {
         let info: Value = self.request("getblockheader", json!([tip.to_hex()]))?;
         let tip_height = info
@@ -0,0 +1,200 @@
+//! A minimal (read-only) HTML status page, served at `/status` on the monitoring port for
+//! operators without a Grafana stack. It is rendered from the registered Prometheus metrics.
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use std::collections::BTreeMap;
+
+pub const STATUS_PATH: &str = "/status";
+
+const SLOWEST_METHODS: usize = 10;
+
+const STATUS_METRICS: &[(&str, &str)] = &[
+    ("electrs_index_height", "Indexed height"),
+    ("electrs_index_blocks", "Blocks indexed since startup"),
+    ("electrs_tip_age", "Best block age (seconds)"),
+    (
+        "electrs_daemon_contact_age",
+        "Last bitcoind contact (seconds ago)",
+    ),
+    ("electrs_electrum_peers", "Connected clients"),
+    ("electrs_electrum_subscriptions", "Subscriptions"),
+    ("electrs_mempool_count", "Mempool transactions"),
+    ("electrs_process_memory_rss", "Resident memory (bytes)"),
+    ("electrs_process_open_fds", "Open file descriptors"),
+];
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn format_labels(labels: &[LabelPair], skip: &[&str]) -> String {
+    labels
+        .iter()
+        .filter(|label| !skip.contains(&label.get_name()))
+        .map(|label| format!("{}={}", label.get_name(), label.get_value()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn metrics<'a>(families: &'a [MetricFamily], name: &str) -> Vec<(MetricType, &'a Metric)> {
+    families
+        .iter()
+        .filter(|family| family.get_name() == name)
+        .flat_map(|family| {
+            let kind = family.get_field_type();
+            family.get_metric().iter().map(move |metric| (kind, metric))
+        })
+        .collect()
+}
+
+fn value(kind: MetricType, metric: &Metric) -> Option<f64> {
+    match kind {
+        MetricType::GAUGE => Some(metric.get_gauge().get_value()),
+        MetricType::COUNTER => Some(metric.get_counter().get_value()),
+        _ => None,
+    }
+}
+
+fn row(cells: &[String]) -> String {
+    let cells: Vec<String> = cells
+        .iter()
+        .map(|cell| format!("<td>{}</td>", escape(cell)))
+        .collect();
+    format!("<tr>{}</tr>\n", cells.concat())
+}
+
+fn table(title: &str, header: &[&str], rows: &[String]) -> String {
+    let header: Vec<String> = header
+        .iter()
+        .map(|name| format!("<th>{}</th>", name))
+        .collect();
+    format!(
+        "<h2>{}</h2>\n<table>\n<tr>{}</tr>\n{}</table>\n",
+        title,
+        header.concat(),
+        rows.concat()
+    )
+}
+
+fn status_rows(families: &[MetricFamily]) -> Vec<String> {
+    let mut rows = vec![];
+    for (name, title) in STATUS_METRICS {
+        for (kind, metric) in metrics(families, name) {
+            if let Some(value) = value(kind, metric) {
+                let labels = format_labels(metric.get_label(), &[]);
+                rows.push(row(&[title.to_string(), labels, value.to_string()]));
+            }
+        }
+    }
+    // the total vsize is the (cumulative) vsize of the highest fee rate
+    let mut mempool_vsize = BTreeMap::<String, f64>::new();
+    for (kind, metric) in metrics(families, "electrs_mempool_vsize") {
+        let labels = format_labels(metric.get_label(), &["fee_rate"]);
+        let vsize = mempool_vsize.entry(labels).or_insert(0.0);
+        *vsize = vsize.max(value(kind, metric).unwrap_or(0.0));
+    }
+    for (labels, vsize) in mempool_vsize {
+        rows.push(row(&[
+            "Mempool vsize (vbytes)".to_owned(),
+            labels,
+            vsize.to_string(),
+        ]));
+    }
+    rows
+}
+
+// The Electrum RPC methods with the highest average latency (since startup).
+fn slowest_rows(families: &[MetricFamily]) -> Vec<String> {
+    let mut methods: Vec<(f64, u64, String)> = metrics(families, "electrs_electrum_rpc")
+        .into_iter()
+        .filter(|(kind, _)| *kind == MetricType::HISTOGRAM)
+        .map(|(_, metric)| {
+            let histogram = metric.get_histogram();
+            let count = histogram.get_sample_count();
+            let average = histogram.get_sample_sum() / (count.max(1) as f64);
+            (average, count, format_labels(metric.get_label(), &[]))
+        })
+        .collect();
+    methods.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+    methods
+        .into_iter()
+        .take(SLOWEST_METHODS)
+        .map(|(average, count, labels)| {
+            row(&[labels, count.to_string(), format!("{:.3}", average)])
+        })
+        .collect()
+}
+
+// The connected clients and their requests (since startup), by user agent.
+fn client_rows(families: &[MetricFamily]) -> Vec<String> {
+    let mut clients = BTreeMap::<String, (f64, f64)>::new();
+    for (kind, metric) in metrics(families, "electrs_electrum_clients") {
+        let labels = format_labels(metric.get_label(), &[]);
+        clients.entry(labels).or_insert((0.0, 0.0)).0 += value(kind, metric).unwrap_or(0.0);
+    }
+    for (kind, metric) in metrics(families, "electrs_electrum_client_requests") {
+        let labels = format_labels(metric.get_label(), &[]);
+        clients.entry(labels).or_insert((0.0, 0.0)).1 += value(kind, metric).unwrap_or(0.0);
+    }
+    clients
+        .into_iter()
+        .map(|(labels, (connected, requests))| {
+            row(&[labels, connected.to_string(), requests.to_string()])
+        })
+        .collect()
+}
+
+/// Renders the status page (refreshed every 10 seconds by the browser).
+pub fn render(families: &[MetricFamily]) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"10\">\n<title>electrs status</title>\n\
+         <style>body {{ font-family: sans-serif; }} td, th {{ padding: 2px 12px; text-align: left; }}</style>\n\
+         </head>\n<body>\n<h1>electrs status</h1>\n{}{}{}</body>\n</html>\n",
+        table("Status", &["Metric", "Labels", "Value"], &status_rows(families)),
+        table(
+            "Slowest requests",
+            &["Method", "Requests", "Average latency (seconds)"],
+            &slowest_rows(families)
+        ),
+        table(
+            "Clients",
+            &["User agent", "Connected", "Requests"],
+            &client_rows(families)
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use prometheus::{GaugeVec, HistogramOpts, HistogramVec, IntGauge, Opts, Registry};
+
+    #[test]
+    fn test_render() {
+        let reg = Registry::new();
+        let height = IntGauge::with_opts(Opts::new("electrs_index_height", "help")).unwrap();
+        reg.register(Box::new(height.clone())).unwrap();
+        height.set(123);
+        let opts = HistogramOpts::new("electrs_electrum_rpc", "help");
+        let latency = HistogramVec::new(opts, &["method"]).unwrap();
+        reg.register(Box::new(latency.clone())).unwrap();
+        latency.with_label_values(&["fast"]).observe(0.001);
+        latency.with_label_values(&["<script>"]).observe(2.0);
+        latency.with_label_values(&["<script>"]).observe(4.0);
+        let opts = Opts::new("electrs_electrum_clients", "help");
+        let clients = GaugeVec::new(opts, &["user_agent"]).unwrap();
+        reg.register(Box::new(clients.clone())).unwrap();
+        clients.with_label_values(&["Electrum 4.1.5"]).set(2.0);
+
+        let html = render(&reg.gather());
+        assert!(html.contains("<td>Indexed height</td><td></td><td>123</td>"));
+        let slow = html.find("<td>method=&lt;script&gt;</td><td>2</td><td>3.000</td>");
+        let fast = html.find("<td>method=fast</td><td>1</td><td>0.001</td>");
+        assert!(slow.unwrap() < fast.unwrap()); // sorted by average latency
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<td>user_agent=Electrum 4.1.5</td><td>2</td><td>0</td>"));
+    }
+}
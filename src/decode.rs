@@ -0,0 +1,160 @@
+//! Decodes transactions into `bitcoind`'s verbose `getrawtransaction` JSON, so verbose
+//! `blockchain.transaction.get` requests are served without additional RPCs (nor `txindex`).
+
+use bitcoin::network::constants::Network;
+use serde_json::Value;
+
+use crate::errors::*;
+use crate::util::HeaderEntry;
+
+#[cfg(not(feature = "liquid"))]
+use bitcoin::{blockdata::script::Script, hashes::hex::ToHex, util::address::Address};
+
+#[cfg(not(feature = "liquid"))]
+use crate::chain::{deserialize, Transaction};
+
+// Same as `bitcoind`'s `GetTxnOutputType()` names.
+#[cfg(not(feature = "liquid"))]
+fn script_type(script: &Script) -> &'static str {
+    let bytes = script.as_bytes();
+    if script.is_p2pkh() {
+        "pubkeyhash"
+    } else if script.is_p2sh() {
+        "scripthash"
+    } else if script.is_v0_p2wpkh() {
+        "witness_v0_keyhash"
+    } else if script.is_v0_p2wsh() {
+        "witness_v0_scripthash"
+    } else if bytes.len() == 34 && bytes[0] == 0x51 && bytes[1] == 0x20 {
+        "witness_v1_taproot" // OP_1 <32-byte key>
+    } else if script.is_witness_program() {
+        "witness_unknown"
+    } else if script.is_p2pk() {
+        "pubkey"
+    } else if script.is_op_return() {
+        "nulldata"
+    } else {
+        "nonstandard"
+    }
+}
+
+/// Returns the verbose JSON of a serialized transaction (confirmed in `block`, followed by the
+/// number of its confirmations), or `None` for Elements-based chains (whose transactions are
+/// decoded by the daemon). The scripts' `asm` is omitted.
+#[cfg(not(feature = "liquid"))]
+pub fn transaction_json(
+    serialized_txn: &[u8],
+    network: Network,
+    block: Option<(&HeaderEntry, usize)>,
+) -> Result<Option<Value>> {
+    let txn: Transaction = deserialize(serialized_txn).chain_err(|| "failed to parse tx")?;
+    let vin: Vec<Value> = txn
+        .input
+        .iter()
+        .map(|input| {
+            let mut value = if txn.is_coin_base() {
+                json!({"coinbase": input.script_sig.as_bytes().to_hex()})
+            } else {
+                json!({
+                    "txid": input.previous_output.txid.to_hex(),
+                    "vout": input.previous_output.vout,
+                    "scriptSig": {"hex": input.script_sig.as_bytes().to_hex()},
+                })
+            };
+            if !input.witness.is_empty() {
+                let witness: Vec<String> = input.witness.iter().map(hex::encode).collect();
+                value["txinwitness"] = json!(witness);
+            }
+            value["sequence"] = json!(input.sequence);
+            value
+        })
+        .collect();
+    let vout: Vec<Value> = txn
+        .output
+        .iter()
+        .enumerate()
+        .map(|(n, output)| {
+            let script = &output.script_pubkey;
+            let mut script_json = json!({
+                "hex": script.as_bytes().to_hex(),
+                "type": script_type(script),
+            });
+            if let Some(address) = Address::from_script(script, network) {
+                script_json["address"] = json!(address.to_string());
+            }
+            json!({
+                "value": output.value as f64 / 100_000_000f64,
+                "n": n,
+                "scriptPubKey": script_json,
+            })
+        })
+        .collect();
+    let weight = txn.get_weight();
+    let mut result = json!({
+        "txid": txn.txid().to_hex(),
+        "hash": txn.wtxid().to_hex(),
+        "version": txn.version,
+        "size": serialized_txn.len(),
+        "vsize": (weight + 3) / 4,
+        "weight": weight,
+        "locktime": txn.lock_time,
+        "vin": vin,
+        "vout": vout,
+        "hex": hex::encode(serialized_txn),
+    });
+    if let Some((entry, confirmations)) = block {
+        result["blockhash"] = json!(entry.hash().to_hex());
+        result["confirmations"] = json!(confirmations);
+        result["time"] = json!(entry.header().time);
+        result["blocktime"] = json!(entry.header().time);
+    }
+    Ok(Some(result))
+}
+
+#[cfg(feature = "liquid")]
+pub fn transaction_json(
+    _serialized_txn: &[u8],
+    _network: Network,
+    _block: Option<(&HeaderEntry, usize)>,
+) -> Result<Option<Value>> {
+    Ok(None)
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::transaction_json;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    use crate::util::HeaderList;
+
+    #[test]
+    fn test_genesis_coinbase() {
+        let block = genesis_block(Network::Bitcoin);
+        let serialized_txn = bitcoin::consensus::serialize(&block.txdata[0]);
+        let entries = HeaderList::empty().order(vec![block.header]);
+
+        let value = transaction_json(&serialized_txn, Network::Bitcoin, Some((&entries[0], 10)))
+            .unwrap()
+            .unwrap();
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        assert_eq!(value["txid"], txid);
+        assert_eq!(value["hash"], txid); // no witness
+        assert_eq!(value["size"], 204);
+        assert_eq!(value["vsize"], 204);
+        assert_eq!(value["weight"], 816);
+        assert_eq!(value["locktime"], 0);
+        assert_eq!(value["vin"][0]["sequence"], 0xffff_ffffu32);
+        assert!(value["vin"][0]["coinbase"]
+            .as_str()
+            .unwrap()
+            .starts_with("04ffff001d"));
+        assert_eq!(value["vout"][0]["value"], 50.0);
+        assert_eq!(value["vout"][0]["scriptPubKey"]["type"], "pubkey");
+        assert!(value["vout"][0]["scriptPubKey"].get("address").is_none());
+        assert_eq!(value["hex"], hex::encode(&serialized_txn));
+        assert_eq!(value["blockhash"], block.header.block_hash().to_string());
+        assert_eq!(value["confirmations"], 10);
+        assert_eq!(value["time"], 1_231_006_505);
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::Write;
+
+use crate::chain::{deserialize, BlockHash, BlockHeader};
+use crate::errors::*;
+use crate::index::StatusRow;
+use crate::store::{DBStore, ReadStore, Row};
+use crate::util::{HASH_LEN, HASH_PREFIX_LEN};
+
+/// Formats hash bytes the way hashes are displayed (i.e. in reversed order).
+/// Prefixes are shown with a leading "..", since they match the end of the displayed hash.
+fn hash_hex(bytes: &[u8]) -> String {
+    let mut bytes = bytes.to_vec();
+    bytes.reverse();
+    let hex = hex::encode(bytes);
+    if hex.len() < HASH_LEN * 2 {
+        format!("..{}", hex)
+    } else {
+        hex
+    }
+}
+
+fn u16_le(bytes: &[u8]) -> u16 {
+    u16::from(bytes[0]) | (u16::from(bytes[1]) << 8)
+}
+
+fn u32_le(bytes: &[u8]) -> Option<u32> {
+    if bytes.len() != 4 {
+        return None;
+    }
+    Some((0..4).fold(0, |acc, i| acc | (u32::from(bytes[i]) << (8 * i))))
+}
+
+#[cfg(not(feature = "liquid"))]
+fn describe_header(value: &[u8]) -> String {
+    match deserialize::<BlockHeader>(value) {
+        Ok(header) => format!(
+            "prev={} merkle_root={} time={} bits={:#x} nonce={}",
+            header.prev_blockhash, header.merkle_root, header.time, header.bits, header.nonce
+        ),
+        Err(_) => format!("invalid header: {}", hex::encode(value)),
+    }
+}
+
+/// Elements headers have no proof-of-work fields (they are signed by the chain's functionaries).
+#[cfg(feature = "liquid")]
+fn describe_header(value: &[u8]) -> String {
+    match deserialize::<BlockHeader>(value) {
+        Ok(header) => format!(
+            "prev={} merkle_root={} time={} height={}",
+            header.prev_blockhash, header.merkle_root, header.time, header.height
+        ),
+        Err(_) => format!("invalid header: {}", hex::encode(value)),
+    }
+}
+
+/// Decodes an index row into a human-readable form (see `index.rs` for the rows' layout).
+pub fn describe_row(row: &Row) -> String {
+    let (key, value) = (&row.key[..], &row.value[..]);
+    let p = HASH_PREFIX_LEN;
+    match (key.first(), key.len()) {
+        (Some(b'O'), len) if len == 1 + p + p => format!(
+            "funding: scripthash={} txid={}",
+            hash_hex(&key[1..1 + p]),
+            hash_hex(&key[1 + p..])
+        ),
+        (Some(b'I'), len) if len == 1 + p + 2 + p => format!(
+            "spending: prev_txid={} prev_vout={} txid={}",
+            hash_hex(&key[1..1 + p]),
+            u16_le(&key[1 + p..3 + p]),
+            hash_hex(&key[3 + p..])
+        ),
+        (Some(b'T'), len) if len == 1 + HASH_LEN => match u32_le(value) {
+            Some(height) => format!(
+                "transaction: txid={} height={}",
+                hash_hex(&key[1..]),
+                height
+            ),
+            None => format!("transaction: txid={} invalid height", hash_hex(&key[1..])),
+        },
+        (Some(b'B'), len) if len == 1 + HASH_LEN => format!(
+            "header: blockhash={} {}",
+            hash_hex(&key[1..]),
+            describe_header(value)
+        ),
+        (Some(b'L'), 1) => match deserialize::<BlockHash>(value) {
+            Ok(blockhash) => format!("last indexed block: {}", blockhash),
+            Err(_) => format!("last indexed block: invalid {}", hex::encode(value)),
+        },
+        (Some(b'F'), 1) => "full compaction marker".to_owned(),
+        (Some(b'H'), 1) => "headers-only marker".to_owned(),
+        (Some(b'Z'), len) if len == 1 + HASH_LEN => match StatusRow::from_value(value) {
+            Some(status) => format!(
+                "status: scripthash={} height={} funding={} spending={}",
+                hash_hex(&key[1..]),
+                status.height,
+                status.funding.len(),
+                status.spending.len()
+            ),
+            None => format!("status: scripthash={} invalid", hash_hex(&key[1..])),
+        },
+        _ => format!(
+            "unknown: key={} value={}",
+            hex::encode(key),
+            hex::encode(value)
+        ),
+    }
+}
+
+fn parse_hex(name: &str, value: &str) -> Result<Vec<u8>> {
+    hex::decode(value).chain_err(|| format!("invalid --{} (expected hex): {}", name, value))
+}
+
+/// Prints (up to `limit`) rows starting with the hex-encoded `prefix`.
+pub fn dump_rows<W: Write>(
+    store: &DBStore,
+    prefix: &str,
+    limit: Option<usize>,
+    mut out: W,
+) -> Result<()> {
+    let prefix = parse_hex("prefix", prefix)?;
+    let rows = store.iter_scan(&prefix);
+    for row in rows.take(limit.unwrap_or(usize::max_value())) {
+        writeln!(out, "{}\t{}", hex::encode(&row.key), describe_row(&row))
+            .chain_err(|| "failed to write row")?;
+    }
+    out.flush().chain_err(|| "failed to flush output")
+}
+
+/// Prints the row with the hex-encoded `key` (if exists).
+pub fn get_row<W: Write>(store: &DBStore, key: &str, mut out: W) -> Result<()> {
+    let key = parse_hex("key", key)?;
+    let value = store
+        .get(&key)
+        .chain_err(|| format!("key {} not found", hex::encode(&key)))?;
+    let row = Row { key, value };
+    writeln!(out, "{}\t{}", hex::encode(&row.key), describe_row(&row))
+        .chain_err(|| "failed to write row")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::describe_row;
+    use bitcoin::hashes::hex::FromHex;
+
+    use crate::chain::{OutPoint, TxOut, Txid};
+    use crate::index::{last_indexed_block, TxInRow, TxOutRow, TxRow};
+    use crate::store::Row;
+
+    #[test]
+    fn test_describe_rows() {
+        let txid =
+            Txid::from_hex("b5c0f8b7d1a1e6ef3b1c4e2f2d0f7e0b2d8c1f3e4a5b6c7d8e9f0a1b2c3d4e5f")
+                .unwrap();
+        let row = TxRow::new(&txid, 123).to_row();
+        assert_eq!(
+            describe_row(&row),
+            "transaction: txid=b5c0f8b7d1a1e6ef3b1c4e2f2d0f7e0b2d8c1f3e4a5b6c7d8e9f0a1b2c3d4e5f height=123"
+        );
+
+        let row = TxOutRow::new(&txid, &TxOut::default()).to_row();
+        assert!(describe_row(&row).ends_with(" txid=..8e9f0a1b2c3d4e5f"));
+
+        let row = TxInRow::new(&txid, &OutPoint { txid, vout: 258 }).to_row();
+        assert_eq!(
+            describe_row(&row),
+            "spending: prev_txid=..8e9f0a1b2c3d4e5f prev_vout=258 txid=..8e9f0a1b2c3d4e5f"
+        );
+
+        let row = last_indexed_block(&Default::default());
+        assert_eq!(
+            describe_row(&row),
+            format!("last indexed block: {}", "0".repeat(64))
+        );
+
+        let row = Row {
+            key: b"X".to_vec(),
+            value: b"\x01".to_vec(),
+        };
+        assert_eq!(describe_row(&row), "unknown: key=58 value=01");
+    }
+}
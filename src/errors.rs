@@ -9,9 +9,46 @@ error_chain! {
             display("Connection error: {}", msg)
         }
 
+        Warmup(msg: String) {
+            description("Daemon warmup")
+            display("Daemon is starting: {}", msg)
+        }
+
+        Corruption(msg: String) {
+            description("Index corruption")
+            display("Index is corrupted: {}", msg)
+        }
+
         Interrupt(sig: i32) {
             description("Interruption by external signal")
             display("Interrupted by signal {}", sig)
         }
+
+        // The following errors are returned to Electrum clients with specific error codes.
+
+        MethodNotFound(method: String) {
+            description("Unknown method")
+            display("unknown method {}", method)
+        }
+
+        InvalidParams(msg: String) {
+            description("Invalid params")
+            display("{}", msg)
+        }
+
+        ExcessiveResourceUsage(msg: String) {
+            description("Excessive resource usage")
+            display("{}", msg)
+        }
+
+        Daemon(method: String, code: i64, msg: String) {
+            description("Daemon error")
+            display("{} RPC error {}: {}", method, code, msg)
+        }
+
+        Rejected(code: i64, reason: String) {
+            description("Transaction rejected")
+            display("transaction rejected: {}", reason)
+        }
     }
 }
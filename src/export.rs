@@ -0,0 +1,88 @@
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::errors::*;
+use crate::query::Query;
+
+pub enum Format {
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Format> {
+        match format {
+            "csv" => Ok(Format::Csv),
+            _ => bail!("unknown export format: {}", format),
+        }
+    }
+}
+
+/// Reads script hashes (in Electrum's hex format), one per line.
+/// Empty lines and lines starting with '#' are ignored.
+pub fn read_script_hashes(path: &Path) -> Result<Vec<Sha256dHash>> {
+    let contents =
+        fs::read_to_string(path).chain_err(|| format!("failed to read {}", path.display()))?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            Sha256dHash::from_hex(line).chain_err(|| format!("invalid scripthash: {}", line))
+        })
+        .collect()
+}
+
+/// Writes the full history of each script hash, as (scripthash, txid, height, value delta, fee)
+/// records. Each history is written as soon as it is loaded from the index, and each confirmed
+/// transaction's fee is computed once (e.g. for transfers between the exported addresses).
+pub fn export_history<W: Write>(
+    query: &Query,
+    script_hashes: &[Sha256dHash],
+    format: Format,
+    mut out: W,
+) -> Result<()> {
+    match format {
+        Format::Csv => writeln!(out, "scripthash,txid,height,value_delta,fee"),
+    }
+    .chain_err(|| "failed to write header")?;
+    let mut fees = HashMap::new();
+    for script_hash in script_hashes {
+        let status = query
+            .status(&script_hash[..])
+            .chain_err(|| format!("failed to load {} history", script_hash))?;
+        let deltas = status.value_deltas();
+        for item in status.history() {
+            let fee = match item.fee() {
+                Some(fee) => Some(fee), // unconfirmed transaction
+                None => match fees.get(item.tx_hash()) {
+                    Some(fee) => *fee,
+                    None => {
+                        let fee = query.get_fee(item.tx_hash())?;
+                        fees.insert(*item.tx_hash(), fee);
+                        fee
+                    }
+                },
+            };
+            match format {
+                Format::Csv => writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    script_hash.to_hex(),
+                    item.tx_hash().to_hex(),
+                    item.height(),
+                    deltas[item.tx_hash()],
+                    fee.map_or_else(String::new, |fee| fee.to_string()),
+                ),
+            }
+            .chain_err(|| "failed to write history")?;
+        }
+    }
+    out.flush().chain_err(|| "failed to flush output")
+}
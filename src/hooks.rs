@@ -0,0 +1,216 @@
+//! External commands, run on chain events (similar to bitcoind's `-blocknotify`).
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::network::constants::Network;
+use serde_json::Value;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use crate::chain::BlockHash;
+use crate::config::Config;
+use crate::errors::*;
+use crate::util::spawn_thread;
+
+#[derive(Debug)]
+pub enum Event {
+    NewBlock {
+        height: usize,
+        blockhash: BlockHash,
+    },
+    // The previous tip (`stale_*`) is no longer part of the best chain.
+    Reorg {
+        height: usize,
+        blockhash: BlockHash,
+        stale_height: usize,
+        stale_blockhash: BlockHash,
+    },
+    DaemonUnreachable {
+        message: String,
+    },
+    IndexCorruption {
+        message: String,
+    },
+    // A standby started serving clients, since its primary stopped responding.
+    Promotion {
+        primary: SocketAddr,
+    },
+    // The status of an address imported from a bitcoind wallet has changed (see `watch.rs`).
+    WalletActivity {
+        address: String,
+        script_hash: String,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::NewBlock { .. } => "new_block",
+            Event::Reorg { .. } => "reorg",
+            Event::DaemonUnreachable { .. } => "daemon_unreachable",
+            Event::IndexCorruption { .. } => "index_corruption",
+            Event::Promotion { .. } => "promotion",
+            Event::WalletActivity { .. } => "wallet_activity",
+        }
+    }
+
+    fn env(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Event::NewBlock { height, blockhash } => vec![
+                ("ELECTRS_HEIGHT", height.to_string()),
+                ("ELECTRS_BLOCKHASH", blockhash.to_hex()),
+            ],
+            Event::Reorg {
+                height,
+                blockhash,
+                stale_height,
+                stale_blockhash,
+            } => vec![
+                ("ELECTRS_HEIGHT", height.to_string()),
+                ("ELECTRS_BLOCKHASH", blockhash.to_hex()),
+                ("ELECTRS_STALE_HEIGHT", stale_height.to_string()),
+                ("ELECTRS_STALE_BLOCKHASH", stale_blockhash.to_hex()),
+            ],
+            Event::DaemonUnreachable { message } | Event::IndexCorruption { message } => {
+                vec![("ELECTRS_MESSAGE", message.clone())]
+            }
+            Event::Promotion { primary } => vec![("ELECTRS_PRIMARY", primary.to_string())],
+            Event::WalletActivity {
+                address,
+                script_hash,
+            } => vec![
+                ("ELECTRS_ADDRESS", address.clone()),
+                ("ELECTRS_SCRIPTHASH", script_hash.clone()),
+            ],
+        }
+    }
+
+    fn to_json(&self, network: &str) -> Value {
+        let mut value = match self {
+            Event::NewBlock { height, blockhash } => {
+                json!({"height": height, "blockhash": blockhash.to_hex()})
+            }
+            Event::Reorg {
+                height,
+                blockhash,
+                stale_height,
+                stale_blockhash,
+            } => json!({
+                "height": height,
+                "blockhash": blockhash.to_hex(),
+                "stale_height": stale_height,
+                "stale_blockhash": stale_blockhash.to_hex(),
+            }),
+            Event::DaemonUnreachable { message } | Event::IndexCorruption { message } => {
+                json!({ "message": message })
+            }
+            Event::Promotion { primary } => json!({ "primary": primary.to_string() }),
+            Event::WalletActivity {
+                address,
+                script_hash,
+            } => json!({"address": address, "scripthash": script_hash}),
+        };
+        value["event"] = json!(self.name());
+        value["network"] = json!(network);
+        value
+    }
+}
+
+/// Runs the configured `event_hook` command (if any) on each event, without waiting for it.
+pub struct Hooks {
+    command: Option<String>,
+    network: String,
+}
+
+impl Hooks {
+    pub fn new(command: Option<String>, network: Network) -> Arc<Hooks> {
+        Arc::new(Hooks {
+            command,
+            network: network.to_string(),
+        })
+    }
+
+    pub fn from_config(config: &Config) -> Arc<Hooks> {
+        Hooks::new(config.event_hook.clone(), config.network_type)
+    }
+
+    pub fn notify(&self, event: Event) {
+        let command = match self.command {
+            Some(ref command) => command.clone(),
+            None => return,
+        };
+        let network = self.network.clone();
+        spawn_thread("event_hook", move || {
+            if let Err(e) = run(&command, &network, &event) {
+                warn!("event hook failed on {:?}: {}", event, e);
+            }
+        });
+    }
+
+    /// Notifies about index corruption errors (before returning them).
+    pub fn check<T>(&self, result: Result<T>) -> Result<T> {
+        if let Err(Error(ErrorKind::Corruption(ref msg), _)) = result {
+            self.notify(Event::IndexCorruption {
+                message: msg.clone(),
+            });
+        }
+        result
+    }
+}
+
+// Event details are passed via environment variables, and as a JSON object via stdin.
+fn run(command: &str, network: &str, event: &Event) -> Result<()> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("ELECTRS_EVENT", event.name())
+        .env("ELECTRS_NETWORK", network)
+        .envs(event.env())
+        .stdin(Stdio::piped())
+        .spawn()
+        .chain_err(|| format!("failed to run {:?}", command))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // the command may exit without reading its input
+        let _ = writeln!(stdin, "{}", event.to_json(network));
+    }
+    let status = child
+        .wait()
+        .chain_err(|| format!("failed to wait for {:?}", command))?;
+    if !status.success() {
+        bail!("{:?} exited: {}", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, Event};
+    use crate::chain::BlockHash;
+
+    #[test]
+    fn test_event_hook() {
+        let event = Event::NewBlock {
+            height: 3,
+            blockhash: BlockHash::default(),
+        };
+        assert_eq!(
+            event.to_json("regtest"),
+            json!({
+                "event": "new_block",
+                "network": "regtest",
+                "height": 3,
+                "blockhash": "0000000000000000000000000000000000000000000000000000000000000000",
+            })
+        );
+        let command =
+            r#"test "$ELECTRS_EVENT/$ELECTRS_HEIGHT" = new_block/3 && grep -q '"height":3'"#;
+        assert!(run(command, "regtest", &event).is_ok());
+        assert!(run("exit 1", "regtest", &event).is_err());
+
+        let event = Event::IndexCorruption {
+            message: "missing header".to_owned(),
+        };
+        let command = r#"test "$ELECTRS_NETWORK: $ELECTRS_MESSAGE" = "regtest: missing header""#;
+        assert!(run(command, "regtest", &event).is_ok());
+    }
+}
@@ -1,22 +1,27 @@
-use bitcoin::blockdata::block::{Block, BlockHeader};
-use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
-use bitcoin::consensus::encode::{deserialize, serialize};
-use bitcoin::hash_types::{BlockHash, Txid};
+use error_chain::ChainedError;
 use sha2::{Digest, Sha256};
 use std::collections::{HashMap, HashSet};
 use std::iter::FromIterator;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
+use crate::chain::{
+    deserialize, serialize, spent_output, Block, BlockHash, BlockHeader, OutPoint, Transaction,
+    TxOut, Txid,
+};
+use crate::checkpoints;
+use crate::cipher::Cipher;
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::metrics::{
     Counter, Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
 };
+use crate::raw_block::{block_hash, index_raw_block, IndexedBlock};
 use crate::signal::Waiter;
-use crate::store::{ReadStore, Row, WriteStore};
+use crate::store::{DBStore, ReadStore, Row, WriteStore};
 use crate::util::{
     full_hash, hash_prefix, spawn_thread, Bytes, FullHash, HashPrefix, HeaderEntry, HeaderList,
-    HeaderMap, SyncChannel, HASH_PREFIX_LEN,
+    HeaderMap, SnapshotLock, SyncChannel, HASH_PREFIX_LEN,
 };
 
 #[derive(Serialize, Deserialize)]
@@ -33,12 +38,12 @@ pub struct TxInRow {
 }
 
 impl TxInRow {
-    pub fn new(txid: &Txid, input: &TxIn) -> TxInRow {
+    pub fn new(txid: &Txid, prevout: &OutPoint) -> TxInRow {
         TxInRow {
             key: TxInKey {
                 code: b'I',
-                prev_hash_prefix: hash_prefix(&input.previous_output.txid[..]),
-                prev_index: input.previous_output.vout as u16,
+                prev_hash_prefix: hash_prefix(&prevout.txid[..]),
+                prev_index: prevout.vout as u16,
             },
             txid_prefix: hash_prefix(&txid[..]),
         }
@@ -79,10 +84,14 @@ pub struct TxOutRow {
 
 impl TxOutRow {
     pub fn new(txid: &Txid, output: &TxOut) -> TxOutRow {
+        TxOutRow::from_script(txid, &output.script_pubkey[..])
+    }
+
+    pub fn from_script(txid: &Txid, script_pubkey: &[u8]) -> TxOutRow {
         TxOutRow {
             key: TxOutKey {
                 code: b'O',
-                script_hash_prefix: hash_prefix(&compute_script_hash(&output.script_pubkey[..])),
+                script_hash_prefix: hash_prefix(&compute_script_hash(script_pubkey)),
             },
             txid_prefix: hash_prefix(&txid[..]),
         }
@@ -153,6 +162,125 @@ impl TxRow {
     }
 }
 
+static TXID_SEARCH: AtomicBool = AtomicBool::new(false);
+
+/// Adds `TxSearchRow`s for the transactions indexed from now on (see `--txid-search`), by all
+/// the networks served by this process (so `electrs multi` requires them to agree).
+pub fn enable_txid_search() {
+    TXID_SEARCH.store(true, Ordering::Relaxed);
+}
+
+/// Maps a txid prefix (in its displayed, i.e. reversed, byte order) to its `TxRow` prefix.
+#[derive(Serialize, Deserialize)]
+pub struct TxSearchRow {
+    code: u8,
+    display_prefix: HashPrefix,
+    pub txid_prefix: HashPrefix,
+}
+
+impl TxSearchRow {
+    pub fn new(txid: &Txid) -> TxSearchRow {
+        let mut reversed = full_hash(&txid[..]);
+        reversed.reverse();
+        TxSearchRow {
+            code: b'S',
+            display_prefix: hash_prefix(&reversed),
+            txid_prefix: hash_prefix(&txid[..]),
+        }
+    }
+
+    pub fn filter(display_prefix: &[u8]) -> Bytes {
+        [b"S", display_prefix].concat()
+    }
+
+    pub fn to_row(&self) -> Row {
+        Row {
+            key: bincode::serialize(&self).unwrap(),
+            value: vec![],
+        }
+    }
+
+    pub fn from_row(row: &Row) -> TxSearchRow {
+        bincode::deserialize(&row.key).expect("failed to parse TxSearchRow")
+    }
+}
+
+/// Returns the transaction's search row (if enabled).
+pub fn txid_search_row(txid: &Txid) -> Option<Row> {
+    if TXID_SEARCH.load(Ordering::Relaxed) {
+        Some(TxSearchRow::new(txid).to_row())
+    } else {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StatusKey {
+    code: u8,
+    script_hash: FullHash,
+}
+
+/// A subscribed script hash's confirmed status, as of the block at `height` (whose hash is kept
+/// for detecting reorgs), so that only the blocks indexed afterwards have to be scanned.
+#[derive(Serialize, Deserialize)]
+pub struct StatusRow {
+    pub height: u32,
+    pub blockhash: FullHash,
+    pub funding: Vec<(FullHash, u32, u32, u64)>, // (txid, vout, height, value)
+    pub spending: Vec<(FullHash, u32, FullHash, u32, u64)>, // (txid, height, prev_txid, prev_vout, value)
+}
+
+impl StatusRow {
+    /// The script hash is blinded in an encrypted index, so it doesn't reveal the subscriptions.
+    pub fn key(script_hash: &[u8], cipher: Option<&Cipher>) -> Bytes {
+        bincode::serialize(&StatusKey {
+            code: b'Z',
+            script_hash: match cipher {
+                Some(cipher) => cipher.blind(script_hash),
+                None => full_hash(script_hash),
+            },
+        })
+        .unwrap()
+    }
+
+    pub fn to_row(&self, script_hash: &[u8], cipher: Option<&Cipher>) -> Row {
+        Row {
+            key: StatusRow::key(script_hash, cipher),
+            value: bincode::serialize(&self).unwrap(),
+        }
+    }
+
+    pub fn from_value(value: &[u8]) -> Option<StatusRow> {
+        bincode::deserialize(value).ok()
+    }
+}
+
+/// Deletes the persisted statuses of blocks below `min_height` (i.e. which no subscription has
+/// refreshed since), and the undecodable ones, returning the number of statuses kept.
+pub fn prune_statuses(store: &DBStore, min_height: u32) -> usize {
+    let mut kept = 0;
+    let mut pruned = vec![];
+    for row in store.iter_scan(b"Z") {
+        match StatusRow::from_value(&row.value) {
+            Some(status) if status.height >= min_height => kept += 1,
+            _ => pruned.push(row.key),
+        }
+    }
+    if !pruned.is_empty() {
+        debug!("pruned {} persisted statuses", pruned.len());
+        store.delete(&pruned);
+    }
+    kept
+}
+
+/// Deletes all the persisted statuses (e.g. the ones keyed by a rotated-out key, which can't be
+/// looked up anymore), returning their number.
+pub fn clear_statuses(store: &DBStore) -> usize {
+    let keys: Vec<Bytes> = store.iter_scan(b"Z").map(|row| row.key).collect();
+    store.delete(&keys);
+    keys.len()
+}
+
 #[derive(Serialize, Deserialize)]
 struct BlockKey {
     code: u8,
@@ -169,16 +297,12 @@ pub fn index_transaction<'a>(
     txn: &'a Transaction,
     height: usize,
 ) -> impl 'a + Iterator<Item = Row> {
-    let null_hash = Txid::default();
     let txid = txn.txid();
 
-    let inputs = txn.input.iter().filter_map(move |input| {
-        if input.previous_output.txid == null_hash {
-            None
-        } else {
-            Some(TxInRow::new(&txid, &input).to_row())
-        }
-    });
+    let inputs = txn
+        .input
+        .iter()
+        .filter_map(move |input| Some(TxInRow::new(&txid, spent_output(input)?).to_row()));
     let outputs = txn
         .output
         .iter()
@@ -188,19 +312,23 @@ pub fn index_transaction<'a>(
     inputs
         .chain(outputs)
         .chain(std::iter::once(TxRow::new(&txid, height as u32).to_row()))
+        .chain(txid_search_row(&txid))
 }
 
-pub fn index_block<'a>(block: &'a Block, height: usize) -> impl 'a + Iterator<Item = Row> {
-    let blockhash = block.block_hash();
-    // Persist block hash and header
-    let row = Row {
+/// Persist block hash and (serialized) header
+pub fn header_row(blockhash: &BlockHash, header: Bytes) -> Row {
+    Row {
         key: bincode::serialize(&BlockKey {
             code: b'B',
             hash: full_hash(&blockhash[..]),
         })
         .unwrap(),
-        value: serialize(&block.header),
-    };
+        value: header,
+    }
+}
+
+pub fn index_block<'a>(block: &'a Block, height: usize) -> impl 'a + Iterator<Item = Row> {
+    let row = header_row(&block.block_hash(), serialize(&block.header));
     block
         .txdata
         .iter()
@@ -216,6 +344,34 @@ pub fn last_indexed_block(blockhash: &BlockHash) -> Row {
     }
 }
 
+// Marks an index which stores only the block headers (see `--headers-only`).
+const HEADERS_ONLY_KEY: &[u8] = b"H";
+
+// The number of headers written per batch (in headers-only mode).
+const HEADERS_BATCH_SIZE: usize = 10_000;
+
+/// Fails if the index was created in another mode, since a full index would be missing the rows
+/// of the blocks indexed in headers-only mode. A new index is created in the requested mode.
+pub fn check_headers_only(store: &DBStore, headers_only: bool) -> Result<()> {
+    let stored = store.get(HEADERS_ONLY_KEY).is_some();
+    if store.iter_scan(b"B").next().is_none() {
+        if headers_only && !stored {
+            store.write(vec![Row {
+                key: HEADERS_ONLY_KEY.to_vec(),
+                value: vec![],
+            }]);
+        } else if !headers_only && stored {
+            store.delete(&[HEADERS_ONLY_KEY.to_vec()]);
+        }
+        return Ok(());
+    }
+    match (stored, headers_only) {
+        (true, false) => bail!("index was created with --headers-only"),
+        (false, true) => bail!("a full index can't be used with --headers-only"),
+        _ => Ok(()),
+    }
+}
+
 pub fn read_indexed_blockhashes(store: &dyn ReadStore) -> HashSet<BlockHash> {
     let mut result = HashSet::new();
     for row in store.scan(b"B") {
@@ -225,18 +381,32 @@ pub fn read_indexed_blockhashes(store: &dyn ReadStore) -> HashSet<BlockHash> {
     result
 }
 
-fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
+// Returns `None` if the row can't be parsed, or if the header doesn't match its hash.
+fn parse_header_row(row: &Row) -> Option<(BlockHash, BlockHeader)> {
+    let key: BlockKey = bincode::deserialize(&row.key).ok()?;
+    let blockhash: BlockHash = deserialize(&key.hash).ok()?;
+    let header: BlockHeader = deserialize(&row.value).ok()?;
+    if header.block_hash() != blockhash {
+        return None;
+    }
+    Some((blockhash, header))
+}
+
+fn read_indexed_headers(store: &dyn ReadStore) -> Result<HeaderList> {
+    let corruption = |msg: String| ErrorKind::Corruption(msg);
     let latest_blockhash: BlockHash = match store.get(b"L") {
         // latest blockheader persisted in the DB.
-        Some(row) => deserialize(&row).unwrap(),
+        Some(row) => {
+            deserialize(&row).chain_err(|| corruption("invalid latest blockhash".to_owned()))?
+        }
         None => BlockHash::default(),
     };
     trace!("latest indexed blockhash: {}", latest_blockhash);
     let mut map = HeaderMap::new();
     for row in store.scan(b"B") {
-        let key: BlockKey = bincode::deserialize(&row.key).unwrap();
-        let header: BlockHeader = deserialize(&row.value).unwrap();
-        map.insert(deserialize(&key.hash).unwrap(), header);
+        let (blockhash, header) = parse_header_row(&row)
+            .chain_err(|| corruption(format!("invalid header row {}", hex::encode(&row.key))))?;
+        map.insert(blockhash, header);
     }
     let mut headers = vec![];
     let null_hash = BlockHash::default();
@@ -244,29 +414,103 @@ fn read_indexed_headers(store: &dyn ReadStore) -> HeaderList {
     while blockhash != null_hash {
         let header = map
             .remove(&blockhash)
-            .unwrap_or_else(|| panic!("missing {} header in DB", blockhash));
+            .chain_err(|| corruption(format!("missing {} header in DB", blockhash)))?;
         blockhash = header.prev_blockhash;
         headers.push(header);
     }
     headers.reverse();
-    assert_eq!(
-        headers
-            .first()
-            .map(|h| h.prev_blockhash)
-            .unwrap_or(null_hash),
-        null_hash
-    );
-    assert_eq!(
-        headers
-            .last()
-            .map(BlockHeader::block_hash)
-            .unwrap_or(null_hash),
-        latest_blockhash
-    );
+    let tip = headers
+        .last()
+        .map(BlockHeader::block_hash)
+        .unwrap_or(null_hash);
+    if tip != latest_blockhash {
+        bail!(corruption(format!(
+            "{} header is stored as {}",
+            tip, latest_blockhash
+        )));
+    }
     let mut result = HeaderList::empty();
     let entries = result.order(headers);
     result.apply(entries, latest_blockhash);
-    result
+    Ok(result)
+}
+
+// Returns the heights of the headers connected (via their stored ancestors) to the genesis block.
+fn connected_heights(headers: &HeaderMap) -> HashMap<BlockHash, usize> {
+    let null_hash = BlockHash::default();
+    let mut heights = HashMap::new();
+    let mut disconnected = HashSet::new();
+    for blockhash in headers.keys() {
+        let mut path = vec![];
+        let mut current = *blockhash;
+        // the height of the first block in `path` (if it's connected)
+        let mut next_height = loop {
+            if current == null_hash {
+                break Some(0);
+            }
+            if let Some(height) = heights.get(&current) {
+                break Some(height + 1);
+            }
+            if disconnected.contains(&current) {
+                break None;
+            }
+            match headers.get(&current) {
+                Some(header) => {
+                    path.push(current);
+                    current = header.prev_blockhash;
+                }
+                None => break None,
+            }
+        };
+        for blockhash in path.into_iter().rev() {
+            match next_height {
+                Some(height) => {
+                    heights.insert(blockhash, height);
+                    next_height = Some(height + 1);
+                }
+                None => {
+                    disconnected.insert(blockhash);
+                }
+            }
+        }
+    }
+    heights
+}
+
+/// Repairs the block header index after a corruption was detected (see `ErrorKind::Corruption`):
+/// invalid header rows are deleted, and the last indexed block is rewound to the highest stored
+/// header whose ancestors are all stored. The following blocks are re-indexed from bitcoind by the
+/// next index update, re-deriving their rows.
+pub fn repair_headers(store: &DBStore) -> Result<()> {
+    let mut headers = HeaderMap::new();
+    let mut invalid = vec![];
+    for row in store.scan(b"B") {
+        match parse_header_row(&row) {
+            Some((blockhash, header)) => {
+                headers.insert(blockhash, header);
+            }
+            None => invalid.push(row.key),
+        }
+    }
+    let latest: Option<BlockHash> = store.get(b"L").and_then(|row| deserialize(&row).ok());
+    let (tip, height) = connected_heights(&headers)
+        .into_iter()
+        .max_by_key(|(_, height)| *height)
+        .map_or((BlockHash::default(), None), |(tip, height)| {
+            (tip, Some(height))
+        });
+    store.delete(&invalid);
+    store.write(vec![last_indexed_block(&tip)]);
+    store.flush();
+    warn!(
+        "repaired index: deleted {} invalid header rows, and rewound the last indexed block from {} to {} (height {})",
+        invalid.len(),
+        latest.map_or("<invalid>".to_owned(), |blockhash| blockhash.to_string()),
+        tip,
+        height.map_or("none".to_owned(), |height| height.to_string()),
+    );
+    warn!("the blocks after {} will be re-indexed", tip);
+    Ok(())
 }
 
 struct Stats {
@@ -303,12 +547,10 @@ impl Stats {
         }
     }
 
-    fn update(&self, block: &Block, height: usize) {
+    fn update(&self, block: &IndexedBlock, height: usize) {
         self.blocks.inc();
-        self.txns.inc_by(block.txdata.len() as i64);
-        for tx in &block.txdata {
-            self.vsize.inc_by(tx.get_weight() as i64 / 4);
-        }
+        self.txns.inc_by(block.txns as i64);
+        self.vsize.inc_by(block.weight as i64 / 4);
         self.update_height(height);
     }
 
@@ -324,9 +566,11 @@ impl Stats {
 pub struct Index {
     // TODO: store also latest snapshot.
     headers: RwLock<HeaderList>,
+    snapshots: SnapshotLock, // see `snapshot()`
     daemon: Daemon,
     stats: Stats,
     batch_size: usize,
+    headers_only: bool, // don't index the blocks' transactions
 }
 
 impl Index {
@@ -337,19 +581,50 @@ impl Index {
         batch_size: usize,
     ) -> Result<Index> {
         let stats = Stats::new(metrics);
-        let headers = read_indexed_headers(store);
+        let headers = read_indexed_headers(store)?;
         stats.height.set((headers.len() as i64) - 1);
         Ok(Index {
             headers: RwLock::new(headers),
+            snapshots: SnapshotLock::default(),
             daemon: daemon.reconnect()?,
             stats,
             batch_size,
+            headers_only: false,
         })
     }
 
-    pub fn reload(&self, store: &dyn ReadStore) {
+    /// Stores only the new blocks' headers (see `check_headers_only`).
+    pub fn with_headers_only(self, headers_only: bool) -> Index {
+        Index {
+            headers_only,
+            ..self
+        }
+    }
+
+    /// Pins the best chain (and the mempool, see `Query::update_mempool`) while the returned guard
+    /// is held, so a request (or a batch of requests) is served from a consistent snapshot.
+    pub fn snapshot(&self) -> RwLockReadGuard<()> {
+        self.snapshots.read()
+    }
+
+    /// Waits for the current snapshots to be released, and blocks new ones while the returned
+    /// guard is held (e.g. while the best chain or the mempool are updated).
+    pub fn exclusive(&self) -> RwLockWriteGuard<()> {
+        self.snapshots.write()
+    }
+
+    pub fn reload(&self, store: &dyn ReadStore) -> Result<()> {
+        self.reload_with(store, || ())
+    }
+
+    /// Reloads the headers after `write` has written new rows (e.g. replicated ones), holding the
+    /// exclusive lock during both, so the snapshots see the rows together with their headers.
+    pub fn reload_with(&self, store: &dyn ReadStore, write: impl FnOnce()) -> Result<()> {
+        let _exclusive = self.exclusive();
+        write();
         let mut headers = self.headers.write().unwrap();
-        *headers = read_indexed_headers(store);
+        *headers = read_indexed_headers(store)?;
+        Ok(())
     }
 
     pub fn best_header(&self) -> Option<HeaderEntry> {
@@ -357,6 +632,14 @@ impl Index {
         headers.header_by_blockhash(&headers.tip()).cloned()
     }
 
+    pub fn get_header_by_blockhash(&self, blockhash: &BlockHash) -> Option<HeaderEntry> {
+        self.headers
+            .read()
+            .unwrap()
+            .header_by_blockhash(blockhash)
+            .cloned()
+    }
+
     pub fn get_header(&self, height: usize) -> Option<HeaderEntry> {
         self.headers
             .read()
@@ -372,13 +655,20 @@ impl Index {
             let indexed_headers = self.headers.read().unwrap();
             indexed_headers.order(daemon.get_new_headers(&indexed_headers, &tip)?)
         };
+        checkpoints::verify(daemon.network(), &new_headers)?;
         if let Some(latest_header) = new_headers.last() {
             info!("{:?} ({} left to index)", latest_header, new_headers.len());
         };
+        if self.headers_only {
+            self.write_headers(store, &new_headers, waiter)?;
+            self.apply_headers(new_headers, tip);
+            return Ok(tip);
+        }
         let height_map = HashMap::<BlockHash, usize>::from_iter(
             new_headers.iter().map(|h| (*h.hash(), h.height())),
         );
 
+        let mut new_chunks = new_headers.chunks(self.batch_size);
         let chan = SyncChannel::new(1);
         let sender = chan.sender();
         let blockhashes: Vec<BlockHash> = new_headers.iter().map(|h| *h.hash()).collect();
@@ -387,7 +677,7 @@ impl Index {
             for blockhashes_chunk in blockhashes.chunks(batch_size) {
                 let blocks = blockhashes_chunk
                     .iter()
-                    .map(|blockhash| daemon.getblock(blockhash))
+                    .map(|blockhash| daemon.getblock_raw(blockhash))
                     .collect();
                 sender
                     .send(blocks)
@@ -409,18 +699,34 @@ impl Index {
                 break;
             }
 
-            let rows_iter = batch.iter().flat_map(|block| {
-                let blockhash = block.block_hash();
+            let timer = self.stats.start_timer("index+write");
+            let mut rows = vec![];
+            for block in &batch {
+                let blockhash = block_hash(block)?;
                 let height = *height_map
                     .get(&blockhash)
                     .unwrap_or_else(|| panic!("missing header for block {}", blockhash));
 
-                self.stats.update(block, height); // TODO: update stats after the block is indexed
-                index_block(block, height).chain(std::iter::once(last_indexed_block(&blockhash)))
-            });
-
-            let timer = self.stats.start_timer("index+write");
-            store.write(rows_iter);
+                let indexed = match index_raw_block(block, height) {
+                    Ok(indexed) => indexed,
+                    Err(e) => {
+                        // the block may have been corrupted in transit, so fetch it again
+                        warn!("refetching block {}: {}", blockhash, e.display_chain());
+                        let block = self.daemon.reconnect()?.getblock_raw(&blockhash)?;
+                        index_raw_block(&block, height)?
+                    }
+                };
+                self.stats.update(&indexed, height); // TODO: update stats after the block is indexed
+                rows.extend(indexed.rows);
+                rows.push(last_indexed_block(&blockhash));
+            }
+            let headers = new_chunks
+                .next()
+                .expect("missing headers of fetched blocks");
+            let exclusive = self.exclusive(); // the rows are visible together with their headers
+            store.write(rows);
+            self.swap_headers(headers.to_vec(), *headers.last().unwrap().hash());
+            drop(exclusive);
             timer.observe_duration();
         }
         let timer = self.stats.start_timer("flush");
@@ -428,10 +734,105 @@ impl Index {
         timer.observe_duration();
 
         fetcher.join().expect("block fetcher failed");
+        if new_headers.is_empty() {
+            self.apply_headers(vec![], tip); // e.g. after a reorg to a shorter chain
+        }
+        assert_eq!(tip, self.headers.read().unwrap().tip());
+        Ok(tip)
+    }
+
+    fn write_headers(
+        &self,
+        store: &impl WriteStore,
+        new_headers: &[HeaderEntry],
+        waiter: &Waiter,
+    ) -> Result<()> {
+        for chunk in new_headers.chunks(HEADERS_BATCH_SIZE) {
+            waiter.poll()?;
+            let timer = self.stats.start_timer("index+write");
+            let mut rows: Vec<Row> = chunk
+                .iter()
+                .map(|entry| header_row(entry.hash(), serialize(entry.header())))
+                .collect();
+            if let Some(entry) = chunk.last() {
+                rows.push(last_indexed_block(entry.hash()));
+            }
+            store.write(rows);
+            timer.observe_duration();
+        }
+        let timer = self.stats.start_timer("flush");
+        store.flush();
+        timer.observe_duration();
+        Ok(())
+    }
+
+    fn apply_headers(&self, new_headers: Vec<HeaderEntry>, tip: BlockHash) {
+        let _exclusive = self.exclusive();
+        self.swap_headers(new_headers, tip);
+    }
+
+    // Should be called while holding the exclusive lock (see `exclusive()`).
+    fn swap_headers(&self, new_headers: Vec<HeaderEntry>, tip: BlockHash) {
         self.headers.write().unwrap().apply(new_headers, tip);
         assert_eq!(tip, self.headers.read().unwrap().tip());
         self.stats
             .update_height(self.headers.read().unwrap().len() - 1);
-        Ok(tip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connected_heights, header_row, parse_header_row, TxSearchRow};
+    use crate::chain::{BlockHash, BlockHeader, TxMerkleNode, Txid};
+    use crate::util::HeaderMap;
+    use bitcoin::consensus::encode::serialize;
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn test_txid_search_row() {
+        let hex = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let txid = Txid::from_hex(hex).unwrap();
+        let row = TxSearchRow::new(&txid).to_row();
+        let display_prefix = hex::decode(&hex[..10]).unwrap();
+        assert!(row.key.starts_with(&TxSearchRow::filter(&display_prefix)));
+        assert_eq!(TxSearchRow::from_row(&row).txid_prefix[..], txid[..8]);
+    }
+
+    fn header(prev_blockhash: BlockHash, nonce: u32) -> BlockHeader {
+        BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::hash(&[255]),
+            time: 0,
+            bits: 0,
+            nonce,
+        }
+    }
+
+    #[test]
+    fn test_connected_heights() {
+        let mut chain = vec![header(BlockHash::default(), 0)];
+        for _ in 1..10 {
+            chain.push(header(chain.last().unwrap().block_hash(), 0));
+        }
+        let fork = header(chain[2].block_hash(), 1);
+        let mut headers: HeaderMap = chain.iter().map(|h| (h.block_hash(), *h)).collect();
+        headers.insert(fork.block_hash(), fork);
+        headers.remove(&chain[5].block_hash());
+
+        let heights = connected_heights(&headers);
+        assert_eq!(heights.len(), 6); // the chain up to height 4, and the fork
+        assert_eq!(heights[&chain[4].block_hash()], 4);
+        assert_eq!(heights[&fork.block_hash()], 3);
+        assert!(!heights.contains_key(&chain[9].block_hash()));
+
+        let row = header_row(&chain[1].block_hash(), serialize(&chain[1]));
+        assert_eq!(
+            parse_header_row(&row),
+            Some((chain[1].block_hash(), chain[1]))
+        );
+        let row = header_row(&chain[1].block_hash(), serialize(&chain[2]));
+        assert_eq!(parse_header_row(&row), None); // mismatching hash
     }
 }
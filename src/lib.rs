@@ -12,17 +12,46 @@ extern crate serde_json;
 extern crate configure_me;
 
 pub mod app;
+pub mod audit;
+pub mod backup;
+pub mod bench;
 pub mod bulk;
 pub mod cache;
+pub mod chain;
+pub mod checkpoints;
+pub mod cipher;
 pub mod config;
+pub mod conformance;
 pub mod daemon;
+pub mod dashboard;
+pub mod decode;
+pub mod dump;
 pub mod errors;
+pub mod export;
 pub mod fake;
+pub mod hooks;
 pub mod index;
 pub mod mempool;
 pub mod metrics;
+pub mod msgpack;
+pub mod protocol;
+pub mod proxy;
 pub mod query;
+#[cfg(feature = "quic")]
+pub mod quic;
+pub mod ratelimit;
+pub mod raw_block;
+pub mod record;
+pub mod redact;
+pub mod replicate;
 pub mod rpc;
+pub mod schedule;
 pub mod signal;
+pub mod standby;
 pub mod store;
+#[cfg(feature = "test_harness")]
+pub mod test_harness;
+pub mod tls;
+pub mod tor;
 pub mod util;
+pub mod watch;
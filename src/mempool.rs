@@ -1,20 +1,22 @@
-use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::hash_types::Txid;
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::iter::FromIterator;
 use std::ops::Bound;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::chain::{spent_output, OutPoint, Transaction, Txid};
 use crate::daemon::{Daemon, MempoolEntry};
 use crate::errors::*;
-use crate::index::index_transaction;
+use crate::index::{compute_script_hash, index_transaction};
 use crate::metrics::{
     Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
 };
+use crate::redact::{redacted, redacted_hashes};
 use crate::store::{ReadStore, Row};
-use crate::util::Bytes;
+use crate::util::{Bytes, FullHash};
 
 const VSIZE_BIN_WIDTH: u32 = 100_000; // in vbytes
+const MAX_RECENT_TXIDS: usize = 100_000; // for streaming the new transactions
 
 struct MempoolStore {
     map: BTreeMap<Bytes, Vec<Bytes>>,
@@ -91,6 +93,66 @@ impl ReadStore for MempoolStore {
 struct Item {
     tx: Transaction,     // stored for faster retrieval and index removal
     entry: MempoolEntry, // caches mempool fee rates
+    first_seen: u64,     // unix time
+}
+
+/// A mempool transaction's BIP125 replaceability and fee rates, considering its unconfirmed
+/// ancestors (the ancestor package includes the transaction itself, as in bitcoind).
+pub struct Replaceability {
+    pub signals_rbf: bool, // explicitly, by an input's sequence number
+    pub replaceable: bool, // explicitly, or by an unconfirmed ancestor
+    pub fee: u64,
+    pub vsize: u32,
+    pub ancestor_count: usize,
+    pub ancestor_vsize: u64,
+    pub ancestor_fees: u64,
+    pub first_seen: u64, // unix time
+}
+
+impl Replaceability {
+    /// The fee rate a miner would consider (in sat/vbyte): a transaction paying more than its
+    /// ancestors is mined at the package's fee rate (e.g. a CPFP child), otherwise at its own.
+    pub fn effective_fee_rate(&self) -> f64 {
+        let own = self.fee as f64 / f64::from(self.vsize);
+        let package = self.ancestor_fees as f64 / self.ancestor_vsize as f64;
+        own.min(package)
+    }
+}
+
+fn signals_rbf(tx: &Transaction) -> bool {
+    tx.input.iter().any(|input| input.sequence < 0xffff_fffe)
+}
+
+fn replaceability(items: &HashMap<Txid, Item>, txid: &Txid) -> Option<Replaceability> {
+    let item = items.get(txid)?;
+    let mut ancestors = HashSet::new();
+    let mut pending = vec![&item.tx];
+    while let Some(tx) = pending.pop() {
+        for input in &tx.input {
+            let parent = &input.previous_output.txid;
+            if let Some(parent_item) = items.get(parent) {
+                if ancestors.insert(*parent) {
+                    pending.push(&parent_item.tx);
+                }
+            }
+        }
+    }
+    let ancestor_items: Vec<&Item> = ancestors.iter().map(|txid| &items[txid]).collect();
+    let signals = signals_rbf(&item.tx);
+    Some(Replaceability {
+        signals_rbf: signals,
+        replaceable: signals || ancestor_items.iter().any(|a| signals_rbf(&a.tx)),
+        fee: item.entry.fee(),
+        vsize: item.entry.vsize(),
+        ancestor_count: 1 + ancestor_items.len(),
+        ancestor_vsize: ancestor_items
+            .iter()
+            .map(|a| u64::from(a.entry.vsize()))
+            .sum::<u64>()
+            + u64::from(item.entry.vsize()),
+        ancestor_fees: ancestor_items.iter().map(|a| a.entry.fee()).sum::<u64>() + item.entry.fee(),
+        first_seen: item.first_seen,
+    })
 }
 
 struct Stats {
@@ -137,10 +199,22 @@ impl Stats {
     }
 }
 
+/// A transaction added to the mempool (see `mempool.transactions.subscribe`).
+pub struct NewTransaction {
+    pub txid: Txid,
+    pub fee_rate: f32, // in sat/vbyte
+    pub vsize: u32,
+    pub script_hashes: Vec<FullHash>, // of its outputs
+    pub spent: Vec<OutPoint>,
+    pub first_seen: u64, // unix time
+}
+
 pub struct Tracker {
     items: HashMap<Txid, Item>,
     index: MempoolStore,
     histogram: Vec<(f32, u32)>,
+    recent: VecDeque<Txid>, // the most recently added transactions
+    added: u64,             // since startup
     stats: Stats,
 }
 
@@ -150,6 +224,8 @@ impl Tracker {
             items: HashMap::new(),
             index: MempoolStore::new(),
             histogram: vec![],
+            recent: VecDeque::new(),
+            added: 0,
             stats: Stats {
                 count: metrics.gauge(MetricOpts::new(
                     "electrs_mempool_count",
@@ -178,10 +254,23 @@ impl Tracker {
         self.items.contains_key(txid)
     }
 
+    pub fn get_txn(&self, txid: &Txid) -> Option<&Transaction> {
+        self.items.get(txid).map(|item| &item.tx)
+    }
+
     pub fn get_fee(&self, txid: &Txid) -> Option<u64> {
         self.items.get(txid).map(|stats| stats.entry.fee())
     }
 
+    /// Returns when the transaction was first seen in the mempool (as unix time).
+    pub fn get_first_seen(&self, txid: &Txid) -> Option<u64> {
+        self.items.get(txid).map(|item| item.first_seen)
+    }
+
+    pub fn get_replaceability(&self, txid: &Txid) -> Option<Replaceability> {
+        replaceability(&self.items, txid)
+    }
+
     /// Returns vector of (fee_rate, vsize) pairs, where fee_{n-1} > fee_n and vsize_n is the
     /// total virtual size of mempool transactions with fee in the bin [fee_{n-1}, fee_n].
     /// Note: fee_{-1} is implied to be infinite.
@@ -193,6 +282,42 @@ impl Tracker {
         &self.index
     }
 
+    /// Returns the transactions added after the first `count` ones (since startup), which are
+    /// still in the mempool, and the number of transactions added so far. Only the most recent
+    /// `MAX_RECENT_TXIDS` transactions are returned.
+    pub fn added_since(&self, count: u64) -> (Vec<NewTransaction>, u64) {
+        let first = self.added - self.recent.len() as u64;
+        let skip = count.saturating_sub(first) as usize;
+        let txs = self
+            .recent
+            .iter()
+            .skip(skip)
+            .filter_map(|txid| {
+                let item = self.items.get(txid)?;
+                Some(NewTransaction {
+                    txid: *txid,
+                    fee_rate: item.entry.fee_per_vbyte(),
+                    vsize: item.entry.vsize(),
+                    script_hashes: item
+                        .tx
+                        .output
+                        .iter()
+                        .map(|txout| compute_script_hash(&txout.script_pubkey[..]))
+                        .collect(),
+                    spent: item
+                        .tx
+                        .input
+                        .iter()
+                        .filter_map(spent_output)
+                        .cloned()
+                        .collect(),
+                    first_seen: item.first_seen,
+                })
+            })
+            .collect();
+        (txs, self.added)
+    }
+
     pub fn update(&mut self, daemon: &Daemon) -> Result<()> {
         let timer = self.stats.start_timer("fetch");
         let new_txids = daemon
@@ -207,7 +332,11 @@ impl Tracker {
             match daemon.getmempoolentry(txid) {
                 Ok(entry) => Some((txid, entry)),
                 Err(err) => {
-                    debug!("no mempool entry {}: {}", txid, err); // e.g. new block or RBF
+                    debug!(
+                        "no mempool entry {}: {}",
+                        redacted(txid),
+                        redacted_hashes(err)
+                    ); // e.g. new block or RBF
                     None // ignore this transaction for now
                 }
             }
@@ -219,7 +348,11 @@ impl Tracker {
                     self.add(txid, tx, entry);
                 }
                 Err(err) => {
-                    debug!("failed to get transaction {}: {}", txid, err); // e.g. new block or RBF
+                    debug!(
+                        "failed to get transaction {}: {}",
+                        redacted(txid),
+                        redacted_hashes(err)
+                    ); // e.g. new block or RBF
                 }
             }
         }
@@ -231,9 +364,12 @@ impl Tracker {
         }
         timer.observe_duration();
 
-        let timer = self.stats.start_timer("fees");
-        self.update_fee_histogram();
-        timer.observe_duration();
+        // the histogram (and the statistics) change only when transactions are added or removed
+        if new_txids != old_txids {
+            let timer = self.stats.start_timer("fees");
+            self.update_fee_histogram();
+            timer.observe_duration();
+        }
 
         self.stats.count.set(self.items.len() as i64);
         Ok(())
@@ -241,7 +377,22 @@ impl Tracker {
 
     fn add(&mut self, txid: &Txid, tx: Transaction, entry: MempoolEntry) {
         self.index.add(&tx);
-        self.items.insert(*txid, Item { tx, entry });
+        let first_seen = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.items.insert(
+            *txid,
+            Item {
+                tx,
+                entry,
+                first_seen,
+            },
+        );
+        self.recent.push_back(*txid);
+        if self.recent.len() > MAX_RECENT_TXIDS {
+            self.recent.pop_front();
+        }
+        self.added += 1;
     }
 
     fn remove(&mut self, txid: &Txid) {
@@ -282,6 +433,40 @@ fn electrum_fees(entries: &[&MempoolEntry]) -> Vec<(f32, u32)> {
     histogram
 }
 
+/// Returns true if the histograms differ materially, i.e. the total vsize of transactions paying
+/// at least some fee rate (0, 1, 2, 4, ... sat/vbyte) changed by 10% or more.
+pub fn fee_histogram_changed(old: &[(f32, u32)], new: &[(f32, u32)]) -> bool {
+    // histograms are ordered by decreasing fee rate
+    let vsize_paying = |histogram: &[(f32, u32)], fee_rate: f32| -> u64 {
+        histogram
+            .iter()
+            .take_while(|(rate, _)| *rate >= fee_rate)
+            .map(|(_, vsize)| u64::from(*vsize))
+            .sum()
+    };
+    let changed = |fee_rate: f32| -> bool {
+        let (a, b) = (vsize_paying(old, fee_rate), vsize_paying(new, fee_rate));
+        let (min, max) = (a.min(b), a.max(b));
+        (max - min) * 10 >= max && max > min
+    };
+    let max_fee_rate = old
+        .iter()
+        .chain(new.iter())
+        .map(|(fee_rate, _)| *fee_rate)
+        .fold(0.0, f32::max);
+    if changed(0.0) {
+        return true;
+    }
+    let mut fee_rate = 1.0;
+    while fee_rate <= max_fee_rate {
+        if changed(fee_rate) {
+            return true;
+        }
+        fee_rate *= 2.0;
+    }
+    false
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -303,4 +488,93 @@ mod tests {
             vec![(3.0, 150_000), (1.0, 121_000)]
         );
     }
+
+    #[test]
+    fn test_replaceability() {
+        use crate::chain::{OutPoint, Transaction, TxIn, Txid};
+        use crate::daemon::MempoolEntry;
+        use crate::mempool::{replaceability, Item};
+        use bitcoin::hashes::Hash;
+        use std::collections::HashMap;
+
+        let tx = |prev_txid: Txid, sequence: u32| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: prev_txid,
+                    vout: 0,
+                },
+                sequence,
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+        let mut items = HashMap::new();
+        let mut add = |tx: Transaction, fee: u64, vsize: u32| {
+            let txid = tx.txid();
+            let entry = MempoolEntry::new(fee, vsize);
+            let first_seen = 1_600_000_000;
+            items.insert(
+                txid,
+                Item {
+                    tx,
+                    entry,
+                    first_seen,
+                },
+            );
+            txid
+        };
+        // a low-fee parent (signaling RBF), bumped by a child paying for it (CPFP)
+        let parent = add(tx(Txid::hash(b"confirmed"), 0xffff_fffd), 100, 100);
+        let child = add(tx(parent, 0xffff_ffff), 1_900, 100);
+        let unrelated = add(tx(Txid::hash(b"other"), 0xffff_fffe), 500, 100);
+
+        let info = replaceability(&items, &parent).unwrap();
+        assert!(info.signals_rbf && info.replaceable);
+        assert_eq!((info.ancestor_count, info.ancestor_vsize), (1, 100));
+        assert_eq!(info.effective_fee_rate(), 1.0);
+        assert_eq!(info.first_seen, 1_600_000_000);
+
+        let info = replaceability(&items, &child).unwrap();
+        assert!(!info.signals_rbf && info.replaceable); // inherited from its parent
+        assert_eq!((info.ancestor_count, info.ancestor_vsize), (2, 200));
+        assert_eq!(info.ancestor_fees, 2_000);
+        assert_eq!(info.effective_fee_rate(), 10.0);
+
+        let info = replaceability(&items, &unrelated).unwrap();
+        assert!(!info.signals_rbf && !info.replaceable);
+        assert_eq!(info.effective_fee_rate(), 5.0);
+
+        assert!(replaceability(&items, &Txid::hash(b"confirmed")).is_none());
+    }
+
+    #[test]
+    fn test_fee_histogram_changed() {
+        use crate::mempool::fee_histogram_changed;
+
+        let histogram = vec![(3.0, 150_000), (1.0, 121_000)];
+        assert!(!fee_histogram_changed(&histogram, &histogram));
+        assert!(!fee_histogram_changed(&[], &[]));
+        assert!(fee_histogram_changed(&[], &histogram));
+        assert!(fee_histogram_changed(&histogram, &[]));
+        // small changes are ignored
+        assert!(!fee_histogram_changed(
+            &histogram,
+            &[(3.0, 155_000), (1.0, 121_000)]
+        ));
+        assert!(!fee_histogram_changed(
+            &histogram,
+            &[(3.1, 150_000), (1.0, 121_000)]
+        ));
+        // large changes (at some fee rate) are not
+        assert!(fee_histogram_changed(
+            &histogram,
+            &[(3.0, 200_000), (1.0, 121_000)]
+        ));
+        assert!(fee_histogram_changed(
+            &histogram,
+            &[(5.0, 20_000), (3.0, 130_000), (1.0, 121_000)]
+        ));
+    }
 }
@@ -1,7 +1,10 @@
 use prometheus::{self, Encoder, IntGauge};
+use std::collections::HashMap;
 use std::fs;
-use std::io;
+use std::io::{self, BufRead, BufReader, Write};
 use std::net::SocketAddr;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::thread;
 use std::time::Duration;
 
@@ -10,12 +13,16 @@ pub use prometheus::{
     IntCounterVec as CounterVec, IntGauge as Gauge, Opts as MetricOpts,
 };
 
+use crate::dashboard::{self, STATUS_PATH};
 use crate::errors::*;
-use crate::util::spawn_thread;
+use crate::util::{constant_time_eq, spawn_thread};
 
 pub struct Metrics {
     reg: prometheus::Registry,
     addr: SocketAddr,
+    socket: Option<PathBuf>,
+    token: Option<String>,
+    const_labels: HashMap<String, String>, // added to all the registered metrics
 }
 
 impl Metrics {
@@ -23,79 +30,226 @@ impl Metrics {
         Metrics {
             reg: prometheus::Registry::new(),
             addr,
+            socket: None,
+            token: None,
+            const_labels: HashMap::new(),
+        }
+    }
+
+    /// Returns metrics sharing this registry, labeled by `network` (for serving several networks
+    /// from a single process).
+    pub fn with_network(&self, network: &str) -> Metrics {
+        let mut const_labels = self.const_labels.clone();
+        const_labels.insert("network".to_owned(), network.to_owned());
+        Metrics {
+            reg: self.reg.clone(),
+            addr: self.addr,
+            socket: self.socket.clone(),
+            token: self.token.clone(),
+            const_labels,
+        }
+    }
+
+    fn opts(&self, mut opts: prometheus::Opts) -> prometheus::Opts {
+        opts.const_labels.extend(self.const_labels.clone());
+        opts
+    }
+
+    fn histogram_opts(&self, mut opts: prometheus::HistogramOpts) -> prometheus::HistogramOpts {
+        opts.common_opts = self.opts(opts.common_opts);
+        opts
+    }
+
+    /// Serve the metrics on a unix socket (instead of TCP), and/or require a bearer token.
+    pub fn with_access(self, socket: Option<PathBuf>, token: Option<String>) -> Metrics {
+        Metrics {
+            socket,
+            token,
+            ..self
         }
     }
 
     pub fn counter(&self, opts: prometheus::Opts) -> Counter {
-        let c = Counter::with_opts(opts).unwrap();
+        let c = Counter::with_opts(self.opts(opts)).unwrap();
         self.reg.register(Box::new(c.clone())).unwrap();
         c
     }
 
     pub fn counter_vec(&self, opts: prometheus::Opts, labels: &[&str]) -> CounterVec {
-        let c = CounterVec::new(opts, labels).unwrap();
+        let c = CounterVec::new(self.opts(opts), labels).unwrap();
         self.reg.register(Box::new(c.clone())).unwrap();
         c
     }
 
     pub fn gauge(&self, opts: prometheus::Opts) -> Gauge {
-        let g = Gauge::with_opts(opts).unwrap();
+        let g = Gauge::with_opts(self.opts(opts)).unwrap();
         self.reg.register(Box::new(g.clone())).unwrap();
         g
     }
 
     pub fn gauge_vec(&self, opts: prometheus::Opts, labels: &[&str]) -> GaugeVec {
-        let g = GaugeVec::new(opts, labels).unwrap();
+        let g = GaugeVec::new(self.opts(opts), labels).unwrap();
         self.reg.register(Box::new(g.clone())).unwrap();
         g
     }
 
     pub fn gauge_int(&self, opts: prometheus::Opts) -> IntGauge {
-        let g = Gauge::with_opts(opts).unwrap();
+        let g = Gauge::with_opts(self.opts(opts)).unwrap();
         self.reg.register(Box::new(g.clone())).unwrap();
         g
     }
 
     pub fn histogram(&self, opts: prometheus::HistogramOpts) -> Histogram {
-        let h = Histogram::with_opts(opts).unwrap();
+        let h = Histogram::with_opts(self.histogram_opts(opts)).unwrap();
         self.reg.register(Box::new(h.clone())).unwrap();
         h
     }
 
     pub fn histogram_vec(&self, opts: prometheus::HistogramOpts, labels: &[&str]) -> HistogramVec {
-        let h = HistogramVec::new(opts, labels).unwrap();
+        let h = HistogramVec::new(self.histogram_opts(opts), labels).unwrap();
         self.reg.register(Box::new(h.clone())).unwrap();
         h
     }
 
     pub fn start(&self) {
+        start_process_exporter(&self);
+        let reg = self.reg.clone();
+        let token = self.token.clone();
+        if let Some(path) = &self.socket {
+            let listener = bind_unix_socket(path).unwrap_or_else(|e| {
+                panic!("failed to start monitoring server at {:?}: {}", path, e)
+            });
+            spawn_thread("metrics", move || {
+                for stream in listener.incoming() {
+                    let result = stream.and_then(|s| handle_unix_request(&reg, &token, s));
+                    if let Err(e) = result {
+                        error!("monitoring error: {}", e);
+                    }
+                }
+            });
+            return;
+        }
         let server = tiny_http::Server::http(self.addr).unwrap_or_else(|e| {
             panic!(
                 "failed to start monitoring HTTP server at {}: {}",
                 self.addr, e
             )
         });
-        start_process_exporter(&self);
-        let reg = self.reg.clone();
         spawn_thread("metrics", move || loop {
-            if let Err(e) = handle_request(&reg, server.recv()) {
+            if let Err(e) = handle_request(&reg, &token, server.recv()) {
                 error!("http error: {}", e);
             }
         });
     }
 }
 
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const STATUS_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+
+fn encode_metrics(reg: &prometheus::Registry) -> Vec<u8> {
+    let mut buffer = vec![];
+    prometheus::TextEncoder::new()
+        .encode(&reg.gather(), &mut buffer)
+        .unwrap();
+    buffer
+}
+
+/// Returns the requested page (the status page, or the metrics for any other path) and its type.
+fn render_page(reg: &prometheus::Registry, path: &str) -> (Vec<u8>, &'static str) {
+    if path == STATUS_PATH {
+        (
+            dashboard::render(&reg.gather()).into_bytes(),
+            STATUS_CONTENT_TYPE,
+        )
+    } else {
+        (encode_metrics(reg), METRICS_CONTENT_TYPE)
+    }
+}
+
+/// Checks the request's `Authorization` header value (if a token is required).
+fn is_authorized(token: &Option<String>, authorization: Option<&str>) -> bool {
+    let token = match token {
+        Some(token) => token,
+        None => return true,
+    };
+    let expected = format!("Bearer {}", token);
+    authorization.map_or(false, |value| {
+        constant_time_eq(value.as_bytes(), expected.as_bytes())
+    })
+}
+
 fn handle_request(
     reg: &prometheus::Registry,
+    token: &Option<String>,
     request: io::Result<tiny_http::Request>,
 ) -> io::Result<()> {
     let request = request?;
-    let mut buffer = vec![];
-    prometheus::TextEncoder::new()
-        .encode(&reg.gather(), &mut buffer)
-        .unwrap();
-    let response = tiny_http::Response::from_data(buffer);
-    request.respond(response)
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.as_str());
+    if !is_authorized(token, authorization) {
+        let response = tiny_http::Response::from_string("unauthorized").with_status_code(401);
+        return request.respond(response);
+    }
+    let (body, content_type) = render_page(reg, request.url());
+    let header = tiny_http::Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .expect("invalid Content-Type header");
+    request.respond(tiny_http::Response::from_data(body).with_header(header))
+}
+
+fn bind_unix_socket(path: &Path) -> Result<UnixListener> {
+    if path.exists() {
+        // remove a stale socket (e.g. left by a previous run)
+        fs::remove_file(path).chain_err(|| format!("failed to remove {:?}", path))?;
+    }
+    let listener = UnixListener::bind(path).chain_err(|| format!("failed to bind {:?}", path))?;
+    info!("serving monitoring on {:?}", path);
+    Ok(listener)
+}
+
+// A minimal HTTP/1.x server (since `tiny_http` doesn't support unix sockets).
+fn handle_unix_request(
+    reg: &prometheus::Registry,
+    token: &Option<String>,
+    mut stream: UnixStream,
+) -> io::Result<()> {
+    let mut authorization = None;
+    let mut path = None;
+    for line in BufReader::new(stream.try_clone()?).lines() {
+        let line = line?;
+        if path.is_none() {
+            // the request line, e.g. "GET /status HTTP/1.1"
+            path = Some(line.split_whitespace().nth(1).unwrap_or("/").to_owned());
+            continue;
+        }
+        if line.is_empty() {
+            break; // end of request headers
+        }
+        let mut parts = line.splitn(2, ':');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            if name.eq_ignore_ascii_case("Authorization") {
+                authorization = Some(value.trim().to_owned());
+            }
+        }
+    }
+    let (status, (body, content_type)) = if is_authorized(token, authorization.as_deref()) {
+        ("200 OK", render_page(reg, path.as_deref().unwrap_or("/")))
+    } else {
+        (
+            "401 Unauthorized",
+            (b"unauthorized".to_vec(), METRICS_CONTENT_TYPE),
+        )
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)
 }
 
 struct Stats {
@@ -160,3 +314,33 @@ fn start_process_exporter(metrics: &Metrics) {
         thread::sleep(Duration::from_secs(5));
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_metrics, is_authorized, MetricOpts, Metrics};
+
+    #[test]
+    fn test_authorization() {
+        assert!(is_authorized(&None, None));
+        assert!(is_authorized(&None, Some("Bearer xyz")));
+
+        let token = Some("secret".to_owned());
+        assert!(is_authorized(&token, Some("Bearer secret")));
+        assert!(!is_authorized(&token, None));
+        assert!(!is_authorized(&token, Some("Bearer secreT")));
+        assert!(!is_authorized(&token, Some("Bearer secret2")));
+        assert!(!is_authorized(&token, Some("Basic secret")));
+    }
+
+    #[test]
+    fn test_network_metrics() {
+        let metrics = Metrics::new("127.0.0.1:60000".parse().unwrap());
+        let opts = || MetricOpts::new("electrs_test", "help");
+        metrics.with_network("bitcoin").counter(opts()).inc();
+        metrics.with_network("testnet").counter(opts()).inc_by(2);
+
+        let text = String::from_utf8(encode_metrics(&metrics.reg)).unwrap();
+        assert!(text.contains("electrs_test{network=\"bitcoin\"} 1"));
+        assert!(text.contains("electrs_test{network=\"testnet\"} 2"));
+    }
+}
@@ -0,0 +1,292 @@
+//! Binary (MessagePack) framing of the Electrum RPC: each message is a JSON-RPC object, encoded
+//! as MessagePack and prefixed by its length (as a 4-byte big-endian integer).
+//!
+//! A connection uses this framing if its first byte is zero (i.e. the first byte of a request
+//! frame's length, since requests are limited to `MAX_REQUEST_LEN`), since JSON requests can't
+//! start with a zero byte.
+use serde_json::{Map, Number, Value};
+use std::io::{self, Read};
+
+use crate::errors::*;
+
+pub const MAX_REQUEST_LEN: usize = (1 << 24) - 1;
+
+/// Returns true if the connection's first bytes start a binary request frame.
+pub fn is_binary(first_bytes: &[u8]) -> bool {
+    first_bytes.first() == Some(&0)
+}
+
+/// Reads a request frame (returning `None` if the connection was closed before it).
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).chain_err(|| "failed to read frame length"),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_REQUEST_LEN {
+        bail!("request frame too large: {} bytes", len);
+    }
+    let mut frame = vec![0u8; len];
+    reader
+        .read_exact(&mut frame)
+        .chain_err(|| format!("failed to read {} bytes frame", len))?;
+    Ok(Some(frame))
+}
+
+/// Appends `value`'s frame to `buffer`.
+pub fn write_frame(buffer: &mut Vec<u8>, value: &Value) {
+    let start = buffer.len();
+    buffer.extend_from_slice(&[0; 4]);
+    encode(buffer, value);
+    let len = (buffer.len() - start - 4) as u32;
+    buffer[start..start + 4].copy_from_slice(&len.to_be_bytes());
+}
+
+// Encodes an array's or a map's length, using its "fix" (single byte), 16-bit or 32-bit format.
+fn encode_len(buffer: &mut Vec<u8>, len: usize, fix: u8, code16: u8) {
+    if len <= 0x0f {
+        buffer.push(fix | len as u8);
+    } else if len <= 0xffff {
+        buffer.push(code16);
+        buffer.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buffer.push(code16 + 1);
+        buffer.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn encode_number(buffer: &mut Vec<u8>, n: &Number) {
+    if let Some(n) = n.as_u64() {
+        if n < 0x80 {
+            buffer.push(n as u8);
+        } else if n <= 0xff {
+            buffer.extend_from_slice(&[0xcc, n as u8]);
+        } else if n <= 0xffff {
+            buffer.push(0xcd);
+            buffer.extend_from_slice(&(n as u16).to_be_bytes());
+        } else if n <= 0xffff_ffff {
+            buffer.push(0xce);
+            buffer.extend_from_slice(&(n as u32).to_be_bytes());
+        } else {
+            buffer.push(0xcf);
+            buffer.extend_from_slice(&n.to_be_bytes());
+        }
+    } else if let Some(n) = n.as_i64() {
+        // negative (non-negative numbers are handled above)
+        if n >= -32 {
+            buffer.push(n as i8 as u8);
+        } else if n >= i64::from(i8::min_value()) {
+            buffer.extend_from_slice(&[0xd0, n as i8 as u8]);
+        } else if n >= i64::from(i16::min_value()) {
+            buffer.push(0xd1);
+            buffer.extend_from_slice(&(n as i16).to_be_bytes());
+        } else if n >= i64::from(i32::min_value()) {
+            buffer.push(0xd2);
+            buffer.extend_from_slice(&(n as i32).to_be_bytes());
+        } else {
+            buffer.push(0xd3);
+            buffer.extend_from_slice(&n.to_be_bytes());
+        }
+    } else {
+        buffer.push(0xcb);
+        buffer.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_bits().to_be_bytes());
+    }
+}
+
+fn encode_str(buffer: &mut Vec<u8>, s: &str) {
+    let len = s.len();
+    if len < 0x20 {
+        buffer.push(0xa0 | len as u8);
+    } else if len <= 0xff {
+        buffer.extend_from_slice(&[0xd9, len as u8]);
+    } else if len <= 0xffff {
+        buffer.push(0xda);
+        buffer.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buffer.push(0xdb);
+        buffer.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+    buffer.extend_from_slice(s.as_bytes());
+}
+
+pub fn encode(buffer: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buffer.push(0xc0),
+        Value::Bool(false) => buffer.push(0xc2),
+        Value::Bool(true) => buffer.push(0xc3),
+        Value::Number(n) => encode_number(buffer, n),
+        Value::String(s) => encode_str(buffer, s),
+        Value::Array(items) => {
+            encode_len(buffer, items.len(), 0x90, 0xdc);
+            for item in items {
+                encode(buffer, item);
+            }
+        }
+        Value::Object(map) => {
+            encode_len(buffer, map.len(), 0x80, 0xde);
+            for (key, item) in map {
+                encode_str(buffer, key);
+                encode(buffer, item);
+            }
+        }
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    depth: usize,
+}
+
+const MAX_DEPTH: usize = 64;
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            bail!("truncated MessagePack value");
+        }
+        let (result, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(result)
+    }
+
+    fn uint(&mut self, size: usize) -> Result<u64> {
+        Ok(self
+            .take(size)?
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | u64::from(*b)))
+    }
+
+    // Sign-extends a big-endian integer of the given size.
+    fn int(&mut self, size: usize) -> Result<i64> {
+        let shift = 64 - 8 * size as u32;
+        Ok(((self.uint(size)? << shift) as i64) >> shift)
+    }
+
+    fn string(&mut self, len: usize) -> Result<String> {
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).chain_err(|| "invalid UTF8 string")
+    }
+
+    fn array(&mut self, len: usize) -> Result<Value> {
+        let mut items = Vec::with_capacity(len.min(self.data.len()));
+        for _ in 0..len {
+            items.push(self.value()?);
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn map(&mut self, len: usize) -> Result<Value> {
+        let mut map = Map::new();
+        for _ in 0..len {
+            let key = match self.value()? {
+                Value::String(key) => key,
+                key => bail!("non-string map key: {}", key),
+            };
+            map.insert(key, self.value()?);
+        }
+        Ok(Value::Object(map))
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        if self.depth >= MAX_DEPTH {
+            bail!("MessagePack value is nested too deeply");
+        }
+        self.depth += 1;
+        let code = self.take(1)?[0];
+        let value = match code {
+            0x00..=0x7f => json!(code),
+            0x80..=0x8f => self.map(usize::from(code & 0x0f))?,
+            0x90..=0x9f => self.array(usize::from(code & 0x0f))?,
+            0xa0..=0xbf => Value::String(self.string(usize::from(code & 0x1f))?),
+            0xc0 => Value::Null,
+            0xc2 => Value::Bool(false),
+            0xc3 => Value::Bool(true),
+            // binary data is passed as a hex string (e.g. a raw transaction)
+            0xc4..=0xc6 => {
+                let len = self.uint(1 << (code - 0xc4))? as usize;
+                Value::String(hex::encode(self.take(len)?))
+            }
+            0xca => json!(f32::from_bits(self.uint(4)? as u32)),
+            0xcb => json!(f64::from_bits(self.uint(8)?)),
+            0xcc..=0xcf => json!(self.uint(1 << (code - 0xcc))?),
+            0xd0..=0xd3 => json!(self.int(1 << (code - 0xd0))?),
+            0xd9..=0xdb => {
+                let len = self.uint(1 << (code - 0xd9))? as usize;
+                Value::String(self.string(len)?)
+            }
+            0xdc | 0xdd => {
+                let len = self.uint(2 << (code - 0xdc))? as usize;
+                self.array(len)?
+            }
+            0xde | 0xdf => {
+                let len = self.uint(2 << (code - 0xde))? as usize;
+                self.map(len)?
+            }
+            0xe0..=0xff => json!(code as i8),
+            _ => bail!("unsupported MessagePack type: {:#x}", code),
+        };
+        self.depth -= 1;
+        Ok(value)
+    }
+}
+
+pub fn decode(data: &[u8]) -> Result<Value> {
+    let mut decoder = Decoder { data, depth: 0 };
+    let value = decoder.value()?;
+    if !decoder.data.is_empty() {
+        bail!("{} trailing bytes", decoder.data.len());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, is_binary, read_frame, write_frame};
+
+    fn encoded(value: &serde_json::Value) -> Vec<u8> {
+        let mut buffer = vec![];
+        encode(&mut buffer, value);
+        buffer
+    }
+
+    #[test]
+    fn test_msgpack() {
+        assert_eq!(encoded(&json!(null)), [0xc0]);
+        assert_eq!(encoded(&json!([true, 1, -1])), [0x93, 0xc3, 0x01, 0xff]);
+        assert_eq!(encoded(&json!(300)), [0xcd, 0x01, 0x2c]);
+        assert_eq!(encoded(&json!(-200)), [0xd1, 0xff, 0x38]);
+        assert_eq!(encoded(&json!({"id": "a"})), b"\x81\xa2id\xa1a");
+        assert_eq!(decode(&[0xc4, 0x02, 0xab, 0xcd]).unwrap(), json!("abcd"));
+
+        let value = json!({
+            "jsonrpc": "2.0",
+            "id": 4294967296u64,
+            "method": "blockchain.scripthash.get_history",
+            "params": ["x".repeat(40), -100000, 0.5, [], {}, "y".repeat(300)],
+        });
+        assert_eq!(decode(&encoded(&value)).unwrap(), value);
+
+        assert!(decode(&[0x92, 0x01]).is_err()); // truncated
+        assert!(decode(&[0x01, 0x02]).is_err()); // trailing
+        assert!(decode(&[0x81, 0x01, 0x02]).is_err()); // non-string key
+        assert!(decode(&[0x91; 100]).is_err()); // too deep
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut buffer = vec![];
+        write_frame(&mut buffer, &json!([1, 2]));
+        write_frame(&mut buffer, &json!(null));
+        assert_eq!(buffer, [0, 0, 0, 3, 0x92, 1, 2, 0, 0, 0, 1, 0xc0]);
+        assert!(is_binary(&buffer));
+        assert!(!is_binary(b"{\"id\": 0}"));
+
+        let mut reader = &buffer[..];
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), [0x92, 1, 2]);
+        assert_eq!(read_frame(&mut reader).unwrap().unwrap(), [0xc0]);
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+        assert!(read_frame(&mut &[0xff, 0, 0, 0][..]).is_err()); // too large
+    }
+}
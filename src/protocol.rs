@@ -0,0 +1,210 @@
+//! Electrum protocol versions, and the version-specific response formats.
+use bitcoin::hashes::hex::ToHex;
+use serde_json::Value;
+use std::fmt;
+
+use crate::chain::serialize;
+use crate::errors::*;
+use crate::util::HeaderEntry;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ProtocolVersion {
+    major: u32,
+    minor: u32,
+}
+
+impl ProtocolVersion {
+    pub const fn new(major: u32, minor: u32) -> ProtocolVersion {
+        ProtocolVersion { major, minor }
+    }
+
+    /// Parses a "major.minor" version string (a patch number, if given, is ignored).
+    pub fn parse(version: &str) -> Result<ProtocolVersion> {
+        let mut parts = version.split('.').map(str::parse::<u32>);
+        match (parts.next(), parts.next()) {
+            (Some(Ok(major)), Some(Ok(minor))) => Ok(ProtocolVersion { major, minor }),
+            (Some(Ok(major)), None) => Ok(ProtocolVersion { major, minor: 0 }),
+            _ => bail!("invalid protocol version: {:?}", version),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+pub const MIN_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 2);
+pub const MAX_PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
+
+/// Returns the highest protocol version supported by both the server and the client, given the
+/// client's version (e.g. `"1.4"`) or its `["min", "max"]` range (as sent via `server.version`).
+/// Clients which don't send a version get the latest one (as `Connection::new` assumes).
+pub fn negotiate(requested: Option<&Value>) -> Result<ProtocolVersion> {
+    let (min, max) = match requested {
+        None | Some(Value::Null) => (MAX_PROTOCOL_VERSION, MAX_PROTOCOL_VERSION),
+        Some(Value::String(version)) => {
+            let version = ProtocolVersion::parse(version)?;
+            (version, version)
+        }
+        Some(Value::Array(range)) if range.len() == 2 => {
+            let parse = |value: &Value| {
+                value
+                    .as_str()
+                    .chain_err(|| format!("invalid protocol version: {}", value))
+                    .and_then(ProtocolVersion::parse)
+            };
+            (parse(&range[0])?, parse(&range[1])?)
+        }
+        Some(value) => bail!("invalid protocol version: {}", value),
+    };
+    let version = max.min(MAX_PROTOCOL_VERSION);
+    if version < min.max(MIN_PROTOCOL_VERSION) {
+        bail!(
+            "unsupported protocol version {}-{} (server supports {}-{})",
+            min,
+            max,
+            MIN_PROTOCOL_VERSION,
+            MAX_PROTOCOL_VERSION
+        );
+    }
+    Ok(version)
+}
+
+/// Before protocol 1.3, headers were sent as dictionaries (instead of hex strings) by default.
+const RAW_HEADERS_VERSION: ProtocolVersion = ProtocolVersion::new(1, 3);
+
+/// Returns whether headers should be sent as hex strings, given the negotiated protocol version
+/// and the optional `raw` argument of `blockchain.headers.subscribe` (which older protocol
+/// versions' clients may set, and is ignored since protocol 1.3).
+pub fn raw_headers(version: ProtocolVersion, raw: Option<&Value>) -> Result<bool> {
+    if version >= RAW_HEADERS_VERSION {
+        return Ok(true);
+    }
+    match raw {
+        None | Some(Value::Null) => Ok(false),
+        Some(Value::Bool(raw)) => Ok(*raw),
+        Some(value) => bail!("invalid raw: {}", value),
+    }
+}
+
+/// Returns a header (for `blockchain.headers.subscribe` and its notifications), as a hex string
+/// (and its height) if `raw`, or as a dictionary otherwise.
+pub fn header_json(entry: &HeaderEntry, raw: bool) -> Value {
+    if !raw {
+        return legacy_header_json(entry);
+    }
+    let hex_header = hex::encode(serialize(entry.header()));
+    json!({"hex": hex_header, "height": entry.height()})
+}
+
+#[cfg(not(feature = "liquid"))]
+fn legacy_header_json(entry: &HeaderEntry) -> Value {
+    let header = entry.header();
+    json!({
+        "block_height": entry.height(),
+        "version": header.version,
+        "prev_block_hash": header.prev_blockhash.to_hex(),
+        "merkle_root": header.merkle_root.to_hex(),
+        "timestamp": header.time,
+        "bits": header.bits,
+        "nonce": header.nonce,
+    })
+}
+
+// Elements headers have no proof-of-work fields.
+#[cfg(feature = "liquid")]
+fn legacy_header_json(entry: &HeaderEntry) -> Value {
+    let header = entry.header();
+    json!({
+        "block_height": entry.height(),
+        "version": header.version,
+        "prev_block_hash": header.prev_blockhash.to_hex(),
+        "merkle_root": header.merkle_root.to_hex(),
+        "timestamp": header.time,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        header_json, negotiate, raw_headers, ProtocolVersion, MAX_PROTOCOL_VERSION,
+        MIN_PROTOCOL_VERSION,
+    };
+    use crate::util::HeaderList;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    #[test]
+    fn test_protocol_version() {
+        let v1_4 = ProtocolVersion::parse("1.4").unwrap();
+        assert_eq!(v1_4, MAX_PROTOCOL_VERSION);
+        assert_eq!(v1_4.to_string(), "1.4");
+        assert_eq!(ProtocolVersion::parse("1.4.2").unwrap(), v1_4);
+        assert_eq!(ProtocolVersion::parse("1").unwrap().to_string(), "1.0");
+        assert!(ProtocolVersion::parse("1.10").unwrap() > v1_4);
+        assert!(ProtocolVersion::parse("1.2").unwrap() < v1_4);
+        assert!(ProtocolVersion::parse("").is_err());
+        assert!(ProtocolVersion::parse("1.x").is_err());
+        assert!(MIN_PROTOCOL_VERSION < MAX_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let negotiated = |requested: serde_json::Value| {
+            negotiate(Some(&requested)).map(|version| version.to_string())
+        };
+        assert_eq!(negotiated(json!("1.4")).unwrap(), "1.4");
+        assert_eq!(negotiated(json!("1.2")).unwrap(), "1.2");
+        assert_eq!(negotiated(json!(["1.1", "1.4"])).unwrap(), "1.4");
+        assert_eq!(negotiated(json!(["1.2", "1.3"])).unwrap(), "1.3");
+        assert_eq!(negotiated(json!(["1.4", "2.0"])).unwrap(), "1.4");
+        assert_eq!(negotiate(None).unwrap().to_string(), "1.4");
+        assert_eq!(negotiate(Some(&json!(null))).unwrap().to_string(), "1.4");
+        assert!(negotiated(json!("1.5")).is_err());
+        assert!(negotiated(json!(["1.0", "1.1"])).is_err());
+        assert!(negotiated(json!(["1.5", "2.0"])).is_err());
+        assert!(negotiated(json!(["1.4", "1.2"])).is_err()); // empty range
+        assert!(negotiated(json!(["1.4"])).is_err());
+        assert!(negotiated(json!(14)).is_err());
+    }
+
+    #[test]
+    fn test_header_json() {
+        let genesis = genesis_block(Network::Bitcoin).header;
+        let mut headers = HeaderList::empty();
+        let entries = headers.order(vec![genesis]);
+        headers.apply(entries, genesis.block_hash());
+        let entry = headers.header_by_height(0).unwrap();
+
+        let raw = header_json(entry, true);
+        assert_eq!(raw["height"], 0);
+        assert_eq!(raw["hex"].as_str().unwrap().len(), 160);
+
+        let legacy = header_json(entry, false);
+        assert_eq!(
+            legacy,
+            json!({
+                "block_height": 0,
+                "version": 1,
+                "prev_block_hash": "0000000000000000000000000000000000000000000000000000000000000000",
+                "merkle_root": "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+                "timestamp": 1231006505,
+                "bits": 486604799,
+                "nonce": 2083236893,
+            })
+        );
+    }
+
+    #[test]
+    fn test_raw_headers() {
+        let v1_3 = ProtocolVersion::new(1, 3);
+        assert!(!raw_headers(MIN_PROTOCOL_VERSION, None).unwrap());
+        assert!(!raw_headers(MIN_PROTOCOL_VERSION, Some(&json!(false))).unwrap());
+        assert!(raw_headers(MIN_PROTOCOL_VERSION, Some(&json!(true))).unwrap());
+        assert!(raw_headers(MIN_PROTOCOL_VERSION, Some(&json!("yes"))).is_err());
+        assert!(raw_headers(v1_3, None).unwrap());
+        assert!(raw_headers(v1_3, Some(&json!(false))).unwrap()); // ignored
+    }
+}
@@ -0,0 +1,207 @@
+//! Parses the PROXY protocol header (v1 or v2, see
+//! https://www.haproxy.org/download/2.4/doc/proxy-protocol.txt), which is sent by a load balancer
+//! (e.g. HAProxy) before its client's data, for recovering the client's address.
+//! The headers are accepted only from the trusted proxies (see `proxy_protocol_trusted`).
+
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::time::Duration;
+
+use crate::errors::*;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107; // including the trailing CRLF
+const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+/// The headers read concurrently (each by its own thread), which are few, since a load balancer
+/// sends the header as soon as it connects.
+pub const MAX_PENDING_HEADERS: usize = 100;
+
+/// Returns whether a connection from `ip` comes from one of the trusted proxies (an IPv4-mapped
+/// address, e.g. accepted by a dual-stack listener, matches its IPv4 address).
+pub fn is_trusted(trusted: &[IpAddr], ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) if v6.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => {
+            let octets = v6.octets();
+            IpAddr::V4(Ipv4Addr::new(
+                octets[12], octets[13], octets[14], octets[15],
+            ))
+        }
+        ip => ip,
+    };
+    trusted.contains(&ip)
+}
+
+/// Reads the header of an accepted connection, returning its client's address (or `None` for the
+/// proxy's own connections, e.g. health checks).
+pub fn read_client_addr(stream: &TcpStream) -> Result<Option<SocketAddr>> {
+    stream
+        .set_read_timeout(Some(HEADER_TIMEOUT))
+        .chain_err(|| "failed to set timeout")?;
+    let mut reader = stream;
+    let addr = read_header(&mut reader)?;
+    stream
+        .set_read_timeout(None)
+        .chain_err(|| "failed to reset timeout")?;
+    Ok(addr)
+}
+
+// Reads only the header's bytes (so the client's data can be read afterwards).
+fn read_header<R: Read>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    // a v1 header is longer than the v2 signature
+    let mut prefix = [0u8; 12];
+    reader
+        .read_exact(&mut prefix)
+        .chain_err(|| "failed to read PROXY header")?;
+    if &prefix == V2_SIGNATURE {
+        return read_v2(reader);
+    }
+    if !prefix.starts_with(b"PROXY ") {
+        bail!("missing PROXY header");
+    }
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            bail!("PROXY header is too long");
+        }
+        let mut byte = [0u8; 1];
+        reader
+            .read_exact(&mut byte)
+            .chain_err(|| "failed to read PROXY header")?;
+        line.push(byte[0]);
+    }
+    parse_v1(&line[..line.len() - 2])
+}
+
+// e.g. "PROXY TCP4 192.168.0.1 192.168.0.11 56324 50001"
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).chain_err(|| "non-ASCII PROXY header")?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields.get(1) == Some(&"UNKNOWN") {
+        return Ok(None);
+    }
+    match fields[..] {
+        ["PROXY", "TCP4", ip, _, port, _] | ["PROXY", "TCP6", ip, _, port, _] => {
+            let ip: IpAddr = ip
+                .parse()
+                .chain_err(|| format!("invalid PROXY address: {}", ip))?;
+            let port: u16 = port
+                .parse()
+                .chain_err(|| format!("invalid PROXY port: {}", port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => bail!("invalid PROXY header: {:?}", line),
+    }
+}
+
+fn read_v2<R: Read>(reader: &mut R) -> Result<Option<SocketAddr>> {
+    let mut header = [0u8; 4]; // version and command, family and transport, length
+    reader
+        .read_exact(&mut header)
+        .chain_err(|| "failed to read PROXY header")?;
+    let mut addrs = vec![0u8; u16::from_be_bytes([header[2], header[3]]) as usize];
+    reader
+        .read_exact(&mut addrs)
+        .chain_err(|| "failed to read PROXY addresses")?;
+    let version = header[0] >> 4;
+    if version != 2 {
+        bail!("unsupported PROXY version {}", version);
+    }
+    // the source address and port are followed by the destination's
+    match (header[0] & 0xf, header[1] >> 4) {
+        (0, _) => Ok(None), // LOCAL
+        (1, 1) if addrs.len() >= 12 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&addrs[..4]);
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port)))
+        }
+        (1, 2) if addrs.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&addrs[..16]);
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port)))
+        }
+        (1, 0) | (1, 3) => Ok(None), // unspecified or unix socket addresses
+        (command, family) => bail!("invalid PROXY command {} (family {})", command, family),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_trusted, read_header, V2_SIGNATURE};
+    use std::io::Read;
+    use std::net::{IpAddr, SocketAddr};
+
+    fn read(header: &[u8]) -> Option<SocketAddr> {
+        let mut data = header.to_vec();
+        data.extend(b"payload");
+        let mut reader = &data[..];
+        let addr = read_header(&mut reader).unwrap();
+        let mut payload = vec![];
+        reader.read_to_end(&mut payload).unwrap();
+        assert_eq!(payload, b"payload"); // only the header is read
+        addr
+    }
+
+    fn v2(command: u8, family: u8, addrs: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend(&[0x20 | command, family << 4 | 1]);
+        header.extend(&(addrs.len() as u16).to_be_bytes());
+        header.extend(addrs);
+        header
+    }
+
+    #[test]
+    fn test_trusted() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let trusted = [ip("10.0.0.2"), ip("fd00::2")];
+        assert!(is_trusted(&trusted, ip("10.0.0.2")));
+        assert!(is_trusted(&trusted, ip("::ffff:10.0.0.2")));
+        assert!(is_trusted(&trusted, ip("fd00::2")));
+        assert!(!is_trusted(&trusted, ip("10.0.0.3")));
+        assert!(!is_trusted(&trusted, ip("::a00:2"))); // IPv4-compatible, not mapped
+        assert!(!is_trusted(&[], ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn test_v1() {
+        let addr = read(b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 50001\r\n");
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+        let addr = read(b"PROXY TCP6 2001:db8::1 ::1 56324 50001\r\n");
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(read(b"PROXY UNKNOWN\r\n"), None);
+        assert_eq!(read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n"), None);
+
+        let mut reader = &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324\r\n"[..];
+        assert!(read_header(&mut reader).is_err());
+        let mut reader = &b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 50001"[..];
+        assert!(read_header(&mut reader).is_err()); // no CRLF
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(100));
+        assert!(read_header(&mut long.as_bytes()).is_err());
+        let mut reader = &b"{\"method\": \"server.ping\"}\n"[..];
+        assert!(read_header(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_v2() {
+        let ipv4 = [192, 168, 0, 1, 192, 168, 0, 11, 0xdc, 0x04, 0xc3, 0x51];
+        let addr = read(&v2(1, 1, &ipv4));
+        assert_eq!(addr, Some("192.168.0.1:56324".parse().unwrap()));
+
+        let mut ipv6 = vec![0x20, 0x01, 0x0d, 0xb8];
+        ipv6.extend(&[0; 11]);
+        ipv6.push(1);
+        ipv6.extend(&[0; 16]);
+        ipv6.extend(&[0xdc, 0x04, 0xc3, 0x51]);
+        let addr = read(&v2(1, 2, &ipv6));
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        assert_eq!(read(&v2(0, 0, &[])), None); // LOCAL (e.g. a health check)
+        assert_eq!(read(&v2(1, 0, &[])), None);
+
+        let mut header = v2(1, 1, &ipv4[..8]); // truncated addresses
+        assert!(read_header(&mut &header[..]).is_err());
+        header = v2(2, 1, &ipv4);
+        assert!(read_header(&mut &header[..]).is_err());
+    }
+}
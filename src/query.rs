@@ -1,22 +1,37 @@
-use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::consensus::encode::deserialize;
-use bitcoin::hash_types::{BlockHash, TxMerkleNode, Txid};
 use bitcoin::hashes::hex::ToHex;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use bitcoin::hashes::Hash;
 use serde_json::Value;
 use sha2::{Digest, Sha256};
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
 
 use crate::app::App;
-use crate::cache::TransactionCache;
+use crate::cache::{BlockCache, HeaderChunkCache, MerkleCache, TransactionCache};
+use crate::chain::{
+    deserialize, is_coinbase, output_value, serialize, BlockHash, Transaction, TxMerkleNode, Txid,
+};
+use crate::daemon::Daemon;
+use crate::decode;
 use crate::errors::*;
-use crate::index::{compute_script_hash, TxInRow, TxOutRow, TxRow};
-use crate::mempool::Tracker;
+use crate::index::{compute_script_hash, StatusRow, TxInRow, TxOutRow, TxRow, TxSearchRow};
+use crate::mempool::{NewTransaction, Replaceability, Tracker};
 use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
-use crate::store::{ReadStore, Row};
-use crate::util::{FullHash, HashPrefix, HeaderEntry};
+use crate::raw_block::{block_transactions, block_txids};
+use crate::redact::redacted_hashes;
+use crate::store::{ReadStore, Row, WriteStore};
+use crate::util::{full_hash, FullHash, HashPrefix, HeaderEntry, WorkerPool, HASH_PREFIX_LEN};
+
+const MAX_SEARCH_RESULTS: usize = 100;
+pub const HEADERS_CHUNK_SIZE: usize = 2016;
+// bitcoind's `estimatesmartfee` results are cached (until the next block), so fee queries by
+// many clients don't translate into RPCs
+const FEE_ESTIMATE_TTL: Duration = Duration::from_secs(60);
+const MAX_FEE_ESTIMATE_BLOCKS: usize = 1008; // bitcoind's maximal confirmation target
+const MAX_BLOCK_SIZE: usize = 4_000_000; // in bytes (as limited by the block weight)
 
 pub struct FundingOutput {
     pub txn_id: Txid,
@@ -38,6 +53,8 @@ pub struct Status {
     confirmed: (Vec<FundingOutput>, Vec<SpendingInput>),
     mempool: (Vec<FundingOutput>, Vec<SpendingInput>),
     txn_fees: HashMap<Txid, u64>,
+    txn_first_seen: HashMap<Txid, u64>, // of the mempool transactions (as unix time)
+    positions: HashMap<Txid, usize>, // in their blocks (if several are confirmed in the same one)
 }
 
 fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>)) -> i64 {
@@ -46,25 +63,83 @@ fn calc_balance((funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>))
     funded as i64 - spent as i64
 }
 
+/// Aggregate statistics of a script hash's unspent outputs.
+pub struct UtxoSummary {
+    pub count: usize,
+    pub total_value: u64,
+    pub largest: Option<u64>,
+    pub smallest: Option<u64>,
+    pub oldest_height: Option<u32>, // of the confirmed outputs
+}
+
 pub struct HistoryItem {
     height: i32,
     tx_hash: Txid,
     fee: Option<u64>, // need to be set only for unconfirmed transactions (i.e. height <= 0)
+    first_seen: Option<u64>, // set only for unconfirmed transactions
 }
 
 impl HistoryItem {
-    pub fn to_json(&self) -> Value {
-        let mut result = json!({ "height": self.height, "tx_hash": self.tx_hash.to_hex()});
-        self.fee.map(|f| {
-            result
-                .as_object_mut()
-                .unwrap()
-                .insert("fee".to_string(), json!(f))
-        });
-        result
+    pub fn height(&self) -> i32 {
+        self.height
+    }
+
+    pub fn tx_hash(&self) -> &Txid {
+        &self.tx_hash
+    }
+
+    pub fn fee(&self) -> Option<u64> {
+        self.fee
+    }
+
+    pub fn first_seen(&self) -> Option<u64> {
+        self.first_seen
+    }
+
+    /// Appends this item's JSON object to `out` (histories may be large, so they are serialized
+    /// without building a `Value` for each item).
+    pub fn write_json(&self, out: &mut String) {
+        out.push('{');
+        if let Some(fee) = self.fee {
+            write!(out, "\"fee\":{},", fee).unwrap();
+        }
+        if let Some(first_seen) = self.first_seen {
+            write!(out, "\"first_seen\":{},", first_seen).unwrap();
+        }
+        write!(
+            out,
+            "\"height\":{},\"tx_hash\":\"{}\"}}",
+            self.height,
+            self.tx_hash.to_hex()
+        )
+        .unwrap();
     }
 }
 
+fn unspent_outputs<'a>(
+    funding: impl Iterator<Item = &'a FundingOutput>,
+    spending: impl Iterator<Item = &'a SpendingInput>,
+) -> Vec<&'a FundingOutput> {
+    let mut outputs_map = HashMap::<OutPoint, &FundingOutput>::new();
+    for f in funding {
+        outputs_map.insert((f.txn_id, f.output_index), f);
+    }
+    for s in spending {
+        if outputs_map.remove(&s.funding_output).is_none() {
+            warn!(
+                "failed to remove {}",
+                redacted_hashes(format!("{:?}", s.funding_output))
+            );
+        }
+    }
+    let mut outputs = outputs_map
+        .into_iter()
+        .map(|item| item.1) // a reference to unspent output
+        .collect::<Vec<&FundingOutput>>();
+    outputs.sort_unstable_by_key(|out| out.height);
+    outputs
+}
+
 impl Status {
     fn funding(&self) -> impl Iterator<Item = &FundingOutput> {
         self.confirmed.0.iter().chain(self.mempool.0.iter())
@@ -96,28 +171,78 @@ impl Status {
                 height: item.1,
                 tx_hash: item.0,
                 fee: self.txn_fees.get(&item.0).cloned(),
+                first_seen: self.txn_first_seen.get(&item.0).cloned(),
             })
             .collect();
-        items.sort_unstable_by_key(|item| item.height);
+        // confirmed transactions (by height, and then by their position in the block, as other
+        // servers do) are followed by the mempool ones, so new blocks only append to the confirmed
+        // history (see `StatusHasher`)
+        items.sort_unstable_by_key(|item| {
+            let position = self.positions.get(&item.tx_hash).cloned().unwrap_or(0);
+            (item.height <= 0, item.height, position, item.tx_hash)
+        });
         items
     }
 
-    pub fn unspent(&self) -> Vec<&FundingOutput> {
-        let mut outputs_map = HashMap::<OutPoint, &FundingOutput>::new();
+    /// Returns a page of the history: the items confirmed at `from_height` or above (followed by
+    /// the unconfirmed ones), stopping after the block where `limit` items are reached (0 -
+    /// unlimited). Since a page never splits a block, the next one starts above its last height,
+    /// and the history is complete once a page has fewer than `limit` items (or ends with an
+    /// unconfirmed one, since these are returned together).
+    pub fn history_page(&self, from_height: u32, limit: usize) -> Vec<HistoryItem> {
+        let mut items: Vec<HistoryItem> = self
+            .history()
+            .into_iter()
+            .filter(|item| item.height <= 0 || item.height as u32 >= from_height)
+            .collect();
+        if limit > 0 && items.len() > limit {
+            let last_height = items[limit - 1].height;
+            if last_height > 0 {
+                let end = items[limit..]
+                    .iter()
+                    .position(|item| item.height != last_height)
+                    .map_or(items.len(), |pos| limit + pos);
+                items.truncate(end);
+            } // else the unconfirmed items are returned together
+        }
+        items
+    }
+
+    /// Returns the balance change (in satoshis) caused by each transaction in the history.
+    pub fn value_deltas(&self) -> HashMap<Txid, i64> {
+        let mut deltas = HashMap::<Txid, i64>::new();
         for f in self.funding() {
-            outputs_map.insert((f.txn_id, f.output_index), f);
+            *deltas.entry(f.txn_id).or_insert(0) += f.value as i64;
         }
         for s in self.spending() {
-            if outputs_map.remove(&s.funding_output).is_none() {
-                warn!("failed to remove {:?}", s.funding_output);
-            }
+            *deltas.entry(s.txn_id).or_insert(0) -= s.value as i64;
+        }
+        deltas
+    }
+
+    pub fn unspent(&self) -> Vec<&FundingOutput> {
+        unspent_outputs(self.funding(), self.spending())
+    }
+
+    /// Returns the outputs which are unspent by the confirmed transactions (ignoring the mempool).
+    pub fn confirmed_unspent(&self) -> Vec<&FundingOutput> {
+        unspent_outputs(self.confirmed.0.iter(), self.confirmed.1.iter())
+    }
+
+    pub fn utxo_summary(&self) -> UtxoSummary {
+        let unspent = self.unspent();
+        let values = unspent.iter().map(|out| out.value);
+        UtxoSummary {
+            count: unspent.len(),
+            total_value: values.clone().sum(),
+            largest: values.clone().max(),
+            smallest: values.min(),
+            oldest_height: unspent
+                .iter()
+                .map(|out| out.height)
+                .filter(|&height| height > 0) // mempool outputs have height 0
+                .min(),
         }
-        let mut outputs = outputs_map
-            .into_iter()
-            .map(|item| item.1) // a reference to unspent output
-            .collect::<Vec<&FundingOutput>>();
-        outputs.sort_unstable_by_key(|out| out.height);
-        outputs
     }
 
     pub fn hash(&self) -> Option<FullHash> {
@@ -126,41 +251,223 @@ impl Status {
             None
         } else {
             let mut sha2 = Sha256::new();
-            for item in txns {
-                let part = format!("{}:{}:", item.tx_hash.to_hex(), item.height);
-                sha2.update(part.as_bytes());
-            }
+            hash_history(&mut sha2, &txns);
             Some(sha2.finalize().into())
         }
     }
 }
 
+// Transactions confirmed after `height` are dropped, since they will be scanned when resuming.
+fn status_row(
+    height: u32,
+    blockhash: &BlockHash,
+    (funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>),
+) -> StatusRow {
+    StatusRow {
+        height,
+        blockhash: full_hash(&blockhash[..]),
+        funding: funding
+            .iter()
+            .filter(|output| output.height <= height)
+            .map(|output| {
+                let txid = full_hash(&output.txn_id[..]);
+                (
+                    txid,
+                    output.output_index as u32,
+                    output.height,
+                    output.value,
+                )
+            })
+            .collect(),
+        spending: spending
+            .iter()
+            .filter(|input| input.height <= height)
+            .map(|input| {
+                let (prev_txid, prev_vout) = input.funding_output;
+                (
+                    full_hash(&input.txn_id[..]),
+                    input.height,
+                    full_hash(&prev_txid[..]),
+                    prev_vout as u32,
+                    input.value,
+                )
+            })
+            .collect(),
+    }
+}
+
+// Fails if a history of `len` transactions exceeds `max_history` (0 - unlimited), so a status is
+// failed while it's scanned (instead of after computing all of it).
+fn check_history(max_history: usize, len: usize) -> Result<()> {
+    if max_history > 0 && len > max_history {
+        bail!("history too large");
+    }
+    Ok(())
+}
+
+fn status_from_row(row: StatusRow) -> (Vec<FundingOutput>, Vec<SpendingInput>) {
+    let funding = row
+        .funding
+        .into_iter()
+        .map(|(txid, vout, height, value)| FundingOutput {
+            txn_id: deserialize(&txid).unwrap(),
+            height,
+            output_index: vout as usize,
+            value,
+        })
+        .collect();
+    let spending = row
+        .spending
+        .into_iter()
+        .map(
+            |(txid, height, prev_txid, prev_vout, value)| SpendingInput {
+                txn_id: deserialize(&txid).unwrap(),
+                height,
+                funding_output: (deserialize(&prev_txid).unwrap(), prev_vout as usize),
+                value,
+            },
+        )
+        .collect();
+    (funding, spending)
+}
+
+fn hash_history(sha2: &mut Sha256, items: &[HistoryItem]) {
+    for item in items {
+        let part = format!("{}:{}:", item.tx_hash.to_hex(), item.height);
+        sha2.update(part.as_bytes());
+    }
+}
+
+/// Computes a script hash's status hash incrementally (e.g. for a subscription): the confirmed
+/// history is hashed once, and only new confirmed transactions and the mempool ones are hashed on
+/// each update (unless a reorg replaces the block of the last hashed transaction).
+#[derive(Clone, Default)]
+pub struct StatusHasher {
+    confirmed: Sha256, // state after hashing the first `confirmed_len` transactions
+    confirmed_len: usize,
+    last_block: Option<(u32, BlockHash)>, // of the last hashed transaction
+}
+
+impl StatusHasher {
+    /// Returns the status hash of `history` (sorted as `Status::history`), using `block_hash_at`
+    /// for looking up the blockhash at a given height in the best chain.
+    pub fn update<F>(&mut self, history: &[HistoryItem], block_hash_at: F) -> Option<FullHash>
+    where
+        F: Fn(u32) -> Option<BlockHash>,
+    {
+        if history.is_empty() {
+            *self = StatusHasher::default();
+            return None;
+        }
+        let confirmed_len = history.iter().take_while(|item| item.height > 0).count();
+        let valid = match self.last_block {
+            Some((height, blockhash)) => {
+                self.confirmed_len <= confirmed_len && block_hash_at(height) == Some(blockhash)
+            }
+            None => self.confirmed_len == 0,
+        };
+        if !valid {
+            *self = StatusHasher::default(); // the hashed history was reorged
+        }
+        if confirmed_len > self.confirmed_len {
+            hash_history(
+                &mut self.confirmed,
+                &history[self.confirmed_len..confirmed_len],
+            );
+            self.confirmed_len = confirmed_len;
+            // if the block is not indexed yet, the state is reset by the next update
+            let height = history[confirmed_len - 1].height as u32;
+            self.last_block = block_hash_at(height).map(|blockhash| (height, blockhash));
+        }
+        let mut sha2 = self.confirmed.clone();
+        hash_history(&mut sha2, &history[confirmed_len..]);
+        Some(sha2.finalize().into())
+    }
+}
+
 struct TxnHeight {
     txn: Transaction,
     height: u32,
 }
 
+// `T` must be a double-SHA256 hash (e.g. `TxMerkleNode`).
+// `sha2` is used (instead of `bitcoin::hashes`), since it supports hardware acceleration (SHA-NI).
 fn merklize<T: Hash>(left: T, right: T) -> T {
-    let data = [&left[..], &right[..]].concat();
-    <T as Hash>::hash(&data)
+    let first = Sha256::new().chain(&left[..]).chain(&right[..]).finalize();
+    let second = Sha256::digest(&first);
+    T::from_slice(&second).expect("invalid hash length")
+}
+
+// Levels of at least this many pairs (i.e. of large blocks, or of the headers' tree) are hashed
+// in chunks by the query's workers.
+const PARALLEL_MERKLE_MIN_PAIRS: usize = 2048;
+
+// Hashes each pair of (an even number of) `hashes`, into the next level of the merkle tree.
+fn merklize_level<T: Hash + Send + 'static>(hashes: &[T], workers: &WorkerPool) -> Vec<T> {
+    let pairs: Vec<(T, T)> = hashes.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+    workers.map(pairs, PARALLEL_MERKLE_MIN_PAIRS, |(left, right)| {
+        merklize(left, right)
+    })
 }
 
-fn create_merkle_branch_and_root<T: Hash>(mut hashes: Vec<T>, mut index: usize) -> (Vec<T>, T) {
-    let mut merkle = vec![];
+// Returns the merkle tree's levels, from the given hashes to the root (where each level, except
+// for the root, is padded to an even length by duplicating its last hash).
+fn merkle_levels<T: Hash + Send + 'static>(
+    mut hashes: Vec<T>,
+    workers: &WorkerPool,
+) -> Vec<Vec<T>> {
+    let mut levels = vec![];
     while hashes.len() > 1 {
         if hashes.len() % 2 != 0 {
             let last = *hashes.last().unwrap();
             hashes.push(last);
         }
-        index = if index % 2 == 0 { index + 1 } else { index - 1 };
-        merkle.push(hashes[index]);
-        index /= 2;
-        hashes = hashes
-            .chunks(2)
-            .map(|pair| merklize(pair[0], pair[1]))
-            .collect()
+        let next = merklize_level(&hashes, workers);
+        levels.push(hashes);
+        hashes = next;
+    }
+    levels.push(hashes);
+    levels
+}
+
+// Returns the merkle branch of the `index`-th hash (of the first level), and the root.
+fn merkle_branch_and_root<T: Hash>(levels: &[Vec<T>], mut index: usize) -> (Vec<T>, T) {
+    let (root, levels) = levels.split_last().expect("empty merkle tree");
+    let branch = levels
+        .iter()
+        .map(|level| {
+            let sibling = level[index ^ 1];
+            index /= 2;
+            sibling
+        })
+        .collect();
+    (branch, root[0])
+}
+
+fn create_merkle_branch_and_root<T: Hash + Send + 'static>(
+    hashes: Vec<T>,
+    index: usize,
+    workers: &WorkerPool,
+) -> (Vec<T>, T) {
+    merkle_branch_and_root(&merkle_levels(hashes, workers), index)
+}
+
+// Estimates the fee rate [BTC/kB] to be confirmed in `blocks` from now, as the fee rate paid at the
+// depth of `blocks` (~1MB each) in the mempool (using its fee histogram), or 0 if the mempool is
+// empty.
+fn mempool_fee_rate(histogram: &[(f32, u32)], blocks: usize) -> f64 {
+    let blocks = blocks.max(1).min(MAX_FEE_ESTIMATE_BLOCKS);
+    let blocks_in_vbytes = blocks as u64 * 1_000_000; // assume ~1MB blocks
+    let mut total_vsize = 0u64;
+    let mut last_fee_rate = 0.0;
+    for (fee_rate, vsize) in histogram {
+        last_fee_rate = *fee_rate;
+        total_vsize += u64::from(*vsize);
+        if total_vsize >= blocks_in_vbytes {
+            break; // under-estimate the fee rate a bit
+        }
     }
-    (merkle, hashes[0])
+    f64::from(last_fee_rate) * 1e-5 // [BTC/kB] = 10^5 [sat/B]
 }
 
 // TODO: the functions below can be part of ReadStore.
@@ -198,26 +505,82 @@ fn txids_by_funding_output(
         .collect()
 }
 
+/// An output's spending status.
+pub enum SpentStatus {
+    Unspent,
+    Mempool { txid: Txid },
+    Confirmed { height: u32, txid: Txid },
+    Unknown, // the funding transaction is not found
+}
+
+/// A transaction's confirmation status (looked up without loading the transaction).
+pub enum TxStatus {
+    Confirmed { height: usize, blockhash: BlockHash },
+    Mempool,
+    Unknown,
+}
+
 pub struct Query {
     app: Arc<App>,
     tracker: RwLock<Tracker>,
     tx_cache: TransactionCache,
+    block_cache: BlockCache,
+    header_chunk_cache: HeaderChunkCache,
+    merkle_cache: MerkleCache,
     txid_limit: usize,
+    max_history: usize,                // transactions per status (0 - unlimited)
+    fee_estimate_mode: Option<String>, // see `estimate_smart_fee()`
+    fee_estimates: Mutex<HashMap<(usize, String), FeeEstimate>>,
+    queued_statuses: Mutex<HashMap<FullHash, QueuedStatus>>, // see `persist_statuses()`
+    persisted_statuses: AtomicUsize, // as of the last pruning (and the new ones since)
+    max_persisted_statuses: usize,   // 0 - don't persist
+    persisted_status_ttl: Option<u32>, // in blocks
+    workers: WorkerPool,             // a thread per CPU
     duration: HistogramVec,
 }
 
+struct QueuedStatus {
+    row: Row,
+    new: bool, // i.e. not replacing a persisted status
+}
+
+struct FeeEstimate {
+    tip: BlockHash,
+    time: Instant,
+    fee_rate: Option<f64>,
+}
+
 impl Query {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         app: Arc<App>,
         metrics: &Metrics,
         tx_cache: TransactionCache,
+        block_cache: BlockCache,
+        header_chunk_cache: HeaderChunkCache,
+        merkle_cache: MerkleCache,
         txid_limit: usize,
+        max_history: usize,
+        fee_estimate_mode: Option<String>,
+        max_persisted_statuses: usize,
+        persisted_status_ttl: Option<u32>,
     ) -> Arc<Query> {
         Arc::new(Query {
             app,
             tracker: RwLock::new(Tracker::new(metrics)),
             tx_cache,
+            block_cache,
+            header_chunk_cache,
+            merkle_cache,
             txid_limit,
+            max_history,
+            fee_estimate_mode,
+            fee_estimates: Mutex::new(HashMap::new()),
+            queued_statuses: Mutex::new(HashMap::new()),
+            persisted_statuses: AtomicUsize::new(0),
+            max_persisted_statuses,
+            persisted_status_ttl,
+            workers: WorkerPool::new("query_worker", num_cpus::get()),
             duration: metrics.histogram_vec(
                 HistogramOpts::new("electrs_query_duration", "Request duration (in seconds)"),
                 &["type"],
@@ -225,14 +588,24 @@ impl Query {
         })
     }
 
+    /// The workers splitting large requests (e.g. batched subscriptions) between the CPUs.
+    pub fn workers(&self) -> &WorkerPool {
+        &self.workers
+    }
+
+    // Transactions confirmed at or below `since` (if set) are skipped, since they were scanned.
     fn load_txns_by_prefix(
         &self,
         store: &dyn ReadStore,
         prefixes: Vec<HashPrefix>,
+        since: Option<u32>,
     ) -> Result<Vec<TxnHeight>> {
         let mut txns = vec![];
         for txid_prefix in prefixes {
             for tx_row in txrows_by_prefix(store, txid_prefix) {
+                if since.map_or(false, |height| tx_row.height <= height) {
+                    continue;
+                }
                 let txid: Txid = deserialize(&tx_row.key.txid).unwrap();
                 let txn = self.load_txn(&txid, Some(tx_row.height))?;
                 txns.push(TxnHeight {
@@ -244,36 +617,43 @@ impl Query {
         Ok(txns)
     }
 
+    // Returns the transaction spending the given output (if any).
+    fn find_spending_txn(
+        &self,
+        store: &dyn ReadStore,
+        txn_id: &Txid,
+        output_index: usize,
+        since: Option<u32>,
+    ) -> Result<Option<TxnHeight>> {
+        let prefixes = txids_by_funding_output(store, txn_id, output_index);
+        let mut spending_txns: Vec<TxnHeight> = self
+            .load_txns_by_prefix(store, prefixes, since)?
+            .into_iter()
+            .filter(|t| {
+                t.txn.input.iter().any(|input| {
+                    input.previous_output.txid == *txn_id
+                        && input.previous_output.vout == output_index as u32
+                })
+            })
+            .collect();
+        assert!(spending_txns.len() <= 1);
+        Ok(spending_txns.pop())
+    }
+
     fn find_spending_input(
         &self,
         store: &dyn ReadStore,
         funding: &FundingOutput,
+        since: Option<u32>,
     ) -> Result<Option<SpendingInput>> {
-        let spending_txns: Vec<TxnHeight> = self.load_txns_by_prefix(
-            store,
-            txids_by_funding_output(store, &funding.txn_id, funding.output_index),
-        )?;
-        let mut spending_inputs = vec![];
-        for t in &spending_txns {
-            for input in t.txn.input.iter() {
-                if input.previous_output.txid == funding.txn_id
-                    && input.previous_output.vout == funding.output_index as u32
-                {
-                    spending_inputs.push(SpendingInput {
-                        txn_id: t.txn.txid(),
-                        height: t.height,
-                        funding_output: (funding.txn_id, funding.output_index),
-                        value: funding.value,
-                    })
-                }
-            }
-        }
-        assert!(spending_inputs.len() <= 1);
-        Ok(if spending_inputs.len() == 1 {
-            Some(spending_inputs.remove(0))
-        } else {
-            None
-        })
+        let spending_txn =
+            self.find_spending_txn(store, &funding.txn_id, funding.output_index, since)?;
+        Ok(spending_txn.map(|t| SpendingInput {
+            txn_id: t.txn.txid(),
+            height: t.height,
+            funding_output: (funding.txn_id, funding.output_index),
+            value: funding.value,
+        }))
     }
 
     fn find_funding_outputs(&self, t: &TxnHeight, script_hash: &[u8]) -> Vec<FundingOutput> {
@@ -285,33 +665,49 @@ impl Query {
                     txn_id,
                     height: t.height,
                     output_index: index,
-                    value: output.value,
+                    value: output_value(output).unwrap_or(0), // confidential values are opaque
                 })
             }
         }
         result
     }
 
+    // Resumes from the persisted status (if given), scanning only the blocks indexed afterwards.
     fn confirmed_status(
         &self,
         script_hash: &[u8],
+        resume: Option<StatusRow>,
+        txid_limit: usize,
     ) -> Result<(Vec<FundingOutput>, Vec<SpendingInput>)> {
-        let mut funding = vec![];
-        let mut spending = vec![];
         let read_store = self.app.read_store();
         let txid_prefixes = txids_by_script_hash(read_store, script_hash);
         // if the limit is enabled
-        if self.txid_limit > 0 && txid_prefixes.len() > self.txid_limit {
-            bail!(
+        if txid_limit > 0 && txid_prefixes.len() > txid_limit {
+            bail!(ErrorKind::ExcessiveResourceUsage(format!(
                 "{}+ transactions found, query may take a long time",
                 txid_prefixes.len()
-            );
+            )));
         }
-        for t in self.load_txns_by_prefix(read_store, txid_prefixes)? {
+        // each of them funds the script hash, so it's in the history
+        check_history(self.max_history, txid_prefixes.len())?;
+        let since = resume.as_ref().map(|row| row.height);
+        let (mut funding, mut spending) = resume.map_or((vec![], vec![]), status_from_row);
+        for t in self.load_txns_by_prefix(read_store, txid_prefixes, since)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
+        let spent: HashSet<OutPoint> = spending.iter().map(|input| input.funding_output).collect();
+        let mut txids: HashSet<Txid> = funding
+            .iter()
+            .map(|output| output.txn_id)
+            .chain(spending.iter().map(|input| input.txn_id))
+            .collect();
         for funding_output in &funding {
-            if let Some(spent) = self.find_spending_input(read_store, &funding_output)? {
+            if spent.contains(&(funding_output.txn_id, funding_output.output_index)) {
+                continue;
+            }
+            if let Some(spent) = self.find_spending_input(read_store, &funding_output, since)? {
+                txids.insert(spent.txn_id);
+                check_history(self.max_history, txids.len())?;
                 spending.push(spent);
             }
         }
@@ -327,12 +723,12 @@ impl Query {
         let mut funding = vec![];
         let mut spending = vec![];
         let txid_prefixes = txids_by_script_hash(tracker.index(), script_hash);
-        for t in self.load_txns_by_prefix(tracker.index(), txid_prefixes)? {
+        for t in self.load_txns_by_prefix(tracker.index(), txid_prefixes, None)? {
             funding.extend(self.find_funding_outputs(&t, script_hash));
         }
         // // TODO: dedup outputs (somehow) both confirmed and in mempool (e.g. reorg?)
         for funding_output in funding.iter().chain(confirmed_funding.iter()) {
-            if let Some(spent) = self.find_spending_input(tracker.index(), &funding_output)? {
+            if let Some(spent) = self.find_spending_input(tracker.index(), &funding_output, None)? {
                 spending.push(spent);
             }
         }
@@ -340,12 +736,125 @@ impl Query {
     }
 
     pub fn status(&self, script_hash: &[u8]) -> Result<Status> {
+        self.status_with_limit(script_hash, self.txid_limit)
+    }
+
+    /// Returns the status, failing if the script hash has more than `txid_limit` transactions
+    /// (0 - unlimited), instead of the configured `txid_limit`.
+    pub fn status_with_limit(&self, script_hash: &[u8], txid_limit: usize) -> Result<Status> {
+        self.compute_status(script_hash, self.load_status_row(script_hash), txid_limit)
+    }
+
+    /// Returns the status of a subscribed script hash, queueing its confirmed part (as of the
+    /// current tip) to be persisted, so it can be updated by scanning only new blocks (e.g. after a
+    /// restart).
+    pub fn subscribed_status(&self, script_hash: &[u8], txid_limit: usize) -> Result<Status> {
+        let resume = self.load_status_row(script_hash);
+        let persisted_height = resume.as_ref().map(|row| row.height);
+        // the tip is read before scanning, so that all its rows are already indexed
+        let tip = self.app.index().best_header();
+        let status = self.compute_status(script_hash, resume, txid_limit)?;
+        if let Some(tip) = tip {
+            if persisted_height != Some(tip.height() as u32) {
+                self.queue_status(script_hash, &tip, &status, persisted_height.is_none());
+            }
+        }
+        Ok(status)
+    }
+
+    // Returns the queued status of `script_hash` (if any), or its persisted one.
+    fn get_status_row(&self, script_hash: &[u8]) -> Option<StatusRow> {
+        let queued = self
+            .queued_statuses
+            .lock()
+            .unwrap()
+            .get(&full_hash(script_hash))
+            .map(|queued| queued.row.value.clone());
+        let value = match queued {
+            Some(value) => value,
+            None => self
+                .app
+                .read_store()
+                .get(&StatusRow::key(script_hash, self.app.cipher()))?,
+        };
+        StatusRow::from_value(&value)
+    }
+
+    // Returns the persisted (or queued) status of `script_hash`, unless its block was reorged.
+    fn load_status_row(&self, script_hash: &[u8]) -> Option<StatusRow> {
+        let row = self.get_status_row(script_hash)?;
+        let header = self.app.index().get_header(row.height as usize)?;
+        if header.hash()[..] == row.blockhash[..] {
+            Some(row)
+        } else {
+            None
+        }
+    }
+
+    // New statuses are dropped when `max_persisted_statuses` are already persisted (or queued),
+    // since anonymous clients may subscribe to any script hash.
+    fn queue_status(&self, script_hash: &[u8], tip: &HeaderEntry, status: &Status, new: bool) {
+        if status.confirmed.0.is_empty() || self.max_persisted_statuses == 0 {
+            return; // unused script hashes are cheap to scan
+        }
+        let mut queued = self.queued_statuses.lock().unwrap();
+        let key = full_hash(script_hash);
+        let new = match queued.get(&key) {
+            Some(status) => status.new,
+            None => {
+                let persisted = self.persisted_statuses.load(Ordering::Relaxed);
+                if new && persisted + queued.len() >= self.max_persisted_statuses {
+                    return;
+                }
+                new
+            }
+        };
+        let row = status_row(tip.height() as u32, tip.hash(), &status.confirmed);
+        let row = row.to_row(script_hash, self.app.cipher());
+        queued.insert(key, QueuedStatus { row, new });
+    }
+
+    /// Writes the queued statuses in a single batch, and after a new block, prunes the persisted
+    /// statuses which no subscription has refreshed during the last `persisted_status_ttl` blocks
+    /// (called by the indexing thread, so requests don't wait for DB writes).
+    pub fn persist_statuses(&self, new_block: bool) {
+        let queued: Vec<QueuedStatus> = self
+            .queued_statuses
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, queued)| queued)
+            .collect();
+        let new = queued.iter().filter(|queued| queued.new).count();
+        if !queued.is_empty() {
+            self.app
+                .write_store()
+                .write(queued.into_iter().map(|queued| queued.row));
+        }
+        if !new_block {
+            self.persisted_statuses.fetch_add(new, Ordering::Relaxed);
+            return;
+        }
+        let min_height = match (self.persisted_status_ttl, self.app.index().best_header()) {
+            (Some(ttl), Some(tip)) => (tip.height() as u32).saturating_sub(ttl),
+            _ => 0,
+        };
+        let kept = self.app.prune_statuses(min_height);
+        self.persisted_statuses.store(kept, Ordering::Relaxed);
+    }
+
+    fn compute_status(
+        &self,
+        script_hash: &[u8],
+        resume: Option<StatusRow>,
+        txid_limit: usize,
+    ) -> Result<Status> {
         let timer = self
             .duration
             .with_label_values(&["confirmed_status"])
             .start_timer();
         let confirmed = self
-            .confirmed_status(script_hash)
+            .confirmed_status(script_hash, resume, txid_limit)
             .chain_err(|| "failed to get confirmed status")?;
         timer.observe_duration();
 
@@ -358,23 +867,225 @@ impl Query {
             .mempool_status(script_hash, &confirmed.0, &tracker)
             .chain_err(|| "failed to get mempool status")?;
         timer.observe_duration();
+        let history_len = confirmed
+            .0
+            .iter()
+            .chain(mempool.0.iter())
+            .map(|output| output.txn_id)
+            .chain(
+                confirmed
+                    .1
+                    .iter()
+                    .chain(mempool.1.iter())
+                    .map(|input| input.txn_id),
+            )
+            .collect::<HashSet<Txid>>()
+            .len();
+        check_history(self.max_history, history_len)?;
 
         let mut txn_fees = HashMap::new();
+        let mut txn_first_seen = HashMap::new();
         let funding_txn_ids = mempool.0.iter().map(|funding| funding.txn_id);
         let spending_txn_ids = mempool.1.iter().map(|spending| spending.txn_id);
         for mempool_txid in funding_txn_ids.chain(spending_txn_ids) {
             tracker
                 .get_fee(&mempool_txid)
                 .map(|fee| txn_fees.insert(mempool_txid, fee));
+            tracker
+                .get_first_seen(&mempool_txid)
+                .map(|time| txn_first_seen.insert(mempool_txid, time));
         }
 
+        let positions = self.block_positions(&confirmed)?;
         Ok(Status {
             confirmed,
             mempool,
             txn_fees,
+            txn_first_seen,
+            positions,
+        })
+    }
+
+    // Returns the positions of the confirmed transactions in their blocks, reading the (cached)
+    // txids only of the blocks which confirmed several of them.
+    fn block_positions(
+        &self,
+        (funding, spending): &(Vec<FundingOutput>, Vec<SpendingInput>),
+    ) -> Result<HashMap<Txid, usize>> {
+        let mut txids_by_height = HashMap::<u32, HashSet<Txid>>::new();
+        let funding = funding.iter().map(|output| (output.height, output.txn_id));
+        let spending = spending.iter().map(|input| (input.height, input.txn_id));
+        for (height, txid) in funding.chain(spending) {
+            txids_by_height.entry(height).or_default().insert(txid);
+        }
+        let mut positions = HashMap::new();
+        for (height, txids) in txids_by_height {
+            if txids.len() < 2 {
+                continue;
+            }
+            let header = self
+                .app
+                .index()
+                .get_header(height as usize)
+                .chain_err(|| format!("missing header #{}", height))?;
+            let block_txids = self.get_block_txids(header.hash())?;
+            positions.extend(
+                block_txids
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, txid)| txids.contains(txid))
+                    .map(|(position, txid)| (txid, position)),
+            );
+        }
+        Ok(positions)
+    }
+
+    /// Returns the status hash, updating `hasher` (see `StatusHasher`).
+    pub fn status_hash(&self, status: &Status, hasher: &mut StatusHasher) -> Option<FullHash> {
+        let index = self.app.index();
+        hasher.update(&status.history(), |height| {
+            index
+                .get_header(height as usize)
+                .map(|header| *header.hash())
         })
     }
 
+    /// Evicts the cached transactions of `script_hash`'s persisted status, and recomputes its
+    /// status from the index (fetching them again from `bitcoind`), e.g. when a client reports a
+    /// stale balance. A persisted status is recomputed from scratch, and replaced.
+    pub fn refresh_status(&self, script_hash: &[u8], txid_limit: usize) -> Result<Status> {
+        // the row is used even if its block was reorged, since its transactions may be stale
+        let persisted = self.get_status_row(script_hash);
+        if let Some(ref row) = persisted {
+            let funding = row.funding.iter().map(|(txid, ..)| txid);
+            let spending = row.spending.iter().map(|(txid, ..)| txid);
+            let evicted = funding
+                .chain(spending)
+                .filter(|txid| self.tx_cache.remove(&deserialize(&txid[..]).unwrap()))
+                .count();
+            debug!("evicted {} cached transactions", evicted);
+        }
+        let tip = self.app.index().best_header();
+        let status = self.compute_status(script_hash, None, txid_limit)?;
+        if let (Some(_), Some(tip)) = (persisted, tip) {
+            self.queue_status(script_hash, &tip, &status, false);
+        }
+        Ok(status)
+    }
+
+    /// Returns the block (i.e. its height and hash) and txid of the first confirmed transaction
+    /// funding `script_hash` (or `None` if it was never used), loading only the transactions
+    /// needed for verifying it.
+    pub fn get_first_use(&self, script_hash: &[u8]) -> Result<Option<(u32, BlockHash, Txid)>> {
+        let _timer = self
+            .duration
+            .with_label_values(&["get_first_use"])
+            .start_timer();
+        let read_store = self.app.read_store();
+        let mut tx_rows: Vec<TxRow> = txids_by_script_hash(read_store, script_hash)
+            .into_iter()
+            .flat_map(|txid_prefix| txrows_by_prefix(read_store, txid_prefix))
+            .collect();
+        tx_rows.sort_unstable_by_key(|tx_row| (tx_row.height, tx_row.key.txid));
+        tx_rows.dedup_by_key(|tx_row| tx_row.key.txid);
+        // since only hash prefixes are indexed, the funding output must be verified
+        for tx_row in tx_rows {
+            let txid: Txid = deserialize(&tx_row.key.txid).unwrap();
+            let txn = self.load_txn(&txid, Some(tx_row.height))?;
+            let t = TxnHeight {
+                txn,
+                height: tx_row.height,
+            };
+            if !self.find_funding_outputs(&t, script_hash).is_empty() {
+                let header = self
+                    .app
+                    .index()
+                    .get_header(tx_row.height as usize)
+                    .chain_err(|| format!("missing header at height {}", tx_row.height))?;
+                return Ok(Some((tx_row.height, *header.hash(), txid)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the (confirmed and mempool) transactions whose txid starts with `prefix` (given in
+    /// hex), with their heights. Requires the `TxSearchRow`s (see `--txid-search`).
+    pub fn search_txids(&self, prefix: &str) -> Result<Vec<(Txid, u32)>> {
+        let prefix = prefix.to_lowercase();
+        if prefix.len() < 8 || !prefix.chars().all(|c| c.is_ascii_hexdigit()) {
+            bail!("txid prefix should have at least 8 hex digits");
+        }
+        let _timer = self
+            .duration
+            .with_label_values(&["search_txids"])
+            .start_timer();
+        let display_prefix =
+            hex::decode(&prefix[..prefix.len().min(2 * HASH_PREFIX_LEN) & !1]).unwrap();
+        let tracker = self.tracker.read().unwrap();
+        let mut result = vec![];
+        for store in &[self.app.read_store(), tracker.index()] {
+            for row in store.scan(&TxSearchRow::filter(&display_prefix)) {
+                let txid_prefix = TxSearchRow::from_row(&row).txid_prefix;
+                for tx_row in txrows_by_prefix(*store, txid_prefix) {
+                    let txid: Txid = deserialize(&tx_row.key.txid).unwrap();
+                    if txid.to_hex().starts_with(&prefix) {
+                        result.push((txid, tx_row.height));
+                    }
+                }
+                if result.len() >= MAX_SEARCH_RESULTS {
+                    result.truncate(MAX_SEARCH_RESULTS);
+                    return Ok(result);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns whether the output is unspent, or its spending transaction (using the index of
+    /// confirmed and mempool transactions' inputs).
+    pub fn get_spent_status(&self, txn_id: &Txid, output_index: usize) -> Result<SpentStatus> {
+        let _timer = self
+            .duration
+            .with_label_values(&["get_spent_status"])
+            .start_timer();
+        let store = self.app.read_store();
+        if let Some(t) = self.find_spending_txn(store, txn_id, output_index, None)? {
+            return Ok(SpentStatus::Confirmed {
+                height: t.height,
+                txid: t.txn.txid(),
+            });
+        }
+        let tracker = self.tracker.read().unwrap();
+        if let Some(t) = self.find_spending_txn(tracker.index(), txn_id, output_index, None)? {
+            return Ok(SpentStatus::Mempool { txid: t.txn.txid() });
+        }
+        if tracker.has_txn(txn_id) || txrow_by_txid(store, txn_id).is_some() {
+            Ok(SpentStatus::Unspent)
+        } else {
+            Ok(SpentStatus::Unknown)
+        }
+    }
+
+    /// Returns the mempool transaction's replaceability (or `None` if it's not in the mempool).
+    pub fn get_replaceability(&self, txid: &Txid) -> Option<Replaceability> {
+        self.tracker.read().unwrap().get_replaceability(txid)
+    }
+
+    pub fn get_tx_status(&self, txid: &Txid) -> TxStatus {
+        if self.tracker.read().unwrap().has_txn(txid) {
+            return TxStatus::Mempool;
+        }
+        let header = txrow_by_txid(self.app.read_store(), txid)
+            .and_then(|tx_row| self.app.index().get_header(tx_row.height as usize));
+        match header {
+            Some(header) => TxStatus::Confirmed {
+                height: header.height(),
+                blockhash: *header.hash(),
+            },
+            None => TxStatus::Unknown,
+        }
+    }
+
     fn lookup_confirmed_blockhash(
         &self,
         tx_hash: &Txid,
@@ -423,11 +1134,149 @@ impl Query {
             .with_label_values(&["get_transaction"])
             .start_timer();
         let blockhash = self.lookup_confirmed_blockhash(tx_hash, /*block_height*/ None)?;
+        if !verbose {
+            let serialized_txn = match blockhash {
+                Some(blockhash) => Some(self.load_confirmed_txn(tx_hash, &blockhash)?),
+                None => self.get_mempool_txn(tx_hash),
+            };
+            if let Some(serialized_txn) = serialized_txn {
+                return Ok(json!(hex::encode(serialized_txn)));
+            }
+        }
+        if verbose {
+            if let Some(value) = self.decode_transaction(tx_hash, blockhash)? {
+                return Ok(value);
+            }
+        }
         self.app
             .daemon()
             .gettransaction_raw(tx_hash, blockhash, verbose)
     }
 
+    // Decodes the (cached) transaction locally, with its confirmations by the indexed chain.
+    fn decode_transaction(
+        &self,
+        tx_hash: &Txid,
+        blockhash: Option<BlockHash>,
+    ) -> Result<Option<Value>> {
+        let network = self.app.daemon().network();
+        match blockhash {
+            Some(blockhash) => {
+                let entry = self
+                    .get_header_by_blockhash(&blockhash)
+                    .chain_err(|| format!("missing header {}", blockhash))?;
+                let confirmations = self.get_best_header()?.height() + 1 - entry.height();
+                let serialized_txn = self.load_confirmed_txn(tx_hash, &blockhash)?;
+                decode::transaction_json(&serialized_txn, network, Some((&entry, confirmations)))
+            }
+            None => {
+                let serialized_txn = match self.get_mempool_txn(tx_hash) {
+                    Some(serialized_txn) => serialized_txn,
+                    None => serialize(&self.load_txn(tx_hash, None)?),
+                };
+                decode::transaction_json(&serialized_txn, network, None)
+            }
+        }
+    }
+
+    // Serializes the transaction stored in the mempool tracker (without any RPC).
+    fn get_mempool_txn(&self, tx_hash: &Txid) -> Option<Vec<u8>> {
+        self.tracker.read().unwrap().get_txn(tx_hash).map(serialize)
+    }
+
+    /// Returns a confirmed transaction out of its (cached) block, so `bitcoind` doesn't need
+    /// `txindex` (nor to re-read the block from disk) for serving it.
+    /// On a cache miss, all the block's transactions are cached, since clients browsing their
+    /// history usually request several transactions from the same block (unless they wouldn't fit
+    /// in the cache, e.g. if it's disabled, so only the transaction is fetched).
+    fn load_confirmed_txn(&self, tx_hash: &Txid, blockhash: &BlockHash) -> Result<Vec<u8>> {
+        if let Some(serialized_txn) = self.tx_cache.get(tx_hash) {
+            return Ok(serialized_txn);
+        }
+        if self.tx_cache.capacity() < MAX_BLOCK_SIZE {
+            let value = self.app.daemon().gettransaction_raw(
+                tx_hash,
+                Some(*blockhash),
+                /*verbose*/ false,
+            )?;
+            let value_hex = value.as_str().chain_err(|| "non-string tx")?;
+            let serialized_txn = hex::decode(&value_hex).chain_err(|| "non-hex tx")?;
+            self.tx_cache.add(tx_hash, serialized_txn.clone());
+            return Ok(serialized_txn);
+        }
+        self.prefetch_block(blockhash)?
+            .into_iter()
+            .find(|(txid, _)| txid == tx_hash)
+            .map(|(_, serialized_txn)| serialized_txn)
+            .chain_err(|| format!("tx {} not found in block {}", tx_hash, blockhash))
+    }
+
+    /// Returns the transactions (in the same order), fetching the ones that are not cached using
+    /// a single batch of requests.
+    pub fn get_transactions(&self, tx_hashes: &[Txid], verbose: bool) -> Result<Vec<Value>> {
+        let _timer = self
+            .duration
+            .with_label_values(&["get_transactions"])
+            .start_timer();
+        let mut result: Vec<Option<Value>> = vec![None; tx_hashes.len()];
+        let mut missing = vec![]; // (index, txid, blockhash)
+        for (i, tx_hash) in tx_hashes.iter().enumerate() {
+            if !verbose {
+                let serialized_txn = self
+                    .tx_cache
+                    .get(tx_hash)
+                    .or_else(|| self.get_mempool_txn(tx_hash));
+                if let Some(serialized_txn) = serialized_txn {
+                    result[i] = Some(json!(hex::encode(serialized_txn)));
+                    continue;
+                }
+            }
+            let blockhash = self.lookup_confirmed_blockhash(tx_hash, /*block_height*/ None)?;
+            missing.push((i, (*tx_hash, blockhash)));
+        }
+        let requests: Vec<(Txid, Option<BlockHash>)> =
+            missing.iter().map(|(_, request)| *request).collect();
+        let values = self.app.daemon().gettransactions_raw(&requests, verbose)?;
+        for ((i, (txid, _)), value) in missing.into_iter().zip(values) {
+            if !verbose {
+                let value_hex = value.as_str().chain_err(|| "non-string tx")?;
+                self.tx_cache
+                    .add(&txid, hex::decode(value_hex).chain_err(|| "non-hex tx")?);
+            }
+            result[i] = Some(value);
+        }
+        Ok(result.into_iter().map(Option::unwrap).collect())
+    }
+
+    /// Computes the fee paid by a transaction (using its inputs' funding transactions).
+    /// Returns `None` for coinbase transactions (or if any of the values is confidential).
+    pub fn get_fee(&self, tx_hash: &Txid) -> Result<Option<u64>> {
+        let txn = self.load_txn(tx_hash, None)?;
+        if is_coinbase(&txn) {
+            return Ok(None);
+        }
+        let mut funded = 0;
+        for input in &txn.input {
+            let prev_txn = self.load_txn(&input.previous_output.txid, None)?;
+            let prev_output = prev_txn
+                .output
+                .get(input.previous_output.vout as usize)
+                .chain_err(|| format!("missing funding output {}", input.previous_output))?;
+            funded += match output_value(prev_output) {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+        }
+        let spent: u64 = match txn.output.iter().map(output_value).sum() {
+            Some(spent) => spent,
+            None => return Ok(None),
+        };
+        let fee = funded
+            .checked_sub(spent)
+            .chain_err(|| format!("negative fee for tx {}", tx_hash))?;
+        Ok(Some(fee))
+    }
+
     pub fn get_confirmed_blockhash(&self, tx_hash: &Txid) -> Result<Value> {
         let blockhash = self.lookup_confirmed_blockhash(tx_hash, None)?;
         Ok(json!({ "block_hash": blockhash }))
@@ -445,6 +1294,90 @@ impl Query {
             .collect()
     }
 
+    /// Returns the number of headers found and their concatenated hex.
+    /// Complete (and aligned) chunks of `HEADERS_CHUNK_SIZE` headers are cached, since
+    /// header-chain bootstrapping clients all request the same ones.
+    pub fn get_headers_hex(&self, start_height: usize, count: usize) -> (usize, Arc<String>) {
+        let heights: Vec<usize> = (start_height..(start_height + count)).collect();
+        let headers = self.get_headers(&heights);
+        let headers_hex = || {
+            let mut result = String::with_capacity(headers.len() * 160);
+            for entry in &headers {
+                result.push_str(&hex::encode(&serialize(entry.header())));
+            }
+            result
+        };
+        let is_chunk = start_height % HEADERS_CHUNK_SIZE == 0
+            && count == HEADERS_CHUNK_SIZE
+            && headers.len() == count;
+        let result = if is_chunk {
+            let last_blockhash = headers.last().unwrap().hash();
+            // a block hash commits to all the previous headers (so reorgs can't make it stale)
+            self.header_chunk_cache
+                .get_or_else(last_blockhash, headers_hex)
+        } else {
+            Arc::new(headers_hex())
+        };
+        (headers.len(), result)
+    }
+
+    /// Returns the header of a block in the best chain.
+    pub fn get_header_by_blockhash(&self, blockhash: &BlockHash) -> Option<HeaderEntry> {
+        self.app.index().get_header_by_blockhash(blockhash)
+    }
+
+    /// Returns the serialized block (caching recently requested ones).
+    pub fn get_block(&self, blockhash: &BlockHash) -> Result<Vec<u8>> {
+        let _timer = self
+            .duration
+            .with_label_values(&["get_block"])
+            .start_timer();
+        self.block_cache
+            .get_or_else(blockhash, || self.app.daemon().getblock_raw(blockhash))
+    }
+
+    /// Fetches the best block, and caches its transactions and txids, so the requests following a
+    /// new block (e.g. `blockchain.transaction.get` and `get_merkle`) don't have to wait for bitcoind.
+    pub fn prefetch_tip(&self) -> Result<()> {
+        let _timer = self
+            .duration
+            .with_label_values(&["prefetch_tip"])
+            .start_timer();
+        let tip = self.get_best_header()?;
+        self.prefetch_block(tip.hash())?;
+        Ok(())
+    }
+
+    // Caches all the block's transactions and txids (returning the serialized transactions).
+    fn prefetch_block(&self, blockhash: &BlockHash) -> Result<Vec<(Txid, Vec<u8>)>> {
+        let _timer = self
+            .duration
+            .with_label_values(&["prefetch_block"])
+            .start_timer();
+        let block = self.get_block(blockhash)?;
+        let txns = block_transactions(&block)
+            .chain_err(|| format!("failed to parse block {}", blockhash))?;
+        for (txid, serialized_txn) in &txns {
+            self.tx_cache.add(txid, serialized_txn.clone());
+        }
+        let txids = txns.iter().map(|(txid, _)| *txid).collect();
+        self.app.daemon().add_blocktxids(blockhash, txids);
+        Ok(txns)
+    }
+
+    pub fn daemon(&self) -> &Daemon {
+        self.app.daemon()
+    }
+
+    /// Returns the block's txids, reading them from the (cached) serialized block on a cache miss,
+    /// instead of making bitcoind decode the whole block into JSON.
+    pub fn get_block_txids(&self, blockhash: &BlockHash) -> Result<Vec<Txid>> {
+        self.app.daemon().getblocktxids(blockhash, || {
+            let block = self.get_block(blockhash)?;
+            block_txids(&block).chain_err(|| format!("failed to parse block {}", blockhash))
+        })
+    }
+
     pub fn get_best_header(&self) -> Result<HeaderEntry> {
         let last_header = self.app.index().best_header();
         Ok(last_header.chain_err(|| "no headers indexed")?)
@@ -460,19 +1393,32 @@ impl Query {
             .index()
             .get_header(height)
             .chain_err(|| format!("missing block #{}", height))?;
-        let txids = self.app.daemon().getblocktxids(&header_entry.hash())?;
+        let txids = self.get_block_txids(header_entry.hash())?;
         let pos = txids
             .iter()
             .position(|txid| txid == tx_hash)
             .chain_err(|| format!("missing txid {}", tx_hash))?;
-        let tx_nodes: Vec<TxMerkleNode> = txids
-            .into_iter()
-            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
-            .collect();
-        let (branch, _root) = create_merkle_branch_and_root(tx_nodes, pos);
+        let levels = self.get_merkle_levels(header_entry.hash(), txids);
+        let (branch, _root) = merkle_branch_and_root(&levels, pos);
         Ok((branch, pos))
     }
 
+    // Recent blocks' merkle proofs are requested for many of their transactions, so their merkle
+    // trees are cached (instead of being recomputed for each proof).
+    fn get_merkle_levels(
+        &self,
+        blockhash: &BlockHash,
+        txids: Vec<Txid>,
+    ) -> Arc<Vec<Vec<TxMerkleNode>>> {
+        self.merkle_cache.get_or_else(blockhash, || {
+            let tx_nodes = txids
+                .into_iter()
+                .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
+                .collect();
+            merkle_levels(tx_nodes, &self.workers)
+        })
+    }
+
     pub fn get_header_merkle_proof(
         &self,
         height: usize,
@@ -502,7 +1448,11 @@ impl Query {
             .map(|block_hash| Sha256dHash::from_inner(block_hash.into_inner()))
             .collect();
         assert_eq!(header_hashes.len(), heights.len());
-        Ok(create_merkle_branch_and_root(merkle_nodes, height))
+        Ok(create_merkle_branch_and_root(
+            merkle_nodes,
+            height,
+            &self.workers,
+        ))
     }
 
     pub fn get_id_from_pos(
@@ -517,26 +1467,26 @@ impl Query {
             .get_header(height)
             .chain_err(|| format!("missing block #{}", height))?;
 
-        let txids = self.app.daemon().getblocktxids(header_entry.hash())?;
+        let txids = self.get_block_txids(header_entry.hash())?;
         let txid = *txids
             .get(tx_pos)
             .chain_err(|| format!("No tx in position #{} in block #{}", tx_pos, height))?;
 
-        let tx_nodes = txids
-            .into_iter()
-            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
-            .collect();
-
         let branch = if want_merkle {
-            create_merkle_branch_and_root(tx_nodes, tx_pos).0
+            let levels = self.get_merkle_levels(header_entry.hash(), txids);
+            merkle_branch_and_root(&levels, tx_pos).0
         } else {
             vec![]
         };
         Ok((txid, branch))
     }
 
-    pub fn broadcast(&self, txn: &Transaction) -> Result<Txid> {
-        self.app.daemon().broadcast(txn)
+    pub fn broadcast(&self, txn: &Transaction, max_fee_rate: Option<f64>) -> Result<Txid> {
+        self.app.daemon().broadcast(txn, max_fee_rate)
+    }
+
+    pub fn test_broadcast(&self, txn: &Transaction, max_fee_rate: Option<f64>) -> Result<()> {
+        self.app.daemon().test_mempool_accept(txn, max_fee_rate)
     }
 
     pub fn update_mempool(&self) -> Result<()> {
@@ -544,9 +1494,21 @@ impl Query {
             .duration
             .with_label_values(&["update_mempool"])
             .start_timer();
+        let _exclusive = self.app.index().exclusive();
         self.tracker.write().unwrap().update(self.app.daemon())
     }
 
+    /// Pins the best chain and the mempool (see `Index::snapshot`).
+    pub fn snapshot(&self) -> RwLockReadGuard<()> {
+        self.app.index().snapshot()
+    }
+
+    /// Returns the mempool transactions added after the first `count` ones (see
+    /// `Tracker::added_since`).
+    pub fn get_new_mempool_txs(&self, count: u64) -> (Vec<NewTransaction>, u64) {
+        self.tracker.read().unwrap().added_since(count)
+    }
+
     /// Returns [vsize, fee_rate] pairs (measured in vbytes and satoshis).
     pub fn get_fee_histogram(&self) -> Vec<(f32, u32)> {
         self.tracker.read().unwrap().fee_histogram().clone()
@@ -554,17 +1516,46 @@ impl Query {
 
     // Fee rate [BTC/kB] to be confirmed in `blocks` from now.
     pub fn estimate_fee(&self, blocks: usize) -> f64 {
-        let mut total_vsize = 0u32;
-        let mut last_fee_rate = 0.0;
-        let blocks_in_vbytes = (blocks * 1_000_000) as u32; // assume ~1MB blocks
-        for (fee_rate, vsize) in self.tracker.read().unwrap().fee_histogram() {
-            last_fee_rate = *fee_rate;
-            total_vsize += vsize;
-            if total_vsize >= blocks_in_vbytes {
-                break; // under-estimate the fee rate a bit
+        mempool_fee_rate(self.tracker.read().unwrap().fee_histogram(), blocks)
+    }
+
+    /// Fee rate [BTC/kB] to be confirmed in `blocks` from now, estimated by bitcoind's
+    /// `estimatesmartfee` using `mode` (or the configured one). Returns `None` if no mode is
+    /// given (or if bitcoind has insufficient data), so the fee is estimated using the mempool.
+    /// The estimates are cached for `FEE_ESTIMATE_TTL`, or until a new block is indexed.
+    pub fn estimate_smart_fee(&self, blocks: usize, mode: Option<&str>) -> Result<Option<f64>> {
+        let mode = match mode.or_else(|| self.fee_estimate_mode.as_deref()) {
+            Some(mode) => mode,
+            None => return Ok(None),
+        };
+        let blocks = blocks.max(1).min(MAX_FEE_ESTIMATE_BLOCKS);
+        let tip = *self.get_best_header()?.hash();
+        let key = (blocks, mode.to_owned());
+        if let Some(estimate) = self.fee_estimates.lock().unwrap().get(&key) {
+            if estimate.tip == tip && estimate.time.elapsed() < FEE_ESTIMATE_TTL {
+                return Ok(estimate.fee_rate);
             }
         }
-        (last_fee_rate as f64) * 1e-5 // [BTC/kB] = 10^5 [sat/B]
+        let fee_rate = self.app.daemon().estimatesmartfee(blocks, mode)?;
+        let estimate = FeeEstimate {
+            tip,
+            time: Instant::now(),
+            fee_rate,
+        };
+        self.fee_estimates.lock().unwrap().insert(key, estimate);
+        Ok(fee_rate)
+    }
+
+    pub fn check_daemon_synced(&self) -> Result<()> {
+        self.app.check_daemon_synced()
+    }
+
+    pub fn headers_only(&self) -> bool {
+        self.app.headers_only()
+    }
+
+    pub fn check_in_sync(&self) -> Result<()> {
+        self.app.check_in_sync()
     }
 
     pub fn get_banner(&self) -> Result<String> {
@@ -575,3 +1566,302 @@ impl Query {
         self.app.daemon().get_relayfee()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::hex::ToHex;
+    use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+    use bitcoin::hashes::Hash;
+
+    use super::{
+        check_history, status_from_row, status_row, FundingOutput, HistoryItem, SpendingInput,
+        Status, StatusHasher,
+    };
+    use crate::chain::{BlockHash, Txid};
+    use crate::index::StatusRow;
+    use crate::util::WorkerPool;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_check_history() {
+        assert!(check_history(0, 1_000_000).is_ok()); // unlimited by default
+        assert!(check_history(100, 100).is_ok());
+        let err = check_history(100, 101).unwrap_err();
+        assert_eq!(err.to_string(), "history too large");
+    }
+
+    #[test]
+    fn test_history_item_json() {
+        let tx_hash = Txid::hash(b"tx");
+        let confirmed = HistoryItem {
+            height: 123,
+            tx_hash,
+            fee: None,
+            first_seen: None,
+        };
+        let unconfirmed = HistoryItem {
+            height: -1,
+            tx_hash,
+            fee: Some(456),
+            first_seen: Some(1_600_000_000),
+        };
+        let mut out = String::new();
+        confirmed.write_json(&mut out);
+        assert_eq!(
+            out,
+            json!({"height": 123, "tx_hash": tx_hash.to_hex()}).to_string()
+        );
+        out.clear();
+        unconfirmed.write_json(&mut out);
+        assert_eq!(
+            out,
+            json!({"height": -1, "tx_hash": tx_hash.to_hex(), "fee": 456, "first_seen": 1_600_000_000})
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_utxo_summary() {
+        let output = |name: &[u8], height, value| FundingOutput {
+            txn_id: Txid::hash(name),
+            height,
+            output_index: 0,
+            value,
+        };
+        let spent = SpendingInput {
+            txn_id: Txid::hash(b"spending"),
+            height: 0,
+            funding_output: (Txid::hash(b"a"), 0),
+            value: 1000,
+        };
+        let status = Status {
+            confirmed: (vec![output(b"a", 100, 1000), output(b"b", 200, 50)], vec![]),
+            mempool: (vec![output(b"c", 0, 300)], vec![spent]),
+            txn_fees: HashMap::new(),
+            txn_first_seen: HashMap::new(),
+            positions: HashMap::new(),
+        };
+        let summary = status.utxo_summary();
+        assert_eq!(summary.count, 2);
+        assert_eq!(summary.total_value, 350);
+        assert_eq!(summary.largest, Some(300));
+        assert_eq!(summary.smallest, Some(50));
+        assert_eq!(summary.oldest_height, Some(200));
+
+        let empty = Status {
+            confirmed: (vec![], vec![]),
+            mempool: (vec![], vec![]),
+            txn_fees: HashMap::new(),
+            txn_first_seen: HashMap::new(),
+            positions: HashMap::new(),
+        };
+        let summary = empty.utxo_summary();
+        assert_eq!(summary.count, 0);
+        assert_eq!(summary.largest, None);
+        assert_eq!(summary.oldest_height, None);
+    }
+
+    #[test]
+    fn test_history_page() {
+        let output = |name: &[u8], height| FundingOutput {
+            txn_id: Txid::hash(name),
+            height,
+            output_index: 0,
+            value: 1000,
+        };
+        let status = Status {
+            confirmed: (
+                vec![
+                    output(b"a", 100),
+                    output(b"b", 200),
+                    output(b"c", 200),
+                    output(b"d", 300),
+                ],
+                vec![],
+            ),
+            mempool: (vec![output(b"e", 0)], vec![]),
+            txn_fees: HashMap::new(),
+            txn_first_seen: HashMap::new(),
+            positions: HashMap::new(),
+        };
+        let heights = |from_height, limit| -> Vec<i32> {
+            status
+                .history_page(from_height, limit)
+                .iter()
+                .map(HistoryItem::height)
+                .collect()
+        };
+        assert_eq!(heights(0, 0), vec![100, 200, 200, 300, 0]);
+        assert_eq!(heights(0, 1), vec![100]);
+        assert_eq!(heights(101, 1), vec![200, 200]); // a block isn't split
+        assert_eq!(heights(201, 1), vec![300]);
+        assert_eq!(heights(301, 1), vec![0]);
+        assert_eq!(heights(201, 2), vec![300, 0]);
+        assert_eq!(heights(1000, 0), vec![0]);
+        assert_eq!(heights(0, 5), vec![100, 200, 200, 300, 0]);
+    }
+
+    #[test]
+    fn test_history_order() {
+        let output = |name: &[u8], height| FundingOutput {
+            txn_id: Txid::hash(name),
+            height,
+            output_index: 0,
+            value: 1000,
+        };
+        let names: [&[u8]; 4] = [b"a", b"b", b"c", b"d"];
+        let mut status = Status {
+            confirmed: (names.iter().map(|name| output(name, 100)).collect(), vec![]),
+            mempool: (vec![output(b"e", 0)], vec![]),
+            txn_fees: HashMap::new(),
+            txn_first_seen: HashMap::new(),
+            positions: HashMap::new(),
+        };
+        // by their position in the block (instead of their txids)
+        for (position, name) in names.iter().rev().enumerate() {
+            status.positions.insert(Txid::hash(name), position + 1);
+        }
+        let txids: Vec<Txid> = status.history().iter().map(|item| item.tx_hash).collect();
+        let expected: Vec<Txid> = [&b"d"[..], b"c", b"b", b"a", b"e"]
+            .iter()
+            .map(|name| Txid::hash(name))
+            .collect();
+        assert_eq!(txids, expected);
+    }
+
+    #[test]
+    fn test_status_row() {
+        let output = |name: &[u8], height| FundingOutput {
+            txn_id: Txid::hash(name),
+            height,
+            output_index: 1,
+            value: 1000,
+        };
+        let spent = |name: &[u8], height| SpendingInput {
+            txn_id: Txid::hash(name),
+            height,
+            funding_output: (Txid::hash(b"a"), 1),
+            value: 1000,
+        };
+        let confirmed = (
+            vec![output(b"a", 100), output(b"b", 201)],
+            vec![spent(b"c", 200), spent(b"d", 202)],
+        );
+        let blockhash = BlockHash::hash(b"block");
+        let row = status_row(200, &blockhash, &confirmed);
+        let row = StatusRow::from_value(&row.to_row(&[1; 32], None).value).unwrap();
+        assert_eq!(row.height, 200);
+        assert_eq!(row.blockhash[..], blockhash[..]);
+
+        // transactions confirmed after the persisted height are scanned again
+        let (funding, spending) = status_from_row(row);
+        assert_eq!(funding.len(), 1);
+        assert_eq!(funding[0].txn_id, Txid::hash(b"a"));
+        assert_eq!((funding[0].height, funding[0].output_index), (100, 1));
+        assert_eq!(spending.len(), 1);
+        assert_eq!(spending[0].txn_id, Txid::hash(b"c"));
+        assert_eq!(spending[0].funding_output, (Txid::hash(b"a"), 1));
+        assert_eq!((spending[0].height, spending[0].value), (200, 1000));
+    }
+
+    #[test]
+    fn test_status_hasher() {
+        let output = |name: &[u8], height| FundingOutput {
+            txn_id: Txid::hash(name),
+            height,
+            output_index: 0,
+            value: 1000,
+        };
+        let block_hash = |height: u32| Some(BlockHash::hash(&height.to_le_bytes()));
+        let mut status = Status {
+            confirmed: (vec![output(b"b", 100), output(b"a", 100)], vec![]),
+            mempool: (vec![output(b"c", 0)], vec![]),
+            txn_fees: HashMap::new(),
+            txn_first_seen: HashMap::new(),
+            positions: HashMap::new(),
+        };
+        let history = status.history();
+        assert_eq!(history.last().unwrap().height(), 0); // mempool transactions are last
+        let mut hasher = StatusHasher::default();
+        assert_eq!(hasher.update(&history, block_hash), status.hash());
+        assert_eq!(hasher.confirmed_len, 2);
+
+        // a new block confirms the mempool transaction
+        status.confirmed.0.push(output(b"c", 101));
+        status.mempool.0.clear();
+        assert_eq!(hasher.update(&status.history(), block_hash), status.hash());
+        assert_eq!(hasher.confirmed_len, 3);
+
+        // a reorg replaces block 101 (and drops its transaction)
+        status.confirmed.0.pop();
+        status.confirmed.0.push(output(b"d", 102));
+        let reorged = |height: u32| match height {
+            101 => Some(BlockHash::hash(b"reorged")),
+            _ => block_hash(height),
+        };
+        assert_eq!(hasher.update(&status.history(), reorged), status.hash());
+        assert_eq!(hasher.last_block, reorged(102).map(|hash| (102, hash)));
+
+        status.confirmed.0.clear();
+        assert_eq!(hasher.update(&status.history(), reorged), None);
+        assert_eq!(hasher.confirmed_len, 0);
+    }
+
+    #[test]
+    fn test_mempool_fee_rate() {
+        let histogram = [(50.0, 600_000), (20.0, 600_000), (5.0, 2_000_000)];
+        assert_eq!(super::mempool_fee_rate(&[], 2), 0.0); // empty mempool
+        assert!((super::mempool_fee_rate(&histogram, 1) - 20e-5).abs() < 1e-12);
+        assert!((super::mempool_fee_rate(&histogram, 0) - 20e-5).abs() < 1e-12); // as 1 block
+        assert!((super::mempool_fee_rate(&histogram, 2) - 5e-5).abs() < 1e-12);
+        // a shallow mempool is confirmed by the lowest fee rate
+        assert!((super::mempool_fee_rate(&histogram, 10_000) - 5e-5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_merklize() {
+        let left = Sha256dHash::hash(b"left");
+        let right = Sha256dHash::hash(b"right");
+        let expected = Sha256dHash::hash(&[&left[..], &right[..]].concat());
+        assert_eq!(super::merklize(left, right), expected);
+    }
+
+    #[test]
+    fn test_merkle_branch_and_root() {
+        let hashes: Vec<Sha256dHash> = (0u8..3).map(|i| Sha256dHash::hash(&[i])).collect();
+        let workers = WorkerPool::new("test", 0);
+        let levels = super::merkle_levels(hashes.clone(), &workers);
+        assert_eq!(levels.len(), 3);
+        assert_eq!(levels[0].len(), 4); // padded
+        let left = super::merklize(hashes[0], hashes[1]);
+        let right = super::merklize(hashes[2], hashes[2]);
+        let root = super::merklize(left, right);
+
+        assert_eq!(
+            super::merkle_branch_and_root(&levels, 2),
+            (vec![hashes[2], left], root)
+        );
+        assert_eq!(
+            super::merkle_branch_and_root(&levels, 1),
+            (vec![hashes[0], right], root)
+        );
+        assert_eq!(
+            super::create_merkle_branch_and_root(vec![hashes[0]], 0, &workers),
+            (vec![], hashes[0])
+        );
+    }
+
+    #[test]
+    fn test_parallel_merklize() {
+        let hashes: Vec<Sha256dHash> = (0..2 * super::PARALLEL_MERKLE_MIN_PAIRS + 6)
+            .map(|i| Sha256dHash::hash(&i.to_le_bytes()))
+            .collect();
+        let expected = super::merklize_level(&hashes, &WorkerPool::new("test", 0));
+        assert_eq!(expected.len(), hashes.len() / 2);
+        assert_eq!(expected[0], super::merklize(hashes[0], hashes[1]));
+        for threads in 2..6 {
+            let workers = WorkerPool::new("test", threads);
+            assert_eq!(super::merklize_level(&hashes, &workers), expected);
+        }
+    }
+}
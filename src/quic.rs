@@ -0,0 +1,192 @@
+//! An experimental QUIC (RFC 9000) listener for the Electrum RPC: its built-in TLS 1.3 and
+//! independent streams avoid TCP's head-of-line blocking, e.g. for notifications over lossy
+//! mobile networks. Each bidirectional stream carries a separate Electrum session (like a TCP
+//! connection), so a client may multiplex a few of them over one QUIC connection.
+//!
+//! The connections are handled by `quinn` (using a small `tokio` runtime), while the sessions
+//! use blocking reads and writes, like the TCP and TLS ones.
+
+use futures_util::future::{self, Either};
+use futures_util::StreamExt;
+use quinn::{Connecting, Endpoint, NewConnection, RecvStream, SendStream};
+use quinn::{ServerConfig, TransportConfig, VarInt};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::runtime::{self, Handle};
+use tokio::sync::Notify;
+
+use crate::config::Config;
+use crate::errors::*;
+use crate::redact::redacted;
+use crate::tls::{load_certs, load_key, Stream};
+
+const MAX_STREAMS_PER_CONNECTION: u32 = 4;
+
+/// A QUIC listening address, with its TLS configuration (the certificate isn't reloaded, unlike
+/// the TLS listener's).
+#[derive(Clone)]
+pub struct QuicListener {
+    addr: SocketAddr,
+    config: ServerConfig,
+}
+
+impl QuicListener {
+    pub fn from_config(config: &Config) -> Result<Option<QuicListener>> {
+        let (addr, cert_path, key_path) =
+            match (config.electrum_quic_addr, &config.tls_cert, &config.tls_key) {
+                (Some(addr), Some(cert_path), Some(key_path)) => (addr, cert_path, key_path),
+                _ => return Ok(None),
+            };
+        // `quinn` uses a newer `rustls`, whose certificate and key have the same encoding
+        let certs = load_certs(cert_path)?
+            .into_iter()
+            .map(|cert| quinn_rustls::Certificate(cert.0))
+            .collect();
+        let key = quinn_rustls::PrivateKey(load_key(key_path)?.0);
+        let mut server_config = ServerConfig::with_single_cert(certs, key)
+            .chain_err(|| format!("invalid TLS certificate or key {}", key_path.display()))?;
+        let mut transport = TransportConfig::default();
+        transport
+            .max_concurrent_bidi_streams(VarInt::from_u32(MAX_STREAMS_PER_CONNECTION))
+            .max_concurrent_uni_streams(VarInt::from_u32(0));
+        server_config.transport = Arc::new(transport);
+        Ok(Some(QuicListener {
+            addr,
+            config: server_config,
+        }))
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Accepts the clients' streams (until the process exits), passing each one that is
+    /// admitted by `admit` (given its client's address) to `accepted`.
+    pub fn serve<A, F>(self, admit: A, accepted: F)
+    where
+        A: Fn(SocketAddr) -> bool + Send + Sync + 'static,
+        F: Fn(Stream, SocketAddr) + Send + Sync + 'static,
+    {
+        let QuicListener { addr, config } = self;
+        let runtime = runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("quic")
+            .enable_all()
+            .build()
+            .expect("failed to start QUIC runtime");
+        let handle = runtime.handle().clone();
+        let (admit, accepted) = (Arc::new(admit), Arc::new(accepted));
+        runtime.block_on(async {
+            let (_endpoint, mut incoming) = Endpoint::server(config, addr)
+                .unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
+            while let Some(connecting) = incoming.next().await {
+                tokio::spawn(accept_streams(
+                    connecting,
+                    handle.clone(),
+                    Arc::clone(&admit),
+                    Arc::clone(&accepted),
+                ));
+            }
+        });
+    }
+}
+
+async fn accept_streams<A, F>(
+    connecting: Connecting,
+    handle: Handle,
+    admit: Arc<A>,
+    accepted: Arc<F>,
+) where
+    A: Fn(SocketAddr) -> bool,
+    F: Fn(Stream, SocketAddr),
+{
+    let addr = connecting.remote_address();
+    let NewConnection { mut bi_streams, .. } = match connecting.await {
+        Ok(connection) => connection,
+        Err(e) => {
+            debug!("[{}] QUIC handshake failed: {}", redacted(addr), e);
+            return;
+        }
+    };
+    while let Some(streams) = bi_streams.next().await {
+        let (send, recv) = match streams {
+            Ok(streams) => streams,
+            Err(e) => {
+                debug!("[{}] QUIC connection closed: {}", redacted(addr), e);
+                return;
+            }
+        };
+        if admit(addr) {
+            accepted(
+                Stream::Quic(QuicStream::new(send, recv, handle.clone())),
+                addr,
+            );
+        } // otherwise, the streams are reset when dropped
+    }
+}
+
+/// An Electrum session's QUIC stream, whose reads and writes block the calling thread (which
+/// must not be one of the runtime's).
+pub struct QuicStream {
+    send: Arc<Mutex<SendStream>>,
+    recv: Arc<Mutex<RecvStream>>,
+    closed: Arc<Notify>, // wakes up a blocked reader, like shutting down a TCP socket
+    runtime: Handle,
+}
+
+impl QuicStream {
+    fn new(send: SendStream, recv: RecvStream, runtime: Handle) -> QuicStream {
+        QuicStream {
+            send: Arc::new(Mutex::new(send)),
+            recv: Arc::new(Mutex::new(recv)),
+            closed: Arc::new(Notify::new()),
+            runtime,
+        }
+    }
+
+    pub fn try_clone(&self) -> QuicStream {
+        QuicStream {
+            send: Arc::clone(&self.send),
+            recv: Arc::clone(&self.recv),
+            closed: Arc::clone(&self.closed),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    /// Finishes the sending side, and stops reading (the connection's other streams are kept).
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        self.closed.notify_one(); // stored, if there is no blocked reader yet
+        let mut send = self.send.lock().unwrap();
+        self.runtime
+            .block_on(send.finish())
+            .map_err(|e| io::Error::new(io::ErrorKind::NotConnected, e))
+    }
+}
+
+impl Read for QuicStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut recv = self.recv.lock().unwrap();
+        let read = Box::pin(recv.read(buf));
+        let closed = Box::pin(self.closed.notified());
+        match self.runtime.block_on(future::select(read, closed)) {
+            Either::Left((Ok(Some(n)), _)) => Ok(n),
+            Either::Left((Ok(None), _)) => Ok(0), // the client has finished its stream
+            Either::Left((Err(e), _)) => Err(io::Error::new(io::ErrorKind::ConnectionReset, e)),
+            Either::Right(_) => Ok(0),
+        }
+    }
+}
+
+impl Write for QuicStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut send = self.send.lock().unwrap();
+        self.runtime
+            .block_on(send.write(buf))
+            .map_err(|e| io::Error::new(io::ErrorKind::ConnectionReset, e))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(()) // the written data is sent by the runtime
+    }
+}
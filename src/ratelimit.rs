@@ -0,0 +1,120 @@
+//! Fixed-window rate limiting, per peer (IP address) and globally.
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::errors::*;
+
+struct Window {
+    start: Instant,
+    total: usize,
+    peers: HashMap<IpAddr, usize>,
+}
+
+pub struct RateLimiter {
+    per_peer: usize, // 0 - unlimited
+    global: usize,   // 0 - unlimited
+    duration: Duration,
+    unit: &'static str, // of the counted costs
+    window: Mutex<Window>,
+}
+
+impl RateLimiter {
+    pub fn new(per_peer: usize, global: usize, duration: Duration) -> RateLimiter {
+        RateLimiter {
+            per_peer,
+            global,
+            duration,
+            unit: "requests",
+            window: Mutex::new(Window {
+                start: Instant::now(),
+                total: 0,
+                peers: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Counts the costs in another unit (e.g. bytes), for the errors' messages.
+    pub fn with_unit(self, unit: &'static str) -> RateLimiter {
+        RateLimiter { unit, ..self }
+    }
+
+    /// Counts a new request from `peer`, failing if it exceeds the per-peer or the global limit.
+    pub fn check(&self, peer: IpAddr) -> Result<()> {
+        self.charge(peer, 1)
+    }
+
+    /// Counts a request of the given cost (i.e. as `cost` requests).
+    pub fn charge(&self, peer: IpAddr, cost: usize) -> Result<()> {
+        self.charge_at(peer, cost, Instant::now())
+    }
+
+    #[cfg(test)]
+    fn check_at(&self, peer: IpAddr, now: Instant) -> Result<()> {
+        self.charge_at(peer, 1, now)
+    }
+
+    fn charge_at(&self, peer: IpAddr, cost: usize, now: Instant) -> Result<()> {
+        let mut window = self.window.lock().unwrap();
+        if now.saturating_duration_since(window.start) >= self.duration {
+            // start a new window (dropping the previous peers, to bound memory usage)
+            window.start = now;
+            window.total = 0;
+            window.peers.clear();
+        }
+        if self.global > 0 && window.total + cost > self.global {
+            bail!(ErrorKind::ExcessiveResourceUsage(format!(
+                "rate limit exceeded: {} {} per {:?}",
+                self.global, self.unit, self.duration
+            )));
+        }
+        let count = window.peers.entry(peer).or_insert(0);
+        if self.per_peer > 0 && *count + cost > self.per_peer {
+            bail!(ErrorKind::ExcessiveResourceUsage(format!(
+                "rate limit exceeded: {} {} per {:?} per client",
+                self.per_peer, self.unit, self.duration
+            )));
+        }
+        *count += cost;
+        window.total += cost;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_rate_limiter() {
+        let minute = Duration::from_secs(60);
+        let limiter = RateLimiter::new(2, 3, minute);
+        let a: IpAddr = "127.0.0.1".parse().unwrap();
+        let b: IpAddr = "::1".parse().unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_at(a, now).is_ok());
+        assert!(limiter.check_at(a, now).is_ok());
+        assert!(limiter.check_at(a, now).is_err()); // per-peer limit
+        assert!(limiter.check_at(b, now).is_ok());
+        assert!(limiter.check_at(b, now).is_err()); // global limit
+
+        let later = now + minute;
+        assert!(limiter.check_at(a, later).is_ok());
+        assert!(limiter.check_at(b, later).is_ok());
+
+        let weighted = RateLimiter::new(10, 0, minute);
+        assert!(weighted.charge_at(a, 8, now).is_ok());
+        assert!(weighted.charge_at(a, 5, now).is_err()); // rejected requests aren't counted
+        assert!(weighted.charge_at(a, 2, now).is_ok());
+        assert!(weighted.charge_at(a, 1, now).is_err());
+
+        let unlimited = RateLimiter::new(0, 0, minute);
+        for _ in 0..100 {
+            assert!(unlimited.check_at(a, now).is_ok());
+        }
+    }
+}
@@ -0,0 +1,429 @@
+//! Indexing of serialized blocks, without deserializing them into `Block` structures: only the
+//! outpoints and scripts needed for the index rows are read (directly from the wire format).
+#![cfg_attr(feature = "liquid", allow(dead_code))]
+use bitcoin::hashes::Hash;
+use sha2::{Digest, Sha256};
+
+use crate::chain::{BlockHash, OutPoint, Txid};
+use crate::errors::*;
+use crate::index::{header_row, txid_search_row, TxInRow, TxOutRow, TxRow};
+use crate::store::Row;
+
+const HEADER_LEN: usize = 80;
+const MERKLE_ROOT_OFFSET: usize = 36;
+// OP_RETURN, a 36 bytes push and the BIP141 commitment header.
+const WITNESS_COMMITMENT_PREFIX: &[u8] = &[0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+// The minimal serialized sizes, bounding the capacities allocated for the counts read from a block.
+const MIN_TX_LEN: usize = 10; // version, no inputs, no outputs and locktime
+const MIN_INPUT_LEN: usize = 41; // outpoint, an empty scriptSig and sequence
+const MIN_OUTPUT_LEN: usize = 9; // value and an empty script
+
+/// The rows (and some statistics) of an indexed block.
+pub struct IndexedBlock {
+    pub blockhash: BlockHash,
+    pub rows: Vec<Row>,
+    pub txns: usize,
+    pub weight: usize, // of the block's transactions
+}
+
+fn sha256d(parts: &[&[u8]]) -> [u8; 32] {
+    let first = parts
+        .iter()
+        .fold(Sha256::new(), |sha2, part| sha2.chain(part))
+        .finalize();
+    Sha256::digest(&first).into()
+}
+
+/// Returns the hash of a serialized block (or header).
+#[cfg(not(feature = "liquid"))]
+pub fn block_hash(block: &[u8]) -> Result<BlockHash> {
+    let header = block.get(..HEADER_LEN).chain_err(|| "truncated header")?;
+    Ok(BlockHash::from_inner(sha256d(&[header])))
+}
+
+/// Returns the hash of a serialized block (Elements headers have a variable length).
+#[cfg(feature = "liquid")]
+pub fn block_hash(block: &[u8]) -> Result<BlockHash> {
+    use crate::chain::{BlockHeader, Decodable};
+
+    let header = BlockHeader::consensus_decode(block).chain_err(|| "failed to parse header")?;
+    Ok(header.block_hash())
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).chain_err(|| "invalid length")?;
+        let result = self
+            .bytes
+            .get(self.pos..end)
+            .chain_err(|| format!("truncated block: {} bytes at {}", len, self.pos))?;
+        self.pos = end;
+        Ok(result)
+    }
+
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.bytes.get(self.pos + offset).cloned()
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |acc, &b| (acc << 8) | u32::from(b)))
+    }
+
+    fn varint(&mut self) -> Result<usize> {
+        let prefix = self.take(1)?[0];
+        let len = match prefix {
+            0xfd => 2,
+            0xfe => 4,
+            0xff => 8,
+            value => return Ok(value as usize),
+        };
+        let value = self
+            .take(len)?
+            .iter()
+            .rev()
+            .fold(0u64, |acc, &b| (acc << 8) | u64::from(b));
+        Ok(value as usize)
+    }
+
+    // Returns the capacity for `count` elements of at least `min_len` bytes, so a corrupted count
+    // can't allocate more than the rest of the block.
+    fn capacity(&self, count: usize, min_len: usize) -> usize {
+        count.min(self.bytes.len().saturating_sub(self.pos) / min_len)
+    }
+
+    fn var_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()?;
+        self.take(len)
+    }
+}
+
+/// A transaction's fields needed for indexing (borrowed from the serialized block).
+struct RawTransaction<'a> {
+    txid: Txid,
+    bytes: &'a [u8],
+    prevouts: Vec<OutPoint>,
+    scripts: Vec<&'a [u8]>,
+    weight: usize,
+    segwit: bool,
+    first_witness_item: Option<&'a [u8]>, // the witness reserved value (for the coinbase)
+}
+
+/// Reads a single (serialized) transaction.
+fn read_transaction<'a>(reader: &mut Reader<'a>) -> Result<RawTransaction<'a>> {
+    let start = reader.pos;
+    let version = reader.take(4)?;
+    // BIP144 marker and flag (blocks can't contain transactions without inputs)
+    let segwit = reader.peek(0) == Some(0) && reader.peek(1) == Some(1);
+    if segwit {
+        reader.take(2)?;
+    }
+    let io_start = reader.pos;
+    let inputs_count = reader.varint()?;
+    let mut prevouts = Vec::with_capacity(reader.capacity(inputs_count, MIN_INPUT_LEN));
+    for _ in 0..inputs_count {
+        let txid = Txid::from_slice(reader.take(32)?).expect("invalid txid length");
+        let vout = reader.u32()?;
+        reader.var_bytes()?; // scriptSig
+        reader.take(4)?; // sequence
+        if txid != Txid::default() {
+            prevouts.push(OutPoint { txid, vout }); // skip coinbase input
+        }
+    }
+    let outputs_count = reader.varint()?;
+    let mut scripts = Vec::with_capacity(reader.capacity(outputs_count, MIN_OUTPUT_LEN));
+    for _ in 0..outputs_count {
+        reader.take(8)?; // value
+        let len = reader.varint()?;
+        scripts.push(reader.take(len)?);
+    }
+    let io_end = reader.pos;
+    let mut first_witness_item = None;
+    if segwit {
+        for _ in 0..inputs_count {
+            let items = reader.varint()?;
+            for _ in 0..items {
+                let item = reader.var_bytes()?;
+                first_witness_item = first_witness_item.or(Some(item));
+            }
+        }
+    }
+    let locktime = reader.take(4)?;
+
+    let io = &reader.bytes[io_start..io_end];
+    let base_size = version.len() + io.len() + locktime.len();
+    let total_size = reader.pos - start;
+    Ok(RawTransaction {
+        txid: Txid::from_inner(sha256d(&[version, io, locktime])),
+        bytes: &reader.bytes[start..reader.pos],
+        prevouts,
+        scripts,
+        weight: base_size * 3 + total_size,
+        segwit,
+        first_witness_item,
+    })
+}
+
+fn merkle_root(mut hashes: Vec<[u8; 32]>) -> [u8; 32] {
+    if hashes.is_empty() {
+        return [0; 32];
+    }
+    while hashes.len() > 1 {
+        // the last hash of an odd level is paired with itself
+        hashes = hashes
+            .chunks(2)
+            .map(|pair| sha256d(&[&pair[0], pair.last().unwrap()]))
+            .collect();
+    }
+    hashes[0]
+}
+
+/// Checks the transactions against the header's merkle root and the coinbase's witness
+/// commitment (see BIP141), before indexing them.
+fn verify_block(
+    header: &[u8],
+    coinbase: &RawTransaction,
+    txids: Vec<[u8; 32]>,
+    mut wtxids: Vec<[u8; 32]>,
+    has_witness: bool,
+) -> Result<()> {
+    let expected_root = &header[MERKLE_ROOT_OFFSET..MERKLE_ROOT_OFFSET + 32];
+    if merkle_root(txids)[..] != *expected_root {
+        bail!("merkle root mismatch");
+    }
+    // the last matching output is the commitment
+    let commitment = coinbase
+        .scripts
+        .iter()
+        .rev()
+        .find(|script| script.len() >= 38 && script.starts_with(WITNESS_COMMITMENT_PREFIX))
+        .map(|script| &script[WITNESS_COMMITMENT_PREFIX.len()..38]);
+    match commitment {
+        Some(commitment) => {
+            let reserved = coinbase
+                .first_witness_item
+                .filter(|item| item.len() == 32)
+                .chain_err(|| "missing witness reserved value")?;
+            wtxids[0] = [0; 32]; // the coinbase's wtxid
+            if sha256d(&[&merkle_root(wtxids), reserved])[..] != *commitment {
+                bail!("witness commitment mismatch");
+            }
+        }
+        // without a commitment, the block can't contain witness data
+        None if has_witness => bail!("unexpected witness data"),
+        None => (),
+    }
+    Ok(())
+}
+
+/// Indexes a single (serialized) transaction, returning it.
+fn index_transaction<'a>(
+    reader: &mut Reader<'a>,
+    height: usize,
+    rows: &mut Vec<Row>,
+) -> Result<RawTransaction<'a>> {
+    let tx = read_transaction(reader)?;
+    let txid = tx.txid;
+    rows.extend(
+        tx.prevouts
+            .iter()
+            .map(|prevout| TxInRow::new(&txid, prevout).to_row()),
+    );
+    rows.extend(
+        tx.scripts
+            .iter()
+            .map(|script| TxOutRow::from_script(&txid, script).to_row()),
+    );
+    rows.push(TxRow::new(&txid, height as u32).to_row());
+    rows.extend(txid_search_row(&txid));
+    Ok(tx)
+}
+
+/// Returns the txids and serialized transactions of a serialized block (in the block's order).
+#[cfg(not(feature = "liquid"))]
+pub fn block_transactions(block: &[u8]) -> Result<Vec<(Txid, Vec<u8>)>> {
+    let mut reader = Reader {
+        bytes: block,
+        pos: HEADER_LEN,
+    };
+    let txns = reader.varint()?;
+    (0..txns)
+        .map(|_| {
+            let tx = read_transaction(&mut reader)?;
+            Ok((tx.txid, tx.bytes.to_vec()))
+        })
+        .collect()
+}
+
+/// Elements transactions use a different wire format, so the block is fully deserialized.
+#[cfg(feature = "liquid")]
+pub fn block_transactions(block: &[u8]) -> Result<Vec<(Txid, Vec<u8>)>> {
+    use crate::chain::{deserialize, serialize, Block};
+
+    let block: Block = deserialize(block).chain_err(|| "failed to parse block")?;
+    Ok(block
+        .txdata
+        .iter()
+        .map(|tx| (tx.txid(), serialize(tx)))
+        .collect())
+}
+
+/// Returns the txids of a serialized block's transactions (in the block's order).
+#[cfg(not(feature = "liquid"))]
+pub fn block_txids(block: &[u8]) -> Result<Vec<Txid>> {
+    let mut reader = Reader {
+        bytes: block,
+        pos: HEADER_LEN,
+    };
+    let txns = reader.varint()?;
+    (0..txns)
+        .map(|_| Ok(read_transaction(&mut reader)?.txid))
+        .collect()
+}
+
+#[cfg(feature = "liquid")]
+pub fn block_txids(block: &[u8]) -> Result<Vec<Txid>> {
+    use crate::chain::{deserialize, Block};
+
+    let block: Block = deserialize(block).chain_err(|| "failed to parse block")?;
+    Ok(block.txdata.iter().map(|tx| tx.txid()).collect())
+}
+
+/// Returns the same rows as `index::index_block()`, for a serialized block.
+#[cfg(not(feature = "liquid"))]
+pub fn index_raw_block(block: &[u8], height: usize) -> Result<IndexedBlock> {
+    let blockhash = block_hash(block)?;
+    let mut reader = Reader {
+        bytes: block,
+        pos: HEADER_LEN,
+    };
+    let txns = reader.varint()?;
+    let mut rows = vec![];
+    let mut weight = 0;
+    let mut coinbase = None;
+    let mut txids = Vec::with_capacity(reader.capacity(txns, MIN_TX_LEN));
+    let mut wtxids = Vec::with_capacity(reader.capacity(txns, MIN_TX_LEN));
+    let mut has_witness = false;
+    for _ in 0..txns {
+        let tx = index_transaction(&mut reader, height, &mut rows)
+            .chain_err(|| format!("failed to parse block {}", blockhash))?;
+        weight += tx.weight;
+        txids.push(tx.txid.into_inner());
+        wtxids.push(if tx.segwit {
+            sha256d(&[tx.bytes])
+        } else {
+            tx.txid.into_inner()
+        });
+        has_witness |= tx.segwit;
+        coinbase = coinbase.or(Some(tx));
+    }
+    if reader.pos != block.len() {
+        bail!(
+            "unexpected {} bytes after block {}",
+            block.len() - reader.pos,
+            blockhash
+        );
+    }
+    let coinbase = coinbase.chain_err(|| format!("block {} has no transactions", blockhash))?;
+    verify_block(&block[..HEADER_LEN], &coinbase, txids, wtxids, has_witness)
+        .chain_err(|| format!("invalid block {}", blockhash))?;
+    rows.push(header_row(&blockhash, block[..HEADER_LEN].to_vec()));
+    Ok(IndexedBlock {
+        blockhash,
+        rows,
+        txns,
+        weight,
+    })
+}
+
+/// Elements transactions use a different wire format, so they are fully deserialized.
+#[cfg(feature = "liquid")]
+pub fn index_raw_block(block: &[u8], height: usize) -> Result<IndexedBlock> {
+    use crate::chain::{deserialize, Block};
+    use crate::index::index_block;
+
+    let block: Block = deserialize(block).chain_err(|| "failed to parse block")?;
+    Ok(IndexedBlock {
+        blockhash: block.block_hash(),
+        rows: index_block(&block, height).collect(),
+        txns: block.txdata.len(),
+        weight: block.txdata.iter().map(|tx| tx.get_weight()).sum(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_transactions, block_txids, index_raw_block, merkle_root, sha256d};
+    use crate::chain::{deserialize, serialize, Block, Txid};
+    use crate::index::index_block;
+
+    fn check_raw_block(block_bytes: &[u8]) {
+        let block: Block = deserialize(block_bytes).unwrap();
+        let indexed = index_raw_block(block_bytes, 123).unwrap();
+        assert_eq!(indexed.blockhash, block.block_hash());
+        assert_eq!(indexed.txns, block.txdata.len());
+        let weight: usize = block.txdata.iter().map(|tx| tx.get_weight()).sum();
+        assert_eq!(indexed.weight, weight);
+        let expected: Vec<_> = index_block(&block, 123).collect();
+        assert_eq!(indexed.rows.len(), expected.len());
+        for (row, expected) in indexed.rows.iter().zip(expected.iter()) {
+            assert_eq!(row.key, expected.key);
+            assert_eq!(row.value, expected.value);
+        }
+        assert!(index_raw_block(&block_bytes[..block_bytes.len() - 1], 123).is_err());
+        let mut tampered = block_bytes.to_vec();
+        *tampered.last_mut().unwrap() ^= 1; // the last transaction's locktime
+        assert!(index_raw_block(&tampered, 123).is_err());
+        let txns = block_transactions(block_bytes).unwrap();
+        assert_eq!(txns.len(), block.txdata.len());
+        for ((txid, bytes), tx) in txns.iter().zip(block.txdata.iter()) {
+            assert_eq!(*txid, tx.txid());
+            assert_eq!(*bytes, serialize(tx));
+        }
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        assert_eq!(block_txids(block_bytes).unwrap(), txids);
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let (a, b, c) = ([1; 32], [2; 32], [3; 32]);
+        assert_eq!(merkle_root(vec![]), [0; 32]);
+        assert_eq!(merkle_root(vec![a]), a);
+        let ab = sha256d(&[&a, &b]);
+        let cc = sha256d(&[&c, &c]);
+        assert_eq!(merkle_root(vec![a, b, c]), sha256d(&[&ab, &cc]));
+    }
+
+    #[test]
+    fn test_index_raw_block() {
+        let blob = hex::decode(include_str!("tests/fixtures/incomplete_block.hex").trim()).unwrap();
+        // see `bulk::tests::test_incomplete_block_parsing()` for the blocks' layout
+        check_raw_block(&blob[8..19297]);
+        check_raw_block(&blob[19313..]);
+    }
+
+    #[test]
+    fn test_corrupted_counts() {
+        let huge = [0xff; 9]; // a varint of 2^64 - 1
+        let block = [&[0u8; 80][..], &huge].concat();
+        assert!(index_raw_block(&block, 0).is_err());
+
+        let mut tx = vec![1, 0, 0, 0];
+        tx.extend_from_slice(&huge); // inputs
+        let block = [&[0u8; 80][..], &[1], &tx].concat();
+        assert!(index_raw_block(&block, 0).is_err());
+
+        let mut tx = vec![1, 0, 0, 0, 0]; // no inputs
+        tx.extend_from_slice(&huge); // outputs
+        let block = [&[0u8; 80][..], &[1], &tx].concat();
+        assert!(index_raw_block(&block, 0).is_err());
+    }
+}
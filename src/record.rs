@@ -0,0 +1,305 @@
+//! Recording of Electrum client sessions (requests and responses, as JSON lines), and replaying
+//! them against a server (see `electrs replay`), e.g. for regression testing of protocol behavior
+//! with real wallet traffic.
+//!
+//! Script hashes and addresses may be anonymized when recording (replaced by salted hashes). Since
+//! their responses can't be reproduced, only the requests' success (or failure) is recorded for
+//! them.
+use serde_json::{from_str, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::config::Config;
+use crate::errors::*;
+use crate::redact::random_salt;
+
+// The first parameter of these methods (a script hash or an address, or a list of script hashes)
+// identifies a wallet.
+const WALLET_METHODS_PREFIXES: &[&str] = &[
+    "blockchain.scripthash.",
+    "blockchain.scripthashes.",
+    "blockchain.address.",
+];
+// The parameters of these methods (e.g. passwords) are not recorded.
+const SECRET_METHODS: &[&str] = &["server.authenticate"];
+
+fn anonymize(salt: u64, script_hash_or_address: &str) -> String {
+    let mut sha2 = Sha256::new();
+    sha2.update(&salt.to_le_bytes());
+    sha2.update(script_hash_or_address.as_bytes());
+    hex::encode(sha2.finalize())
+}
+
+fn is_error(response: &Value) -> bool {
+    response
+        .get("error")
+        .map_or(false, |error| !error.is_null())
+}
+
+pub struct Recorder {
+    file: Mutex<BufWriter<File>>,
+    salt: Option<u64>, // for anonymizing script hashes
+    sessions: AtomicUsize,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, anonymize: bool) -> Result<Recorder> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .chain_err(|| format!("failed to open {:?}", path))?;
+        info!("recording client sessions to {:?}", path);
+        Ok(Recorder {
+            file: Mutex::new(BufWriter::new(file)),
+            salt: if anonymize { Some(random_salt()) } else { None },
+            sessions: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn from_config(config: &Config) -> Result<Option<Arc<Recorder>>> {
+        match config.record_sessions {
+            Some(ref path) => Ok(Some(Arc::new(Recorder::create(
+                path,
+                config.anonymize_recorded_sessions,
+            )?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns a new session ID (unique across the recordings appended to the same file).
+    pub fn new_session(&self) -> String {
+        let session = self.sessions.fetch_add(1, Ordering::Relaxed);
+        format!("{}.{}", process::id(), session)
+    }
+
+    fn entry(&self, session: &str, request: &Value, response: &str) -> Value {
+        let mut request = request.clone();
+        let method = request["method"].as_str().unwrap_or("").to_owned();
+        let response: Value = from_str(response).unwrap_or(Value::Null);
+        let mut reproducible = true;
+        if SECRET_METHODS.contains(&method.as_str()) {
+            request["params"] = json!([]);
+            reproducible = false;
+        }
+        if let Some(salt) = self.salt {
+            let identifies_wallet = WALLET_METHODS_PREFIXES
+                .iter()
+                .any(|prefix| method.starts_with(prefix));
+            if identifies_wallet {
+                let param = &mut request["params"][0];
+                let anonymized = match param {
+                    Value::String(param) => Some(json!(anonymize(salt, param))),
+                    Value::Array(params) => Some(
+                        params
+                            .iter()
+                            .map(|param| match param.as_str() {
+                                Some(param) => json!(anonymize(salt, param)),
+                                None => json!(anonymize(salt, &param.to_string())),
+                            })
+                            .collect(),
+                    ),
+                    _ => None,
+                };
+                // their responses (e.g. the statuses) aren't recorded either
+                if let Some(anonymized) = anonymized {
+                    *param = anonymized;
+                    reproducible = false;
+                }
+            }
+        }
+        if reproducible {
+            json!({"session": session, "request": request, "response": response})
+        } else {
+            json!({"session": session, "request": request, "failed": is_error(&response)})
+        }
+    }
+
+    /// Appends a request and its (serialized) response to the recording.
+    pub fn record(&self, session: &str, request: &Value, response: &str) {
+        let entry = self.entry(session, request, response);
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", entry).and_then(|()| file.flush()) {
+            warn!("failed to record session {}: {}", session, e);
+        }
+    }
+}
+
+// Returns the recorded sessions' entries (by their first appearance).
+fn read_sessions(path: &Path) -> Result<Vec<(String, Vec<Value>)>> {
+    let file = File::open(path).chain_err(|| format!("failed to open {:?}", path))?;
+    let mut sessions: Vec<(String, Vec<Value>)> = vec![];
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.chain_err(|| format!("failed to read {:?}", path))?;
+        let entry: Value =
+            from_str(&line).chain_err(|| format!("invalid entry at {:?}:{}", path, index + 1))?;
+        let session = entry["session"].to_string();
+        match sessions.iter_mut().find(|(id, _)| *id == session) {
+            Some((_, entries)) => entries.push(entry),
+            None => sessions.push((session, vec![entry])),
+        }
+    }
+    Ok(sessions)
+}
+
+// Returns a description of the mismatch (if the response doesn't match the recorded one).
+// Error messages may change between builds, so only the errors' codes are compared.
+fn compare(entry: &Value, response: &Value) -> Option<String> {
+    let failed = is_error(response);
+    let matches = match entry.get("response") {
+        Some(expected) if failed => expected["error"]["code"] == response["error"]["code"],
+        Some(expected) => !is_error(expected) && expected["result"] == response["result"],
+        None => entry["failed"].as_bool() == Some(failed),
+    };
+    if matches {
+        return None;
+    }
+    let expected = match entry.get("response") {
+        Some(expected) => expected.to_string(),
+        None if failed => "a result".to_owned(),
+        None => "an error".to_owned(),
+    };
+    Some(format!("expected {}, got {}", expected, response))
+}
+
+struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Client {
+    fn connect(addr: SocketAddr) -> Result<Client> {
+        let stream =
+            TcpStream::connect(addr).chain_err(|| format!("failed to connect {}", addr))?;
+        let reader = BufReader::new(stream.try_clone().chain_err(|| "failed to clone stream")?);
+        Ok(Client { stream, reader })
+    }
+
+    /// Returns the response to the request (skipping any notifications sent before it).
+    fn call(&mut self, request: &Value) -> Result<Value> {
+        self.stream
+            .write_all((request.to_string() + "\n").as_bytes())
+            .chain_err(|| "failed to send request")?;
+        loop {
+            let mut line = String::new();
+            self.reader
+                .read_line(&mut line)
+                .chain_err(|| "failed to read response")?;
+            if line.is_empty() {
+                bail!("server disconnected");
+            }
+            let response: Value =
+                from_str(&line).chain_err(|| format!("invalid JSON: {}", line))?;
+            if response.get("id") == request.get("id") {
+                return Ok(response);
+            }
+        }
+    }
+}
+
+/// Replays the recorded sessions (each on its own connection) against the server at `addr`,
+/// reporting the responses which don't match the recorded ones.
+pub fn replay(addr: SocketAddr, path: &Path) -> Result<()> {
+    let sessions = read_sessions(path)?;
+    let (mut requests, mut mismatches) = (0, 0);
+    for (session, entries) in &sessions {
+        let mut client = Client::connect(addr)?;
+        for entry in entries {
+            requests += 1;
+            let response = client.call(&entry["request"])?;
+            if let Some(mismatch) = compare(entry, &response) {
+                mismatches += 1;
+                println!("session {}: {}: {}", session, entry["request"], mismatch);
+            }
+        }
+    }
+    println!(
+        "replayed {} requests ({} sessions): {} mismatches",
+        requests,
+        sessions.len(),
+        mismatches
+    );
+    if mismatches > 0 {
+        bail!("{} responses don't match the recording", mismatches);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compare, Recorder};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_record_entry() {
+        let file = tempfile();
+        let mut recorder = Recorder {
+            file: Mutex::new(std::io::BufWriter::new(file)),
+            salt: None,
+            sessions: AtomicUsize::new(0),
+        };
+        let request =
+            json!({"id": 1, "method": "blockchain.scripthash.get_balance", "params": ["ab"]});
+        let response = r#"{"id":1,"jsonrpc":"2.0","result":{"confirmed":1}}"#;
+        let entry = recorder.entry("s", &request, response);
+        assert_eq!(entry["request"], request);
+        assert_eq!(entry["response"]["result"], json!({"confirmed": 1}));
+        assert_eq!(
+            compare(&entry, &json!({"id": 1, "result": {"confirmed": 1}})),
+            None
+        );
+        assert!(compare(&entry, &json!({"id": 1, "result": {"confirmed": 2}})).is_some());
+        assert!(compare(&entry, &json!({"id": 1, "error": {"code": 1}})).is_some());
+
+        recorder.salt = Some(1);
+        let entry = recorder.entry("s", &request, response);
+        let anonymized = entry["request"]["params"][0].as_str().unwrap();
+        assert_eq!(anonymized.len(), 64);
+        assert_ne!(anonymized, "ab");
+        assert_eq!(entry["failed"], json!(false));
+        assert_eq!(compare(&entry, &json!({"id": 1, "result": 0})), None);
+        assert!(compare(&entry, &json!({"id": 1, "error": {"code": 1}})).is_some());
+
+        let address = "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq";
+        let request =
+            json!({"id": 3, "method": "blockchain.address.get_history", "params": [address]});
+        let entry = recorder.entry("s", &request, r#"{"id":3,"result":[]}"#);
+        let anonymized = entry["request"]["params"][0].as_str().unwrap();
+        assert_eq!(anonymized.len(), 64);
+        assert_ne!(anonymized, address);
+        assert!(entry.get("response").is_none());
+        assert_eq!(entry["failed"], json!(false));
+
+        let request = json!({
+            "id": 4,
+            "method": "blockchain.scripthashes.subscribe",
+            "params": [["ab", "cd"]],
+        });
+        let entry = recorder.entry("s", &request, r#"{"id":4,"result":["ef",null]}"#);
+        let anonymized = entry["request"]["params"][0].as_array().unwrap();
+        assert_eq!(anonymized.len(), 2);
+        assert!(anonymized.iter().all(|h| h.as_str().unwrap().len() == 64));
+        assert_eq!(anonymized[0], json!(super::anonymize(1, "ab"))); // as when subscribed alone
+        assert_ne!(anonymized[0], anonymized[1]);
+        assert!(entry.get("response").is_none()); // nor the statuses
+
+        let request = json!({"id": 2, "method": "server.authenticate", "params": ["secret"]});
+        let entry = recorder.entry("s", &request, r#"{"id":2,"error":{"code":1}}"#);
+        assert_eq!(entry["request"]["params"], json!([]));
+        assert_eq!(entry["failed"], json!(true));
+    }
+
+    fn tempfile() -> std::fs::File {
+        let path = std::env::temp_dir().join(format!("electrs-record-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file
+    }
+}
@@ -0,0 +1,129 @@
+//! Log redaction (see `--redact-logs`): client addresses, script hashes and txids are replaced by
+//! short salted hashes, so they can be correlated within a single run (but not across runs).
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SALT: AtomicU64 = AtomicU64::new(0);
+
+const HASH_HEX_LEN: usize = 64;
+
+pub fn random_salt() -> u64 {
+    let mut salt = [0u8; 8];
+    File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut salt))
+        .expect("failed to read /dev/urandom");
+    u64::from_le_bytes(salt)
+}
+
+/// Enables redaction for the rest of the process' lifetime, using a random salt.
+pub fn enable() {
+    SALT.store(random_salt(), Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn token(salt: u64, value: &str) -> String {
+    let mut sha2 = Sha256::new();
+    sha2.update(&salt.to_le_bytes());
+    sha2.update(value.as_bytes());
+    format!("<{}>", hex::encode(&sha2.finalize()[..4]))
+}
+
+/// Replaces each hex-encoded hash (i.e. script hash or txid) in `text` by its token.
+fn redact_hashes(salt: u64, text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(|c: char| c.is_ascii_hexdigit()) {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let len = rest
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or_else(|| rest.len());
+        if len == HASH_HEX_LEN {
+            result.push_str(&token(salt, &rest[..len]));
+        } else {
+            result.push_str(&rest[..len]);
+        }
+        rest = &rest[len..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Displays a value (e.g. a client address or a txid) as a token, if redaction is enabled.
+pub struct Redacted<T>(T);
+
+pub fn redacted<T: fmt::Display>(value: T) -> Redacted<T> {
+    Redacted(value)
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if is_enabled() {
+            write!(
+                f,
+                "{}",
+                token(SALT.load(Ordering::Relaxed), &self.0.to_string())
+            )
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+/// Displays a text (e.g. RPC parameters or an error message) with its hashes replaced by tokens,
+/// if redaction is enabled.
+pub struct RedactedHashes<T>(T);
+
+pub fn redacted_hashes<T: fmt::Display>(text: T) -> RedactedHashes<T> {
+    RedactedHashes(text)
+}
+
+impl<T: fmt::Display> fmt::Display for RedactedHashes<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if is_enabled() {
+            let salt = SALT.load(Ordering::Relaxed);
+            write!(f, "{}", redact_hashes(salt, &self.0.to_string()))
+        } else {
+            self.0.fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{redact_hashes, token};
+
+    #[test]
+    fn test_redact_hashes() {
+        let hash = "c6a4d1cd9b9ed4c1dd2b5c1e7fa6c6e8d4b6b3a0fd0cb8ec3ce0e1b1f8e1f3a1";
+        let text = format!(
+            "rpc #1 blockchain.scripthash.subscribe [String(\"{}\")] failed",
+            hash
+        );
+        let redacted = redact_hashes(1, &text);
+        assert_eq!(
+            redacted,
+            format!(
+                "rpc #1 blockchain.scripthash.subscribe [String(\"{}\")] failed",
+                token(1, hash)
+            )
+        );
+        assert!(!redacted.contains(hash));
+
+        // same salt & value => same token (for correlation)
+        assert_eq!(token(1, hash), token(1, hash));
+        assert_ne!(token(1, hash), token(2, hash));
+
+        // short hex strings (e.g. numbers) are kept
+        assert_eq!(redact_hashes(1, "height 123 abc"), "height 123 abc");
+        assert_eq!(redact_hashes(1, hash), token(1, hash));
+    }
+}
@@ -0,0 +1,526 @@
+//! Index replication: a primary streams its index writes (including the new blocks' headers) to
+//! followers, which serve clients from a copy of the primary's index without syncing and indexing
+//! blocks themselves (they still use their own bitcoind for fetching transactions and tracking the
+//! mempool).
+//!
+//! A follower starts from a copy of the primary's index (e.g. a filesystem snapshot), and resumes
+//! from its last indexed block, as long as the primary still keeps the following write batches.
+//!
+//! The primary and its followers prove to each other that they know the shared secret (see
+//! `replication_secret`), and the primary's messages are authenticated (but not encrypted), so a
+//! follower applies only the primary's batches. The rows are sent as stored, i.e. with their
+//! values encrypted if the index is encrypted (so the followers need the primary's keys).
+use bitcoin::hashes::{hmac, sha256, Hash, HashEngine};
+use error_chain::ChainedError;
+use std::collections::VecDeque;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::errors::*;
+use crate::redact::random_salt;
+use crate::store::{DBStore, ReadStore, Row, WriteStore};
+use crate::util::{constant_time_eq, spawn_thread, Bytes, Channel};
+
+// The primary keeps the latest write batches (up to this size) for resuming followers.
+const MAX_LOG_BYTES: usize = 256 << 20;
+const MAX_FRAME_LEN: usize = 1 << 30;
+const MAX_FOLLOWERS: usize = 16; // each one is served by its own thread
+const MAC_LEN: usize = 32;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+// The last indexed block row's key (see `index::last_indexed_block`), marking the batches'
+// boundaries.
+const LAST_INDEXED_KEY: &[u8] = b"L";
+
+#[derive(Serialize, Deserialize)]
+enum Message {
+    Batch(Option<Bytes>, Vec<(Bytes, Bytes)>), // the batch's marker, and its sealed rows
+    Heartbeat,
+    Rejected(String),
+    Challenge(u64), // the primary's nonce, sent before the follower's `Hello`
+}
+
+// The follower's first frame.
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    marker: Bytes, // the follower's last indexed block
+    nonce: u64,    // for authenticating the primary's messages
+    proof: Bytes,  // see `follower_proof()`
+}
+
+fn encode(msg: &Message) -> Vec<u8> {
+    bincode::serialize(msg).expect("failed to serialize replication message")
+}
+
+fn mac(secret: &[u8], parts: &[&[u8]]) -> Bytes {
+    let mut engine = hmac::HmacEngine::<sha256::Hash>::new(secret);
+    for part in parts {
+        engine.input(part);
+    }
+    hmac::Hmac::<sha256::Hash>::from_engine(engine)
+        .into_inner()
+        .to_vec()
+}
+
+fn follower_proof(secret: &[u8], primary_nonce: u64) -> Bytes {
+    mac(secret, &[b"follower", &primary_nonce.to_be_bytes()])
+}
+
+// Authenticates the primary's messages after the handshake, binding them to the connection's
+// nonces and to their order (so they can't be modified, replayed or reordered).
+struct Session<'a> {
+    secret: &'a [u8],
+    nonces: [u8; 16],
+    seq: u64,
+}
+
+impl<'a> Session<'a> {
+    fn new(secret: &'a [u8], primary_nonce: u64, follower_nonce: u64) -> Session<'a> {
+        let mut nonces = [0u8; 16];
+        nonces[..8].copy_from_slice(&primary_nonce.to_be_bytes());
+        nonces[8..].copy_from_slice(&follower_nonce.to_be_bytes());
+        Session {
+            secret,
+            nonces,
+            seq: 0,
+        }
+    }
+
+    fn next_mac(&mut self, frame: &[u8]) -> Bytes {
+        let seq = self.seq.to_be_bytes();
+        self.seq += 1;
+        mac(self.secret, &[b"primary", &self.nonces, &seq, frame])
+    }
+
+    fn write(&mut self, writer: &mut impl Write, frame: &[u8]) -> Result<()> {
+        let mac = self.next_mac(frame);
+        write_parts(writer, &[frame, &mac])
+    }
+
+    fn open<'f>(&mut self, sealed: &'f [u8]) -> Result<&'f [u8]> {
+        if sealed.len() < MAC_LEN {
+            bail!("missing replication message MAC");
+        }
+        let (frame, mac) = sealed.split_at(sealed.len() - MAC_LEN);
+        if !constant_time_eq(mac, &self.next_mac(frame)) {
+            bail!("invalid replication message MAC (mismatching replication_secret?)");
+        }
+        Ok(frame)
+    }
+}
+
+fn write_frame(writer: &mut impl Write, frame: &[u8]) -> Result<()> {
+    write_parts(writer, &[frame])
+}
+
+// Writes a single frame, concatenating the parts.
+fn write_parts(writer: &mut impl Write, parts: &[&[u8]]) -> Result<()> {
+    let len: usize = parts.iter().map(|part| part.len()).sum();
+    writer
+        .write_all(&(len as u32).to_be_bytes())
+        .and_then(|()| parts.iter().try_for_each(|part| writer.write_all(part)))
+        .and_then(|()| writer.flush())
+        .chain_err(|| "failed to send frame")
+}
+
+fn read_frame(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader
+        .read_exact(&mut len)
+        .chain_err(|| "failed to read frame length")?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        bail!("replication frame too large: {} bytes", len);
+    }
+    let mut frame = vec![0u8; len];
+    reader
+        .read_exact(&mut frame)
+        .chain_err(|| format!("failed to read {} bytes frame", len))?;
+    Ok(frame)
+}
+
+fn last_marker<'a>(rows: impl Iterator<Item = &'a Row>) -> Option<&'a Bytes> {
+    rows.filter(|row| row.key == LAST_INDEXED_KEY)
+        .last()
+        .map(|row| &row.value)
+}
+
+/// A write batch, whose rows are sealed (see `DBStore::seal`).
+pub struct SealedBatch {
+    pub marker: Option<Bytes>, // the last indexed block (if updated by this batch)
+    pub rows: Vec<Row>,
+}
+
+struct Entry {
+    frame: Arc<Vec<u8>>,
+    marker: Bytes, // the last indexed block, after applying this batch
+}
+
+struct LogState {
+    base: Bytes, // the last indexed block, before applying the first kept batch
+    first_seq: u64,
+    entries: VecDeque<Entry>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl LogState {
+    fn last_marker(&self) -> &Bytes {
+        self.entries
+            .back()
+            .map_or(&self.base, |entry| &entry.marker)
+    }
+
+    fn end_seq(&self) -> u64 {
+        self.first_seq + self.entries.len() as u64
+    }
+
+    fn append(&mut self, frame: Vec<u8>, marker: Option<Bytes>) {
+        let marker = marker.unwrap_or_else(|| self.last_marker().clone());
+        self.bytes += frame.len();
+        self.entries.push_back(Entry {
+            frame: Arc::new(frame),
+            marker,
+        });
+        while self.bytes > self.max_bytes && self.entries.len() > 1 {
+            let entry = self.entries.pop_front().unwrap();
+            self.bytes -= entry.frame.len();
+            self.base = entry.marker;
+            self.first_seq += 1;
+        }
+    }
+
+    // Returns the sequence number of the batch following `marker` (if it is kept).
+    fn resume_seq(&self, marker: &[u8]) -> Option<u64> {
+        match self
+            .entries
+            .iter()
+            .rposition(|entry| entry.marker == marker)
+        {
+            Some(index) => Some(self.first_seq + index as u64 + 1),
+            None if self.base == marker => Some(self.first_seq),
+            None => None,
+        }
+    }
+
+    fn get(&self, seq: u64) -> Option<Arc<Vec<u8>>> {
+        let index = seq.checked_sub(self.first_seq)?;
+        self.entries
+            .get(index as usize)
+            .map(|entry| Arc::clone(&entry.frame))
+    }
+}
+
+/// The primary's latest write batches, streamed to its followers.
+pub struct ReplicationLog {
+    state: Mutex<LogState>,
+    appended: Condvar,
+    secret: Bytes, // shared with the followers (empty if not configured)
+}
+
+impl ReplicationLog {
+    fn new(base: Bytes, max_bytes: usize, secret: Bytes) -> ReplicationLog {
+        ReplicationLog {
+            state: Mutex::new(LogState {
+                base,
+                first_seq: 0,
+                entries: VecDeque::new(),
+                bytes: 0,
+                max_bytes,
+            }),
+            appended: Condvar::new(),
+            secret,
+        }
+    }
+
+    /// Starts accepting followers (which know `secret`) at `addr`, streaming the batches written
+    /// after `store`'s current last indexed block.
+    pub fn start(
+        addr: SocketAddr,
+        secret: Option<&str>,
+        store: &dyn ReadStore,
+    ) -> Arc<ReplicationLog> {
+        let base = store.get(LAST_INDEXED_KEY).unwrap_or_default();
+        let secret = secret.unwrap_or_default().as_bytes().to_vec();
+        let log = Arc::new(ReplicationLog::new(base, MAX_LOG_BYTES, secret));
+        let listener =
+            TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
+        info!("replication server running on {}", addr);
+        let acceptor_log = Arc::clone(&log);
+        let followers = Arc::new(AtomicUsize::new(0));
+        spawn_thread("replication", move || loop {
+            let (stream, addr) = listener.accept().expect("accept failed");
+            if followers.fetch_add(1, Ordering::SeqCst) >= MAX_FOLLOWERS {
+                followers.fetch_sub(1, Ordering::SeqCst);
+                warn!("[{}] refused follower: too many followers", addr);
+                continue; // the stream is closed when dropped
+            }
+            let log = Arc::clone(&acceptor_log);
+            let followers = Arc::clone(&followers);
+            spawn_thread("replica", move || {
+                info!("[{}] follower connected", addr);
+                if let Err(e) = log.serve(stream) {
+                    warn!("[{}] follower disconnected: {}", addr, e.display_chain());
+                }
+                followers.fetch_sub(1, Ordering::SeqCst);
+            });
+        });
+        log
+    }
+
+    fn append(&self, frame: Vec<u8>, marker: Option<Bytes>) {
+        self.state.lock().unwrap().append(frame, marker);
+        self.appended.notify_all();
+    }
+
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        stream
+            .set_read_timeout(Some(READ_TIMEOUT))
+            .chain_err(|| "failed to set read timeout")?;
+        let mut writer = BufWriter::new(&stream);
+        let nonce = random_salt();
+        write_frame(&mut writer, &encode(&Message::Challenge(nonce)))?;
+        let hello = read_frame(&mut BufReader::new(&stream))?;
+        let hello: Hello = bincode::deserialize(&hello).chain_err(|| "invalid follower hello")?;
+        let mut session = Session::new(&self.secret, nonce, hello.nonce);
+        if !constant_time_eq(&hello.proof, &follower_proof(&self.secret, nonce)) {
+            let reason = "authentication failed (mismatching replication_secret?)".to_owned();
+            session.write(&mut writer, &encode(&Message::Rejected(reason.clone())))?;
+            bail!(reason);
+        }
+        let marker = hello.marker;
+        let resumed = self.state.lock().unwrap().resume_seq(&marker);
+        let mut next = match resumed {
+            Some(seq) => seq,
+            None => {
+                let reason = format!("unknown last indexed block {}", hex::encode(&marker));
+                session.write(&mut writer, &encode(&Message::Rejected(reason.clone())))?;
+                bail!(reason);
+            }
+        };
+        let heartbeat = encode(&Message::Heartbeat);
+        loop {
+            let frame = {
+                let mut state = self.state.lock().unwrap();
+                if next < state.first_seq {
+                    drop(state);
+                    let reason = "follower is too far behind".to_owned();
+                    session.write(&mut writer, &encode(&Message::Rejected(reason.clone())))?;
+                    bail!(reason);
+                }
+                if next == state.end_seq() {
+                    state = self
+                        .appended
+                        .wait_timeout(state, HEARTBEAT_INTERVAL)
+                        .unwrap()
+                        .0;
+                }
+                state.get(next)
+            };
+            match frame {
+                Some(frame) => {
+                    session.write(&mut writer, &frame)?;
+                    next += 1;
+                }
+                None => session.write(&mut writer, &heartbeat)?,
+            }
+        }
+    }
+}
+
+/// Writes the rows to the underlying store, and appends them to the replication log.
+pub struct ReplicatedStore<'a> {
+    store: &'a DBStore,
+    log: &'a ReplicationLog,
+}
+
+impl<'a> ReplicatedStore<'a> {
+    pub fn new(store: &'a DBStore, log: &'a ReplicationLog) -> Self {
+        ReplicatedStore { store, log }
+    }
+
+    pub fn write_sealed(&self, batch: SealedBatch) {
+        let pairs = batch.rows.iter().cloned().map(Row::into_pair).collect();
+        let frame = encode(&Message::Batch(batch.marker.clone(), pairs));
+        self.store.write_sealed(batch.rows); // followers shouldn't get ahead of the primary
+        self.log.append(frame, batch.marker);
+    }
+}
+
+impl<'a> WriteStore for ReplicatedStore<'a> {
+    fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
+        let rows: Vec<Row> = rows.into_iter().collect();
+        let marker = last_marker(rows.iter()).cloned();
+        let rows = rows.into_iter().map(|row| self.store.seal(row)).collect();
+        self.write_sealed(SealedBatch { marker, rows });
+    }
+
+    fn flush(&self) {
+        self.store.flush()
+    }
+}
+
+/// Receives the write batches streamed by the primary (reconnecting as needed).
+pub struct Follower {
+    primary: SocketAddr,
+    receiver: Receiver<SealedBatch>,
+}
+
+impl Follower {
+    pub fn start(primary: SocketAddr, secret: Option<&str>, store: &dyn ReadStore) -> Follower {
+        let mut marker = store.get(LAST_INDEXED_KEY).unwrap_or_default();
+        let secret = secret.unwrap_or_default().as_bytes().to_vec();
+        let chan = Channel::unbounded();
+        let sender = chan.sender();
+        spawn_thread("follower", move || loop {
+            if let Err(e) = follow(primary, &secret, &mut marker, &sender) {
+                warn!("replication from {} failed: {}", primary, e.display_chain());
+            }
+            thread::sleep(RECONNECT_DELAY);
+        });
+        Follower {
+            primary,
+            receiver: chan.into_receiver(),
+        }
+    }
+
+    pub fn primary(&self) -> SocketAddr {
+        self.primary
+    }
+
+    /// Returns the batches received since the last call.
+    pub fn received(&self) -> Vec<SealedBatch> {
+        self.receiver.try_iter().collect()
+    }
+}
+
+// Streams the batches following `marker` (updating it as they are received).
+fn follow(
+    primary: SocketAddr,
+    secret: &[u8],
+    marker: &mut Bytes,
+    sender: &Sender<SealedBatch>,
+) -> Result<()> {
+    let stream =
+        TcpStream::connect(primary).chain_err(|| format!("failed to connect {}", primary))?;
+    stream
+        .set_read_timeout(Some(READ_TIMEOUT))
+        .chain_err(|| "failed to set read timeout")?;
+    let mut reader = BufReader::new(&stream);
+    let challenge = read_frame(&mut reader)?;
+    let primary_nonce = match bincode::deserialize(&challenge) {
+        Ok(Message::Challenge(nonce)) => nonce,
+        _ => bail!("missing replication challenge"),
+    };
+    let hello = Hello {
+        marker: marker.clone(),
+        nonce: random_salt(),
+        proof: follower_proof(secret, primary_nonce),
+    };
+    let mut session = Session::new(secret, primary_nonce, hello.nonce);
+    write_frame(
+        &mut BufWriter::new(&stream),
+        &bincode::serialize(&hello).unwrap(),
+    )?;
+    info!("replicating from {}", primary);
+    loop {
+        let sealed = read_frame(&mut reader)?;
+        let frame = session.open(&sealed)?;
+        match bincode::deserialize(frame).chain_err(|| "invalid replication message")? {
+            Message::Batch(new_marker, pairs) => {
+                let rows: Vec<Row> = pairs
+                    .into_iter()
+                    .map(|(key, value)| Row { key, value })
+                    .collect();
+                if let Some(ref new_marker) = new_marker {
+                    *marker = new_marker.clone();
+                }
+                let batch = SealedBatch {
+                    marker: new_marker,
+                    rows,
+                };
+                sender.send(batch).chain_err(|| "follower stopped")?;
+            }
+            Message::Heartbeat => (),
+            Message::Rejected(reason) => bail!("rejected by primary: {}", reason),
+            Message::Challenge(_) => bail!("unexpected replication challenge"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{follow, Message, ReplicationLog, LAST_INDEXED_KEY};
+    use crate::store::Row;
+    use crate::util::Channel;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    fn batch(marker: u8) -> (Vec<u8>, Option<Vec<u8>>) {
+        let row = Row {
+            key: LAST_INDEXED_KEY.to_vec(),
+            value: vec![marker],
+        };
+        let frame = super::encode(&Message::Batch(Some(vec![marker]), vec![row.into_pair()]));
+        (frame, Some(vec![marker]))
+    }
+
+    #[test]
+    fn test_log() {
+        let log = ReplicationLog::new(vec![0], 2 * batch(1).0.len() - 1, vec![]);
+        for marker in 1..=3 {
+            let (frame, marker) = batch(marker);
+            log.append(frame, marker);
+        }
+        log.append(vec![], None); // keeps the last marker
+        let state = log.state.lock().unwrap();
+        assert_eq!(state.first_seq, 2); // the first batches were dropped
+        assert_eq!(state.base, vec![2]);
+        assert_eq!(state.resume_seq(&[0]), None);
+        assert_eq!(state.resume_seq(&[2]), Some(2));
+        assert_eq!(state.resume_seq(&[3]), Some(4));
+        assert_eq!(state.get(2).unwrap().as_slice(), batch(3).0.as_slice());
+        assert_eq!(state.get(4), None);
+    }
+
+    #[test]
+    fn test_replication() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let log = Arc::new(ReplicationLog::new(vec![0], 1 << 20, b"secret".to_vec()));
+        let (frame, marker) = batch(1);
+        log.append(frame, marker);
+        let primary = Arc::clone(&log);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let _ = primary.serve(stream.unwrap());
+            }
+        });
+
+        let chan = Channel::unbounded();
+        let mut marker = vec![0];
+        let err = follow(addr, b"wrong", &mut marker, &chan.sender()).unwrap_err();
+        assert!(err.to_string().contains("invalid replication message MAC"));
+        marker = vec![9];
+        let err = follow(addr, b"secret", &mut marker, &chan.sender()).unwrap_err();
+        assert!(err.to_string().contains("rejected by primary")); // unknown marker
+
+        marker = vec![0];
+        let sender = chan.sender();
+        std::thread::spawn(move || follow(addr, b"secret", &mut marker, &sender));
+        let received = chan.receiver().recv().unwrap();
+        assert_eq!(received.marker, Some(vec![1]));
+        assert_eq!(received.rows[0].value, vec![1]);
+        let (frame, marker) = batch(2);
+        log.append(frame, marker);
+        let received = chan.receiver().recv().unwrap();
+        assert_eq!(received.rows[0].value, vec![2]);
+    }
+}
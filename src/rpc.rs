@@ -1,35 +1,192 @@
-use bitcoin::blockdata::transaction::Transaction;
-use bitcoin::consensus::encode::{deserialize, serialize};
 use bitcoin::hashes::hex::{FromHex, ToHex};
 use bitcoin::hashes::{sha256d::Hash as Sha256dHash, Hash};
 use error_chain::ChainedError;
 use serde_json::{from_str, Value};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::chain::{
+    address_script, deserialize, serialize, BlockHash, OutPoint, Transaction, Txid,
+};
+use crate::config::Config;
+use crate::daemon::parse_estimate_mode;
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
-use crate::query::{Query, Status};
-use crate::util::{spawn_thread, Channel, HeaderEntry};
+use crate::index::compute_script_hash;
+use crate::mempool::{fee_histogram_changed, NewTransaction};
+use crate::metrics::{
+    CounterVec, Gauge, GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics,
+};
+use crate::msgpack;
+use crate::protocol::{
+    header_json, negotiate, raw_headers, ProtocolVersion, MAX_PROTOCOL_VERSION,
+    MIN_PROTOCOL_VERSION,
+};
+use crate::proxy;
+use crate::query::{
+    HistoryItem, Query, SpentStatus, Status, StatusHasher, TxStatus, HEADERS_CHUNK_SIZE,
+};
+#[cfg(feature = "quic")]
+use crate::quic::QuicListener;
+use crate::ratelimit::RateLimiter;
+use crate::record::Recorder;
+use crate::redact::{redacted, redacted_hashes};
+use crate::schedule::{Scheduler, ANONYMOUS_TIER};
+use crate::tls::{Stream, TlsListener};
+use crate::tor::OnionService;
+use crate::util::{constant_time_eq, spawn_thread, Channel, FullHash, HeaderEntry};
 
 const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
-const PROTOCOL_VERSION: &str = "1.4";
+const MAX_BATCH_TXIDS: usize = 1000; // for `blockchain.transaction.get_batch`
+const MAX_BATCH_OUTPOINTS: usize = 1000; // for `blockchain.outpoint.get_spent_status`
+const MAX_BATCH_SCRIPTHASHES: usize = 1000; // for `blockchain.scripthashes.subscribe`
+const MAX_BATCH_REQUESTS: usize = 100; // per JSON-RPC batch (served under a single snapshot)
+const PARALLEL_SUBSCRIPTIONS_MIN_LEN: usize = 10; // smaller batches aren't worth the workers
+const MAX_HTTP_REQUEST_SIZE: u64 = 10_000_000; // fits a batch of large transactions' broadcasts
+
+// Clients' user agents (sent via `server.version`) are used as metrics' labels, so their number
+// (and length) is limited.
+const MAX_USER_AGENTS: usize = 100;
+const MAX_USER_AGENT_LEN: usize = 64;
+const UNKNOWN_USER_AGENT: &str = "unknown"; // before `server.version` is called
+const OTHER_USER_AGENT: &str = "other";
+
+// JSON-RPC (and Electrum protocol) error codes.
+const BAD_REQUEST: i64 = 1;
+const DAEMON_ERROR: i64 = 2;
+const EXCESSIVE_RESOURCE_USAGE: i64 = -101;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+fn invalid_params(msg: &str) -> Error {
+    ErrorKind::InvalidParams(msg.to_owned()).into()
+}
+
+// Returns the first typed error in the chain (i.e. one with a specific error code).
+fn typed_error_kind(e: &Error) -> Option<&ErrorKind> {
+    let mut next: Option<&(dyn std::error::Error + 'static)> = Some(e);
+    while let Some(err) = next {
+        next = err.source();
+        let kind = match err.downcast_ref::<Error>() {
+            Some(err) => err.kind(),
+            None => continue,
+        };
+        match kind {
+            ErrorKind::MethodNotFound(_)
+            | ErrorKind::InvalidParams(_)
+            | ErrorKind::ExcessiveResourceUsage(_)
+            | ErrorKind::Daemon(..)
+            | ErrorKind::Rejected(..)
+            | ErrorKind::Connection(_)
+            | ErrorKind::Warmup(_) => return Some(kind),
+            _ => (),
+        }
+    }
+    None
+}
+
+fn error_code(e: &Error) -> i64 {
+    match typed_error_kind(e) {
+        Some(ErrorKind::MethodNotFound(_)) => METHOD_NOT_FOUND,
+        Some(ErrorKind::InvalidParams(_)) => INVALID_PARAMS,
+        Some(ErrorKind::ExcessiveResourceUsage(_)) => EXCESSIVE_RESOURCE_USAGE,
+        Some(ErrorKind::Daemon(..))
+        | Some(ErrorKind::Rejected(..))
+        | Some(ErrorKind::Connection(_))
+        | Some(ErrorKind::Warmup(_)) => DAEMON_ERROR,
+        _ => BAD_REQUEST,
+    }
+}
+
+// bitcoind's own error code and message, so clients can tell why a transaction was rejected.
+fn error_data(e: &Error) -> Option<Value> {
+    match typed_error_kind(e) {
+        Some(ErrorKind::Daemon(_, code, msg)) | Some(ErrorKind::Rejected(code, msg)) => {
+            Some(json!({"daemon_code": code, "daemon_message": msg}))
+        }
+        _ => None,
+    }
+}
+
+fn error_json(id: &Value, code: i64, message: &str, data: Option<Value>) -> RawJson {
+    let mut error = json!({"code": code, "message": message});
+    if let Some(data) = data {
+        error["data"] = data;
+    }
+    json!({"jsonrpc": "2.0", "id": id, "error": error}).into()
+}
+
+// A request's method, params and id (or `None` for a notification, which must not be replied).
+type Request<'a> = (&'a str, &'a [Value], Option<&'a Value>);
+
+// Validates the request as JSON-RPC 2.0 requires, or returns the error reply (whose id is `null`
+// if the request's id is missing or invalid).
+fn parse_request(cmd: &Value) -> std::result::Result<Request, RawJson> {
+    let id = match cmd.get("id") {
+        None => None,
+        Some(id) if id.is_string() || id.is_number() || id.is_null() => Some(id),
+        Some(_) => {
+            return Err(error_json(
+                &Value::Null,
+                INVALID_REQUEST,
+                "invalid id",
+                None,
+            ))
+        }
+    };
+    let params = match cmd.get("params") {
+        None => Some(&[][..]),
+        Some(Value::Array(params)) => Some(&params[..]),
+        Some(_) => None,
+    };
+    match (cmd.get("method"), params) {
+        (Some(Value::String(method)), Some(params)) => Ok((method, params, id)),
+        _ => Err(error_json(
+            id.unwrap_or(&Value::Null),
+            INVALID_REQUEST,
+            "invalid request",
+            None,
+        )),
+    }
+}
 
 // TODO: Sha256dHash should be a generic hash-container (since script hash is single SHA256)
 fn hash_from_value<T: Hash>(val: Option<&Value>) -> Result<T> {
-    let script_hash = val.chain_err(|| "missing hash")?;
-    let script_hash = script_hash.as_str().chain_err(|| "non-string hash")?;
-    let script_hash = T::from_hex(script_hash).chain_err(|| "non-hex hash")?;
+    let script_hash = val.ok_or_else(|| invalid_params("missing hash"))?;
+    let script_hash = script_hash
+        .as_str()
+        .ok_or_else(|| invalid_params("non-string hash"))?;
+    let script_hash = T::from_hex(script_hash).map_err(|_| invalid_params("non-hex hash"))?;
     Ok(script_hash)
 }
 
+// Converts the address into its script hash (using the daemon for Elements addresses).
+fn address_script_hash(query: &Query, val: Option<&Value>) -> Result<Sha256dHash> {
+    let address = val.ok_or_else(|| invalid_params("missing address"))?;
+    let address = address
+        .as_str()
+        .ok_or_else(|| invalid_params("non-string address"))?;
+    let daemon = query.daemon();
+    let script = match address_script(address, daemon.network()) {
+        Ok(Some(script)) => script,
+        Ok(None) => daemon.address_scripts(&[address.to_owned()])?.remove(0),
+        Err(e) => bail!(invalid_params(&e.to_string())),
+    };
+    Ok(Sha256dHash::from_inner(compute_script_hash(&script)))
+}
+
 fn usize_from_value(val: Option<&Value>, name: &str) -> Result<usize> {
-    let val = val.chain_err(|| format!("missing {}", name))?;
-    let val = val.as_u64().chain_err(|| format!("non-integer {}", name))?;
+    let val = val.ok_or_else(|| invalid_params(&format!("missing {}", name)))?;
+    let val = val
+        .as_u64()
+        .ok_or_else(|| invalid_params(&format!("non-integer {}", name)))?;
     Ok(val as usize)
 }
 
@@ -41,8 +198,10 @@ fn usize_from_value_or(val: Option<&Value>, name: &str, default: usize) -> Resul
 }
 
 fn bool_from_value(val: Option<&Value>, name: &str) -> Result<bool> {
-    let val = val.chain_err(|| format!("missing {}", name))?;
-    let val = val.as_bool().chain_err(|| format!("not a bool {}", name))?;
+    let val = val.ok_or_else(|| invalid_params(&format!("missing {}", name)))?;
+    let val = val
+        .as_bool()
+        .ok_or_else(|| invalid_params(&format!("not a bool {}", name)))?;
     Ok(val)
 }
 
@@ -53,6 +212,68 @@ fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<
     bool_from_value(val, name)
 }
 
+/// Restrictions on `blockchain.transaction.broadcast`, which is the easiest method to abuse on a
+/// public server (to relay spam through the operator's node).
+pub struct BroadcastPolicy {
+    limiter: RateLimiter,
+    token: Option<String>,
+    max_fee_rate: Option<f64>, // default (in BTC/kvB)
+    precheck: bool,
+}
+
+impl BroadcastPolicy {
+    pub fn from_config(config: &Config) -> BroadcastPolicy {
+        BroadcastPolicy {
+            limiter: RateLimiter::new(
+                config.broadcast_rate_limit,
+                config.broadcast_global_rate_limit,
+                Duration::from_secs(60),
+            ),
+            token: config.broadcast_token.clone(),
+            max_fee_rate: config.broadcast_max_fee_rate,
+            precheck: config.broadcast_precheck,
+        }
+    }
+
+    fn check(&self, peer: IpAddr, params: &[Value]) -> Result<()> {
+        if let Some(ref token) = self.token {
+            let given = match params.get(2) {
+                None | Some(Value::Null) => "",
+                Some(value) => value
+                    .as_str()
+                    .ok_or_else(|| invalid_params("non-string token"))?,
+            };
+            if !constant_time_eq(given.as_bytes(), token.as_bytes()) {
+                bail!("broadcast is not authorized");
+            }
+        }
+        self.limiter.check(peer)
+    }
+
+    /// The client may override the default maximum fee rate, e.g. for deliberately paying a high fee.
+    fn max_fee_rate(&self, params: &[Value]) -> Result<Option<f64>> {
+        let value = match params.get(1) {
+            None | Some(Value::Null) => return Ok(self.max_fee_rate),
+            Some(value) => value,
+        };
+        match value.as_f64() {
+            Some(max_fee_rate) if max_fee_rate >= 0.0 => Ok(Some(max_fee_rate)),
+            _ => Err(invalid_params(&format!("invalid maxfeerate: {}", value))),
+        }
+    }
+}
+
+/// A serialized JSON value: large responses (e.g. histories and headers) are written directly,
+/// instead of building (and then serializing) an intermediate `Value` tree.
+#[derive(Debug)]
+struct RawJson(String);
+
+impl From<Value> for RawJson {
+    fn from(value: Value) -> Self {
+        RawJson(value.to_string())
+    }
+}
+
 fn unspent_from_status(status: &Status) -> Value {
     json!(Value::Array(
         status
@@ -68,78 +289,354 @@ fn unspent_from_status(status: &Status) -> Value {
     ))
 }
 
+fn history_json(history: &[HistoryItem]) -> String {
+    let mut result = String::with_capacity(2 + history.len() * 100);
+    result.push('[');
+    for (i, item) in history.iter().enumerate() {
+        if i > 0 {
+            result.push(',');
+        }
+        item.write_json(&mut result);
+    }
+    result.push(']');
+    result
+}
+
+// The `tx_hash` and `txout_idx` params (followed by an optional `spk_hint`, which isn't needed).
+fn outpoint_from_params(params: &[Value]) -> Result<OutPoint> {
+    let txid = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
+    let vout = usize_from_value(params.get(1), "txout_idx")?;
+    Ok(OutPoint {
+        txid,
+        vout: vout as u32,
+    })
+}
+
+// The funding transaction's `height` and the spending one's `spender_txhash` and
+// `spender_height` (0 for mempool transactions), or an empty object if the funding transaction
+// is not found (as in the protocol 1.5 draft).
+fn outpoint_status_json(query: &Query, outpoint: &OutPoint) -> Result<Value> {
+    let mut result = json!({});
+    match query.get_tx_status(&outpoint.txid) {
+        TxStatus::Confirmed { height, .. } => result["height"] = json!(height),
+        TxStatus::Mempool => result["height"] = json!(0),
+        TxStatus::Unknown => return Ok(result),
+    }
+    match query.get_spent_status(&outpoint.txid, outpoint.vout as usize)? {
+        SpentStatus::Mempool { txid } => {
+            result["spender_txhash"] = json!(txid.to_hex());
+            result["spender_height"] = json!(0);
+        }
+        SpentStatus::Confirmed { height, txid } => {
+            result["spender_txhash"] = json!(txid.to_hex());
+            result["spender_height"] = json!(height);
+        }
+        SpentStatus::Unspent | SpentStatus::Unknown => (),
+    }
+    Ok(result)
+}
+
+fn status_hash_json(status_hash: Option<FullHash>) -> Value {
+    status_hash.map_or(Value::Null, |h| json!(hex::encode(h)))
+}
+
+// Computes the (persisted) statuses of several script hashes, and their status hashes (e.g. for
+// wallets restoring from a seed), splitting large batches between the query's workers.
+fn subscribed_statuses(
+    query: &Arc<Query>,
+    script_hashes: &[Sha256dHash],
+    txid_limit: usize,
+) -> Result<Vec<(Value, StatusHasher)>> {
+    let worker_query = Arc::clone(query);
+    query
+        .workers()
+        .map(
+            script_hashes.to_vec(),
+            PARALLEL_SUBSCRIPTIONS_MIN_LEN,
+            move |script_hash| {
+                let query = &worker_query;
+                let status = query.subscribed_status(&script_hash[..], txid_limit)?;
+                let mut hasher = StatusHasher::default();
+                let status_hash = status_hash_json(query.status_hash(&status, &mut hasher));
+                Ok((status_hash, hasher))
+            },
+        )
+        .into_iter()
+        .collect()
+}
+
+// Call bitcoind, so they are served without pinning a snapshot (see `Connection::handle_message`).
+const DAEMON_METHODS: &[&str] = &["blockchain.estimatefee", "blockchain.transaction.broadcast"];
+
+fn calls_daemon(cmd: &Value) -> bool {
+    cmd.get("method")
+        .and_then(Value::as_str)
+        .map_or(false, |method| DAEMON_METHODS.contains(&method))
+}
+
+const HEADERS_ONLY_METHODS: &[&str] = &[
+    "blockchain.block.header",
+    "blockchain.block.headers",
+    "blockchain.estimatefee",
+    "blockchain.headers.subscribe",
+    "blockchain.numblocks.subscribe",
+    "blockchain.relayfee",
+    "blockchain.transaction.broadcast",
+    "blockchain.transaction.get",
+    "blockchain.transaction.get_merkle",
+    "blockchain.transaction.id_from_pos",
+    "mempool.fee_histogram.subscribe",
+    "mempool.get_fee_histogram",
+    "server.authenticate",
+    "server.banner",
+    "server.donation_address",
+    "server.features",
+    "server.peers.subscribe",
+    "server.ping",
+    "server.version",
+];
+
+fn new_transaction_json(tx: &NewTransaction) -> Value {
+    let script_hashes: Vec<String> = tx
+        .script_hashes
+        .iter()
+        .map(|script_hash| Sha256dHash::from_inner(*script_hash).to_hex())
+        .collect();
+    let spent: Vec<Value> = tx
+        .spent
+        .iter()
+        .map(|outpoint| json!([outpoint.txid.to_hex(), outpoint.vout]))
+        .collect();
+    json!({
+        "tx_hash": tx.txid.to_hex(),
+        "fee_rate": tx.fee_rate,
+        "vsize": tx.vsize,
+        "scripthashes": script_hashes,
+        "spent": spent,
+        "first_seen": tx.first_seen,
+    })
+}
+
+struct Subscription {
+    status_hash: Value,
+    address: Option<String>, // if subscribed via `blockchain.address.subscribe`
+    hasher: StatusHasher,    // for updating the status hash incrementally
+}
+
 struct Connection {
     query: Arc<Query>,
     last_header_entry: Option<HeaderEntry>,
-    status_hashes: HashMap<Sha256dHash, Value>, // ScriptHash -> StatusHash
-    stream: TcpStream,
+    raw_headers: bool, // for header subscription (see `protocol::raw_headers`)
+    last_height: Option<usize>, // for (legacy) block count subscription
+    last_fee_histogram: Option<Vec<(f32, u32)>>,
+    mempool_txs: Option<u64>, // # of mempool transactions added before the last notification
+    protocol_version: ProtocolVersion, // negotiated by `server.version`
+    version_negotiated: bool, // `server.version` may be sent only once
+    status_hashes: HashMap<Sha256dHash, Subscription>, // ScriptHash -> StatusHash
+    outpoints: HashMap<OutPoint, Value>, // OutPoint -> its last status
     addr: SocketAddr,
+    binary: bool, // MessagePack framing (see `msgpack.rs`), selected by the client's requests
+    mempool_outdated: bool, // by a broadcast (updated before the next notifications)
+    stateless: bool, // serving a single HTTP request (so it can't subscribe)
     sender: SyncSender<Message>,
     stats: Arc<Stats>,
     relayfee: f64,
+    broadcast: Arc<BroadcastPolicy>,
+    scheduler: Arc<Scheduler>,
+    tier: usize,                                // QoS tier (see `server.authenticate`)
+    user_agent: String,                         // the client's name (see `server.version`)
+    last_request: Arc<Mutex<Instant>>, // for expiring idle connections' subscriptions (or evicting them)
+    recording: Option<(Arc<Recorder>, String)>, // and the session ID (see `record.rs`)
+    hosts: Arc<Value>,                 // announced by `server.features`
+    bytes_sent: usize,
+    bytes_received: usize,
 }
 
 impl Connection {
     pub fn new(
         query: Arc<Query>,
-        stream: TcpStream,
         addr: SocketAddr,
         stats: Arc<Stats>,
         relayfee: f64,
+        broadcast: Arc<BroadcastPolicy>,
+        scheduler: Arc<Scheduler>,
         sender: SyncSender<Message>,
     ) -> Connection {
         Connection {
             query,
             last_header_entry: None, // disable header subscription for now
+            raw_headers: true,
+            last_height: None,
+            last_fee_histogram: None,
+            mempool_txs: None,
+            protocol_version: MAX_PROTOCOL_VERSION,
+            version_negotiated: false,
+            user_agent: stats.connect(UNKNOWN_USER_AGENT),
             status_hashes: HashMap::new(),
-            stream,
+            outpoints: HashMap::new(),
             addr,
+            binary: false,
+            mempool_outdated: false,
+            stateless: false,
             sender,
             stats,
             relayfee,
+            broadcast,
+            scheduler,
+            tier: ANONYMOUS_TIER,
+            last_request: Arc::new(Mutex::new(Instant::now())),
+            recording: None,
+            hosts: Arc::new(json!({})),
+            bytes_sent: 0,
+            bytes_received: 0,
+        }
+    }
+
+    fn with_recorder(self, recorder: Option<Arc<Recorder>>) -> Connection {
+        let recording = recorder.map(|recorder| {
+            let session = recorder.new_session();
+            (recorder, session)
+        });
+        Connection { recording, ..self }
+    }
+
+    fn with_hosts(self, hosts: &Arc<Value>) -> Connection {
+        let hosts = Arc::clone(hosts);
+        Connection { hosts, ..self }
+    }
+
+    // Shared with the server, for evicting the longest idle connection (see `max_connections`).
+    fn last_request(&self) -> Arc<Mutex<Instant>> {
+        Arc::clone(&self.last_request)
+    }
+
+    fn record(&self, request: &Value, response: &str) {
+        if let Some((ref recorder, ref session)) = self.recording {
+            recorder.record(session, request, response);
         }
     }
 
-    fn blockchain_headers_subscribe(&mut self) -> Result<Value> {
+    // Both script hash and outpoint subscriptions count towards `max_subscriptions`.
+    fn subscriptions(&self) -> usize {
+        self.status_hashes.len() + self.outpoints.len()
+    }
+
+    fn add_subscriptions(&self, count: usize) {
+        self.stats.subscriptions.add(count as i64);
+        self.scheduler.add_subscriptions(self.tier, count);
+        self.stats
+            .tier_subscriptions
+            .with_label_values(&[self.scheduler.tier_name(self.tier)])
+            .add(count as f64);
+    }
+
+    fn remove_subscriptions(&self, count: usize) {
+        self.stats.subscriptions.sub(count as i64);
+        self.scheduler.remove_subscriptions(self.tier, count);
+        self.stats
+            .tier_subscriptions
+            .with_label_values(&[self.scheduler.tier_name(self.tier)])
+            .sub(count as f64);
+    }
+
+    fn txid_limit(&self) -> usize {
+        self.scheduler.txid_limit(self.tier)
+    }
+
+    fn status(&self, script_hash: &Sha256dHash) -> Result<Status> {
+        self.query
+            .status_with_limit(&script_hash[..], self.txid_limit())
+    }
+
+    fn blockchain_headers_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        self.raw_headers = raw_headers(self.protocol_version, params.get(0))
+            .map_err(|e| invalid_params(&e.to_string()))?;
         let entry = self.query.get_best_header()?;
-        let hex_header = hex::encode(serialize(entry.header()));
-        let result = json!({"hex": hex_header, "height": entry.height()});
+        let result = header_json(&entry, self.raw_headers);
         self.last_header_entry = Some(entry);
         Ok(result)
     }
 
-    fn server_version(&self, params: &[Value]) -> Result<Value> {
-        if params.len() != 2 {
-            bail!("invalid params: {:?}", params);
-        }
-        let client_id = params[0]
-            .as_str()
-            .chain_err(|| format!("invalid client_id: {:?}", params[0]))?;
-        // TODO: support (min, max) protocol version limits
-        let client_version = params[1]
-            .as_str()
-            .chain_err(|| format!("invalid client_version: {:?}", params[1]))?;
+    /// Deprecated (removed in protocol 1.2), but still used by old clients and simple scripts.
+    fn blockchain_numblocks_subscribe(&mut self) -> Result<Value> {
+        let height = self.query.get_best_header()?.height();
+        self.last_height = Some(height);
+        Ok(json!(height))
+    }
 
-        if client_version != PROTOCOL_VERSION {
-            bail!(
-                "{} requested protocol version {}, server supports {}",
-                client_id,
-                client_version,
-                PROTOCOL_VERSION
-            );
+    fn server_version(&mut self, params: &[Value]) -> Result<Value> {
+        if params.len() > 2 {
+            bail!(ErrorKind::InvalidParams(format!(
+                "invalid params: {:?}",
+                params
+            )));
+        }
+        let client_id = match params.get(0) {
+            Some(value) => value
+                .as_str()
+                .ok_or_else(|| invalid_params(&format!("invalid client_id: {:?}", value)))?,
+            None => "",
+        };
+        let version = negotiate(params.get(1))
+            .chain_err(|| format!("{} requested an unsupported protocol version", client_id))?;
+        if self.version_negotiated {
+            // a repeated (identical) negotiation is harmless, but the version can't be changed
+            if version != self.protocol_version {
+                bail!(
+                    "server.version already sent (negotiated {})",
+                    self.protocol_version
+                );
+            }
+        } else {
+            self.protocol_version = version;
+            self.version_negotiated = true;
+            self.stats.disconnect(&self.user_agent);
+            self.user_agent = self.stats.connect(client_id);
         }
         Ok(json!([
             format!("electrs {}", ELECTRS_VERSION),
-            PROTOCOL_VERSION
+            version.to_string()
         ]))
     }
 
+    // Switches the connection to the QoS tier with the given password.
+    fn server_authenticate(&mut self, params: &[Value]) -> Result<Value> {
+        let password = params
+            .get(0)
+            .and_then(Value::as_str)
+            .ok_or_else(|| invalid_params("missing password"))?;
+        let tier = self
+            .scheduler
+            .authenticate(password)
+            .chain_err(|| "invalid password")?;
+        // the connection's subscriptions are moved to the new tier (e.g. to a tenant's quota)
+        let count = self.subscriptions();
+        self.remove_subscriptions(count);
+        self.tier = tier;
+        self.add_subscriptions(count);
+        Ok(json!(self.scheduler.tier_name(self.tier)))
+    }
+
     fn server_banner(&self) -> Result<Value> {
         Ok(json!(self.query.get_banner()?))
     }
 
+    fn server_features(&self) -> Result<Value> {
+        let genesis = self.query.get_headers(&[0]);
+        let genesis = genesis.first().chain_err(|| "missing genesis header")?;
+        Ok(json!({
+            "genesis_hash": genesis.hash().to_hex(),
+            "hosts": *self.hosts,
+            "protocol_max": MAX_PROTOCOL_VERSION.to_string(),
+            "protocol_min": MIN_PROTOCOL_VERSION.to_string(),
+            "pruning": null,
+            "server_version": format!("electrs {}", ELECTRS_VERSION),
+            "hash_function": "sha256",
+        }))
+    }
+
     fn server_donation_address(&self) -> Result<Value> {
-        Ok(Value::Null)
+        Ok(json!("")) // the protocol requires a string
     }
 
     fn server_peers_subscribe(&self) -> Result<Value> {
@@ -150,6 +647,24 @@ impl Connection {
         Ok(json!(self.query.get_fee_histogram()))
     }
 
+    /// Notifies the client when the fee histogram changes materially (instead of polling it).
+    fn mempool_fee_histogram_subscribe(&mut self) -> Result<Value> {
+        let histogram = self.query.get_fee_histogram();
+        let result = json!(histogram);
+        self.last_fee_histogram = Some(histogram);
+        Ok(result)
+    }
+
+    /// Streams the new mempool transactions to authenticated clients (e.g. for analytics).
+    fn mempool_transactions_subscribe(&mut self) -> Result<Value> {
+        if self.tier == ANONYMOUS_TIER {
+            bail!("mempool.transactions.subscribe requires authentication");
+        }
+        let (_, count) = self.query.get_new_mempool_txs(u64::max_value());
+        self.mempool_txs = Some(count);
+        Ok(Value::Null)
+    }
+
     fn blockchain_block_header(&self, params: &[Value]) -> Result<Value> {
         let height = usize_from_value(params.get(0), "height")?;
         let cp_height = usize_from_value_or(params.get(1), "cp_height", 0)?;
@@ -160,6 +675,9 @@ impl Connection {
             .into_iter()
             .map(|entry| hex::encode(&serialize(entry.header())))
             .collect();
+        if raw_header_hex.is_empty() {
+            bail!("missing header #{}", height); // instead of returning an empty header
+        }
 
         if cp_height == 0 {
             return Ok(json!(raw_header_hex));
@@ -175,45 +693,138 @@ impl Connection {
         }))
     }
 
-    fn blockchain_block_headers(&self, params: &[Value]) -> Result<Value> {
+    /// Returns a block (by hash or height) as hex, or its txids.
+    fn blockchain_block_get(&self, params: &[Value]) -> Result<RawJson> {
+        let entry = match params.get(0) {
+            Some(Value::Number(_)) => {
+                let height = usize_from_value(params.get(0), "height")?;
+                self.query
+                    .get_headers(&[height])
+                    .pop()
+                    .chain_err(|| format!("no block at height {}", height))?
+            }
+            _ => {
+                let blockhash =
+                    hash_from_value::<BlockHash>(params.get(0)).chain_err(|| "bad block_hash")?;
+                self.query
+                    .get_header_by_blockhash(&blockhash)
+                    .chain_err(|| format!("block {} not found", blockhash))?
+            }
+        };
+        if bool_from_value_or(params.get(1), "txids", false)? {
+            let txids = self.query.get_block_txids(entry.hash())?;
+            let txids: Vec<String> = txids.iter().map(|txid| txid.to_hex()).collect();
+            return Ok(json!(txids).into());
+        }
+        let block = self.query.get_block(entry.hash())?;
+        Ok(RawJson(format!("\"{}\"", hex::encode(block))))
+    }
+
+    fn blockchain_block_headers(&self, params: &[Value]) -> Result<RawJson> {
         let start_height = usize_from_value(params.get(0), "start_height")?;
         let count = usize_from_value(params.get(1), "count")?;
         let cp_height = usize_from_value_or(params.get(2), "cp_height", 0)?;
-        let heights: Vec<usize> = (start_height..(start_height + count)).collect();
-        let headers: Vec<String> = self
-            .query
-            .get_headers(&heights)
-            .into_iter()
-            .map(|entry| hex::encode(&serialize(entry.header())))
-            .collect();
-
-        if count == 0 || cp_height == 0 {
-            return Ok(json!({
-                "count": headers.len(),
-                "hex": headers.join(""),
-                "max": 2016,
-            }));
+        let (headers_count, headers_hex) = self.query.get_headers_hex(start_height, count);
+
+        // hex strings don't need escaping, so they are written as-is
+        let mut result = String::with_capacity(64 + headers_hex.len());
+        write!(result, "{{\"count\":{},\"hex\":\"", headers_count).unwrap();
+        result.push_str(&headers_hex);
+        write!(result, "\",\"max\":{}", HEADERS_CHUNK_SIZE).unwrap();
+
+        if count > 0 && cp_height > 0 {
+            let (branch, root) = self
+                .query
+                .get_header_merkle_proof(start_height + (count - 1), cp_height)?;
+
+            let branch_vec: Vec<String> = branch.into_iter().map(|b| b.to_hex()).collect();
+            write!(
+                result,
+                ",\"root\":\"{}\",\"branch\":{}",
+                root.to_hex(),
+                json!(branch_vec)
+            )
+            .unwrap();
         }
+        result.push('}');
+        Ok(RawJson(result))
+    }
 
-        let (branch, root) = self
+    fn blockchain_estimatefee(&self, params: &[Value]) -> Result<Value> {
+        let blocks_count = usize_from_value(params.get(0), "blocks_count")?;
+        let mode = match params.get(1) {
+            None | Some(Value::Null) => None,
+            Some(mode) => {
+                let mode = mode
+                    .as_str()
+                    .ok_or_else(|| invalid_params("non-string estimate mode"))?;
+                Some(parse_estimate_mode(mode).map_err(|e| invalid_params(&e.to_string()))?)
+            }
+        };
+        let fee_rate = match self
             .query
-            .get_header_merkle_proof(start_height + (count - 1), cp_height)?;
+            .estimate_smart_fee(blocks_count, mode.as_deref())?
+        {
+            Some(fee_rate) => fee_rate,
+            None => self.query.estimate_fee(blocks_count), // in BTC/kB
+        };
+        Ok(json!(fee_rate.max(self.relayfee)))
+    }
 
-        let branch_vec: Vec<String> = branch.into_iter().map(|b| b.to_hex()).collect();
+    fn blockchain_outpoint_get_spent_status(&self, params: &[Value]) -> Result<Value> {
+        let outpoints = params
+            .get(0)
+            .ok_or_else(|| invalid_params("missing outpoints"))?;
+        let outpoints = outpoints
+            .as_array()
+            .ok_or_else(|| invalid_params("non-array outpoints"))?;
+        if outpoints.len() > MAX_BATCH_OUTPOINTS {
+            let msg = format!("too many outpoints (max {})", MAX_BATCH_OUTPOINTS);
+            return Err(invalid_params(&msg));
+        }
+        let mut result = Vec::with_capacity(outpoints.len());
+        for outpoint in outpoints {
+            let outpoint = outpoint
+                .as_array()
+                .ok_or_else(|| invalid_params("outpoint should be a [tx_hash, tx_pos] pair"))?;
+            let tx_hash: Txid = hash_from_value(outpoint.get(0)).chain_err(|| "bad tx_hash")?;
+            let tx_pos = usize_from_value(outpoint.get(1), "tx_pos")?;
+            result.push(match self.query.get_spent_status(&tx_hash, tx_pos)? {
+                SpentStatus::Unspent => json!({"status": "unspent"}),
+                SpentStatus::Mempool { txid } => {
+                    json!({"status": "mempool", "tx_hash": txid.to_hex()})
+                }
+                SpentStatus::Confirmed { height, txid } => json!({
+                    "status": "confirmed",
+                    "height": height,
+                    "tx_hash": txid.to_hex(),
+                }),
+                SpentStatus::Unknown => json!({"status": "unknown"}),
+            });
+        }
+        Ok(json!(result))
+    }
 
-        Ok(json!({
-            "count": headers.len(),
-            "hex": headers.join(""),
-            "max": 2016,
-            "root": root.to_hex(),
-            "branch" : branch_vec
-        }))
+    fn blockchain_outpoint_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let outpoint = outpoint_from_params(params)?;
+        if !self.outpoints.contains_key(&outpoint) {
+            self.scheduler
+                .check_subscriptions(self.tier, self.subscriptions())?;
+        }
+        let status = outpoint_status_json(&self.query, &outpoint)?;
+        if self.outpoints.insert(outpoint, status.clone()).is_none() {
+            self.add_subscriptions(1);
+        }
+        Ok(status)
     }
 
-    fn blockchain_estimatefee(&self, params: &[Value]) -> Result<Value> {
-        let blocks_count = usize_from_value(params.get(0), "blocks_count")?;
-        let fee_rate = self.query.estimate_fee(blocks_count); // in BTC/kB
-        Ok(json!(fee_rate.max(self.relayfee)))
+    fn blockchain_outpoint_unsubscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let outpoint = outpoint_from_params(params)?;
+        let removed = self.outpoints.remove(&outpoint).is_some();
+        if removed {
+            self.remove_subscriptions(1);
+        }
+        Ok(json!(removed))
     }
 
     fn blockchain_relayfee(&self) -> Result<Value> {
@@ -223,14 +834,91 @@ impl Connection {
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash =
             hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
-        let result = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
+        self.subscribe(script_hash, None)
+    }
+
+    fn blockchain_scripthashes_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hashes = params
+            .get(0)
+            .ok_or_else(|| invalid_params("missing scripthashes"))?
+            .as_array()
+            .ok_or_else(|| invalid_params("non-array scripthashes"))?;
+        if script_hashes.len() > MAX_BATCH_SCRIPTHASHES {
+            let msg = format!("too many scripthashes (max {})", MAX_BATCH_SCRIPTHASHES);
+            return Err(invalid_params(&msg));
+        }
+        let script_hashes = script_hashes
+            .iter()
+            .map(|script_hash| hash_from_value::<Sha256dHash>(Some(script_hash)))
+            .collect::<Result<Vec<_>>>()
+            .chain_err(|| "bad script_hash")?;
+        let new_count = script_hashes
+            .iter()
+            .filter(|script_hash| !self.status_hashes.contains_key(*script_hash))
+            .collect::<HashSet<_>>()
+            .len();
+        if new_count > 0 {
+            // as if subscribing to the last one
+            self.scheduler
+                .check_subscriptions(self.tier, self.subscriptions() + new_count - 1)?;
+        }
+        let statuses = subscribed_statuses(&self.query, &script_hashes, self.txid_limit())?;
+        let mut result = Vec::with_capacity(statuses.len());
+        let mut added = 0;
+        for (script_hash, (status_hash, hasher)) in script_hashes.into_iter().zip(statuses) {
+            result.push(status_hash.clone());
+            let subscription = Subscription {
+                status_hash,
+                address: None,
+                hasher,
+            };
+            if self
+                .status_hashes
+                .insert(script_hash, subscription)
+                .is_none()
+            {
+                added += 1;
+            }
+        }
+        self.add_subscriptions(added);
+        Ok(json!(result))
+    }
+
+    fn blockchain_address_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let script_hash = address_script_hash(&self.query, params.get(0))?;
+        let address = params[0].as_str().map(str::to_owned);
+        self.subscribe(script_hash, address)
+    }
+
+    // Serves `blockchain.address.*` requests by their `blockchain.scripthash.*` counterparts.
+    fn address_params(&self, params: &[Value]) -> Result<Vec<Value>> {
+        let script_hash = address_script_hash(&self.query, params.get(0))?;
+        let mut params = params.to_vec();
+        params[0] = json!(script_hash.to_hex());
+        Ok(params)
+    }
+
+    fn subscribe(&mut self, script_hash: Sha256dHash, address: Option<String>) -> Result<Value> {
+        if !self.status_hashes.contains_key(&script_hash) {
+            self.scheduler
+                .check_subscriptions(self.tier, self.subscriptions())?;
+        }
+        let status = self
+            .query
+            .subscribed_status(&script_hash[..], self.txid_limit())?;
+        let mut hasher = StatusHasher::default();
+        let result = status_hash_json(self.query.status_hash(&status, &mut hasher));
+        let subscription = Subscription {
+            status_hash: result.clone(),
+            address,
+            hasher,
+        };
         if self
             .status_hashes
-            .insert(script_hash, result.clone())
+            .insert(script_hash, subscription)
             .is_none()
         {
-            self.stats.subscriptions.inc();
+            self.add_subscriptions(1);
         }
 
         Ok(result)
@@ -239,38 +927,146 @@ impl Connection {
     fn blockchain_scripthash_get_balance(&self, params: &[Value]) -> Result<Value> {
         let script_hash =
             hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
+        let status = self.status(&script_hash)?;
         Ok(
             json!({ "confirmed": status.confirmed_balance(), "unconfirmed": status.mempool_balance() }),
         )
     }
 
-    fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<Value> {
+    fn blockchain_scripthash_get_history(&self, params: &[Value]) -> Result<RawJson> {
+        let script_hash =
+            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
+        let from_height = usize_from_value_or(params.get(1), "from_height", 0)?;
+        let limit = usize_from_value_or(params.get(2), "limit", 0)?;
+        let history = if from_height > 0 || limit > 0 {
+            // paginated histories are large, so their confirmed part is persisted (as for
+            // subscriptions), and the following pages are computed by scanning only new blocks
+            let status = self
+                .query
+                .subscribed_status(&script_hash[..], self.txid_limit())?;
+            status.history_page(from_height as u32, limit)
+        } else {
+            self.status(&script_hash)?.history()
+        };
+        Ok(RawJson(history_json(&history)))
+    }
+
+    fn blockchain_scripthash_get_mempool(&self, params: &[Value]) -> Result<RawJson> {
         let script_hash =
             hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        let status = self.query.status(&script_hash[..])?;
-        Ok(json!(Value::Array(
-            status
-                .history()
-                .into_iter()
-                .map(|item| item.to_json())
-                .collect()
+        let status = self.status(&script_hash)?;
+        let mempool: Vec<HistoryItem> = status
+            .history()
+            .into_iter()
+            .filter(|item| item.height() <= 0)
+            .collect();
+        Ok(RawJson(history_json(&mempool)))
+    }
+
+    // Returns the history items added since the client's last known `height` (and all the
+    // unconfirmed ones), or the whole history if the client's block at `height` was reorged.
+    fn blockchain_scripthash_get_history_since(&self, params: &[Value]) -> Result<RawJson> {
+        let script_hash =
+            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
+        let since = usize_from_value(params.get(1), "height")?;
+        let blockhash = match params.get(2) {
+            None | Some(Value::Null) => None,
+            value => Some(hash_from_value::<BlockHash>(value).chain_err(|| "bad block_hash")?),
+        };
+        let tip = self.query.get_best_header()?;
+        let reset = blockhash.map_or(false, |blockhash| {
+            let headers = self.query.get_headers(&[since]);
+            headers.first().map(HeaderEntry::hash) != Some(&blockhash)
+        });
+        let status = self.status(&script_hash)?;
+        let history: Vec<HistoryItem> = status
+            .history()
+            .into_iter()
+            .filter(|item| reset || item.height() <= 0 || item.height() as usize > since)
+            .collect();
+        Ok(RawJson(format!(
+            "{{\"status\":{},\"height\":{},\"block_hash\":\"{}\",\"reset\":{},\"history\":{}}}",
+            status_hash_json(status.hash()),
+            tip.height(),
+            tip.hash().to_hex(),
+            reset,
+            history_json(&history)
         )))
     }
 
+    fn blockchain_scripthash_get_first_use(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
+        Ok(match self.query.get_first_use(&script_hash[..])? {
+            Some((height, blockhash, txid)) => json!({
+                "height": height,
+                "block_hash": blockhash.to_hex(),
+                "tx_hash": txid.to_hex(),
+            }),
+            None => Value::Null,
+        })
+    }
+
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash =
             hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
-        Ok(unspent_from_status(&self.query.status(&script_hash[..])?))
+        Ok(unspent_from_status(&self.status(&script_hash)?))
+    }
+
+    // Restricted to authenticated connections, since evicting the shared cache (and recomputing
+    // the status from scratch) slows down the other clients.
+    fn blockchain_scripthash_refresh(&mut self, params: &[Value]) -> Result<Value> {
+        if self.tier == ANONYMOUS_TIER {
+            bail!("blockchain.scripthash.refresh requires authentication");
+        }
+        let script_hash =
+            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
+        let status = self
+            .query
+            .refresh_status(&script_hash[..], self.txid_limit())?;
+        let status_hash = status_hash_json(status.hash());
+        if let Some(subscription) = self.status_hashes.get_mut(&script_hash) {
+            // the client gets the fresh status below
+            subscription.status_hash = status_hash.clone();
+            subscription.hasher = StatusHasher::default();
+        }
+        Ok(json!({
+            "status": status_hash,
+            "confirmed": status.confirmed_balance(),
+            "unconfirmed": status.mempool_balance(),
+            "tx_count": status.history().len(),
+        }))
     }
 
-    fn blockchain_transaction_broadcast(&self, params: &[Value]) -> Result<Value> {
-        let tx = params.get(0).chain_err(|| "missing tx")?;
-        let tx = tx.as_str().chain_err(|| "non-string tx")?;
-        let tx = hex::decode(&tx).chain_err(|| "non-hex tx")?;
-        let tx: Transaction = deserialize(&tx).chain_err(|| "failed to parse tx")?;
-        let txid = self.query.broadcast(&tx)?;
-        self.query.update_mempool()?;
+    fn blockchain_scripthash_get_utxo_summary(&self, params: &[Value]) -> Result<Value> {
+        let script_hash =
+            hash_from_value::<Sha256dHash>(params.get(0)).chain_err(|| "bad script_hash")?;
+        let summary = self.status(&script_hash)?.utxo_summary();
+        Ok(json!({
+            "count": summary.count,
+            "total_value": summary.total_value,
+            "largest": summary.largest,
+            "smallest": summary.smallest,
+            "oldest_height": summary.oldest_height,
+        }))
+    }
+
+    fn blockchain_transaction_broadcast(&mut self, params: &[Value]) -> Result<Value> {
+        self.broadcast.check(self.addr.ip(), params)?;
+        let tx = params.get(0).ok_or_else(|| invalid_params("missing tx"))?;
+        let tx = tx.as_str().ok_or_else(|| invalid_params("non-string tx"))?;
+        let tx = hex::decode(&tx).map_err(|_| invalid_params("non-hex tx"))?;
+        let tx: Transaction = deserialize(&tx).map_err(|_| invalid_params("failed to parse tx"))?;
+        let max_fee_rate = self.broadcast.max_fee_rate(params)?;
+        if self.broadcast.precheck {
+            self.query.test_broadcast(&tx, max_fee_rate)?;
+        }
+        let txid = self.query.broadcast(&tx, max_fee_rate)?;
+        // can't be updated during this request's snapshot
+        self.mempool_outdated = true;
+        if self.stateless {
+            return Ok(json!(txid.to_hex())); // the mempool is updated after the HTTP request
+        }
         if let Err(e) = self.sender.try_send(Message::PeriodicUpdate) {
             warn!("failed to issue PeriodicUpdate after broadcast: {}", e);
         }
@@ -280,12 +1076,38 @@ impl Connection {
     fn blockchain_transaction_get(&self, params: &[Value]) -> Result<Value> {
         let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
         let verbose = match params.get(1) {
-            Some(value) => value.as_bool().chain_err(|| "non-bool verbose value")?,
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| invalid_params("non-bool verbose value"))?,
             None => false,
         };
         Ok(self.query.get_transaction(&tx_hash, verbose)?)
     }
 
+    fn blockchain_transaction_get_batch(&self, params: &[Value]) -> Result<Value> {
+        let tx_hashes = params
+            .get(0)
+            .ok_or_else(|| invalid_params("missing tx_hashes"))?;
+        let tx_hashes = tx_hashes
+            .as_array()
+            .ok_or_else(|| invalid_params("non-array tx_hashes"))?;
+        if tx_hashes.len() > MAX_BATCH_TXIDS {
+            let msg = format!("too many tx_hashes (max {})", MAX_BATCH_TXIDS);
+            return Err(invalid_params(&msg));
+        }
+        let tx_hashes = tx_hashes
+            .iter()
+            .map(|value| hash_from_value(Some(value)).chain_err(|| "bad tx_hash"))
+            .collect::<Result<Vec<Txid>>>()?;
+        let verbose = match params.get(1) {
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| invalid_params("non-bool verbose value"))?,
+            None => false,
+        };
+        Ok(json!(self.query.get_transactions(&tx_hashes, verbose)?))
+    }
+
     fn blockchain_transaction_get_confirmed_blockhash(&self, params: &[Value]) -> Result<Value> {
         let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
         self.query.get_confirmed_blockhash(&tx_hash)
@@ -305,6 +1127,53 @@ impl Connection {
                 "pos": pos}))
     }
 
+    fn blockchain_transaction_get_status(&self, params: &[Value]) -> Result<Value> {
+        let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
+        Ok(match self.query.get_tx_status(&tx_hash) {
+            TxStatus::Confirmed { height, blockhash } => json!({
+                "status": "confirmed",
+                "height": height,
+                "block_hash": blockhash.to_hex(),
+            }),
+            TxStatus::Mempool => json!({"status": "mempool"}),
+            TxStatus::Unknown => json!({"status": "unknown"}),
+        })
+    }
+
+    fn blockchain_transaction_get_replaceability(&self, params: &[Value]) -> Result<Value> {
+        let tx_hash = hash_from_value(params.get(0)).chain_err(|| "bad tx_hash")?;
+        Ok(match self.query.get_replaceability(&tx_hash) {
+            Some(info) => json!({
+                "bip125_replaceable": info.replaceable,
+                "signals_rbf": info.signals_rbf,
+                "fee": info.fee,
+                "vsize": info.vsize,
+                "effective_fee_rate": info.effective_fee_rate(),
+                "ancestor_count": info.ancestor_count,
+                "ancestor_vsize": info.ancestor_vsize,
+                "ancestor_fees": info.ancestor_fees,
+                "first_seen": info.first_seen,
+            }),
+            None => Value::Null, // not in the mempool
+        })
+    }
+
+    fn blockchain_transaction_search(&self, params: &[Value]) -> Result<Value> {
+        let prefix = params
+            .get(0)
+            .ok_or_else(|| invalid_params("missing txid prefix"))?;
+        let prefix = prefix
+            .as_str()
+            .ok_or_else(|| invalid_params("non-string txid prefix"))?;
+        let matches: Vec<Value> = self
+            .query
+            .search_txids(prefix)?
+            .into_iter()
+            .map(|(txid, height)| json!({"tx_hash": txid.to_hex(), "height": height}))
+            .collect();
+        Ok(json!(matches))
+    }
+
     fn blockchain_transaction_id_from_pos(&self, params: &[Value]) -> Result<Value> {
         let height = usize_from_value(params.get(0), "height")?;
         let tx_pos = usize_from_value(params.get(1), "tx_pos")?;
@@ -323,25 +1192,143 @@ impl Connection {
             "merkle" : merkle_vec}))
     }
 
-    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<Value> {
+    // Only these methods are served without the transaction index (see `--headers-only`).
+    fn check_headers_only(&self, method: &str) -> Result<()> {
+        if self.query.headers_only() && !HEADERS_ONLY_METHODS.contains(&method) {
+            bail!("{} is not supported by this server (headers only)", method);
+        }
+        Ok(())
+    }
+
+    // HTTP requests are replied once, so their connection can't be notified.
+    fn check_stateless(&self, method: &str) -> Result<()> {
+        if self.stateless && method.ends_with(".subscribe") {
+            bail!(
+                "{} is not supported over HTTP (use a TCP connection)",
+                method
+            );
+        }
+        Ok(())
+    }
+
+    // Only `server.*` methods don't depend on the daemon's (and the index's) chain state.
+    fn check_daemon_synced(&self, method: &str) -> Result<()> {
+        if method.starts_with("server.") {
+            return Ok(());
+        }
+        self.query.check_daemon_synced()?;
+        self.query.check_in_sync()
+    }
+
+    fn handle_command(&mut self, method: &str, params: &[Value], id: &Value) -> Result<RawJson> {
         let timer = self
             .stats
             .latency
             .with_label_values(&[method])
             .start_timer();
-        let result = match method {
+        self.stats
+            .client_requests
+            .with_label_values(&[&self.user_agent])
+            .inc();
+        self.stats
+            .tier_requests
+            .with_label_values(&[self.scheduler.tier_name(self.tier)])
+            .inc();
+        let scheduler = Arc::clone(&self.scheduler);
+        let result = scheduler
+            .check_version(method, self.version_negotiated)
+            .and_then(|()| self.check_headers_only(method))
+            .and_then(|()| self.check_stateless(method))
+            .and_then(|()| self.check_daemon_synced(method))
+            .and_then(|()| {
+                scheduler.check_method(self.tier, method)?;
+                let _slot = scheduler.admit(self.addr.ip(), self.tier, method)?;
+                match method {
+                    "blockchain.address.get_history" => {
+                        self.blockchain_scripthash_get_history(&self.address_params(params)?)
+                    }
+                    "blockchain.address.get_mempool" => {
+                        self.blockchain_scripthash_get_mempool(&self.address_params(params)?)
+                    }
+                    "blockchain.block.get" => self.blockchain_block_get(&params),
+                    "blockchain.block.headers" => self.blockchain_block_headers(&params),
+                    "blockchain.scripthash.get_history" => {
+                        self.blockchain_scripthash_get_history(&params)
+                    }
+                    "blockchain.scripthash.get_history_since" => {
+                        self.blockchain_scripthash_get_history_since(&params)
+                    }
+                    "blockchain.scripthash.get_mempool" => {
+                        self.blockchain_scripthash_get_mempool(&params)
+                    }
+                    _ => self.handle_value_command(method, params).map(RawJson::from),
+                }
+            });
+        timer.observe_duration();
+        Ok(match result {
+            Ok(RawJson(result)) => RawJson(format!(
+                "{{\"id\":{},\"jsonrpc\":\"2.0\",\"result\":{}}}",
+                id, result
+            )),
+            Err(e) => {
+                let params = match method {
+                    // don't log the broadcast token
+                    "blockchain.transaction.broadcast" => &params[..params.len().min(1)],
+                    // don't log the password
+                    "server.authenticate" => &[],
+                    _ => params,
+                };
+                warn!(
+                    "rpc #{} {} {} failed: {}",
+                    id,
+                    method,
+                    redacted_hashes(format!("{:?}", params)),
+                    redacted_hashes(e.display_chain())
+                );
+                error_json(id, error_code(&e), &e.to_string(), error_data(&e))
+            }
+        })
+    }
+
+    fn handle_value_command(&mut self, method: &str, params: &[Value]) -> Result<Value> {
+        match method {
+            "blockchain.address.get_balance" => {
+                self.blockchain_scripthash_get_balance(&self.address_params(params)?)
+            }
+            "blockchain.address.listunspent" => {
+                self.blockchain_scripthash_listunspent(&self.address_params(params)?)
+            }
+            "blockchain.address.subscribe" => self.blockchain_address_subscribe(&params),
             "blockchain.block.header" => self.blockchain_block_header(&params),
-            "blockchain.block.headers" => self.blockchain_block_headers(&params),
             "blockchain.estimatefee" => self.blockchain_estimatefee(&params),
-            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
+            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(&params),
+            "blockchain.numblocks.subscribe" => self.blockchain_numblocks_subscribe(),
+            "blockchain.outpoint.subscribe" => self.blockchain_outpoint_subscribe(&params),
+            "blockchain.outpoint.unsubscribe" => self.blockchain_outpoint_unsubscribe(&params),
+            "blockchain.outpoint.get_spent_status" => {
+                self.blockchain_outpoint_get_spent_status(&params)
+            }
             "blockchain.relayfee" => self.blockchain_relayfee(),
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
-            "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
+            "blockchain.scripthash.get_first_use" => {
+                self.blockchain_scripthash_get_first_use(&params)
+            }
+            "blockchain.scripthash.get_utxo_summary" => {
+                self.blockchain_scripthash_get_utxo_summary(&params)
+            }
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
+            "blockchain.scripthash.refresh" => self.blockchain_scripthash_refresh(&params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
+            "blockchain.scripthashes.subscribe" => self.blockchain_scripthashes_subscribe(&params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
             "blockchain.transaction.get" => self.blockchain_transaction_get(&params),
+            "blockchain.transaction.get_batch" => self.blockchain_transaction_get_batch(&params),
             "blockchain.transaction.get_merkle" => self.blockchain_transaction_get_merkle(&params),
+            "blockchain.transaction.get_status" => self.blockchain_transaction_get_status(&params),
+            "blockchain.transaction.get_replaceability" => {
+                self.blockchain_transaction_get_replaceability(&params)
+            }
+            "blockchain.transaction.search" => self.blockchain_transaction_search(&params),
             "blockchain.transaction.get_confirmed_blockhash" => {
                 self.blockchain_transaction_get_confirmed_blockhash(&params)
             }
@@ -349,28 +1336,17 @@ impl Connection {
                 self.blockchain_transaction_id_from_pos(&params)
             }
             "mempool.get_fee_histogram" => self.mempool_get_fee_histogram(),
+            "mempool.fee_histogram.subscribe" => self.mempool_fee_histogram_subscribe(),
+            "mempool.transactions.subscribe" => self.mempool_transactions_subscribe(),
+            "server.authenticate" => self.server_authenticate(&params),
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
+            "server.features" => self.server_features(),
             "server.peers.subscribe" => self.server_peers_subscribe(),
             "server.ping" => Ok(Value::Null),
             "server.version" => self.server_version(params),
-            &_ => bail!("unknown method {} {:?}", method, params),
-        };
-        timer.observe_duration();
-        // TODO: return application errors should be sent to the client
-        Ok(match result {
-            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
-            Err(e) => {
-                warn!(
-                    "rpc #{} {} {:?} failed: {}",
-                    id,
-                    method,
-                    params,
-                    e.display_chain()
-                );
-                json!({"jsonrpc": "2.0", "id": id, "error": format!("{}", e)})
-            }
-        })
+            &_ => bail!(ErrorKind::MethodNotFound(method.to_owned())),
+        }
     }
 
     fn update_subscriptions(&mut self) -> Result<Vec<Value>> {
@@ -384,74 +1360,256 @@ impl Connection {
             let entry = self.query.get_best_header()?;
             if *last_entry != entry {
                 *last_entry = entry;
-                let hex_header = hex::encode(serialize(last_entry.header()));
-                let header = json!({"hex": hex_header, "height": last_entry.height()});
+                let header = header_json(last_entry, self.raw_headers);
                 result.push(json!({
                     "jsonrpc": "2.0",
                     "method": "blockchain.headers.subscribe",
                     "params": [header]}));
             }
         }
-        for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let status = self.query.status(&script_hash[..])?;
-            let new_status_hash = status.hash().map_or(Value::Null, |h| json!(hex::encode(h)));
-            if new_status_hash == *status_hash {
+        if let Some(ref mut last_height) = self.last_height {
+            let height = self.query.get_best_header()?.height();
+            if *last_height != height {
+                *last_height = height;
+                result.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.numblocks.subscribe",
+                    "params": [height]}));
+            }
+        }
+        if let Some(ref mut last_histogram) = self.last_fee_histogram {
+            let histogram = self.query.get_fee_histogram();
+            if fee_histogram_changed(last_histogram, &histogram) {
+                result.push(json!({
+                    "jsonrpc": "2.0",
+                    "method": "mempool.fee_histogram.subscribe",
+                    "params": [histogram]}));
+                *last_histogram = histogram;
+            }
+        }
+        if let Some(ref mut count) = self.mempool_txs {
+            let (txs, new_count) = self.query.get_new_mempool_txs(*count);
+            *count = new_count;
+            result.extend(txs.into_iter().map(|tx| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "method": "mempool.transactions.subscribe",
+                    "params": [new_transaction_json(&tx)]})
+            }));
+        }
+        let idle = self.last_request.lock().unwrap().elapsed();
+        if self.subscriptions() > 0 && self.scheduler.subscriptions_expired(idle) {
+            let count = self.subscriptions();
+            self.status_hashes.clear();
+            self.outpoints.clear();
+            self.remove_subscriptions(count);
+            info!(
+                "[{}] dropped {} subscriptions, idle for {:?}",
+                redacted(self.addr),
+                count,
+                idle
+            );
+            result.push(json!({
+                "jsonrpc": "2.0",
+                "method": "server.subscriptions.expired",
+                "params": [count]}));
+        }
+        let txid_limit = self.txid_limit();
+        for (script_hash, subscription) in self.status_hashes.iter_mut() {
+            let status = self.query.subscribed_status(&script_hash[..], txid_limit)?;
+            let new_status_hash =
+                status_hash_json(self.query.status_hash(&status, &mut subscription.hasher));
+            if new_status_hash == subscription.status_hash {
+                continue;
+            }
+            result.push(match subscription.address {
+                Some(ref address) => json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.address.subscribe",
+                    "params": [address, new_status_hash]}),
+                None => json!({
+                    "jsonrpc": "2.0",
+                    "method": "blockchain.scripthash.subscribe",
+                    "params": [script_hash.to_hex(), new_status_hash]}),
+            });
+            subscription.status_hash = new_status_hash;
+        }
+        for (outpoint, status) in self.outpoints.iter_mut() {
+            let new_status = outpoint_status_json(&self.query, outpoint)?;
+            if new_status == *status {
                 continue;
             }
             result.push(json!({
                 "jsonrpc": "2.0",
-                "method": "blockchain.scripthash.subscribe",
-                "params": [script_hash.to_hex(), new_status_hash]}));
-            *status_hash = new_status_hash;
+                "method": "blockchain.outpoint.subscribe",
+                "params": [[outpoint.txid.to_hex(), outpoint.vout], new_status]}));
+            *status = new_status;
         }
         timer.observe_duration();
         Ok(result)
     }
 
-    fn send_values(&mut self, values: &[Value]) -> Result<()> {
+    fn send_values(&mut self, stream: &mut Stream, values: &[Value]) -> Result<()> {
+        // serialize all values into a single buffer, to be sent using a single write
+        let mut buffer = vec![];
         for value in values {
-            let line = value.to_string() + "\n";
-            self.stream
-                .write_all(line.as_bytes())
-                .chain_err(|| format!("failed to send {}", value))?;
+            if self.binary {
+                msgpack::write_frame(&mut buffer, value);
+                continue;
+            }
+            serde_json::to_writer(&mut buffer, value)
+                .chain_err(|| format!("failed to serialize {}", value))?;
+            buffer.push(b'\n');
         }
-        Ok(())
+        self.send_buffer(stream, &buffer)
+    }
+
+    fn send_buffer(&mut self, stream: &mut Stream, buffer: &[u8]) -> Result<()> {
+        self.count_bytes("send", buffer.len())?;
+        stream
+            .write_all(buffer)
+            .chain_err(|| format!("failed to send {} bytes", buffer.len()))
+    }
+
+    // Counts the bytes sent (or received), failing if the peer exceeds the bandwidth limits.
+    fn count_bytes(&mut self, dir: &str, len: usize) -> Result<()> {
+        match dir {
+            "send" => self.bytes_sent += len,
+            _ => self.bytes_received += len,
+        }
+        self.stats
+            .bytes
+            .with_label_values(&[dir])
+            .inc_by(len as i64);
+        self.scheduler.charge_bandwidth(self.addr.ip(), len)
     }
 
-    fn handle_replies(&mut self, receiver: Receiver<Message>) -> Result<()> {
-        let empty_params = json!([]);
+    // Returns `None` for notifications (which are handled, but not replied).
+    fn handle_request(&mut self, cmd: &Value) -> Result<Option<RawJson>> {
+        *self.last_request.lock().unwrap() = Instant::now();
+        let reply = match parse_request(cmd) {
+            Ok((method, params, Some(id))) => self.handle_command(method, params, id)?,
+            Ok((method, params, None)) => {
+                self.handle_command(method, params, &Value::Null)?;
+                return Ok(None);
+            }
+            Err(reply) => reply,
+        };
+        self.record(cmd, &reply.0);
+        Ok(Some(reply))
+    }
+
+    // Handles a single request, or a batch of requests (replied by an array of their responses,
+    // in the same order, or not at all if they are all notifications).
+    // Serves the requests from a consistent snapshot (see `Query::snapshot`), which is released
+    // while calling bitcoind (see `DAEMON_METHODS`), so a slow RPC doesn't block the index updates.
+    fn handle_message(&mut self, cmd: &Value) -> Result<Option<RawJson>> {
+        let query = Arc::clone(&self.query);
+        let cmds = match cmd {
+            Value::Array(cmds) => cmds,
+            _ => {
+                let _snapshot = if calls_daemon(cmd) {
+                    None
+                } else {
+                    Some(query.snapshot())
+                };
+                return self.handle_request(cmd);
+            }
+        };
+        if cmds.is_empty() {
+            return Ok(Some(error_json(
+                &Value::Null,
+                INVALID_REQUEST,
+                "empty batch",
+                None,
+            )));
+        }
+        if cmds.len() > MAX_BATCH_REQUESTS {
+            // so a batch can't pin its snapshot (and delay the index updates) for long
+            return Ok(Some(error_json(
+                &Value::Null,
+                INVALID_REQUEST,
+                &format!("too many requests in batch (max {})", MAX_BATCH_REQUESTS),
+                None,
+            )));
+        }
+        // the requests are served in order, since they may depend on the previous ones (e.g.
+        // `server.version`, or subscriptions) and they share the connection's state
+        let mut replies = vec![];
+        let mut snapshot = None;
+        for cmd in cmds {
+            if calls_daemon(cmd) {
+                snapshot = None;
+            } else if snapshot.is_none() {
+                snapshot = Some(query.snapshot());
+            }
+            if let Some(RawJson(reply)) = self.handle_request(cmd)? {
+                replies.push(reply);
+            }
+        }
+        drop(snapshot);
+        if replies.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(RawJson(format!("[{}]", replies.join(",")))))
+    }
+
+    fn handle_replies(&mut self, stream: &mut Stream, receiver: Receiver<Message>) -> Result<()> {
         loop {
             let msg = receiver.recv().chain_err(|| "channel closed")?;
-            trace!("RPC {:?}", msg);
+            trace!("RPC {}", redacted_hashes(format!("{:?}", msg)));
             match msg {
                 Message::Request(line) => {
+                    self.count_bytes("recv", line.len())?;
                     let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
-                    let reply = match (
-                        cmd.get("method"),
-                        cmd.get("params").unwrap_or_else(|| &empty_params),
-                        cmd.get("id"),
-                    ) {
-                        (
-                            Some(&Value::String(ref method)),
-                            &Value::Array(ref params),
-                            Some(ref id),
-                        ) => self.handle_command(method, params, id)?,
-                        _ => bail!("invalid command: {}", cmd),
-                    };
-                    self.send_values(&[reply])?
+                    let reply = self.handle_message(&cmd)?;
+                    if let Some(RawJson(mut line)) = reply {
+                        line.push('\n');
+                        self.send_buffer(stream, line.as_bytes())?
+                    }
+                }
+                Message::BinaryRequest(cmd, len) => {
+                    self.count_bytes("recv", len)?;
+                    self.binary = true;
+                    let reply = self.handle_message(&cmd)?;
+                    if let Some(RawJson(reply)) = reply {
+                        let reply: Value = from_str(&reply).chain_err(|| "invalid JSON reply")?;
+                        self.send_values(stream, &[reply])?
+                    }
                 }
                 Message::PeriodicUpdate => {
+                    // e.g. a mobile client which vanished without closing its connection
+                    let idle = self.last_request.lock().unwrap().elapsed();
+                    if self.scheduler.connection_expired(idle) {
+                        info!(
+                            "[{}] disconnecting peer, idle for {:?}",
+                            redacted(self.addr),
+                            idle
+                        );
+                        return Ok(()); // its subscriptions are dropped by `run()`
+                    }
+                    if self.mempool_outdated {
+                        self.query.update_mempool()?;
+                        self.mempool_outdated = false;
+                    }
+                    let query = Arc::clone(&self.query);
+                    let snapshot = query.snapshot();
                     let values = self
                         .update_subscriptions()
                         .chain_err(|| "failed to update subscriptions")?;
-                    self.send_values(&values)?
+                    drop(snapshot); // before blocking on a slow client
+                    self.send_values(stream, &values)?
                 }
                 Message::Done => return Ok(()),
             }
         }
     }
 
-    fn parse_requests(mut reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
+    fn parse_requests(mut reader: BufReader<Stream>, tx: SyncSender<Message>) -> Result<()> {
+        let first_bytes = reader.fill_buf().chain_err(|| "failed to read a request")?;
+        if msgpack::is_binary(first_bytes) {
+            return Connection::parse_binary_requests(reader, tx);
+        }
         loop {
             let mut line = Vec::<u8>::new();
             reader
@@ -479,24 +1637,98 @@ impl Connection {
         }
     }
 
-    pub fn run(mut self, receiver: Receiver<Message>) {
-        let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
+    fn parse_binary_requests(mut reader: BufReader<Stream>, tx: SyncSender<Message>) -> Result<()> {
+        loop {
+            let cmd = msgpack::read_frame(&mut reader).and_then(|frame| {
+                frame
+                    .map(|frame| msgpack::decode(&frame).map(|cmd| (cmd, frame.len() + 4)))
+                    .transpose()
+            });
+            match cmd {
+                Ok(Some((cmd, len))) => tx
+                    .send(Message::BinaryRequest(cmd, len))
+                    .chain_err(|| "channel closed")?,
+                Ok(None) => {
+                    tx.send(Message::Done).chain_err(|| "channel closed")?;
+                    return Ok(());
+                }
+                Err(e) => {
+                    let _ = tx.send(Message::Done);
+                    return Err(e.chain_err(|| "invalid binary request"));
+                }
+            }
+        }
+    }
+
+    // Replies to a single HTTP POST request (or batch), using this (transient) connection.
+    fn handle_http(mut self, mut request: tiny_http::Request) -> Result<()> {
+        self.stateless = true;
+        let reply = self.http_reply(&mut request);
+        self.stats.disconnect(&self.user_agent);
+        let response = match reply {
+            Ok(Some(RawJson(body))) => {
+                let header =
+                    tiny_http::Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                        .expect("invalid Content-Type header");
+                tiny_http::Response::from_string(body).with_header(header)
+            }
+            // only notifications were sent
+            Ok(None) => tiny_http::Response::from_string("").with_status_code(204),
+            Err(e) => tiny_http::Response::from_string(e.to_string()).with_status_code(400),
+        };
+        request
+            .respond(response)
+            .chain_err(|| "failed to send HTTP response")
+    }
+
+    fn http_reply(&mut self, request: &mut tiny_http::Request) -> Result<Option<RawJson>> {
+        if *request.method() != tiny_http::Method::Post {
+            bail!("only POST requests are supported");
+        }
+        let mut body = String::new();
+        request
+            .as_reader()
+            .take(MAX_HTTP_REQUEST_SIZE)
+            .read_to_string(&mut body)
+            .chain_err(|| "failed to read a request")?;
+        self.count_bytes("recv", body.len())?;
+        let cmd: Value = from_str(&body).chain_err(|| "invalid JSON format")?;
+        let reply = self.handle_message(&cmd)?;
+        if self.mempool_outdated {
+            self.query.update_mempool()?; // e.g. for the client's next request
+        }
+        if let Some(RawJson(ref reply)) = reply {
+            self.count_bytes("send", reply.len())?;
+        }
+        Ok(reply)
+    }
+
+    pub fn run(mut self, mut stream: Stream, receiver: Receiver<Message>) {
+        let reader = BufReader::new(stream.try_clone().expect("failed to clone stream"));
         let sender = self.sender.clone();
         let child = spawn_thread("reader", || Connection::parse_requests(reader, sender));
-        if let Err(e) = self.handle_replies(receiver) {
+        if let Err(e) = self.handle_replies(&mut stream, receiver) {
             error!(
                 "[{}] connection handling failed: {}",
-                self.addr,
-                e.display_chain().to_string()
+                redacted(self.addr),
+                redacted_hashes(e.display_chain())
             );
         }
-        self.stats
-            .subscriptions
-            .sub(self.status_hashes.len() as i64);
-        debug!("[{}] shutting down connection", self.addr);
-        let _ = self.stream.shutdown(Shutdown::Both);
+        self.remove_subscriptions(self.subscriptions());
+        self.stats.disconnect(&self.user_agent);
+        debug!(
+            "[{}] shutting down connection (sent {} bytes, received {} bytes)",
+            redacted(self.addr),
+            self.bytes_sent,
+            self.bytes_received
+        );
+        let _ = stream.shutdown(Shutdown::Both);
         if let Err(err) = child.join().expect("receiver panicked") {
-            error!("[{}] receiver failed: {}", self.addr, err);
+            error!(
+                "[{}] receiver failed: {}",
+                redacted(self.addr),
+                redacted_hashes(err)
+            );
         }
     }
 }
@@ -504,6 +1736,7 @@ impl Connection {
 #[derive(Debug)]
 pub enum Message {
     Request(String),
+    BinaryRequest(Value, usize), // and the frame's length
     PeriodicUpdate,
     Done,
 }
@@ -513,21 +1746,207 @@ pub enum Notification {
     Exit,
 }
 
+/// The Electrum RPC server's listening addresses.
+#[derive(Clone)]
+pub struct Listeners {
+    pub rpc: Vec<SocketAddr>,
+    pub tls: Option<TlsListener>,
+    #[cfg(feature = "quic")]
+    pub quic: Option<QuicListener>,
+    pub http: Option<SocketAddr>, // for single requests (or batches), sent using HTTP POST
+    // the proxies sending the TCP (and TLS) clients' addresses (see `proxy_protocol`)
+    pub trusted_proxies: Option<Arc<Vec<IpAddr>>>,
+    pub onion: Option<Arc<OnionService>>, // published using Tor's control port
+    pub max_connections: Option<usize>,   // of the TCP (and TLS, QUIC) clients
+    pub evict_idle_peers: bool,           // instead of refusing new connections
+}
+
+impl Listeners {
+    pub fn from_config(config: &Config) -> Result<Listeners> {
+        Ok(Listeners {
+            rpc: config.electrum_rpc_addrs.clone(),
+            tls: TlsListener::from_config(config)?,
+            #[cfg(feature = "quic")]
+            quic: QuicListener::from_config(config)?,
+            http: config.electrum_http_addr,
+            trusted_proxies: if config.proxy_protocol {
+                Some(Arc::new(config.proxy_protocol_trusted.clone()))
+            } else {
+                None
+            },
+            onion: OnionService::from_config(config)?.map(Arc::new),
+            max_connections: config.max_connections,
+            evict_idle_peers: config.evict_idle_peers,
+        })
+    }
+
+    // Only the onion service is announced, since the public addresses may be unknown (e.g.
+    // behind NAT or a load balancer).
+    fn hosts(&self) -> Value {
+        let mut hosts = json!({});
+        if let Some(ref onion) = self.onion {
+            hosts[onion.host()] = json!({"tcp_port": onion.port(), "ssl_port": null});
+        }
+        hosts
+    }
+}
+
 pub struct RPC {
     notification: Sender<Notification>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
 }
 
+// A connection's thread, and its handles for evicting it (see `max_connections`).
+struct Peer {
+    thread: thread::JoinHandle<()>,
+    addr: SocketAddr,
+    sender: SyncSender<Message>,
+    last_request: Arc<Mutex<Instant>>,
+    evicted: bool, // until its thread is joined
+}
+
+// Disconnects the connection which sent no requests for the longest time, returning false if
+// there is none (or if it is busy, i.e. its queue of messages is full).
+fn evict_idle_peer(peers: &mut HashMap<thread::ThreadId, Peer>) -> bool {
+    let idle = peers
+        .values_mut()
+        .filter(|peer| !peer.evicted)
+        .min_by_key(|peer| *peer.last_request.lock().unwrap());
+    let peer = match idle {
+        Some(peer) => peer,
+        None => return false,
+    };
+    if let Err(TrySendError::Full(_)) = peer.sender.try_send(Message::Done) {
+        return false;
+    }
+    info!("[{}] evicting idle peer", redacted(peer.addr));
+    peer.evicted = true;
+    true
+}
+
+// The connected peers, limited by `max_connections`. The acceptors count each connection (and may
+// refuse it) before spawning any work for it, so the connections still sending their PROXY header
+// are counted as well.
+struct Peers {
+    map: Mutex<HashMap<thread::ThreadId, Peer>>,
+    pending: AtomicUsize, // accepted, but not connected yet
+    max_connections: Option<usize>,
+    evict_idle_peers: bool,
+    disconnected: (
+        crossbeam_channel::Sender<thread::ThreadId>,
+        crossbeam_channel::Receiver<thread::ThreadId>,
+    ),
+}
+
+impl Peers {
+    fn new(max_connections: Option<usize>, evict_idle_peers: bool) -> Peers {
+        Peers {
+            map: Mutex::new(HashMap::new()),
+            pending: AtomicUsize::new(0),
+            max_connections,
+            evict_idle_peers,
+            disconnected: crossbeam_channel::unbounded(),
+        }
+    }
+
+    // Counts a new connection as pending, returning false if it should be refused.
+    fn admit(&self, addr: SocketAddr, stats: &Stats) -> bool {
+        let mut map = self.map.lock().unwrap();
+        // join the disconnected peers' threads, before counting the connected ones
+        while let Ok(id) = self.disconnected.1.try_recv() {
+            if let Some(peer) = map.remove(&id) {
+                trace!("[{}] joining {:?}", redacted(peer.addr), id);
+                if let Err(error) = peer.thread.join() {
+                    error!("failed to join {:?}: {:?}", id, error);
+                }
+            }
+        }
+        let connected =
+            map.values().filter(|peer| !peer.evicted).count() + self.pending.load(Ordering::SeqCst);
+        if self.max_connections.map_or(false, |max| connected >= max) {
+            if self.evict_idle_peers && evict_idle_peer(&mut map) {
+                stats
+                    .limited_connections
+                    .with_label_values(&["evicted"])
+                    .inc();
+            } else {
+                debug!(
+                    "[{}] refused connection ({} peers)",
+                    redacted(addr),
+                    connected
+                );
+                stats
+                    .limited_connections
+                    .with_label_values(&["refused"])
+                    .inc();
+                return false;
+            }
+        }
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        true
+    }
+
+    // Stops counting a pending connection (e.g. which failed to send its PROXY header).
+    fn abandon(&self) {
+        self.pending.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 struct Stats {
     latency: HistogramVec,
     subscriptions: Gauge,
+    peers: Gauge,
+    bytes: CounterVec,
+    clients: GaugeVec,           // by user agent
+    client_requests: CounterVec, // by user agent
+    user_agents: Mutex<HashSet<String>>,
+    tier_requests: CounterVec,       // by QoS tier (or tenant)
+    tier_subscriptions: GaugeVec,    // by QoS tier (or tenant)
+    limited_connections: CounterVec, // refused or evicted (see `max_connections`)
+}
+
+// Keeps only the printable characters of the client's name (truncated).
+fn sanitize_user_agent(client_id: &str) -> String {
+    let user_agent: String = client_id
+        .trim()
+        .chars()
+        .filter(|c| c.is_ascii_graphic() || *c == ' ')
+        .take(MAX_USER_AGENT_LEN)
+        .collect();
+    if user_agent.is_empty() {
+        UNKNOWN_USER_AGENT.to_owned()
+    } else {
+        user_agent
+    }
+}
+
+impl Stats {
+    /// Counts a connected client, returning its user agent label.
+    fn connect(&self, client_id: &str) -> String {
+        let user_agent = sanitize_user_agent(client_id);
+        let user_agent = {
+            let mut user_agents = self.user_agents.lock().unwrap();
+            if user_agents.contains(&user_agent) || user_agents.len() < MAX_USER_AGENTS {
+                user_agents.insert(user_agent.clone());
+                user_agent
+            } else {
+                OTHER_USER_AGENT.to_owned()
+            }
+        };
+        self.clients.with_label_values(&[&user_agent]).inc();
+        user_agent
+    }
+
+    fn disconnect(&self, user_agent: &str) {
+        self.clients.with_label_values(&[user_agent]).dec();
+    }
 }
 
 impl RPC {
     fn start_notifier(
         notification: Channel<Notification>,
         senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+        acceptor: Sender<Option<(Stream, SocketAddr)>>,
     ) {
         spawn_thread("notification", move || {
             for msg in notification.receiver().iter() {
@@ -550,28 +1969,169 @@ impl RPC {
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::unbounded();
-        let acceptor = chan.sender();
+    fn start_acceptor(
+        addr: SocketAddr,
+        tls: Option<TlsListener>,
+        trusted_proxies: Option<Arc<Vec<IpAddr>>>,
+        peers: Arc<Peers>,
+        stats: Arc<Stats>,
+        acceptor: Sender<Option<(Stream, SocketAddr)>>,
+    ) {
         spawn_thread("acceptor", move || {
             let listener =
                 TcpListener::bind(addr).unwrap_or_else(|e| panic!("bind({}) failed: {}", addr, e));
             info!(
-                "Electrum RPC server running on {} (protocol {})",
-                addr, PROTOCOL_VERSION
+                "Electrum RPC server running on {} ({}protocol {})",
+                addr,
+                if tls.is_some() { "TLS, " } else { "" },
+                MAX_PROTOCOL_VERSION
             );
+            let reading = Arc::new(AtomicUsize::new(0)); // PROXY headers
             loop {
                 let (stream, addr) = listener.accept().expect("accept failed");
+                if !peers.admit(addr, &stats) {
+                    continue; // the stream is closed when dropped
+                }
                 stream
                     .set_nonblocking(false)
                     .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
+                let tls = tls.clone();
+                let acceptor = acceptor.clone();
+                let accepted = move |stream, addr| {
+                    let stream = match tls {
+                        Some(ref tls) => tls.accept(stream),
+                        None => Stream::Plain(stream),
+                    };
+                    acceptor.send(Some((stream, addr))).expect("send failed");
+                };
+                let trusted_proxies = match trusted_proxies {
+                    Some(ref trusted_proxies) => trusted_proxies,
+                    None => {
+                        accepted(stream, addr);
+                        continue;
+                    }
+                };
+                if !proxy::is_trusted(trusted_proxies, addr.ip()) {
+                    // so its PROXY header can't spoof a client's address
+                    warn!("[{}] untrusted PROXY source", redacted(addr));
+                    peers.abandon();
+                    continue; // the stream is closed when dropped
+                }
+                if reading.fetch_add(1, Ordering::SeqCst) >= proxy::MAX_PENDING_HEADERS {
+                    reading.fetch_sub(1, Ordering::SeqCst);
+                    warn!("[{}] too many pending PROXY headers", redacted(addr));
+                    peers.abandon();
+                    continue; // the stream is closed when dropped
+                }
+                // in a separate thread, so a slow client can't delay the others
+                let peers = Arc::clone(&peers);
+                let reading = Arc::clone(&reading);
+                spawn_thread("proxy", move || {
+                    let client = proxy::read_client_addr(&stream);
+                    reading.fetch_sub(1, Ordering::SeqCst);
+                    match client {
+                        Ok(client) => accepted(stream, client.unwrap_or(addr)),
+                        Err(e) => {
+                            warn!("[{}] {}", redacted(addr), e.display_chain());
+                            peers.abandon();
+                        }
+                    }
+                });
             }
         });
-        chan
     }
 
-    pub fn start(addr: SocketAddr, query: Arc<Query>, metrics: &Metrics, relayfee: f64) -> RPC {
+    // QUIC clients are counted (and evicted) like the TCP ones, but each of their streams is a
+    // separate peer (there is no PROXY header, since QUIC isn't forwarded by TCP proxies).
+    #[cfg(feature = "quic")]
+    fn start_quic_acceptor(
+        quic: QuicListener,
+        peers: Arc<Peers>,
+        stats: Arc<Stats>,
+        acceptor: Sender<Option<(Stream, SocketAddr)>>,
+    ) {
+        spawn_thread("quic", move || {
+            info!(
+                "Electrum RPC server running on {} (QUIC, protocol {})",
+                quic.addr(),
+                MAX_PROTOCOL_VERSION
+            );
+            let acceptor = Mutex::new(acceptor);
+            quic.serve(
+                move |addr| peers.admit(addr, &stats),
+                move |stream, addr| {
+                    let acceptor = acceptor.lock().unwrap();
+                    acceptor.send(Some((stream, addr))).expect("send failed");
+                },
+            );
+        });
+    }
+
+    // Serves each HTTP request using a new connection (by a few threads, since the requests are
+    // already limited by the scheduler).
+    fn start_http(
+        addr: SocketAddr,
+        query: Arc<Query>,
+        stats: Arc<Stats>,
+        relayfee: f64,
+        broadcast: Arc<BroadcastPolicy>,
+        scheduler: Arc<Scheduler>,
+        hosts: Arc<Value>,
+    ) {
+        let server = tiny_http::Server::http(addr)
+            .unwrap_or_else(|e| panic!("failed to start HTTP server at {}: {}", addr, e));
+        info!(
+            "Electrum HTTP server running on {} (protocol {})",
+            addr, MAX_PROTOCOL_VERSION
+        );
+        let server = Arc::new(server);
+        for _ in 0..num_cpus::get() {
+            let server = Arc::clone(&server);
+            let query = Arc::clone(&query);
+            let stats = Arc::clone(&stats);
+            let broadcast = Arc::clone(&broadcast);
+            let scheduler = Arc::clone(&scheduler);
+            let hosts = Arc::clone(&hosts);
+            spawn_thread("http", move || loop {
+                let request = match server.recv() {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("HTTP error: {}", e);
+                        continue;
+                    }
+                };
+                let addr = *request.remote_addr();
+                let (sender, _) = mpsc::sync_channel(0); // HTTP connections are not notified
+                let conn = Connection::new(
+                    Arc::clone(&query),
+                    addr,
+                    Arc::clone(&stats),
+                    relayfee,
+                    Arc::clone(&broadcast),
+                    Arc::clone(&scheduler),
+                    sender,
+                )
+                .with_hosts(&hosts);
+                if let Err(e) = conn.handle_http(request) {
+                    warn!(
+                        "[{}] HTTP request failed: {}",
+                        redacted(addr),
+                        redacted_hashes(e.display_chain())
+                    );
+                }
+            });
+        }
+    }
+
+    pub fn start(
+        listeners: Listeners,
+        query: Arc<Query>,
+        metrics: &Metrics,
+        relayfee: f64,
+        broadcast: BroadcastPolicy,
+        scheduler: Scheduler,
+        recorder: Option<Arc<Recorder>>,
+    ) -> RPC {
         let stats = Arc::new(Stats {
             latency: metrics.histogram_vec(
                 HistogramOpts::new("electrs_electrum_rpc", "Electrum RPC latency (seconds)"),
@@ -581,8 +2141,55 @@ impl RPC {
                 "electrs_electrum_subscriptions",
                 "# of Electrum subscriptions",
             )),
+            peers: metrics.gauge(MetricOpts::new(
+                "electrs_electrum_peers",
+                "# of connected Electrum clients",
+            )),
+            bytes: metrics.counter_vec(
+                MetricOpts::new("electrs_electrum_bytes", "Electrum RPC traffic (in bytes)"),
+                &["dir"],
+            ),
+            clients: metrics.gauge_vec(
+                MetricOpts::new(
+                    "electrs_electrum_clients",
+                    "# of connected Electrum clients (by user agent)",
+                ),
+                &["user_agent"],
+            ),
+            client_requests: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrs_electrum_client_requests",
+                    "# of Electrum requests (by user agent)",
+                ),
+                &["user_agent"],
+            ),
+            user_agents: Mutex::new(HashSet::new()),
+            tier_requests: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrs_electrum_tier_requests",
+                    "# of Electrum requests (by QoS tier or tenant)",
+                ),
+                &["tier"],
+            ),
+            tier_subscriptions: metrics.gauge_vec(
+                MetricOpts::new(
+                    "electrs_electrum_tier_subscriptions",
+                    "# of Electrum subscriptions (by QoS tier or tenant)",
+                ),
+                &["tier"],
+            ),
+            limited_connections: metrics.counter_vec(
+                MetricOpts::new(
+                    "electrs_electrum_limited_connections",
+                    "# of Electrum connections refused or evicted by max_connections",
+                ),
+                &["action"],
+            ),
         });
         stats.subscriptions.set(0);
+        stats.peers.set(0);
+        let broadcast = Arc::new(broadcast);
+        let scheduler = Arc::new(scheduler);
         let notification = Channel::unbounded();
 
         RPC {
@@ -590,47 +2197,111 @@ impl RPC {
             server: Some(spawn_thread("rpc", move || {
                 let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
 
-                let acceptor = RPC::start_acceptor(addr);
+                let hosts = Arc::new(listeners.hosts());
+                let acceptor = Channel::unbounded();
+                let trusted_proxies = listeners.trusted_proxies;
+                let peers = Arc::new(Peers::new(
+                    listeners.max_connections,
+                    listeners.evict_idle_peers,
+                ));
+                for addr in listeners.rpc {
+                    RPC::start_acceptor(
+                        addr,
+                        None,
+                        trusted_proxies.clone(),
+                        Arc::clone(&peers),
+                        Arc::clone(&stats),
+                        acceptor.sender(),
+                    );
+                }
+                if let Some(tls) = listeners.tls {
+                    let addr = tls.addr();
+                    RPC::start_acceptor(
+                        addr,
+                        Some(tls),
+                        trusted_proxies.clone(),
+                        Arc::clone(&peers),
+                        Arc::clone(&stats),
+                        acceptor.sender(),
+                    );
+                }
+                #[cfg(feature = "quic")]
+                {
+                    if let Some(quic) = listeners.quic {
+                        RPC::start_quic_acceptor(
+                            quic,
+                            Arc::clone(&peers),
+                            Arc::clone(&stats),
+                            acceptor.sender(),
+                        );
+                    }
+                }
+                if let Some(addr) = listeners.http {
+                    RPC::start_http(
+                        addr,
+                        Arc::clone(&query),
+                        Arc::clone(&stats),
+                        relayfee,
+                        Arc::clone(&broadcast),
+                        Arc::clone(&scheduler),
+                        Arc::clone(&hosts),
+                    );
+                }
                 RPC::start_notifier(notification, senders.clone(), acceptor.sender());
 
-                let mut threads = HashMap::new();
-                let (garbage_sender, garbage_receiver) = crossbeam_channel::unbounded();
-
+                // the connections were counted (as pending) by their acceptors
                 while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
                     // explicitely scope the shadowed variables for the new thread
-                    let query = Arc::clone(&query);
                     let stats = Arc::clone(&stats);
-                    let garbage_sender = garbage_sender.clone();
+                    let garbage_sender = peers.disconnected.0.clone();
                     let (sender, receiver) = mpsc::sync_channel(10);
 
                     senders.lock().unwrap().push(sender.clone());
 
+                    let conn = Connection::new(
+                        Arc::clone(&query),
+                        addr,
+                        Arc::clone(&stats),
+                        relayfee,
+                        Arc::clone(&broadcast),
+                        Arc::clone(&scheduler),
+                        sender.clone(),
+                    )
+                    .with_recorder(recorder.clone())
+                    .with_hosts(&hosts);
+                    let last_request = conn.last_request();
+
+                    // locked before spawning, so the peer is joined only after it's inserted
+                    let mut map = peers.map.lock().unwrap();
                     let spawned = spawn_thread("peer", move || {
-                        info!("[{}] connected peer", addr);
-                        let conn = Connection::new(query, stream, addr, stats, relayfee, sender);
-                        conn.run(receiver);
-                        info!("[{}] disconnected peer", addr);
+                        info!("[{}] connected peer", redacted(addr));
+                        let peers = stats.peers.clone();
+                        peers.inc();
+                        conn.run(stream, receiver);
+                        peers.dec();
+                        info!("[{}] disconnected peer", redacted(addr));
                         let _ = garbage_sender.send(std::thread::current().id());
                     });
 
-                    trace!("[{}] spawned {:?}", addr, spawned.thread().id());
-                    threads.insert(spawned.thread().id(), spawned);
-                    while let Ok(id) = garbage_receiver.try_recv() {
-                        if let Some(thread) = threads.remove(&id) {
-                            trace!("[{}] joining {:?}", addr, id);
-                            if let Err(error) = thread.join() {
-                                error!("failed to join {:?}: {:?}", id, error);
-                            }
-                        }
-                    }
+                    trace!("[{}] spawned {:?}", redacted(addr), spawned.thread().id());
+                    let peer = Peer {
+                        thread: spawned,
+                        addr,
+                        sender,
+                        last_request,
+                        evicted: false,
+                    };
+                    map.insert(peer.thread.thread().id(), peer);
+                    peers.pending.fetch_sub(1, Ordering::SeqCst);
                 }
                 trace!("closing {} RPC connections", senders.lock().unwrap().len());
                 for sender in senders.lock().unwrap().iter() {
                     let _ = sender.send(Message::Done);
                 }
-                for (id, thread) in threads {
+                let map = std::mem::take(&mut *peers.map.lock().unwrap());
+                for (id, peer) in map {
                     trace!("joining {:?}", id);
-                    if let Err(error) = thread.join() {
+                    if let Err(error) = peer.thread.join() {
                         error!("failed to join {:?}: {:?}", id, error);
                     }
                 }
@@ -655,3 +2326,119 @@ impl Drop for RPC {
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        error_code, error_data, error_json, hash_from_value, parse_request, BroadcastPolicy,
+        BAD_REQUEST, DAEMON_ERROR, INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND,
+    };
+    use crate::errors::*;
+    use crate::ratelimit::RateLimiter;
+    use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+    use serde_json::Value;
+    use std::time::Duration;
+
+    #[test]
+    fn test_error_code() {
+        let err = hash_from_value::<Sha256dHash>(Some(&json!("xyz"))).unwrap_err();
+        assert_eq!(error_code(&err), INVALID_PARAMS);
+        let err = Err::<(), _>(err)
+            .chain_err(|| "bad script_hash")
+            .unwrap_err();
+        assert_eq!(error_code(&err), INVALID_PARAMS); // by the chained error's kind
+
+        let err: Error = ErrorKind::MethodNotFound("foo".to_owned()).into();
+        assert_eq!(error_code(&err), METHOD_NOT_FOUND);
+        assert_eq!(err.to_string(), "unknown method foo");
+        assert_eq!(error_code(&"failed".into()), BAD_REQUEST);
+        assert_eq!(error_data(&err), None);
+    }
+
+    #[test]
+    fn test_rejection_error() {
+        let err: Error = ErrorKind::Rejected(-26, "min relay fee not met".to_owned()).into();
+        let err = Err::<(), _>(err)
+            .chain_err(|| "broadcast failed")
+            .unwrap_err();
+        assert_eq!(error_code(&err), DAEMON_ERROR);
+        assert_eq!(
+            error_data(&err),
+            Some(json!({"daemon_code": -26, "daemon_message": "min relay fee not met"}))
+        );
+        let reply = error_json(&json!(1), error_code(&err), "rejected", error_data(&err));
+        let reply: Value = serde_json::from_str(&reply.0).unwrap();
+        assert_eq!(reply["error"]["data"]["daemon_code"], json!(-26));
+    }
+
+    #[test]
+    fn test_broadcast_params() {
+        let policy = BroadcastPolicy {
+            limiter: RateLimiter::new(0, 0, Duration::from_secs(60)),
+            token: Some("secret".to_owned()),
+            max_fee_rate: Some(0.1),
+            precheck: false,
+        };
+        let peer = "127.0.0.1".parse().unwrap();
+        assert!(policy
+            .check(peer, &[json!("00"), json!(1.0), json!("secret")])
+            .is_ok());
+        assert!(policy
+            .check(peer, &[json!("00"), json!(null), json!("secret")])
+            .is_ok());
+        assert!(policy.check(peer, &[json!("00"), json!("secret")]).is_err()); // not a fee rate
+        assert!(policy.check(peer, &[json!("00")]).is_err());
+        let err = policy.check(peer, &[json!("00"), json!(null), json!(1)]);
+        assert_eq!(error_code(&err.unwrap_err()), INVALID_PARAMS);
+
+        assert_eq!(policy.max_fee_rate(&[json!("00")]).unwrap(), Some(0.1));
+        assert_eq!(
+            policy.max_fee_rate(&[json!("00"), json!(null)]).unwrap(),
+            Some(0.1)
+        );
+        assert_eq!(
+            policy.max_fee_rate(&[json!("00"), json!(0.5)]).unwrap(),
+            Some(0.5)
+        );
+        for invalid in &[json!(-1), json!("secret")] {
+            let err = policy.max_fee_rate(&[json!("00"), invalid.clone()]);
+            assert_eq!(error_code(&err.unwrap_err()), INVALID_PARAMS);
+        }
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let params = [json!("abc")];
+        let request = json!({"id": 7, "method": "foo", "params": ["abc"]});
+        assert_eq!(
+            parse_request(&request).unwrap(),
+            ("foo", &params[..], Some(&json!(7)))
+        );
+        let request = json!({"id": null, "method": "foo"});
+        assert_eq!(
+            parse_request(&request).unwrap(),
+            ("foo", &[][..], Some(&Value::Null))
+        );
+        let notification = json!({"method": "foo", "params": ["abc"]});
+        assert_eq!(
+            parse_request(&notification).unwrap(),
+            ("foo", &params[..], None)
+        );
+
+        let error = |request: Value| {
+            let reply: Value =
+                serde_json::from_str(&parse_request(&request).unwrap_err().0).unwrap();
+            assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+            reply["id"].clone()
+        };
+        assert_eq!(error(json!({"id": [1], "method": "foo"})), Value::Null);
+        assert_eq!(error(json!({"id": {}, "method": "foo"})), Value::Null);
+        assert_eq!(error(json!({"id": "x", "method": 5})), json!("x"));
+        assert_eq!(
+            error(json!({"id": 8, "method": "foo", "params": 1})),
+            json!(8)
+        );
+        assert_eq!(error(json!({"params": []})), Value::Null);
+        assert_eq!(error(json!(42)), Value::Null);
+    }
+}
@@ -0,0 +1,446 @@
+//! Cost-based scheduling of Electrum requests: expensive methods (e.g. histories, bulk headers and
+//! merkle proofs) run in a limited number of concurrent slots, so that cheap interactive ones
+//! (e.g. pings, subscriptions and broadcasts) aren't starved behind them.
+//! In addition, each peer (IP address) may spend a limited total cost per minute, and each
+//! connection may subscribe to a limited number of script hashes (which expire if the connection is
+//! idle). These limits (and the number of transactions per script hash) depend on the connection's
+//! QoS tier. The bytes sent and received by each peer (and by all of them) may also be limited per
+//! minute, disconnecting the peers which exceed them.
+//! Tenants are tiers whose budget and subscriptions are shared by all their connections (instead of
+//! being limited per peer and per connection), and which may only call their allowed methods.
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::config::{Config, TierConfig};
+use crate::errors::*;
+use crate::ratelimit::RateLimiter;
+use crate::util::constant_time_eq;
+
+/// Requests costing at least this much have to wait for a free slot.
+const EXPENSIVE_COST: usize = 10;
+
+/// Returns the method's cost, relative to a cheap request's.
+pub fn method_cost(method: &str) -> usize {
+    match method {
+        "blockchain.address.get_balance"
+        | "blockchain.address.get_history"
+        | "blockchain.address.get_mempool"
+        | "blockchain.address.listunspent"
+        | "blockchain.scripthash.get_balance"
+        | "blockchain.scripthash.get_first_use"
+        | "blockchain.scripthash.get_history"
+        | "blockchain.scripthash.get_history_since"
+        | "blockchain.scripthash.get_mempool"
+        | "blockchain.scripthash.get_utxo_summary"
+        | "blockchain.scripthash.listunspent"
+        | "blockchain.scripthash.refresh"
+        // slows down password guessing
+        | "server.authenticate" => 20,
+        "blockchain.block.get"
+        | "blockchain.block.headers"
+        | "blockchain.outpoint.get_spent_status"
+        | "blockchain.scripthashes.subscribe"
+        | "blockchain.transaction.get_batch"
+        | "blockchain.transaction.get_merkle"
+        | "blockchain.transaction.id_from_pos"
+        | "blockchain.transaction.search" => EXPENSIVE_COST,
+        // computes the script hash's status (but shouldn't wait behind expensive requests)
+        "blockchain.address.subscribe"
+        | "blockchain.scripthash.subscribe"
+        | "blockchain.outpoint.subscribe" => 5,
+        _ => 1,
+    }
+}
+
+/// Connections start in the anonymous tier, until authenticated with another tier's password.
+pub const ANONYMOUS_TIER: usize = 0;
+
+struct Tier {
+    config: TierConfig,
+    budget: RateLimiter,        // each tier's clients are charged separately
+    subscriptions: AtomicUsize, // by all the tier's connections
+}
+
+fn method_allowed(allowed_methods: &[String], method: &str) -> bool {
+    allowed_methods.is_empty()
+        || method.starts_with("server.") // e.g. for negotiating the version and authenticating
+        || allowed_methods.iter().any(|allowed| {
+            if allowed.ends_with('*') {
+                method.starts_with(&allowed[..allowed.len() - 1])
+            } else {
+                allowed == method
+            }
+        })
+}
+
+pub struct Scheduler {
+    slots: usize, // for concurrent expensive requests
+    busy: Mutex<usize>,
+    released: Condvar,
+    tiers: Vec<Tier>,
+    subscription_ttl: Option<Duration>,
+    idle_timeout: Option<Duration>, // of the connections
+    require_version: bool,          // before any other request (see `server.version`)
+    bandwidth: RateLimiter,         // in bytes
+}
+
+/// A slot taken by an expensive request (released when dropped).
+pub struct Slot<'a> {
+    scheduler: Option<&'a Scheduler>,
+}
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler {
+            *scheduler.busy.lock().unwrap() -= 1;
+            scheduler.released.notify_one();
+        }
+    }
+}
+
+impl Scheduler {
+    /// The first tier is the anonymous one (whose password is ignored).
+    pub fn new(slots: usize, tiers: Vec<TierConfig>, interval: Duration) -> Scheduler {
+        assert!(!tiers.is_empty(), "missing anonymous tier");
+        let tiers = tiers
+            .into_iter()
+            .map(|config| Tier {
+                budget: if config.tenant {
+                    RateLimiter::new(0, config.request_cost_budget, interval)
+                } else {
+                    RateLimiter::new(config.request_cost_budget, 0, interval)
+                },
+                config,
+                subscriptions: AtomicUsize::new(0),
+            })
+            .collect();
+        Scheduler {
+            slots,
+            busy: Mutex::new(0),
+            released: Condvar::new(),
+            tiers,
+            subscription_ttl: None,
+            idle_timeout: None,
+            require_version: false,
+            bandwidth: RateLimiter::new(0, 0, interval),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Scheduler {
+        let anonymous = TierConfig {
+            name: "anonymous".to_owned(),
+            password: String::new(),
+            request_cost_budget: config.request_cost_budget,
+            max_subscriptions: config.max_subscriptions,
+            txid_limit: config.txid_limit,
+            tenant: false,
+            allowed_methods: vec![],
+        };
+        let mut scheduler = Scheduler::new(
+            config.expensive_request_slots,
+            std::iter::once(anonymous)
+                .chain(config.qos_tiers.iter().cloned())
+                .collect(),
+            Duration::from_secs(60),
+        );
+        scheduler.subscription_ttl = config.subscription_ttl;
+        scheduler.idle_timeout = config.idle_timeout;
+        scheduler.require_version = config.require_version_negotiation;
+        scheduler.bandwidth = RateLimiter::new(
+            config.peer_bandwidth_limit_kb * 1024,
+            config.global_bandwidth_limit_kb * 1024,
+            Duration::from_secs(60),
+        )
+        .with_unit("bytes");
+        scheduler
+    }
+
+    /// Returns true if the subscriptions of a connection, which sent no requests during `idle`,
+    /// should be dropped.
+    pub fn subscriptions_expired(&self, idle: Duration) -> bool {
+        self.subscription_ttl.map_or(false, |ttl| idle >= ttl)
+    }
+
+    /// Returns true if a connection, which sent no requests during `idle`, should be closed.
+    pub fn connection_expired(&self, idle: Duration) -> bool {
+        self.idle_timeout.map_or(false, |timeout| idle >= timeout)
+    }
+
+    /// Returns the tier whose password was given (comparing all of them in constant time).
+    pub fn authenticate(&self, password: &str) -> Option<usize> {
+        let mut result = None;
+        for (index, tier) in self.tiers.iter().enumerate().skip(1) {
+            if constant_time_eq(password.as_bytes(), tier.config.password.as_bytes()) {
+                result = Some(index);
+            }
+        }
+        result
+    }
+
+    pub fn tier_name(&self, tier: usize) -> &str {
+        &self.tiers[tier].config.name
+    }
+
+    /// The maximal number of transactions per script hash (0 - unlimited).
+    pub fn txid_limit(&self, tier: usize) -> usize {
+        self.tiers[tier].config.txid_limit
+    }
+
+    /// Fails if the method may not be called before the protocol version was negotiated.
+    pub fn check_version(&self, method: &str, negotiated: bool) -> Result<()> {
+        if self.require_version && !negotiated && method != "server.version" {
+            bail!("server.version must be sent first");
+        }
+        Ok(())
+    }
+
+    /// Fails if a connection, already subscribed to `count` script hashes, may not subscribe to
+    /// another one (or if its tenant's connections may not).
+    pub fn check_subscriptions(&self, tier: usize, count: usize) -> Result<()> {
+        let tier = &self.tiers[tier];
+        let max_subscriptions = tier.config.max_subscriptions;
+        if tier.config.tenant {
+            if max_subscriptions > 0
+                && tier.subscriptions.load(Ordering::SeqCst) >= max_subscriptions
+            {
+                bail!(ErrorKind::ExcessiveResourceUsage(format!(
+                    "too many subscriptions: up to {} script hashes per tenant",
+                    max_subscriptions
+                )));
+            }
+        } else if max_subscriptions > 0 && count >= max_subscriptions {
+            bail!(ErrorKind::ExcessiveResourceUsage(format!(
+                "too many subscriptions: up to {} script hashes per connection",
+                max_subscriptions
+            )));
+        }
+        Ok(())
+    }
+
+    /// Counts the subscriptions added by one of the tier's connections.
+    pub fn add_subscriptions(&self, tier: usize, count: usize) {
+        self.tiers[tier]
+            .subscriptions
+            .fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Counts the subscriptions removed (or dropped) by one of the tier's connections.
+    pub fn remove_subscriptions(&self, tier: usize, count: usize) {
+        self.tiers[tier]
+            .subscriptions
+            .fetch_sub(count, Ordering::SeqCst);
+    }
+
+    /// Fails if the tier (i.e. tenant) may not call the method.
+    pub fn check_method(&self, tier: usize, method: &str) -> Result<()> {
+        let config = &self.tiers[tier].config;
+        if !method_allowed(&config.allowed_methods, method) {
+            bail!(ErrorKind::MethodNotFound(format!(
+                "{} (not allowed for {})",
+                method, config.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Counts the bytes sent to (or received from) the peer, failing if it exceeds the bandwidth
+    /// limits.
+    pub fn charge_bandwidth(&self, peer: IpAddr, bytes: usize) -> Result<()> {
+        self.bandwidth
+            .charge(peer, bytes)
+            .chain_err(|| "bandwidth limit exceeded")
+    }
+
+    /// Charges the method's cost to the peer's budget (in its tier), and waits for a free slot (if
+    /// the method is expensive).
+    pub fn admit(&self, peer: IpAddr, tier: usize, method: &str) -> Result<Slot> {
+        let cost = method_cost(method);
+        self.tiers[tier]
+            .budget
+            .charge(peer, cost)
+            .chain_err(|| "request cost budget exceeded, please retry later")?;
+        if cost < EXPENSIVE_COST {
+            return Ok(Slot { scheduler: None });
+        }
+        let mut busy = self.busy.lock().unwrap();
+        while *busy >= self.slots {
+            busy = self.released.wait(busy).unwrap();
+        }
+        *busy += 1;
+        Ok(Slot {
+            scheduler: Some(self),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scheduler, ANONYMOUS_TIER};
+    use crate::config::TierConfig;
+    use crate::ratelimit::RateLimiter;
+    use error_chain::ChainedError;
+    use std::net::IpAddr;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+    use std::time::Duration;
+
+    fn tier(name: &str, password: &str, budget: usize, max_subscriptions: usize) -> TierConfig {
+        TierConfig {
+            name: name.to_owned(),
+            password: password.to_owned(),
+            request_cost_budget: budget,
+            max_subscriptions,
+            txid_limit: 100,
+            tenant: false,
+            allowed_methods: vec![],
+        }
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+        let minute = Duration::from_secs(60);
+        let tiers = vec![tier("anonymous", "", 50, 0)];
+        let scheduler = Arc::new(Scheduler::new(1, tiers, minute));
+        let admit = |method| scheduler.admit(peer, ANONYMOUS_TIER, method);
+
+        let slot = admit("blockchain.scripthash.get_history").unwrap();
+        assert_eq!(*scheduler.busy.lock().unwrap(), 1);
+        // cheap requests don't need a slot
+        drop(admit("server.ping").unwrap());
+
+        let (sender, receiver) = mpsc::channel();
+        let waiting = {
+            let scheduler = Arc::clone(&scheduler);
+            thread::spawn(move || {
+                let _slot = scheduler
+                    .admit(peer, ANONYMOUS_TIER, "blockchain.block.headers")
+                    .unwrap();
+                sender.send(()).unwrap();
+            })
+        };
+        thread::sleep(Duration::from_millis(10));
+        assert!(receiver.try_recv().is_err()); // waiting for the slot
+        drop(slot);
+        receiver.recv().unwrap();
+        waiting.join().unwrap();
+        assert_eq!(*scheduler.busy.lock().unwrap(), 0);
+
+        // 20 + 1 + 10 were charged, so another history request exceeds the budget
+        assert!(admit("blockchain.scripthash.get_history").is_err());
+        assert!(admit("server.ping").is_ok());
+
+        // unlimited by default
+        assert!(scheduler
+            .check_subscriptions(ANONYMOUS_TIER, 1_000_000)
+            .is_ok());
+        assert!(!scheduler.subscriptions_expired(Duration::from_secs(1_000_000)));
+        assert!(!scheduler.connection_expired(Duration::from_secs(1_000_000)));
+        let mut scheduler = Scheduler::new(1, vec![tier("anonymous", "", 0, 0)], minute);
+        scheduler.subscription_ttl = Some(minute * 10);
+        scheduler.idle_timeout = Some(minute * 30);
+        assert!(!scheduler.subscriptions_expired(minute * 9));
+        assert!(scheduler.subscriptions_expired(minute * 10));
+        assert!(!scheduler.connection_expired(minute * 29));
+        assert!(scheduler.connection_expired(minute * 30));
+    }
+
+    #[test]
+    fn test_tiers() {
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+        let tiers = vec![
+            tier("anonymous", "", 20, 2),
+            tier("own", "secret", 0, 0),
+            tier("partner", "pass", 100, 10),
+        ];
+        let scheduler = Scheduler::new(1, tiers, Duration::from_secs(60));
+        assert_eq!(scheduler.authenticate(""), None); // the anonymous tier has no password
+        assert_eq!(scheduler.authenticate("guess"), None);
+        let own = scheduler.authenticate("secret").unwrap();
+        let partner = scheduler.authenticate("pass").unwrap();
+        assert_eq!(scheduler.tier_name(own), "own");
+        assert_eq!(scheduler.tier_name(partner), "partner");
+
+        assert!(scheduler.check_subscriptions(ANONYMOUS_TIER, 1).is_ok());
+        assert!(scheduler.check_subscriptions(ANONYMOUS_TIER, 2).is_err());
+        assert!(scheduler.check_subscriptions(partner, 2).is_ok());
+        assert!(scheduler.check_subscriptions(own, 1_000_000).is_ok());
+
+        // each tier has its own budget
+        let history = "blockchain.scripthash.get_history";
+        assert!(scheduler.admit(peer, ANONYMOUS_TIER, history).is_ok());
+        assert!(scheduler.admit(peer, ANONYMOUS_TIER, history).is_err());
+        for _ in 0..5 {
+            assert!(scheduler.admit(peer, partner, history).is_ok());
+        }
+        assert!(scheduler.admit(peer, partner, history).is_err());
+        for _ in 0..100 {
+            assert!(scheduler.admit(peer, own, history).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_tenants() {
+        let peer1: IpAddr = "127.0.0.1".parse().unwrap();
+        let peer2: IpAddr = "127.0.0.2".parse().unwrap();
+        let mut shop = tier("shop", "pass", 30, 2);
+        shop.tenant = true;
+        shop.allowed_methods = vec!["blockchain.scripthash.*".to_owned()];
+        let tiers = vec![tier("anonymous", "", 0, 0), shop];
+        let scheduler = Scheduler::new(1, tiers, Duration::from_secs(60));
+        let shop = scheduler.authenticate("pass").unwrap();
+
+        assert!(scheduler
+            .check_method(shop, "blockchain.scripthash.get_history")
+            .is_ok());
+        assert!(scheduler.check_method(shop, "server.version").is_ok());
+        assert!(scheduler
+            .check_method(shop, "blockchain.block.headers")
+            .is_err());
+        assert!(scheduler
+            .check_method(ANONYMOUS_TIER, "blockchain.block.headers")
+            .is_ok());
+
+        // the budget is shared by all the tenant's clients
+        let history = "blockchain.scripthash.get_history";
+        assert!(scheduler.admit(peer1, shop, history).is_ok());
+        assert!(scheduler.admit(peer2, shop, history).is_err());
+
+        // and so are the subscriptions (regardless of each connection's own count)
+        scheduler.add_subscriptions(shop, 1);
+        assert!(scheduler.check_subscriptions(shop, 0).is_ok());
+        scheduler.add_subscriptions(shop, 1);
+        assert!(scheduler.check_subscriptions(shop, 0).is_err());
+        scheduler.remove_subscriptions(shop, 2);
+        assert!(scheduler.check_subscriptions(shop, 5).is_ok());
+    }
+
+    #[test]
+    fn test_bandwidth() {
+        let peer: IpAddr = "127.0.0.1".parse().unwrap();
+        let minute = Duration::from_secs(60);
+        let mut scheduler = Scheduler::new(1, vec![tier("anonymous", "", 0, 0)], minute);
+        assert!(scheduler.charge_bandwidth(peer, 1 << 30).is_ok()); // unlimited by default
+
+        scheduler.bandwidth = RateLimiter::new(100, 0, minute).with_unit("bytes");
+        assert!(scheduler.charge_bandwidth(peer, 60).is_ok());
+        let err = scheduler.charge_bandwidth(peer, 60).unwrap_err();
+        assert!(err.display_chain().to_string().contains("100 bytes per"));
+        assert!(scheduler.charge_bandwidth(peer, 40).is_ok());
+    }
+
+    #[test]
+    fn test_require_version() {
+        let minute = Duration::from_secs(60);
+        let mut scheduler = Scheduler::new(1, vec![tier("anonymous", "", 0, 0)], minute);
+        assert!(scheduler.check_version("server.banner", false).is_ok()); // not required by default
+
+        scheduler.require_version = true;
+        assert!(scheduler.check_version("server.version", false).is_ok());
+        assert!(scheduler.check_version("server.banner", true).is_ok());
+        let err = scheduler.check_version("server.banner", false).unwrap_err();
+        assert_eq!(err.to_string(), "server.version must be sent first");
+    }
+}
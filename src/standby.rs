@@ -0,0 +1,146 @@
+//! Hot-standby mode: the standby indexes independently (keeping its index, mempool and caches
+//! warm), but serves Electrum clients only after its primary stops responding.
+//!
+//! It doesn't follow the primary's replication stream, which stops with the primary. Subscriptions
+//! are not handed off either: the persisted statuses are a local cache (which isn't replicated), so
+//! the clients resubscribe after reconnecting to the standby.
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::errors::*;
+use crate::signal::Waiter;
+use crate::util::spawn_thread;
+
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Standby {
+    primary: SocketAddr,
+    primary_down: Arc<AtomicBool>, // set by the pinging thread
+}
+
+impl Standby {
+    /// Pings the primary every `wait_duration` in a separate thread, so the index updates don't
+    /// wait for an unresponsive primary.
+    pub fn start(config: &Config, signal: Waiter) -> Option<Standby> {
+        let primary = config.standby_of?;
+        let primary_down = Arc::new(AtomicBool::new(false));
+        let mut monitor = Monitor {
+            primary,
+            failover_timeout: config.failover_timeout,
+            last_seen: Instant::now(),
+        };
+        let (down, interval) = (Arc::clone(&primary_down), config.wait_duration);
+        spawn_thread("standby", move || loop {
+            if monitor.should_promote_at(ping(primary), Instant::now()) {
+                down.store(true, Ordering::Relaxed);
+                break;
+            }
+            if signal.wait(interval).is_err() {
+                break;
+            }
+        });
+        Some(Standby {
+            primary,
+            primary_down,
+        })
+    }
+
+    pub fn primary(&self) -> SocketAddr {
+        self.primary
+    }
+
+    /// Returns true once the primary didn't respond during the failover timeout.
+    pub fn should_promote(&self) -> bool {
+        self.primary_down.load(Ordering::Relaxed)
+    }
+}
+
+struct Monitor {
+    primary: SocketAddr,
+    failover_timeout: Duration,
+    last_seen: Instant,
+}
+
+impl Monitor {
+    fn should_promote_at(&mut self, ping_result: Result<()>, now: Instant) -> bool {
+        match ping_result {
+            Ok(()) => {
+                self.last_seen = now;
+                false
+            }
+            Err(e) => {
+                let elapsed = now.saturating_duration_since(self.last_seen);
+                warn!(
+                    "primary {} is not responding for {:?}: {}",
+                    self.primary, elapsed, e
+                );
+                elapsed >= self.failover_timeout
+            }
+        }
+    }
+}
+
+// Sends `server.ping` to the primary's Electrum RPC.
+fn ping(addr: SocketAddr) -> Result<()> {
+    let mut stream = TcpStream::connect_timeout(&addr, PING_TIMEOUT)
+        .chain_err(|| format!("failed to connect {}", addr))?;
+    stream
+        .set_read_timeout(Some(PING_TIMEOUT))
+        .chain_err(|| "failed to set read timeout")?;
+    let request = json!({"jsonrpc": "2.0", "id": 0, "method": "server.ping", "params": []});
+    stream
+        .write_all((request.to_string() + "\n").as_bytes())
+        .chain_err(|| "failed to send ping")?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .chain_err(|| "failed to receive pong")?;
+    if line.is_empty() {
+        bail!("primary disconnected");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ping, Monitor};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_standby_promotion() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            assert!(line.contains("server.ping"));
+            (&stream)
+                .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":0,\"result\":null}\n")
+                .unwrap();
+        });
+        assert!(ping(primary).is_ok());
+        server.join().unwrap();
+        assert!(ping(primary).is_err()); // the listener is closed
+
+        let start = Instant::now();
+        let mut standby = Monitor {
+            primary,
+            failover_timeout: Duration::from_secs(30),
+            last_seen: start,
+        };
+        let down = || Err("connection refused".into());
+        assert!(!standby.should_promote_at(down(), start + Duration::from_secs(10)));
+        assert!(!standby.should_promote_at(Ok(()), start + Duration::from_secs(20)));
+        assert!(!standby.should_promote_at(down(), start + Duration::from_secs(40)));
+        assert!(standby.should_promote_at(down(), start + Duration::from_secs(50)));
+    }
+}
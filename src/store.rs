@@ -1,5 +1,9 @@
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use crate::cipher::Cipher;
+use crate::errors::*;
 use crate::util::Bytes;
 
 #[derive(Clone)]
@@ -27,18 +31,42 @@ pub trait WriteStore: Sync {
 #[derive(Clone)]
 struct Options {
     path: PathBuf,
+    shard_paths: Vec<PathBuf>,
     bulk_import: bool,
     low_memory: bool,
+    cipher: Option<Arc<Cipher>>, // encrypting the rows' values
+}
+
+// Script hash rows are sharded by the script hash's first byte, and the rest are stored in the
+// first shard (at the main DB path).
+const SHARDED_CODE: u8 = b'O';
+const SHARDS_KEY: &[u8] = b"N";
+// An encrypted index stores this (encrypted) marker, to check the configured key on startup.
+const ENCRYPTION_KEY: &[u8] = b"E";
+const ENCRYPTION_MARKER: &[u8] = b"electrs";
+const REENCRYPT_BATCH_SIZE: usize = 10_000;
+
+/// Returns the shard storing all the keys starting with `prefix`, or `None` if they are spread
+/// across all the shards.
+fn shard_of(prefix: &[u8], shards: usize) -> Option<usize> {
+    if shards == 1 {
+        return Some(0);
+    }
+    match prefix.first() {
+        Some(&SHARDED_CODE) => prefix.get(1).map(|byte| usize::from(*byte) % shards),
+        Some(_) => Some(0),
+        None => None,
+    }
 }
 
 pub struct DBStore {
-    db: rocksdb::DB,
+    shards: Vec<rocksdb::DB>,
     opts: Options,
 }
 
 impl DBStore {
-    fn open_opts(opts: Options) -> Self {
-        debug!("opening DB at {:?}", opts.path);
+    fn open_db(path: &Path, opts: &Options) -> rocksdb::DB {
+        debug!("opening DB at {:?}", path);
         let mut db_opts = rocksdb::Options::default();
         db_opts.create_if_missing(true);
         // db_opts.set_keep_log_file_num(10);
@@ -55,55 +83,278 @@ impl DBStore {
 
         let mut block_opts = rocksdb::BlockBasedOptions::default();
         block_opts.set_block_size(if opts.low_memory { 256 << 10 } else { 1 << 20 });
-        DBStore {
-            db: rocksdb::DB::open(&db_opts, &opts.path).unwrap(),
-            opts,
+        rocksdb::DB::open(&db_opts, path)
+            .unwrap_or_else(|e| panic!("failed to open DB at {:?}: {}", path, e))
+    }
+
+    fn open_opts(opts: Options) -> Self {
+        let shards = std::iter::once(&opts.path)
+            .chain(opts.shard_paths.iter())
+            .map(|path| DBStore::open_db(path, &opts))
+            .collect();
+        let store = DBStore { shards, opts };
+        if store.shards[0]
+            .iterator(rocksdb::IteratorMode::Start)
+            .next()
+            .is_none()
+        {
+            store.init();
+        }
+        store.check_encryption();
+        store.check_shards();
+        store
+    }
+
+    fn init(&self) {
+        let mut rows = vec![Row {
+            key: SHARDS_KEY.to_vec(),
+            value: self.shards.len().to_string().into_bytes(),
+        }];
+        if self.opts.cipher.is_some() {
+            rows.push(Row {
+                key: ENCRYPTION_KEY.to_vec(),
+                value: ENCRYPTION_MARKER.to_vec(),
+            });
+        }
+        self.write(rows);
+    }
+
+    // An index is encrypted (or not) when it is created, since its existing values would stay
+    // readable otherwise.
+    fn check_encryption(&self) {
+        let marker = self.shards[0].get(ENCRYPTION_KEY).unwrap();
+        match (&self.opts.cipher, marker) {
+            (None, None) => (),
+            (None, Some(_)) => panic!(
+                "index at {:?} is encrypted, but db_encryption_key_file isn't configured",
+                self.opts.path
+            ),
+            (Some(cipher), Some(value)) => {
+                if let Err(e) = cipher.decrypt(ENCRYPTION_KEY, &value) {
+                    panic!("failed to decrypt index at {:?}: {}", self.opts.path, e);
+                }
+            }
+            (Some(_), None) => panic!(
+                "index at {:?} isn't encrypted (it has to be re-created for encryption)",
+                self.opts.path
+            ),
+        }
+    }
+
+    // The number of shards can't change after the index is created, since the rows would be
+    // looked up in the wrong shards.
+    fn check_shards(&self) {
+        let count = self.shards.len();
+        let stored = match self.get(SHARDS_KEY) {
+            Some(value) => String::from_utf8_lossy(&value)
+                .parse::<usize>()
+                .expect("invalid shards count"),
+            None => 1, // created before sharding was supported
+        };
+        if stored != count {
+            panic!(
+                "index at {:?} was created with {} shard(s), but {} are configured",
+                self.opts.path, stored, count
+            );
         }
     }
 
     /// Opens a new RocksDB at the specified location.
     pub fn open(path: &Path, low_memory: bool) -> Self {
+        DBStore::open_shards(path, &[], low_memory, None)
+    }
+
+    /// Opens a RocksDB at each location, sharding the script hash rows across all of them (and
+    /// encrypting the rows' values, if a cipher is given).
+    pub fn open_shards(
+        path: &Path,
+        shard_paths: &[PathBuf],
+        low_memory: bool,
+        cipher: Option<Cipher>,
+    ) -> Self {
         DBStore::open_opts(Options {
             path: path.to_path_buf(),
+            shard_paths: shard_paths.to_vec(),
             bulk_import: true,
             low_memory,
+            cipher: cipher.map(Arc::new),
         })
     }
 
+    fn shards_of(&self, prefix: &[u8]) -> Vec<&rocksdb::DB> {
+        match shard_of(prefix, self.shards.len()) {
+            Some(index) => vec![&self.shards[index]],
+            None => self.shards.iter().collect(),
+        }
+    }
+
     pub fn enable_compaction(self) -> Self {
         let mut opts = self.opts.clone();
         if opts.bulk_import {
             opts.bulk_import = false;
             info!("enabling auto-compactions");
             let opts = [("disable_auto_compactions", "false")];
-            self.db.set_options(&opts).unwrap();
+            for db in &self.shards {
+                db.set_options(&opts).unwrap();
+            }
         }
         self
     }
 
     pub fn compact(self) -> Self {
         info!("starting full compaction");
-        self.db.compact_range(None::<&[u8]>, None::<&[u8]>); // would take a while
+        for db in &self.shards {
+            db.compact_range(None::<&[u8]>, None::<&[u8]>); // would take a while
+        }
         info!("finished full compaction");
         self
     }
 
+    /// Creates a consistent copy of each shard at `path` (as `shard-0`, `shard-1`, ...), using
+    /// RocksDB checkpoints (hard-linking the DB files, if `path` is on the same filesystem).
+    pub fn checkpoint(&self, path: &Path) -> Result<()> {
+        self.flush();
+        for (index, db) in self.shards.iter().enumerate() {
+            let shard_path = path.join(format!("shard-{}", index));
+            rocksdb::checkpoint::Checkpoint::new(db)
+                .and_then(|checkpoint| checkpoint.create_checkpoint(&shard_path))
+                .map_err(|e| format!("failed to create checkpoint at {:?}: {}", shard_path, e))?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the specified rows (e.g. invalid ones, when repairing the index).
+    pub fn delete(&self, keys: &[Bytes]) {
+        let mut batches: Vec<rocksdb::WriteBatch> = self
+            .shards
+            .iter()
+            .map(|_| rocksdb::WriteBatch::default())
+            .collect();
+        for key in keys {
+            let index = shard_of(key, self.shards.len()).unwrap_or(0);
+            batches[index].delete(key.as_slice()).unwrap();
+        }
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(true);
+        for (db, batch) in self.shards.iter().zip(batches) {
+            db.write_opt(batch, &opts).unwrap();
+        }
+    }
+
     pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
         ScanIterator {
-            prefix: prefix.to_vec(),
-            iter: self.db.prefix_iterator(prefix),
-            done: false,
+            shards: self
+                .shards_of(prefix)
+                .into_iter()
+                .map(|db| {
+                    ShardIterator {
+                        prefix: prefix.to_vec(),
+                        iter: db.prefix_iterator(prefix),
+                        done: false,
+                        cipher: self.opts.cipher.as_deref(),
+                    }
+                    .peekable()
+                })
+                .collect(),
+        }
+    }
+
+    /// Re-encrypts the values which are encrypted by a rotated-out key using the current one
+    /// (after which the old key is no longer needed), returning the number of updated rows.
+    pub fn reencrypt(&self) -> Result<usize> {
+        let cipher = match &self.opts.cipher {
+            Some(cipher) => cipher,
+            None => bail!("index at {:?} isn't encrypted", self.opts.path),
+        };
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(true);
+        let mut count = 0;
+        for db in &self.shards {
+            let mut batch = rocksdb::WriteBatch::default();
+            let mut batch_size = 0;
+            for (key, value) in db.iterator(rocksdb::IteratorMode::Start) {
+                if cipher.is_current(&value) {
+                    continue;
+                }
+                let plain = cipher
+                    .decrypt(&key, &value)
+                    .chain_err(|| format!("failed to decrypt row {}", hex::encode(&key)))?;
+                batch
+                    .put(&key, cipher.encrypt(&key, &plain).as_slice())
+                    .unwrap();
+                batch_size += 1;
+                if batch_size == REENCRYPT_BATCH_SIZE {
+                    db.write_opt(batch, &opts).unwrap();
+                    batch = rocksdb::WriteBatch::default();
+                    count += batch_size;
+                    batch_size = 0;
+                    debug!("re-encrypted {} rows", count);
+                }
+            }
+            db.write_opt(batch, &opts).unwrap();
+            count += batch_size;
         }
+        Ok(count)
+    }
+
+    pub fn cipher(&self) -> Option<&Cipher> {
+        self.opts.cipher.as_deref()
+    }
+
+    /// Returns the row as stored, i.e. with its value encrypted (if the index is encrypted).
+    pub fn seal(&self, row: Row) -> Row {
+        match &self.opts.cipher {
+            Some(cipher) => Row {
+                value: cipher.encrypt(&row.key, &row.value),
+                key: row.key,
+            },
+            None => row,
+        }
+    }
+
+    /// Writes sealed rows (see `seal()`), e.g. the ones replicated from a primary.
+    pub fn write_sealed<I: IntoIterator<Item = Row>>(&self, rows: I) {
+        let mut batches: Vec<rocksdb::WriteBatch> = self
+            .shards
+            .iter()
+            .map(|_| rocksdb::WriteBatch::default())
+            .collect();
+        for row in rows {
+            let index = shard_of(&row.key, self.shards.len()).unwrap_or(0);
+            batches[index]
+                .put(row.key.as_slice(), row.value.as_slice())
+                .unwrap();
+        }
+        let mut opts = rocksdb::WriteOptions::new();
+        opts.set_sync(!self.opts.bulk_import);
+        opts.disable_wal(self.opts.bulk_import);
+        for (db, batch) in self.shards.iter().zip(batches) {
+            db.write_opt(batch, &opts).unwrap();
+        }
+    }
+
+    fn decrypt(&self, key: &[u8], value: &[u8]) -> Bytes {
+        decrypt(self.opts.cipher.as_deref(), key, value)
     }
 }
 
-pub struct ScanIterator<'a> {
+fn decrypt(cipher: Option<&Cipher>, key: &[u8], value: &[u8]) -> Bytes {
+    match cipher {
+        Some(cipher) => cipher.decrypt(key, value).unwrap_or_else(|e| {
+            panic!("failed to decrypt row {}: {}", hex::encode(key), e);
+        }),
+        None => value.to_vec(),
+    }
+}
+
+struct ShardIterator<'a> {
     prefix: Vec<u8>,
     iter: rocksdb::DBIterator<'a>,
     done: bool,
+    cipher: Option<&'a Cipher>,
 }
 
-impl<'a> Iterator for ScanIterator<'a> {
+impl<'a> Iterator for ShardIterator<'a> {
     type Item = Row;
 
     fn next(&mut self) -> Option<Row> {
@@ -116,31 +367,58 @@ impl<'a> Iterator for ScanIterator<'a> {
             return None;
         }
         Some(Row {
+            value: decrypt(self.cipher, &key, &value),
             key: key.to_vec(),
-            value: value.to_vec(),
         })
     }
 }
 
+/// Merges the (sorted) rows of the scanned shards.
+pub struct ScanIterator<'a> {
+    shards: Vec<Peekable<ShardIterator<'a>>>,
+}
+
+impl<'a> Iterator for ScanIterator<'a> {
+    type Item = Row;
+
+    fn next(&mut self) -> Option<Row> {
+        let (index, _) = self
+            .shards
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, shard)| shard.peek().map(|row| (index, row.key.clone())))
+            .min_by(|(_, a), (_, b)| a.cmp(b))?;
+        self.shards[index].next()
+    }
+}
+
 impl ReadStore for DBStore {
     fn get(&self, key: &[u8]) -> Option<Bytes> {
-        self.db.get(key).unwrap().map(|v| v.to_vec())
+        self.shards_of(key)
+            .into_iter()
+            .find_map(|db| db.get(key).unwrap().map(|v| self.decrypt(key, &v)))
     }
 
     // TODO: use generators
     fn scan(&self, prefix: &[u8]) -> Vec<Row> {
+        let shards = self.shards_of(prefix);
         let mut rows = vec![];
-        for (key, value) in self.db.iterator(rocksdb::IteratorMode::From(
-            prefix,
-            rocksdb::Direction::Forward,
-        )) {
-            if !key.starts_with(prefix) {
-                break;
+        for db in &shards {
+            for (key, value) in db.iterator(rocksdb::IteratorMode::From(
+                prefix,
+                rocksdb::Direction::Forward,
+            )) {
+                if !key.starts_with(prefix) {
+                    break;
+                }
+                rows.push(Row {
+                    value: self.decrypt(&key, &value),
+                    key: key.to_vec(),
+                });
             }
-            rows.push(Row {
-                key: key.to_vec(),
-                value: value.to_vec(),
-            });
+        }
+        if shards.len() > 1 {
+            rows.sort_unstable_by(|a, b| a.key.cmp(&b.key));
         }
         rows
     }
@@ -148,22 +426,17 @@ impl ReadStore for DBStore {
 
 impl WriteStore for DBStore {
     fn write<I: IntoIterator<Item = Row>>(&self, rows: I) {
-        let mut batch = rocksdb::WriteBatch::default();
-        for row in rows {
-            batch.put(row.key.as_slice(), row.value.as_slice()).unwrap();
-        }
-        let mut opts = rocksdb::WriteOptions::new();
-        opts.set_sync(!self.opts.bulk_import);
-        opts.disable_wal(self.opts.bulk_import);
-        self.db.write_opt(batch, &opts).unwrap();
+        self.write_sealed(rows.into_iter().map(|row| self.seal(row)))
     }
 
     fn flush(&self) {
         let mut opts = rocksdb::WriteOptions::new();
         opts.set_sync(true);
         opts.disable_wal(false);
-        let empty = rocksdb::WriteBatch::default();
-        self.db.write_opt(empty, &opts).unwrap();
+        for db in &self.shards {
+            let empty = rocksdb::WriteBatch::default();
+            db.write_opt(empty, &opts).unwrap();
+        }
     }
 }
 
@@ -191,3 +464,19 @@ pub fn is_fully_compacted(store: &dyn ReadStore) -> bool {
     let marker = store.get(&full_compaction_marker().key);
     marker.is_some()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::shard_of;
+
+    #[test]
+    fn test_shard_of() {
+        assert_eq!(shard_of(b"O\x05abc", 1), Some(0));
+        assert_eq!(shard_of(b"O\x05abc", 4), Some(1));
+        assert_eq!(shard_of(b"O\xff", 4), Some(3));
+        assert_eq!(shard_of(b"O", 4), None); // all the script hash rows
+        assert_eq!(shard_of(b"", 4), None); // all the rows
+        assert_eq!(shard_of(b"I\x05abc", 4), Some(0));
+        assert_eq!(shard_of(b"L", 4), Some(0));
+    }
+}
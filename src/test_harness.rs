@@ -0,0 +1,527 @@
+//! End-to-end test harness (enabled by the `test_harness` feature): spawns `bitcoind -regtest`,
+//! runs an in-process electrs instance against it, and drives it via the Electrum protocol.
+//!
+//! `bitcoind` is looked up in `$PATH`, unless the `BITCOIND_EXE` environment variable is set.
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::Network;
+use serde_json::{from_value, Value};
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Lines, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::app::App;
+use crate::cache::{BlockCache, BlockTxIDsCache, HeaderChunkCache, MerkleCache, TransactionCache};
+use crate::chain::{BlockHash, Txid};
+use crate::config::Config;
+use crate::daemon::{CookieGetter, Daemon};
+use crate::errors::*;
+use crate::hooks::Hooks;
+use crate::index::{compute_script_hash, Index};
+use crate::metrics::Metrics;
+use crate::query::Query;
+use crate::rpc::{BroadcastPolicy, Listeners, RPC};
+use crate::schedule::Scheduler;
+use crate::signal::Waiter;
+use crate::store::{full_compaction, DBStore};
+
+const RPC_USER: &str = "electrs";
+const RPC_PASSWORD: &str = "electrs";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(60);
+const COINBASE_MATURITY: usize = 100; // blocks
+
+struct TestCookie;
+
+impl CookieGetter for TestCookie {
+    fn get(&self) -> Result<Vec<u8>> {
+        Ok(format!("{}:{}", RPC_USER, RPC_PASSWORD).into_bytes())
+    }
+}
+
+/// Returns a new (empty) temporary directory, which should be removed by the caller.
+fn temp_dir(name: &str) -> Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!(
+        "electrs-{}-{}-{}",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&path).chain_err(|| format!("failed to create {:?}", path))?;
+    Ok(path)
+}
+
+fn free_local_addr() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").chain_err(|| "failed to bind a free port")?;
+    listener
+        .local_addr()
+        .chain_err(|| "failed to get local address")
+}
+
+fn wait_for_listener(addr: SocketAddr, mut is_alive: impl FnMut() -> Result<()>) -> Result<()> {
+    let start = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        is_alive()?;
+        if start.elapsed() > STARTUP_TIMEOUT {
+            bail!("timeout while waiting for {}", addr);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// Returns the Electrum script hash (as a hex string) of the given address.
+pub fn address_script_hash(bitcoind: &Bitcoind, address: &str) -> Result<String> {
+    let info = bitcoind.rpc("getaddressinfo", json!([address]))?;
+    let script_hex = info["scriptPubKey"]
+        .as_str()
+        .chain_err(|| format!("missing scriptPubKey: {}", info))?;
+    let script = hex::decode(script_hex).chain_err(|| "non-hex scriptPubKey")?;
+    let hash = compute_script_hash(&script);
+    Ok(Sha256dHash::from_slice(&hash)
+        .expect("invalid script hash")
+        .to_hex())
+}
+
+/// A `bitcoind -regtest` process (with a wallet), which is stopped on drop.
+pub struct Bitcoind {
+    process: Child,
+    datadir: PathBuf,
+    rpc_addr: SocketAddr,
+    daemon: Daemon,
+}
+
+impl Bitcoind {
+    pub fn start(signal: &Waiter, metrics: &Metrics) -> Result<Bitcoind> {
+        let datadir = temp_dir("bitcoind")?;
+        let rpc_addr = free_local_addr()?;
+        let exe = env::var("BITCOIND_EXE").unwrap_or_else(|_| "bitcoind".to_owned());
+        let mut process = Command::new(&exe)
+            .arg("-regtest")
+            .arg(format!("-datadir={}", datadir.display()))
+            .arg(format!("-rpcport={}", rpc_addr.port()))
+            .arg(format!("-rpcuser={}", RPC_USER))
+            .arg(format!("-rpcpassword={}", RPC_PASSWORD))
+            .arg("-listen=0")
+            .arg("-server=1")
+            .arg("-txindex=1")
+            .arg("-fallbackfee=0.0001")
+            .stdout(Stdio::null())
+            .spawn()
+            .chain_err(|| format!("failed to run {}", exe))?;
+        let started = wait_for_listener(rpc_addr, || match process.try_wait() {
+            Ok(None) => Ok(()),
+            Ok(Some(status)) => bail!("{} exited: {}", exe, status),
+            Err(e) => bail!("failed to wait for {}: {}", exe, e),
+        });
+        if let Err(e) = started {
+            let _ = process.kill();
+            let _ = fs::remove_dir_all(&datadir);
+            return Err(e);
+        }
+        let blocktxids_cache = Arc::new(BlockTxIDsCache::new(0, metrics));
+        let daemon = Daemon::new(
+            &datadir,
+            &datadir.join("regtest").join("blocks"),
+            rpc_addr,
+            Arc::new(TestCookie),
+            Network::Regtest,
+            signal.clone(),
+            blocktxids_cache,
+            Hooks::new(None, Network::Regtest),
+            metrics,
+        )?;
+        let bitcoind = Bitcoind {
+            process,
+            datadir,
+            rpc_addr,
+            daemon,
+        };
+        // recent bitcoind versions don't create a default wallet
+        let _ = bitcoind.rpc("createwallet", json!(["electrs"]));
+        Ok(bitcoind)
+    }
+
+    pub fn daemon(&self) -> &Daemon {
+        &self.daemon
+    }
+
+    pub fn rpc_addr(&self) -> SocketAddr {
+        self.rpc_addr
+    }
+
+    pub fn datadir(&self) -> &Path {
+        &self.datadir
+    }
+
+    /// Sends a bitcoind JSONRPC request.
+    pub fn rpc(&self, method: &str, params: Value) -> Result<Value> {
+        self.daemon.request(method, params)
+    }
+
+    pub fn new_address(&self) -> Result<String> {
+        from_value(self.rpc("getnewaddress", json!([]))?).chain_err(|| "invalid address")
+    }
+
+    /// Mines `count` blocks (to a wallet address), returning their hashes.
+    pub fn mine(&self, count: usize) -> Result<Vec<BlockHash>> {
+        let address = self.new_address()?;
+        let hashes: Vec<String> =
+            from_value(self.rpc("generatetoaddress", json!([count, address]))?)
+                .chain_err(|| "invalid block hashes")?;
+        hashes
+            .iter()
+            .map(|hash| BlockHash::from_hex(hash).chain_err(|| "non-hex block hash"))
+            .collect()
+    }
+
+    /// Sends `amount` (in BTC) from the wallet to the given address.
+    pub fn send_to(&self, address: &str, amount: f64) -> Result<Txid> {
+        let txid = self.rpc("sendtoaddress", json!([address, amount]))?;
+        Txid::from_hex(txid.as_str().chain_err(|| "non-string txid")?).chain_err(|| "non-hex txid")
+    }
+
+    /// Replaces the last `depth` blocks by `depth + 1` new blocks.
+    pub fn reorg(&self, depth: usize) -> Result<Vec<BlockHash>> {
+        let height = self
+            .rpc("getblockcount", json!([]))?
+            .as_u64()
+            .chain_err(|| "invalid block count")?;
+        if depth == 0 || depth as u64 > height {
+            bail!("invalid reorg depth {} at height {}", depth, height);
+        }
+        let fork_height = height + 1 - depth as u64;
+        let fork_hash = self.rpc("getblockhash", json!([fork_height]))?;
+        self.rpc("invalidateblock", json!([fork_hash]))?;
+        self.mine(depth + 1)
+    }
+}
+
+impl Drop for Bitcoind {
+    fn drop(&mut self) {
+        // don't send RPCs to an exited process (the daemon would keep reconnecting)
+        let running = match self.process.try_wait() {
+            Ok(None) => true,
+            Ok(Some(_)) | Err(_) => false,
+        };
+        if !running || self.rpc("stop", json!([])).is_err() {
+            let _ = self.process.kill();
+        }
+        let _ = self.process.wait();
+        let _ = fs::remove_dir_all(&self.datadir);
+    }
+}
+
+/// An in-process electrs instance, indexing a regtest `bitcoind` and serving Electrum RPC.
+pub struct TestServer {
+    rpc: RPC,
+    query: Arc<Query>,
+    app: Arc<App>,
+    signal: Waiter,
+    electrum_addr: SocketAddr,
+    db_dir: PathBuf,
+    bitcoind: Bitcoind, // should be dropped after electrs is stopped
+}
+
+impl TestServer {
+    /// Starts `bitcoind`, mines enough blocks to have spendable coins, and starts electrs.
+    pub fn start() -> Result<TestServer> {
+        let signal = Waiter::start();
+        let monitoring_addr: SocketAddr = ([127, 0, 0, 1], 0).into(); // not started by the harness
+        let metrics = Metrics::new(monitoring_addr);
+        let bitcoind = Bitcoind::start(&signal, &metrics)?;
+        bitcoind.mine(COINBASE_MATURITY + 1)?;
+
+        let db_dir = temp_dir("db")?;
+        let electrum_addr = free_local_addr()?;
+        let config = Config {
+            log: stderrlog::new(),
+            network_type: Network::Regtest,
+            db_path: db_dir.clone(),
+            db_shard_paths: vec![],
+            db_encryption_key_file: None,
+            db_old_encryption_key_files: vec![],
+            daemon_dir: bitcoind.datadir.clone(),
+            blocks_dir: bitcoind.datadir.join("regtest").join("blocks"),
+            daemon_rpc_addr: bitcoind.rpc_addr,
+            electrum_rpc_addr: electrum_addr,
+            electrum_rpc_addrs: vec![electrum_addr],
+            electrum_http_addr: None,
+            proxy_protocol: false,
+            proxy_protocol_trusted: vec![],
+            electrum_tls_addr: None,
+            electrum_quic_addr: None,
+            tls_cert: None,
+            tls_key: None,
+            tor_control_addr: None,
+            tor_control_password: None,
+            monitoring_addr,
+            monitoring_socket: None,
+            monitoring_token: None,
+            redact_logs: false,
+            txid_search: false,
+            jsonrpc_import: true,
+            headers_only: false,
+            wait_duration: Duration::from_millis(100),
+            index_batch_size: 10,
+            bulk_index_threads: 1,
+            tx_cache_size: 0,
+            txid_limit: 100,
+            max_persisted_statuses: 100,
+            persisted_status_ttl: None,
+            max_history: 0,
+            require_version_negotiation: false,
+            expensive_request_slots: 4,
+            request_cost_budget: 0,
+            max_subscriptions: 0,
+            subscription_ttl: None,
+            idle_timeout: None,
+            qos_tiers: vec![],
+            peer_bandwidth_limit_kb: 0,
+            max_connections: None,
+            evict_idle_peers: false,
+            global_bandwidth_limit_kb: 0,
+            fee_estimate_mode: None,
+            broadcast_rate_limit: 0,
+            broadcast_global_rate_limit: 0,
+            broadcast_max_fee_rate: None,
+            broadcast_token: None,
+            broadcast_precheck: false,
+            server_banner: "electrs test harness".to_owned(),
+            blocktxids_cache_size: 0,
+            block_cache_size: 0,
+            header_chunk_cache_size: 0,
+            merkle_cache_size: 0,
+            event_hook: None,
+            import_wallet: None,
+            stale_tip_threshold: None,
+            stale_tip_banner: false,
+            out_of_sync_blocks: None,
+            out_of_sync_age: None,
+            standby_of: None,
+            failover_timeout: Duration::from_secs(30),
+            replication_addr: None,
+            replicate_from: None,
+            replication_secret: None,
+            backup_dir: None,
+            backup_interval: Duration::from_secs(24 * 3600),
+            backup_retention: 7,
+            record_sessions: None,
+            anonymize_recorded_sessions: false,
+            cookie_getter: Arc::new(TestCookie),
+        };
+        let daemon = bitcoind.daemon.reconnect()?;
+        let store = DBStore::open(&config.db_path, /*low_memory=*/ true);
+        let index = Index::load(&store, &daemon, &metrics, config.index_batch_size)?;
+        index.update(&store, &signal)?;
+        let store = full_compaction(store).enable_compaction();
+        let app = App::new(store, index, daemon, &config, &metrics)?;
+        let tx_cache = TransactionCache::new(config.tx_cache_size, &metrics);
+        let block_cache = BlockCache::new(config.block_cache_size, &metrics);
+        let header_chunk_cache = HeaderChunkCache::new(config.header_chunk_cache_size, &metrics);
+        let merkle_cache = MerkleCache::new(config.merkle_cache_size, &metrics);
+        let query = Query::new(
+            app.clone(),
+            &metrics,
+            tx_cache,
+            block_cache,
+            header_chunk_cache,
+            merkle_cache,
+            config.txid_limit,
+            config.max_history,
+            config.fee_estimate_mode.clone(),
+            config.max_persisted_statuses,
+            config.persisted_status_ttl,
+        );
+        query.update_mempool()?;
+        let relayfee = query.get_relayfee()?;
+        let broadcast = BroadcastPolicy::from_config(&config);
+        let scheduler = Scheduler::from_config(&config);
+        let listeners = Listeners {
+            rpc: vec![electrum_addr],
+            tls: None,
+            http: None,
+            trusted_proxies: None,
+            onion: None,
+            max_connections: None,
+            evict_idle_peers: false,
+        };
+        let rpc = RPC::start(
+            listeners,
+            query.clone(),
+            &metrics,
+            relayfee,
+            broadcast,
+            scheduler,
+            None,
+        );
+        wait_for_listener(electrum_addr, || Ok(()))?;
+        Ok(TestServer {
+            rpc,
+            query,
+            app,
+            signal,
+            electrum_addr,
+            db_dir,
+            bitcoind,
+        })
+    }
+
+    pub fn bitcoind(&self) -> &Bitcoind {
+        &self.bitcoind
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    pub fn electrum_addr(&self) -> SocketAddr {
+        self.electrum_addr
+    }
+
+    /// Indexes new blocks and mempool transactions, and notifies the subscribed clients
+    /// (as done periodically by the main loop).
+    pub fn sync(&self) -> Result<()> {
+        let new_block = self.app.update(&self.signal, None)?;
+        self.query.persist_statuses(new_block);
+        self.query.update_mempool()?;
+        self.rpc.notify();
+        Ok(())
+    }
+
+    pub fn connect(&self) -> Result<TestClient> {
+        TestClient::connect(self.electrum_addr)
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.db_dir);
+    }
+}
+
+/// A (blocking) Electrum protocol client.
+pub struct TestClient {
+    stream: TcpStream,
+    lines: Lines<BufReader<TcpStream>>,
+    next_id: u64,
+}
+
+impl TestClient {
+    pub fn connect(addr: SocketAddr) -> Result<TestClient> {
+        let stream =
+            TcpStream::connect(addr).chain_err(|| format!("failed to connect {}", addr))?;
+        stream
+            .set_read_timeout(Some(STARTUP_TIMEOUT))
+            .chain_err(|| "failed to set read timeout")?;
+        let reader = BufReader::new(stream.try_clone().chain_err(|| "failed to clone stream")?);
+        Ok(TestClient {
+            stream,
+            lines: reader.lines(),
+            next_id: 0,
+        })
+    }
+
+    pub fn send(&mut self, request: &Value) -> Result<()> {
+        let line = request.to_string() + "\n";
+        self.stream
+            .write_all(line.as_bytes())
+            .chain_err(|| "failed to send request")
+    }
+
+    /// Receives the next message (a response or a notification).
+    pub fn recv(&mut self) -> Result<Value> {
+        let line = self
+            .lines
+            .next()
+            .chain_err(|| "server disconnected")?
+            .chain_err(|| "failed to read message")?;
+        serde_json::from_str(&line).chain_err(|| format!("invalid message: {}", line))
+    }
+
+    /// Sends a request, and returns its response (skipping notifications).
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.send(&json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))?;
+        loop {
+            let mut response = self.recv()?;
+            if response.get("id") != Some(&json!(id)) {
+                continue;
+            }
+            if let Some(err) = response.get("error") {
+                bail!("{} failed: {}", method, err);
+            }
+            match response.get_mut("result") {
+                Some(result) => return Ok(result.take()),
+                None => bail!("no result in response: {}", response),
+            }
+        }
+    }
+
+    /// Waits for the next notification of the given method.
+    pub fn wait_for_notification(&mut self, method: &str) -> Result<Value> {
+        loop {
+            let mut message = self.recv()?;
+            if message["method"] == method {
+                return Ok(message["params"].take());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[ignore] // requires `bitcoind`
+    fn test_history_and_reorg() {
+        let server = TestServer::start().unwrap();
+        let bitcoind = server.bitcoind();
+        let mut client = server.connect().unwrap();
+
+        let address = bitcoind.new_address().unwrap();
+        let script_hash = address_script_hash(bitcoind, &address).unwrap();
+        let status = client
+            .call("blockchain.scripthash.subscribe", json!([script_hash]))
+            .unwrap();
+        assert_eq!(status, Value::Null);
+
+        let txid = bitcoind.send_to(&address, 1.5).unwrap().to_hex();
+        server.sync().unwrap();
+        client
+            .wait_for_notification("blockchain.scripthash.subscribe")
+            .unwrap();
+        let history = client
+            .call("blockchain.scripthash.get_history", json!([script_hash]))
+            .unwrap();
+        assert_eq!(history[0]["tx_hash"], txid);
+        assert_eq!(history[0]["height"], 0);
+
+        let blockhash = bitcoind.mine(1).unwrap().remove(0);
+        server.sync().unwrap();
+        let header = client
+            .call("blockchain.headers.subscribe", json!([]))
+            .unwrap();
+        assert_eq!(header["height"], COINBASE_MATURITY + 2);
+        let balance = client
+            .call("blockchain.scripthash.get_balance", json!([script_hash]))
+            .unwrap();
+        assert_eq!(balance["confirmed"], 150_000_000);
+
+        bitcoind.reorg(1).unwrap();
+        server.sync().unwrap();
+        let best = server.query().get_best_header().unwrap();
+        assert_ne!(*best.hash(), blockhash);
+        assert_eq!(best.height(), COINBASE_MATURITY + 3);
+    }
+}
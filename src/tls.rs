@@ -0,0 +1,255 @@
+//! Terminates TLS (using `rustls`) for the Electrum RPC clients, so public servers don't need
+//! a separate proxy (e.g. nginx or stunnel) for offering SSL connections.
+
+use error_chain::ChainedError;
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{NoClientAuth, ServerConfig, ServerSession, Session};
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::config::Config;
+use crate::errors::*;
+#[cfg(feature = "quic")]
+use crate::quic::QuicStream;
+
+/// A TLS listening address, with its certificate chain and private key.
+/// The certificate is reloaded when its files are modified (e.g. renewed by certbot), without
+/// dropping the connected clients (whose sessions keep using the previous one).
+#[derive(Clone)]
+pub struct TlsListener {
+    addr: SocketAddr,
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: Arc<Mutex<Certified>>,
+}
+
+struct Certified {
+    modified: (SystemTime, SystemTime), // of the certificate and key files
+    config: Arc<ServerConfig>,
+}
+
+impl TlsListener {
+    pub fn from_config(config: &Config) -> Result<Option<TlsListener>> {
+        let (addr, cert_path, key_path) =
+            match (config.electrum_tls_addr, &config.tls_cert, &config.tls_key) {
+                (Some(addr), Some(cert_path), Some(key_path)) => (addr, cert_path, key_path),
+                _ => return Ok(None),
+            };
+        let current = load(cert_path, key_path)?;
+        Ok(Some(TlsListener {
+            addr,
+            cert_path: cert_path.clone(),
+            key_path: key_path.clone(),
+            current: Arc::new(Mutex::new(current)),
+        }))
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Wraps an accepted TCP connection (the handshake is done by its first read).
+    pub fn accept(&self, tcp: TcpStream) -> Stream {
+        let config = Arc::clone(&self.current.lock().unwrap().config);
+        Stream::Tls(TlsStream {
+            session: Arc::new(Mutex::new(ServerSession::new(&config))),
+            tcp,
+        })
+    }
+
+    /// Reloads the certificate and key if their files were modified (keeping the current ones
+    /// if the new files are invalid, e.g. while they are being written).
+    pub fn reload(&self) {
+        let modified = match (modified(&self.cert_path), modified(&self.key_path)) {
+            (Ok(cert), Ok(key)) => (cert, key),
+            _ => return,
+        };
+        let mut current = self.current.lock().unwrap();
+        if current.modified == modified {
+            return;
+        }
+        match load(&self.cert_path, &self.key_path) {
+            Ok(reloaded) => {
+                info!("reloaded TLS certificate {}", self.cert_path.display());
+                *current = reloaded;
+            }
+            Err(e) => warn!("failed to reload TLS certificate: {}", e.display_chain()),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Result<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .chain_err(|| format!("failed to stat {}", path.display()))
+}
+
+fn load(cert_path: &Path, key_path: &Path) -> Result<Certified> {
+    // read before loading, so a concurrent renewal is detected by the next reload
+    let modified = (modified(cert_path)?, modified(key_path)?);
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(load_certs(cert_path)?, load_key(key_path)?)
+        .chain_err(|| format!("invalid TLS certificate or key {}", key_path.display()))?;
+    Ok(Certified {
+        modified,
+        config: Arc::new(config),
+    })
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).chain_err(|| format!("failed to open {}", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+pub(crate) fn load_certs(path: &Path) -> Result<Vec<rustls::Certificate>> {
+    let chain = certs(&mut open(path)?)
+        .map_err(|()| Error::from(format!("invalid PEM certificates {}", path.display())))?;
+    if chain.is_empty() {
+        bail!("no certificates found in {}", path.display());
+    }
+    Ok(chain)
+}
+
+// Supports both PKCS#8 ("BEGIN PRIVATE KEY") and RSA ("BEGIN RSA PRIVATE KEY") keys.
+pub(crate) fn load_key(path: &Path) -> Result<rustls::PrivateKey> {
+    let invalid = |()| Error::from(format!("invalid PEM private key {}", path.display()));
+    let mut keys = pkcs8_private_keys(&mut open(path)?).map_err(invalid)?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open(path)?).map_err(invalid)?;
+    }
+    keys.into_iter()
+        .next()
+        .chain_err(|| format!("no private key found in {}", path.display()))
+}
+
+/// An Electrum client's connection (which may be encrypted).
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(TlsStream),
+    #[cfg(feature = "quic")]
+    Quic(QuicStream),
+}
+
+impl Stream {
+    /// Returns another handle to the same connection (e.g. for reading the requests in a
+    /// separate thread).
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        Ok(match self {
+            Stream::Plain(tcp) => Stream::Plain(tcp.try_clone()?),
+            Stream::Tls(tls) => Stream::Tls(TlsStream {
+                session: Arc::clone(&tls.session),
+                tcp: tls.tcp.try_clone()?,
+            }),
+            #[cfg(feature = "quic")]
+            Stream::Quic(quic) => Stream::Quic(quic.try_clone()),
+        })
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.shutdown(how),
+            Stream::Tls(tls) => {
+                {
+                    let mut session = tls.session.lock().unwrap();
+                    session.send_close_notify();
+                    let _ = write_records(&mut session, &mut tls.tcp);
+                }
+                tls.tcp.shutdown(how)
+            }
+            #[cfg(feature = "quic")]
+            Stream::Quic(quic) => quic.shutdown(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+            #[cfg(feature = "quic")]
+            Stream::Quic(quic) => quic.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+            #[cfg(feature = "quic")]
+            Stream::Quic(quic) => quic.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            Stream::Tls(tls) => tls.flush(),
+            #[cfg(feature = "quic")]
+            Stream::Quic(quic) => quic.flush(),
+        }
+    }
+}
+
+/// The TLS session is shared by the connection's reader and writer handles, and it is locked
+/// only while processing records (not while waiting for the client's data).
+pub struct TlsStream {
+    session: Arc<Mutex<ServerSession>>,
+    tcp: TcpStream,
+}
+
+// Sends the pending TLS records (handshake messages, alerts or encrypted data).
+fn write_records(session: &mut ServerSession, tcp: &mut TcpStream) -> io::Result<()> {
+    while session.wants_write() {
+        session.write_tls(tcp)?;
+    }
+    Ok(())
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut records = [0u8; 16 * 1024];
+        loop {
+            match self.session.lock().unwrap().read(buf) {
+                Ok(0) => (), // no decrypted data yet
+                Ok(n) => return Ok(n),
+                // the client has sent a `close_notify` alert
+                Err(ref e) if e.kind() == io::ErrorKind::ConnectionAborted => return Ok(0),
+                Err(e) => return Err(e),
+            }
+            let n = self.tcp.read(&mut records)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let mut session = self.session.lock().unwrap();
+            let mut received = &records[..n];
+            while !received.is_empty() {
+                session.read_tls(&mut received)?;
+                let result = session.process_new_packets();
+                write_records(&mut session, &mut self.tcp)?; // e.g. handshake replies or alerts
+                result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut session = self.session.lock().unwrap();
+        let n = session.write(buf)?; // buffered until the handshake is done
+        write_records(&mut session, &mut self.tcp)?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        write_records(&mut self.session.lock().unwrap(), &mut self.tcp)?;
+        self.tcp.flush()
+    }
+}
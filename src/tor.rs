@@ -0,0 +1,260 @@
+//! Publishes an onion (v3) service for the Electrum RPC, using Tor's control port (see
+//! https://spec.torproject.org/control-spec), so Tor users can connect without a manually
+//! configured hidden service.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+use crate::errors::*;
+
+const KEY_FILE: &str = "onion_v3_key"; // so the service keeps its address after restarts
+
+/// An onion service, which is removed by Tor when its control connection is closed.
+pub struct OnionService {
+    host: String, // e.g. "<56 base32 characters>.onion"
+    port: u16,
+    _control: Control,
+}
+
+impl OnionService {
+    pub fn from_config(config: &Config) -> Result<Option<OnionService>> {
+        let control_addr = match config.tor_control_addr {
+            Some(addr) => addr,
+            None => return Ok(None),
+        };
+        let mut target = config.electrum_rpc_addr;
+        if target.ip().is_unspecified() {
+            target.set_ip(match target.ip() {
+                IpAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                IpAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let mut control = Control::connect(control_addr)?;
+        control.authenticate(config.tor_control_password.as_deref())?;
+        let key_path = config.db_path.join(KEY_FILE);
+        let (host, new_key) = control.add_onion(&key_path, target)?;
+        if let Some(key) = new_key {
+            save_key(&key_path, &key)?;
+        }
+        Ok(Some(OnionService {
+            host,
+            port: target.port(),
+            _control: control,
+        }))
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+fn save_key(path: &Path, key: &str) -> Result<()> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600) // the key allows impersonating the service
+        .open(path)
+        .and_then(|mut file| file.write_all(key.as_bytes()))
+        .chain_err(|| format!("failed to save onion service key to {}", path.display()))
+}
+
+struct Control {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Control {
+    fn connect(addr: SocketAddr) -> Result<Control> {
+        let writer = TcpStream::connect(addr)
+            .chain_err(|| format!("failed to connect to Tor control port at {}", addr))?;
+        let reader = BufReader::new(writer.try_clone().chain_err(|| "failed to clone")?);
+        Ok(Control { reader, writer })
+    }
+
+    // Returns the reply's lines (without their status codes), failing on an error reply.
+    fn command(&mut self, command: &str) -> Result<Vec<String>> {
+        let name = command.split(' ').next().unwrap_or_default().to_owned();
+        self.writer
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .chain_err(|| format!("failed to send {} command", name))?;
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            self.reader
+                .read_line(&mut line)
+                .chain_err(|| format!("failed to read {} reply", name))?;
+            let line = line.trim_end();
+            if line.len() < 4 || !line.is_char_boundary(4) {
+                bail!("invalid {} reply: {:?}", name, line);
+            }
+            let (status, text) = (&line[..3], &line[4..]);
+            if status != "250" {
+                bail!("{} failed: {} {}", name, status, text);
+            }
+            lines.push(text.to_owned());
+            match &line[3..4] {
+                " " => return Ok(lines),
+                "-" => (),
+                "+" => self.skip_data()?, // unused multi-line values
+                _ => bail!("invalid {} reply: {:?}", name, line),
+            }
+        }
+    }
+
+    fn skip_data(&mut self) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            let n = self
+                .reader
+                .read_line(&mut line)
+                .chain_err(|| "failed to read reply")?;
+            if n == 0 || line.trim_end() == "." {
+                return Ok(());
+            }
+        }
+    }
+
+    // Uses the password (if configured), or the cookie file (if readable by electrs).
+    fn authenticate(&mut self, password: Option<&str>) -> Result<()> {
+        let (methods, cookie_file) = parse_auth(&self.command("PROTOCOLINFO 1")?)?;
+        let command = match password {
+            Some(password) => format!("AUTHENTICATE {}", quote(password)),
+            None if methods.iter().any(|m| m == "NULL") => "AUTHENTICATE".to_owned(),
+            None if methods.iter().any(|m| m == "COOKIE") => {
+                let path = cookie_file.chain_err(|| "missing Tor cookie file")?;
+                let cookie = fs::read(&path)
+                    .chain_err(|| format!("failed to read Tor cookie file {}", path.display()))?;
+                format!("AUTHENTICATE {}", hex::encode(cookie))
+            }
+            None => bail!(
+                "unsupported Tor authentication methods {:?} (set tor_control_password)",
+                methods
+            ),
+        };
+        self.command(&command)?;
+        Ok(())
+    }
+
+    // Returns the service's host, and its new private key (if it wasn't saved before).
+    fn add_onion(
+        &mut self,
+        key_path: &Path,
+        target: SocketAddr,
+    ) -> Result<(String, Option<String>)> {
+        let key = match fs::read_to_string(key_path) {
+            Ok(key) => Some(key.trim().to_owned()),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => bail!("failed to read {}: {}", key_path.display(), e),
+        };
+        let command = format!(
+            "ADD_ONION {} Port={},{}",
+            key.as_deref().unwrap_or("NEW:ED25519-V3"),
+            target.port(),
+            target
+        );
+        let reply = self.command(&command)?;
+        let service_id = reply
+            .iter()
+            .find_map(|line| value(line, "ServiceID="))
+            .chain_err(|| "missing onion ServiceID")?;
+        let new_key = match key {
+            Some(_) => None,
+            None => Some(
+                reply
+                    .iter()
+                    .find_map(|line| value(line, "PrivateKey="))
+                    .chain_err(|| "missing onion PrivateKey")?,
+            ),
+        };
+        Ok((format!("{}.onion", service_id), new_key))
+    }
+}
+
+fn value(line: &str, prefix: &str) -> Option<String> {
+    if line.starts_with(prefix) {
+        Some(line[prefix.len()..].to_owned())
+    } else {
+        None
+    }
+}
+
+// e.g. `AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE="/run/tor/control.authcookie"`
+fn parse_auth(reply: &[String]) -> Result<(Vec<String>, Option<PathBuf>)> {
+    let auth = reply
+        .iter()
+        .find_map(|line| value(line, "AUTH "))
+        .chain_err(|| "missing Tor authentication methods")?;
+    let methods = auth
+        .split(' ')
+        .find_map(|field| value(field, "METHODS="))
+        .map(|names| names.split(',').map(str::to_owned).collect())
+        .unwrap_or_default();
+    // the last field, since its (quoted) path may contain spaces
+    let cookie_file = match auth.find("COOKIEFILE=") {
+        Some(i) => Some(PathBuf::from(unquote(&auth[i + "COOKIEFILE=".len()..])?)),
+        None => None,
+    };
+    Ok((methods, cookie_file))
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn unquote(quoted: &str) -> Result<String> {
+    if quoted.len() < 2 || !quoted.starts_with('"') || !quoted.ends_with('"') {
+        bail!("invalid quoted string: {}", quoted);
+    }
+    let mut result = String::new();
+    let mut chars = quoted[1..quoted.len() - 1].chars();
+    while let Some(c) = chars.next() {
+        result.push(match c {
+            '\\' => chars.next().chain_err(|| "invalid escape")?,
+            c => c,
+        });
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_auth, quote, unquote};
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse_auth() {
+        let reply: Vec<String> = vec![
+            "PROTOCOLINFO 1".to_owned(),
+            r#"AUTH METHODS=COOKIE,SAFECOOKIE COOKIEFILE="/var/lib/my tor/control_auth_cookie""#
+                .to_owned(),
+            r#"VERSION Tor="0.4.5.10""#.to_owned(),
+            "OK".to_owned(),
+        ];
+        let (methods, cookie_file) = parse_auth(&reply).unwrap();
+        assert_eq!(methods, vec!["COOKIE", "SAFECOOKIE"]);
+        assert_eq!(
+            cookie_file,
+            Some(PathBuf::from("/var/lib/my tor/control_auth_cookie"))
+        );
+
+        let reply = vec!["AUTH METHODS=NULL".to_owned()];
+        assert_eq!(parse_auth(&reply).unwrap(), (vec!["NULL".to_owned()], None));
+        assert!(parse_auth(&["OK".to_owned()]).is_err());
+    }
+
+    #[test]
+    fn test_quote() {
+        let password = r#"a "quoted" \ password"#;
+        assert_eq!(quote(password), r#""a \"quoted\" \\ password""#);
+        assert_eq!(unquote(&quote(password)).unwrap(), password);
+        assert!(unquote("unquoted").is_err());
+    }
+}
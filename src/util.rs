@@ -1,18 +1,20 @@
-use bitcoin::blockdata::block::BlockHeader;
-use bitcoin::hash_types::BlockHash;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::fmt;
 use std::iter::FromIterator;
+use std::panic::{self, AssertUnwindSafe};
 use std::slice;
 use std::sync::mpsc::{channel, sync_channel, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::thread;
 
+use crate::chain::{BlockHash, BlockHeader};
+
 pub type Bytes = Vec<u8>;
 pub type HeaderMap = HashMap<BlockHash, BlockHeader>;
 
 // TODO: consolidate serialization/deserialize code for bincode/bitcoin.
-const HASH_LEN: usize = 32;
+pub const HASH_LEN: usize = 32;
 pub const HASH_PREFIX_LEN: usize = 8;
 
 pub type FullHash = [u8; HASH_LEN];
@@ -28,6 +30,11 @@ pub fn full_hash(hash: &[u8]) -> FullHash {
     hash.try_into().expect("failed to convert into FullHash")
 }
 
+/// Compares all the bytes (e.g. of a secret token), to avoid leaking them via timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 #[derive(Eq, PartialEq, Clone)]
 pub struct HeaderEntry {
     height: usize,
@@ -266,6 +273,89 @@ impl<T> Channel<T> {
     }
 }
 
+/// Pins a consistent snapshot of the indexed chain and the mempool: requests hold it for reading,
+/// while their updates wait for it (and block new snapshots), so the updates are not starved by a
+/// steady stream of requests.
+#[derive(Default)]
+pub struct SnapshotLock {
+    turnstile: Mutex<()>, // taken by a waiting update, so new snapshots wait behind it
+    lock: RwLock<()>,
+}
+
+impl SnapshotLock {
+    pub fn read(&self) -> RwLockReadGuard<()> {
+        let _turn = self.turnstile.lock().unwrap();
+        self.lock.read().unwrap()
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<()> {
+        let _turn = self.turnstile.lock().unwrap();
+        self.lock.write().unwrap()
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed set of worker threads, shared by the requests which split their work into chunks (so
+/// concurrent requests queue for the workers, instead of multiplying the server's threads).
+/// The workers exit when the pool is dropped.
+pub struct WorkerPool {
+    jobs: crossbeam_channel::Sender<Job>,
+    threads: usize,
+}
+
+impl WorkerPool {
+    pub fn new(name: &str, threads: usize) -> WorkerPool {
+        let (jobs, receiver) = crossbeam_channel::unbounded::<Job>();
+        for _ in 0..threads {
+            let receiver = receiver.clone();
+            spawn_thread(name, move || {
+                for job in receiver.iter() {
+                    // a panicking job fails its `map()` call, but keeps the worker
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                }
+            });
+        }
+        WorkerPool { jobs, threads }
+    }
+
+    /// Maps `items` by `f` (in up to a chunk per worker), returning the results in order.
+    /// Inputs shorter than `min_len` are mapped by the calling thread.
+    pub fn map<T, R, F>(&self, items: Vec<T>, min_len: usize, f: F) -> Vec<R>
+    where
+        T: Send + 'static,
+        R: Send + 'static,
+        F: Fn(T) -> R + Send + Sync + 'static,
+    {
+        if self.threads <= 1 || items.len() < min_len.max(2) {
+            return items.into_iter().map(f).collect();
+        }
+        let chunk_len = (items.len() + self.threads - 1) / self.threads;
+        let f = Arc::new(f);
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut items = items.into_iter();
+        let mut chunks = 0;
+        loop {
+            let chunk: Vec<T> = items.by_ref().take(chunk_len).collect();
+            if chunk.is_empty() {
+                break;
+            }
+            let (f, tx, index) = (Arc::clone(&f), tx.clone(), chunks);
+            let job = move || {
+                let results: Vec<R> = chunk.into_iter().map(|item| f(item)).collect();
+                let _ = tx.send((index, results));
+            };
+            self.jobs.send(Box::new(job)).expect("worker pool stopped");
+            chunks += 1;
+        }
+        drop(tx);
+        let mut results: Vec<(usize, Vec<R>)> = rx.iter().collect();
+        assert_eq!(results.len(), chunks, "worker pool job panicked");
+        results.sort_by_key(|(index, _)| *index);
+        results.into_iter().flat_map(|(_, chunk)| chunk).collect()
+    }
+}
+
 pub fn spawn_thread<F, T>(name: &str, f: F) -> thread::JoinHandle<T>
 where
     F: FnOnce() -> T,
@@ -280,6 +370,55 @@ where
 
 #[cfg(test)]
 mod tests {
+    #[test]
+    fn test_snapshot_lock() {
+        use super::SnapshotLock;
+        use std::sync::{mpsc, Arc};
+        use std::thread;
+        use std::time::Duration;
+
+        let lock = Arc::new(SnapshotLock::default());
+        let first = lock.read();
+        let second = lock.read(); // snapshots don't exclude each other
+        let (tx, rx) = mpsc::channel();
+        let updater = {
+            let lock = Arc::clone(&lock);
+            thread::spawn(move || {
+                let _update = lock.write();
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err()); // waits for the snapshots
+        drop((first, second));
+        rx.recv().unwrap();
+        updater.join().unwrap();
+        let _snapshot = lock.read();
+    }
+
+    #[test]
+    fn test_worker_pool() {
+        use super::WorkerPool;
+
+        let items: Vec<u64> = (0..1000).collect();
+        let expected: Vec<u64> = items.iter().map(|i| i * i).collect();
+        for threads in 0..5 {
+            let pool = WorkerPool::new("test", threads);
+            assert_eq!(pool.map(items.clone(), 0, |i| i * i), expected);
+            assert_eq!(pool.map(items.clone(), 2000, |i| i * i), expected);
+            assert!(pool.map(vec![], 0, |i: u64| i).is_empty());
+        }
+        let pool = WorkerPool::new("test", 2);
+        let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.map(
+                items.clone(),
+                0,
+                |i| if i == 999 { panic!("oops") } else { i },
+            )
+        }));
+        assert!(panicked.is_err());
+        assert_eq!(pool.map(items.clone(), 0, |i| i * i), expected); // the workers survive
+    }
+
     #[test]
     fn test_headers() {
         use bitcoin::blockdata::block::BlockHeader;
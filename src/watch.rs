@@ -0,0 +1,102 @@
+//! Monitoring the addresses of a bitcoind (e.g. watch-only) wallet, without exporting them: the
+//! wallet's addresses are imported (and re-imported as the wallet adds new ones), their statuses
+//! are persisted in the index (as for subscribed script hashes, so clients querying them are
+//! served quickly), and a `wallet_activity` event is notified whenever one of them changes.
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::hashes::Hash;
+use std::collections::{HashMap, HashSet};
+
+use crate::config::Config;
+use crate::daemon::Daemon;
+use crate::errors::*;
+use crate::hooks::{Event, Hooks};
+use crate::index::compute_script_hash;
+use crate::query::{Query, StatusHasher};
+use crate::util::FullHash;
+
+struct Watched {
+    address: String,
+    status_hash: Option<FullHash>,
+    hasher: StatusHasher,
+}
+
+pub struct WalletWatcher {
+    daemon: Daemon, // connected to the wallet's endpoint
+    wallet: String,
+    addresses: HashSet<String>,
+    watched: HashMap<FullHash, Watched>, // by script hash
+    txid_limit: usize,
+}
+
+impl WalletWatcher {
+    pub fn from_config(config: &Config, daemon: &Daemon) -> Result<Option<WalletWatcher>> {
+        let wallet = match config.import_wallet {
+            Some(ref wallet) => wallet.clone(),
+            None => return Ok(None),
+        };
+        if config.headers_only {
+            bail!("importing a wallet requires a full index (not --headers-only)");
+        }
+        Ok(Some(WalletWatcher {
+            daemon: daemon.wallet(&wallet)?,
+            wallet,
+            addresses: HashSet::new(),
+            watched: HashMap::new(),
+            txid_limit: config.txid_limit,
+        }))
+    }
+
+    pub fn wallet(&self) -> &str {
+        &self.wallet
+    }
+
+    // Imports the wallet's new addresses (their current statuses aren't notified).
+    fn import(&mut self, query: &Query) -> Result<()> {
+        let new_addresses = self.daemon.list_wallet_addresses(&self.addresses)?;
+        if new_addresses.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "importing {} addresses from wallet {:?}",
+            new_addresses.len(),
+            self.wallet
+        );
+        for (address, script) in new_addresses {
+            let script_hash = compute_script_hash(&script);
+            let mut hasher = StatusHasher::default();
+            let status = query.subscribed_status(&script_hash, self.txid_limit)?;
+            let status_hash = query.status_hash(&status, &mut hasher);
+            self.addresses.insert(address.clone());
+            self.watched.insert(
+                script_hash,
+                Watched {
+                    address,
+                    status_hash,
+                    hasher,
+                },
+            );
+        }
+        Ok(())
+    }
+
+    /// Imports the wallet's new addresses, and notifies about the watched addresses whose status
+    /// has changed (e.g. after a new block or mempool transaction).
+    pub fn update(&mut self, query: &Query, hooks: &Hooks) -> Result<()> {
+        self.import(query)
+            .chain_err(|| format!("failed to import wallet {:?}", self.wallet))?;
+        for (script_hash, watched) in self.watched.iter_mut() {
+            let status = query.subscribed_status(script_hash, self.txid_limit)?;
+            let status_hash = query.status_hash(&status, &mut watched.hasher);
+            if status_hash == watched.status_hash {
+                continue;
+            }
+            watched.status_hash = status_hash;
+            hooks.notify(Event::WalletActivity {
+                address: watched.address.clone(),
+                script_hash: Sha256dHash::from_inner(*script_hash).to_hex(),
+            });
+        }
+        Ok(())
+    }
+}